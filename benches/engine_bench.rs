@@ -1,5 +1,5 @@
 use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use kvs::{KvStore, KvStoreOptions, KvsEngine, LsmKvsEngine, LsmOptions, SledKvsEngine};
 use rand::prelude::*;
 use tempfile::TempDir;
 
@@ -11,7 +11,7 @@ fn set_bench(c: &mut Criterion) {
                 let temp_dir = TempDir::new().unwrap();
                 (KvStore::open(temp_dir.path()).unwrap(), temp_dir)
             },
-            |(mut store, _temp_dir)| {
+            |(store, _temp_dir)| {
                 for i in 1..(1 << 12) {
                     store.set(format!("key{}", i), "value".to_string()).unwrap();
                 }
@@ -25,7 +25,21 @@ fn set_bench(c: &mut Criterion) {
                 let temp_dir = TempDir::new().unwrap();
                 (SledKvsEngine::open(temp_dir.path()).unwrap(), temp_dir)
             },
-            |(mut db, _temp_dir)| {
+            |(db, _temp_dir)| {
+                for i in 1..(1 << 12) {
+                    db.set(format!("key{}", i), "value".to_string()).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("lsm", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (LsmKvsEngine::open(temp_dir.path()).unwrap(), temp_dir)
+            },
+            |(db, _temp_dir)| {
                 for i in 1..(1 << 12) {
                     db.set(format!("key{}", i), "value".to_string()).unwrap();
                 }
@@ -38,10 +52,31 @@ fn set_bench(c: &mut Criterion) {
 
 fn get_bench(c: &mut Criterion) {
     let mut group = c.benchmark_group("get_bench");
-    for i in &vec![8, 12, 16, 20] {
+    for i in &[8, 12, 16, 20] {
         group.bench_with_input(format!("kvs_{}", i), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
-            let mut store = KvStore::open(temp_dir.path()).unwrap();
+            let store = KvStore::open(temp_dir.path()).unwrap();
+            for key_i in 1..(1 << i) {
+                store
+                    .set(format!("key{}", key_i), "value".to_string())
+                    .unwrap();
+            }
+            let mut rng = SmallRng::from_seed([0; 32]);
+            b.iter(|| {
+                store
+                    .get(format!("key{}", rng.gen_range(1..1 << i)))
+                    .unwrap();
+            })
+        });
+    }
+    for i in &[8, 12, 16, 20] {
+        group.bench_with_input(format!("kvs_mmap_{}", i), i, |b, i| {
+            let temp_dir = TempDir::new().unwrap();
+            let options = KvStoreOptions {
+                mmap_reads: true,
+                ..KvStoreOptions::default()
+            };
+            let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
             for key_i in 1..(1 << i) {
                 store
                     .set(format!("key{}", key_i), "value".to_string())
@@ -55,10 +90,24 @@ fn get_bench(c: &mut Criterion) {
             })
         });
     }
-    for i in &vec![8, 12, 16, 20] {
+    for i in &[8, 12, 16, 20] {
         group.bench_with_input(format!("sled_{}", i), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
-            let mut db = SledKvsEngine::open(temp_dir.path()).unwrap();
+            let db = SledKvsEngine::open(temp_dir.path()).unwrap();
+            for key_i in 1..(1 << i) {
+                db.set(format!("key{}", key_i), "value".to_string())
+                    .unwrap();
+            }
+            let mut rng = SmallRng::from_seed([0; 32]);
+            b.iter(|| {
+                db.get(format!("key{}", rng.gen_range(1..1 << i))).unwrap();
+            })
+        });
+    }
+    for i in &[8, 12, 16, 20] {
+        group.bench_with_input(format!("lsm_{}", i), i, |b, i| {
+            let temp_dir = TempDir::new().unwrap();
+            let db = LsmKvsEngine::open(temp_dir.path()).unwrap();
             for key_i in 1..(1 << i) {
                 db.set(format!("key{}", key_i), "value".to_string())
                     .unwrap();
@@ -72,5 +121,69 @@ fn get_bench(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, set_bench, get_bench);
+fn scan_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("scan_bench");
+    let (kvs_dir, sled_dir, lsm_dir) = (
+        TempDir::new().unwrap(),
+        TempDir::new().unwrap(),
+        TempDir::new().unwrap(),
+    );
+    let store = KvStore::open(kvs_dir.path()).unwrap();
+    let db = SledKvsEngine::open(sled_dir.path()).unwrap();
+    let lsm = LsmKvsEngine::open(lsm_dir.path()).unwrap();
+    for key_i in 0..(1 << 14) {
+        let key = format!("key{:05}", key_i);
+        store.set(key.clone(), "value".to_string()).unwrap();
+        db.set(key.clone(), "value".to_string()).unwrap();
+        lsm.set(key, "value".to_string()).unwrap();
+    }
+    let range = || "key01000".to_string().."key02000".to_string();
+    group.bench_function("kvs", |b| b.iter(|| store.scan(range()).unwrap()));
+    group.bench_function("sled", |b| b.iter(|| db.scan(range()).unwrap()));
+    group.bench_function("lsm", |b| b.iter(|| lsm.scan(range()).unwrap()));
+    group.finish();
+}
+
+// Gets of keys that were never written, the case the bloom filters of the
+// sled and lsm engines short-circuit.
+fn miss_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("miss_bench");
+    let (kvs_dir, sled_dir, lsm_dir) = (
+        TempDir::new().unwrap(),
+        TempDir::new().unwrap(),
+        TempDir::new().unwrap(),
+    );
+    let store = KvStore::open(kvs_dir.path()).unwrap();
+    let db = SledKvsEngine::open(sled_dir.path()).unwrap();
+    let lsm = LsmKvsEngine::open_with_options(
+        lsm_dir.path(),
+        LsmOptions {
+            memtable_size: 64 * 1024,
+            ..LsmOptions::default()
+        },
+    )
+    .unwrap();
+    for key_i in 0..(1 << 16) {
+        let key = format!("key{}", key_i);
+        store.set(key.clone(), "value".to_string()).unwrap();
+        db.set(key.clone(), "value".to_string()).unwrap();
+        lsm.set(key, "value".to_string()).unwrap();
+    }
+    // nine misses for every hit
+    let key = |rng: &mut SmallRng| {
+        let i = rng.gen_range(0..1 << 16);
+        if rng.gen_ratio(9, 10) {
+            format!("missing{}", i)
+        } else {
+            format!("key{}", i)
+        }
+    };
+    let mut rng = SmallRng::from_seed([0; 32]);
+    group.bench_function("kvs", |b| b.iter(|| store.get(key(&mut rng)).unwrap()));
+    group.bench_function("sled", |b| b.iter(|| db.get(key(&mut rng)).unwrap()));
+    group.bench_function("lsm", |b| b.iter(|| lsm.get(key(&mut rng)).unwrap()));
+    group.finish();
+}
+
+criterion_group!(benches, set_bench, get_bench, scan_bench, miss_bench);
 criterion_main!(benches);
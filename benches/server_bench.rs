@@ -0,0 +1,92 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use kvs::{AsyncKvsClient, KvsClient};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+/// Blocking clients, each on a thread of its own.
+const THREADS: usize = 8;
+/// Async clients, each a task.
+const TASKS: usize = 64;
+const REQUESTS: usize = 50;
+
+/// kvs-server, killed when dropped.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn server(runtime: &str, addr: &str) -> (Server, TempDir) {
+    let dir = TempDir::new().unwrap();
+    let child = Command::new(env!("CARGO_BIN_EXE_kvs-server"))
+        .args(["--addr", addr, "--runtime", runtime])
+        .current_dir(dir.path())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+    (Server(child), dir)
+}
+
+fn server_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("server_bench");
+    let tokio = Runtime::new().unwrap();
+    for (runtime, addr) in [("threads", "127.0.0.1:4100"), ("async", "127.0.0.1:4101")] {
+        let (_server, _dir) = server(runtime, addr);
+        let clients: Vec<KvsClient> = (0..THREADS)
+            .map(|_| KvsClient::connect(addr).unwrap())
+            .collect();
+        group.throughput(Throughput::Elements((THREADS * REQUESTS) as u64));
+        group.bench_function(format!("{}/set", runtime), |b| {
+            b.iter(|| {
+                thread::scope(|scope| {
+                    for (i, client) in clients.iter().enumerate() {
+                        scope.spawn(move || {
+                            for j in 0..REQUESTS {
+                                let key = format!("key{}", i * REQUESTS + j);
+                                client.set(key, "value".to_owned()).unwrap();
+                            }
+                        });
+                    }
+                })
+            })
+        });
+
+        let mut clients = tokio.block_on(async {
+            let mut clients = Vec::new();
+            for _ in 0..TASKS {
+                clients.push(AsyncKvsClient::connect(addr).await.unwrap());
+            }
+            clients
+        });
+        group.throughput(Throughput::Elements((TASKS * REQUESTS) as u64));
+        group.bench_function(format!("{}/get", runtime), |b| {
+            b.iter(|| {
+                tokio.block_on(async {
+                    let gets = clients.drain(..).enumerate().map(|(i, mut client)| {
+                        tokio::spawn(async move {
+                            for j in 0..REQUESTS {
+                                let key = format!("key{}", (i * REQUESTS + j) % 400);
+                                client.get(key).await.unwrap();
+                            }
+                            client
+                        })
+                    });
+                    let gets: Vec<_> = gets.collect();
+                    for get in gets {
+                        clients.push(get.await.unwrap());
+                    }
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, server_bench);
+criterion_main!(benches);
@@ -0,0 +1,160 @@
+use std::io;
+
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::time;
+
+use super::{write_frame, write_json, JsonReader};
+use crate::client::{done, offered, value};
+use crate::{
+    Capability, Envelope, KvsClientOptions, KvsError, Reply, Request, Response, Result, Welcome,
+    PROTOCOL_VERSION,
+};
+
+/// Client of one kvs-server for async code, over one connection. Unlike
+/// `KvsClient` it does not shard keys nor follow cluster redirects. After an
+/// error the connection may be out of step, and the client should be
+/// dropped.
+#[derive(Debug)]
+pub struct AsyncKvsClient {
+    conn: JsonReader<TcpStream>,
+    options: KvsClientOptions,
+    welcome: Welcome,
+    /// ID of the last request sent in a frame.
+    last_id: u64,
+}
+
+impl AsyncKvsClient {
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<AsyncKvsClient> {
+        AsyncKvsClient::connect_with_options(addr, KvsClientOptions::default()).await
+    }
+
+    /// Connects with `options`, which may not ask for TLS: the async
+    /// client speaks in the clear only.
+    pub async fn connect_with_options(
+        addr: impl ToSocketAddrs,
+        options: KvsClientOptions,
+    ) -> Result<AsyncKvsClient> {
+        if options.tls.is_some() {
+            return Err(KvsError::Tls(
+                "the async client does not speak TLS".to_owned(),
+            ));
+        }
+        let stream = time::timeout(options.connect_timeout, TcpStream::connect(addr))
+            .await
+            .map_err(|_| timed_out())??;
+        let mut client = AsyncKvsClient {
+            conn: JsonReader::new(stream),
+            options,
+            welcome: Welcome::unversioned(),
+            last_id: 0,
+        };
+        client.welcome = client.hello().await?;
+        if let Some(credentials) = client.options.credentials.clone() {
+            done(client.request(&Request::Auth { credentials }).await?)?;
+        }
+        Ok(client)
+    }
+
+    /// What the server agreed to: the protocol version requests are sent
+    /// in, and the capabilities both sides support.
+    pub fn welcome(&self) -> &Welcome {
+        &self.welcome
+    }
+
+    /// Offers the protocol version and capabilities of this client, like
+    /// `KvsClient` does.
+    async fn hello(&mut self) -> Result<Welcome> {
+        let mut capabilities = offered(&self.options);
+        // requests go out one at a time
+        capabilities.remove(&Capability::Pipelining);
+        let hello = Request::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities,
+        };
+        match self.request(&hello).await?.into_reply() {
+            Ok(Reply::Welcome(welcome)) => Ok(welcome),
+            Ok(reply) => Err(reply.unexpected()),
+            Err(KvsError::InvalidRequest(_) | KvsError::UnsupportedVersion(_)) => {
+                Ok(Welcome::unversioned())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Gets the value of `key`, `None` if it is missing.
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        value(self.request(&Request::Get { key, ns: None }).await?)
+    }
+
+    /// Sets the value of `key`.
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        done(
+            self.request(&Request::Set {
+                key,
+                value,
+                ns: None,
+            })
+            .await?,
+        )
+    }
+
+    /// Removes `key`.
+    ///
+    /// # Errors
+    ///
+    /// `KvsError::KeyNotFound` if the key is missing.
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        done(self.request(&Request::Rm { key, ns: None }).await?)
+    }
+
+    /// Sends `op` and returns the response. Streams are not supported.
+    pub async fn request(&mut self, op: &Request) -> Result<Response> {
+        if let Request::Watch { .. } | Request::Changes { .. } | Request::Replicate = op {
+            return Err(KvsError::UnsupportedOperation);
+        }
+        let envelope = Envelope {
+            version: self.welcome.version,
+            request: op,
+        };
+        let framed = self
+            .welcome
+            .capabilities
+            .contains(&Capability::BinaryFrames);
+        if framed {
+            self.last_id += 1;
+            write_frame(self.conn.get_mut(), self.last_id, &envelope).await?;
+        } else {
+            write_json(self.conn.get_mut(), &envelope).await?;
+        }
+        let read_timeout = self.options.read_timeout;
+        let response = self.receive(framed);
+        let response = match read_timeout {
+            Some(timeout) => time::timeout(timeout, response)
+                .await
+                .map_err(|_| timed_out())??,
+            None => response.await?,
+        };
+        response.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof).into())
+    }
+
+    /// Reads the response to the last request, `None` if the server hung up.
+    async fn receive(&mut self, framed: bool) -> Result<Option<Response>> {
+        if !framed {
+            return self.conn.next().await;
+        }
+        let Some(frame) = self.conn.next_frame().await? else {
+            return Ok(None);
+        };
+        if frame.id != self.last_id {
+            return Err(KvsError::Frame(format!(
+                "response to request {} while awaiting {}",
+                frame.id, self.last_id
+            )));
+        }
+        frame.message().map(Some)
+    }
+}
+
+fn timed_out() -> io::Error {
+    io::Error::from(io::ErrorKind::TimedOut)
+}
@@ -0,0 +1,64 @@
+use std::future::Future;
+use std::ops::RangeBounds;
+use std::panic;
+
+use crate::{KvsEngine, Result, Stats};
+
+/// `KvsEngine` for async code. Calls run on the blocking threads of tokio,
+/// so that disk reads and writes do not hold up other tasks. The futures
+/// own a clone of the engine, so engines need not be `Sync`.
+#[derive(Debug, Clone)]
+pub struct AsyncKvsEngine<E> {
+    engine: E,
+}
+
+impl<E: KvsEngine> AsyncKvsEngine<E> {
+    pub fn new(engine: E) -> AsyncKvsEngine<E> {
+        AsyncKvsEngine { engine }
+    }
+
+    pub fn engine(&self) -> &E {
+        &self.engine
+    }
+
+    /// Calls `f` with the engine on a blocking thread.
+    ///
+    /// # Panics
+    ///
+    /// If `f` panics, or the runtime shuts down before it returns.
+    pub fn run<T, F>(&self, f: F) -> impl Future<Output = T> + Send + 'static
+    where
+        T: Send + 'static,
+        F: FnOnce(&E) -> T + Send + 'static,
+    {
+        let engine = self.engine.clone();
+        async move {
+            tokio::task::spawn_blocking(move || f(&engine))
+                .await
+                .unwrap_or_else(|e| panic::resume_unwind(e.into_panic()))
+        }
+    }
+
+    pub fn get(&self, key: String) -> impl Future<Output = Result<Option<String>>> + Send {
+        self.run(move |engine| engine.get(key))
+    }
+
+    pub fn set(&self, key: String, value: String) -> impl Future<Output = Result<()>> + Send {
+        self.run(move |engine| engine.set(key, value))
+    }
+
+    pub fn remove(&self, key: String) -> impl Future<Output = Result<()>> + Send {
+        self.run(move |engine| engine.remove(key))
+    }
+
+    pub fn scan<R>(&self, range: R) -> impl Future<Output = Result<Vec<(String, String)>>> + Send
+    where
+        R: RangeBounds<String> + Send + 'static,
+    {
+        self.run(move |engine| engine.scan(range))
+    }
+
+    pub fn stats(&self) -> impl Future<Output = Result<Stats>> + Send {
+        self.run(|engine| engine.stats())
+    }
+}
@@ -0,0 +1,163 @@
+//! Async counterparts of the server side and client of kvs, on tokio.
+pub use self::client::AsyncKvsClient;
+pub use self::engine::AsyncKvsEngine;
+
+use std::io;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Deserializer;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::frame::take_frame;
+use crate::{Frame, Result, MAX_FRAME_LEN};
+
+mod client;
+mod engine;
+
+/// Bytes to make room for before each read.
+const READ_SIZE: usize = 4096;
+
+/// Reads back-to-back JSON values, like `serde_json::StreamDeserializer`
+/// does from blocking readers, or binary frames once a connection switches
+/// to them. Values are no longer than frames.
+#[derive(Debug)]
+pub struct JsonReader<R> {
+    inner: R,
+    /// Bytes read past the last value.
+    buf: Vec<u8>,
+    scan: Scan,
+}
+
+/// How far the next value in a `JsonReader` has been scanned, so that each
+/// read only scans the new bytes and the value is parsed once it is whole.
+#[derive(Debug, Default)]
+struct Scan {
+    pos: usize,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl Scan {
+    /// Scans `buf` on from where the last call stopped, and tells whether the
+    /// value it starts with may be whole. Values other than objects, arrays
+    /// and strings are left to the parser.
+    fn whole(&mut self, buf: &[u8]) -> bool {
+        while let Some(&byte) = buf.get(self.pos) {
+            self.pos += 1;
+            if self.in_string {
+                if self.escaped {
+                    self.escaped = false;
+                } else if byte == b'\\' {
+                    self.escaped = true;
+                } else if byte == b'"' {
+                    self.in_string = false;
+                    if self.depth == 0 {
+                        return true;
+                    }
+                }
+                continue;
+            }
+            match byte {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => self.depth += 1,
+                b'}' | b']' if self.depth > 1 => self.depth -= 1,
+                b'}' | b']' => return true,
+                _ if byte.is_ascii_whitespace() || self.depth > 0 => {}
+                _ => {
+                    self.pos -= 1;
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+impl<R: AsyncRead + Unpin> JsonReader<R> {
+    pub fn new(inner: R) -> JsonReader<R> {
+        JsonReader {
+            inner,
+            buf: Vec::new(),
+            scan: Scan::default(),
+        }
+    }
+
+    /// The reader, to write to if it is a connection.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// The reader back. Bytes read past the last value are lost.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Reads the next value, or `None` if the stream ends before one starts.
+    pub async fn next<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        loop {
+            if self.scan.whole(&self.buf) {
+                let mut values = Deserializer::from_slice(&self.buf).into_iter::<T>();
+                match values.next() {
+                    Some(Ok(value)) => {
+                        let end = values.byte_offset();
+                        self.buf.drain(..end);
+                        self.scan = Scan::default();
+                        return Ok(Some(value));
+                    }
+                    Some(Err(e)) if !e.is_eof() => return Err(e.into()),
+                    _ => {}
+                }
+            }
+            if self.buf.len() > MAX_FRAME_LEN {
+                let message = format!("JSON value over {} bytes", MAX_FRAME_LEN);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message).into());
+            }
+            self.buf.reserve(READ_SIZE);
+            if self.inner.read_buf(&mut self.buf).await? == 0 {
+                if self.buf.iter().all(u8::is_ascii_whitespace) {
+                    return Ok(None);
+                }
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+    }
+
+    /// Reads the next frame, or `None` if the stream ends before one starts.
+    pub async fn next_frame(&mut self) -> Result<Option<Frame>> {
+        loop {
+            if let Some(frame) = take_frame(&mut self.buf)? {
+                return Ok(Some(frame));
+            }
+            self.buf.reserve(READ_SIZE);
+            if self.inner.read_buf(&mut self.buf).await? == 0 {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+    }
+}
+
+/// Writes `value` as JSON in one write, lest Nagle's algorithm hold back
+/// part of it.
+pub async fn write_json<W, T>(writer: &mut W, value: &T) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    writer.write_all(&serde_json::to_vec(value)?).await?;
+    Ok(())
+}
+
+/// Writes `message` in a frame under `id`, in one write.
+pub async fn write_frame<W, T>(writer: &mut W, id: u64, message: &T) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    writer.write_all(&Frame::new(id, message)?.encode()).await?;
+    Ok(())
+}
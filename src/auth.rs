@@ -0,0 +1,349 @@
+//! Users of a server and what they may do.
+//!
+//! Users are read from a JSON file mapping their names to their credentials
+//! and grants:
+//!
+//! ```json
+//! {
+//!   "alice": { "password": "$argon2id$v=19$...", "grants": [{ "permission": "admin" }] },
+//!   "app": {
+//!     "token": "<SHA-256 of the token, in hex>",
+//!     "grants": [
+//!       { "prefix": "app/", "permission": "write" },
+//!       { "namespace": "users", "permission": "read" }
+//!     ]
+//!   }
+//! }
+//! ```
+//!
+//! A grant covers the keys starting with its `prefix`, all keys if it has
+//! none, in its `namespace`, every keyspace if it has none. Requests on
+//! several keys need a grant covering them all, and those on no key in
+//! particular, like `Stats` or `Changes`, one covering the whole keyspace.
+//! Cluster and replication requests need an admin grant covering every
+//! keyspace.
+//!
+//! Only the native protocol authenticates. `kvs-server` serves its RESP and
+//! HTTP front ends to anyone, and the nodes of a cluster authenticate to
+//! each other with a token.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::Arc;
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{prefix_range, KvsError, Request, Result};
+
+/// Hash of no user's password, checked for names that are no user's so that
+/// they take as long to turn away as wrong passwords, lest the time tell
+/// which names are users.
+const DUMMY_HASH: &str =
+    "$argon2id$v=19$m=19456,t=2,p=1$x8dJF2k7q7KC2xgTohSZZg$ze4D73rex40zq4ee37bKhCdVyhtjtR+BKkoe73sbOss";
+
+/// What a client authenticates with.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Credentials {
+    Password {
+        user: String,
+        password: String,
+    },
+    /// Token of a user, which stands for both its name and password.
+    Token(String),
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credentials::Password { user, .. } => f
+                .debug_struct("Password")
+                .field("user", user)
+                .finish_non_exhaustive(),
+            Credentials::Token(_) => f.write_str("Token(..)"),
+        }
+    }
+}
+
+/// What a grant allows, each permission including those before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Read,
+    Write,
+    /// Cluster membership and replication, besides writes.
+    Admin,
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Permission::Read => "read",
+            Permission::Write => "write",
+            Permission::Admin => "administer",
+        })
+    }
+}
+
+/// A permission on the keys starting with `prefix` in `namespace`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Grant {
+    /// Namespace of the keys, every keyspace if `None`.
+    #[serde(default)]
+    pub namespace: Option<String>,
+    #[serde(default)]
+    pub prefix: String,
+    pub permission: Permission,
+}
+
+impl Grant {
+    /// Whether the grant covers every key `scope` stands for in the
+    /// keyspace `ns`.
+    fn covers(&self, ns: Option<&str>, scope: &Scope) -> bool {
+        if self.namespace.is_some() && self.namespace.as_deref() != ns {
+            return false;
+        }
+        if self.prefix.is_empty() {
+            return true;
+        }
+        match scope {
+            Scope::Key(key) => key.starts_with(&self.prefix),
+            Scope::Prefix(prefix) => prefix.starts_with(&self.prefix),
+            Scope::Range(start, end) => {
+                let (_, prefix_end) = prefix_range(&self.prefix);
+                let after_start = match start {
+                    Bound::Included(key) | Bound::Excluded(key) => *key >= self.prefix,
+                    Bound::Unbounded => false,
+                };
+                let before_end = match (end, prefix_end) {
+                    (_, Bound::Unbounded) => true,
+                    (Bound::Included(key), Bound::Excluded(limit)) => *key < limit,
+                    (Bound::Excluded(key), Bound::Excluded(limit)) => *key <= limit,
+                    _ => false,
+                };
+                after_start && before_end
+            }
+            Scope::Keyspace => false,
+        }
+    }
+}
+
+/// Keys a request is on.
+enum Scope<'a> {
+    Key(&'a str),
+    Prefix(&'a str),
+    Range(&'a Bound<String>, &'a Bound<String>),
+    Keyspace,
+}
+
+/// A user, with its credentials as hashes.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct User {
+    #[serde(skip)]
+    name: String,
+    /// Argon2 hash of the password, in the PHC string format.
+    #[serde(default)]
+    password: Option<String>,
+    /// SHA-256 of the token, in hex.
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(default)]
+    grants: Vec<Grant>,
+}
+
+impl User {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn grants(&self) -> &[Grant] {
+        &self.grants
+    }
+
+    /// Checks that the user may make `op`.
+    ///
+    /// # Errors
+    ///
+    /// `KvsError::Unauthorized` if no grant of the user allows it.
+    pub fn authorize(&self, op: &Request) -> Result<()> {
+        let Some((permission, scope)) = needs(op) else {
+            return Ok(());
+        };
+        let ns = op.namespace();
+        let granted = self
+            .grants
+            .iter()
+            .any(|grant| grant.permission >= permission && grant.covers(ns, &scope));
+        if granted {
+            return Ok(());
+        }
+        let what = match scope {
+            Scope::Key(key) => format!("key {:?}", key),
+            Scope::Prefix(prefix) => format!("keys starting with {:?}", prefix),
+            Scope::Range(..) => "keys in the range".to_owned(),
+            Scope::Keyspace if permission == Permission::Admin => "the server".to_owned(),
+            Scope::Keyspace => "all keys".to_owned(),
+        };
+        let keyspace = match ns {
+            Some(ns) if permission != Permission::Admin => format!(" of namespace {}", ns),
+            _ => String::new(),
+        };
+        Err(KvsError::Unauthorized(format!(
+            "{} may not {} {}{}",
+            self.name, permission, what, keyspace
+        )))
+    }
+}
+
+/// The permission `op` needs and the keys it needs it on, `None` for
+/// requests anyone may make.
+fn needs(op: &Request) -> Option<(Permission, Scope<'_>)> {
+    let permission = match op {
+        Request::Hello { .. } | Request::Auth { .. } => return None,
+        Request::Replicate
+        | Request::Raft { .. }
+        | Request::AddNode { .. }
+        | Request::RemoveNode { .. } => return Some((Permission::Admin, Scope::Keyspace)),
+        op if op.is_write() => Permission::Write,
+        _ => Permission::Read,
+    };
+    let scope = match op {
+        Request::RmPrefix { prefix, .. } => Scope::Prefix(prefix),
+        Request::RmRange { start, end, .. }
+        | Request::Scan { start, end, .. }
+        | Request::Watch { start, end, .. } => Scope::Range(start, end),
+        op => op.key().map_or(Scope::Keyspace, Scope::Key),
+    };
+    Some((permission, scope))
+}
+
+/// The users of a server.
+#[derive(Debug)]
+pub struct Users {
+    users: BTreeMap<String, Arc<User>>,
+}
+
+impl Users {
+    /// Reads the users in the JSON file at `path`.
+    pub fn load(path: &Path) -> Result<Users> {
+        let users: BTreeMap<String, User> = serde_json::from_slice(&fs::read(path)?)?;
+        let users = users
+            .into_iter()
+            .map(|(name, mut user)| {
+                if user.password.is_none() && user.token.is_none() {
+                    return Err(invalid_user(&name, "has neither password nor token"));
+                }
+                if let Some(hash) = &user.password {
+                    PasswordHash::new(hash).map_err(|e| invalid_user(&name, e))?;
+                }
+                if let Some(hash) = &mut user.token {
+                    if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+                        return Err(invalid_user(&name, "token is not a SHA-256 in hex"));
+                    }
+                    hash.make_ascii_lowercase();
+                }
+                user.name = name.clone();
+                Ok((name, Arc::new(user)))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Users { users })
+    }
+
+    /// The user `credentials` are those of.
+    ///
+    /// # Errors
+    ///
+    /// `KvsError::Unauthorized` if they are no user's.
+    pub fn authenticate(&self, credentials: &Credentials) -> Result<Arc<User>> {
+        let user = match credentials {
+            Credentials::Password { user, password } => {
+                let user = self.users.get(user);
+                let hash = user.and_then(|user| user.password.as_deref());
+                let verified = verify_password(password, hash.unwrap_or(DUMMY_HASH));
+                user.filter(|_| verified && hash.is_some())
+            }
+            Credentials::Token(token) => {
+                let hash = hash_token(token);
+                self.users
+                    .values()
+                    .find(|user| user.token.as_deref() == Some(hash.as_str()))
+            }
+        };
+        user.cloned()
+            .ok_or_else(|| KvsError::Unauthorized("invalid credentials".to_owned()))
+    }
+}
+
+fn invalid_user(name: &str, e: impl fmt::Display) -> KvsError {
+    KvsError::InvalidRequest(format!("user {}: {}", name, e))
+}
+
+/// Who a connection authenticated as, if anyone yet.
+#[derive(Debug, Clone)]
+pub struct Session {
+    users: Arc<Users>,
+    user: Option<Arc<User>>,
+}
+
+impl Session {
+    pub fn new(users: Arc<Users>) -> Session {
+        Session { users, user: None }
+    }
+
+    pub fn user(&self) -> Option<&User> {
+        self.user.as_deref()
+    }
+
+    /// Authenticates as the user `credentials` are those of. On failure the
+    /// session is left unauthenticated.
+    pub fn authenticate(&mut self, credentials: &Credentials) -> Result<()> {
+        self.user = None;
+        self.user = Some(self.users.authenticate(credentials)?);
+        Ok(())
+    }
+
+    /// Checks that the user of the session may make `op`.
+    ///
+    /// # Errors
+    ///
+    /// `KvsError::Unauthorized` if the session is not authenticated, or the
+    /// user may not make `op`.
+    pub fn authorize(&self, op: &Request) -> Result<()> {
+        match &self.user {
+            Some(user) => user.authorize(op),
+            None if needs(op).is_none() => Ok(()),
+            None => Err(KvsError::Unauthorized("authentication required".to_owned())),
+        }
+    }
+}
+
+/// Argon2 hash of `password` under a random salt, in the PHC string format
+/// of the `password` of users.
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| KvsError::Server(e.to_string()))
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash)
+        .and_then(|hash| Argon2::default().verify_password(password.as_bytes(), &hash))
+        .is_ok()
+}
+
+/// SHA-256 of `token` in hex, the `token` of users.
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
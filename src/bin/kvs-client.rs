@@ -1,22 +1,53 @@
-use env_logger;
 use log::error;
-use serde::Deserialize;
-use serde_json::de::Deserializer;
-use std::{
-    io::{BufReader, BufWriter, Write},
-    net::{SocketAddr, TcpStream},
-    time::Duration,
-};
+use std::{env, net::SocketAddr, ops::Bound, path::PathBuf};
 
-use clap::{Parser, Subcommand};
+use clap::{ErrorKind, IntoApp, Parser, Subcommand};
 
-use kvs::{KvsError, Request, Response, Result, DEFAULT_IP_ADDR};
+use kvs::{
+    prefix_range, Credentials, KvsClient, KvsClientOptions, KvsError, Reply, Request, Response,
+    Result, TlsClientConfig, DEFAULT_IP_ADDR,
+};
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 struct Cli {
     #[clap(subcommand)]
     command: Option<Command>,
+    /// Namespace of the key, the default keyspace if not given
+    #[clap(long, value_parser, global = true)]
+    ns: Option<String>,
+    /// Servers to spread the keys over, comma separated
+    #[clap(
+        long,
+        value_parser,
+        global = true,
+        use_value_delimiter = true,
+        default_value = DEFAULT_IP_ADDR
+    )]
+    addr: Vec<SocketAddr>,
+    /// Speak TLS, trusting servers whose certificate the CAs in this PEM
+    /// file signed
+    #[clap(long, value_parser, global = true)]
+    ca: Option<PathBuf>,
+    /// PEM certificate chain to show servers that require one
+    #[clap(long, value_parser, global = true, requires_all = &["ca", "cert-key"])]
+    cert: Option<PathBuf>,
+    /// PEM file of the private key of --cert
+    #[clap(long, value_parser, global = true, requires = "cert")]
+    cert_key: Option<PathBuf>,
+    /// Name servers' certificates are for, instead of their IP address
+    #[clap(long, value_parser, global = true, requires = "ca")]
+    server_name: Option<String>,
+    /// Authenticate as this user, with the password in --password or else
+    /// the KVS_PASSWORD environment variable
+    #[clap(long, value_parser, global = true, conflicts_with = "token")]
+    user: Option<String>,
+    /// Password of --user
+    #[clap(long, value_parser, global = true, requires = "user")]
+    password: Option<String>,
+    /// Authenticate with this token
+    #[clap(long, value_parser, global = true)]
+    token: Option<String>,
 }
 
 #[derive(Subcommand)]
@@ -26,58 +57,243 @@ enum Command {
         key: String,
         #[clap(value_parser)]
         value: String,
-        #[clap(long, value_parser, default_value = DEFAULT_IP_ADDR)]
-        addr: SocketAddr,
     },
     Get {
         #[clap(value_parser)]
         key: String,
-        #[clap(long, value_parser, default_value = DEFAULT_IP_ADDR)]
-        addr: SocketAddr,
     },
+    /// Remove a key, or with --prefix every key starting with the prefix
     Rm {
+        #[clap(value_parser, required_unless_present = "prefix")]
+        key: Option<String>,
+        /// Remove every key starting with this prefix and print their count
+        #[clap(long, value_parser, conflicts_with = "key")]
+        prefix: Option<String>,
+    },
+    /// Atomically add to an integer value and print the result
+    Incr {
+        #[clap(value_parser)]
+        key: String,
+        #[clap(value_parser, default_value_t = 1)]
+        delta: i64,
+        /// Value to start from if the key is missing
+        #[clap(long, value_parser, default_value_t = 0)]
+        initial: i64,
+    },
+    /// Atomically subtract from an integer value and print the result
+    Decr {
         #[clap(value_parser)]
         key: String,
-        #[clap(long, value_parser, default_value = DEFAULT_IP_ADDR)]
-        addr: SocketAddr,
+        #[clap(value_parser, default_value_t = 1)]
+        delta: i64,
+        /// Value to start from if the key is missing
+        #[clap(long, value_parser, default_value_t = 0)]
+        initial: i64,
+    },
+    /// Atomically append to a value
+    Append {
+        #[clap(value_parser)]
+        key: String,
+        #[clap(value_parser)]
+        value: String,
+    },
+    /// Print every pair, or with --prefix every pair whose key starts with
+    /// the prefix, ordered by key
+    Scan {
+        #[clap(long, value_parser)]
+        prefix: Option<String>,
+    },
+    /// Print the changes to a key, or with --prefix to every key starting
+    /// with the prefix, as they happen
+    Watch {
+        #[clap(value_parser, required_unless_present = "prefix")]
+        key: Option<String>,
+        #[clap(long, value_parser, conflicts_with = "key")]
+        prefix: Option<String>,
+    },
+    /// Print the changes after a sequence number, then the changes to come
+    Changes {
+        /// Sequence number of the last change seen before
+        #[clap(long, value_parser, default_value_t = 0)]
+        since: u64,
+    },
+    /// Print the counters and settings of the server
+    Stats,
+    /// Print the retained versions of a key, oldest first
+    History {
+        #[clap(value_parser)]
+        key: String,
+    },
+    /// Add a kvs-server to the cluster and print the members
+    AddNode {
+        #[clap(value_parser)]
+        node: SocketAddr,
+    },
+    /// Remove a kvs-server from the cluster and print the members
+    RemoveNode {
+        #[clap(value_parser)]
+        node: SocketAddr,
     },
 }
 
 fn main() -> Result<()> {
     env_logger::init();
     let cli = Cli::parse();
-    match cli.command {
-        Some(Command::Set { key, value, addr }) => run(Request::Set { key, value }, addr),
-        Some(Command::Get { key, addr }) => run(Request::Get { key }, addr),
-        Some(Command::Rm { key, addr }) => run(Request::Rm { key }, addr),
+    let ns = cli.ns;
+    let op = match cli.command {
+        Some(Command::Set { key, value }) => Request::Set { key, value, ns },
+        Some(Command::Get { key }) => Request::Get { key, ns },
+        Some(Command::Rm { key, prefix }) => match (key, prefix) {
+            (_, Some(prefix)) => Request::RmPrefix { prefix, ns },
+            (Some(key), None) => Request::Rm { key, ns },
+            (None, None) => unreachable!(),
+        },
+        Some(Command::Watch { key, prefix }) => {
+            let (start, end) = match (key, prefix) {
+                (_, Some(prefix)) => prefix_range(&prefix),
+                (Some(key), None) => (Bound::Included(key.clone()), Bound::Included(key)),
+                (None, None) => unreachable!(),
+            };
+            Request::Watch { start, end, ns }
+        }
+        Some(Command::Scan { prefix }) => {
+            let (start, end) = match prefix {
+                Some(prefix) => prefix_range(&prefix),
+                None => (Bound::Unbounded, Bound::Unbounded),
+            };
+            Request::Scan { start, end, ns }
+        }
+        Some(Command::Changes { since }) => Request::Changes { since, ns },
+        Some(Command::Stats) => Request::Stats { ns },
+        Some(Command::History { key }) => Request::History { key, ns },
+        Some(Command::Incr {
+            key,
+            delta,
+            initial,
+        }) => Request::Incr {
+            key,
+            delta,
+            initial,
+            ns,
+        },
+        Some(Command::Decr {
+            key,
+            delta,
+            initial,
+        }) => {
+            let delta = delta.checked_neg().ok_or(KvsError::MergeOverflow)?;
+            Request::Incr {
+                key,
+                delta,
+                initial,
+                ns,
+            }
+        }
+        Some(Command::Append { key, value }) => Request::Append { key, value, ns },
+        Some(Command::AddNode { node }) => Request::AddNode {
+            addr: node.to_string(),
+        },
+        Some(Command::RemoveNode { node }) => Request::RemoveNode {
+            addr: node.to_string(),
+        },
         None => {
             unimplemented!();
         }
+    };
+    let tls = match &cli.ca {
+        Some(ca) => {
+            let identity = cli.cert.as_deref().zip(cli.cert_key.as_deref());
+            let tls = TlsClientConfig::new(ca, identity)?;
+            Some(match &cli.server_name {
+                Some(name) => tls.with_server_name(name)?,
+                None => tls,
+            })
+        }
+        None => None,
+    };
+    let credentials = match (cli.user, cli.token) {
+        (Some(user), _) => {
+            let Some(password) = cli.password.or_else(|| env::var("KVS_PASSWORD").ok()) else {
+                Cli::command()
+                    .error(
+                        ErrorKind::MissingRequiredArgument,
+                        "--user needs --password or KVS_PASSWORD",
+                    )
+                    .exit();
+            };
+            Some(Credentials::Password { user, password })
+        }
+        (None, Some(token)) => Some(Credentials::Token(token)),
+        (None, None) => None,
+    };
+    let options = KvsClientOptions {
+        tls,
+        credentials,
+        ..KvsClientOptions::default()
+    };
+    run(op, cli.addr, options)
+}
+
+/// Sends `op` to the servers at `addrs` and prints the response, or the
+/// responses streamed for a watch.
+fn run(op: Request, addrs: Vec<SocketAddr>, options: KvsClientOptions) -> Result<()> {
+    let client = KvsClient::with_options(addrs, options);
+    if let Request::Watch { .. } | Request::Changes { .. } = op {
+        for response in client.stream(&op)? {
+            print(&op, response?)?;
+        }
+        return Ok(());
     }
+    let response = client.request(&op)?;
+    print(&op, response)
 }
 
-fn run(op: Request, addr: SocketAddr) -> Result<()> {
-    let stream = TcpStream::connect(addr)?;
-    stream.set_read_timeout(Some(Duration::from_secs(3)))?;
-    let mut reader = Deserializer::from_reader(BufReader::new(&stream));
-    let mut writer = BufWriter::new(&stream);
-    serde_json::to_writer(&mut writer, &op)?;
-    writer.flush()?;
-    match op {
-        Request::Get { .. } => {
-            if let Response::Get { value } = Response::deserialize(&mut reader)? {
-                println!("{}", value);
-            };
+/// Prints `response` to `op`.
+fn print(op: &Request, response: Response) -> Result<()> {
+    let reply = match response.into_reply() {
+        Ok(reply) => reply,
+        Err(KvsError::KeyNotFound) if matches!(op, Request::Get { .. }) => {
+            println!("{}", KvsError::KeyNotFound);
+            return Ok(());
+        }
+        Err(e) => {
+            error!("{}", e);
+            return Err(e);
+        }
+    };
+    match reply {
+        Reply::Done => {}
+        Reply::Value(value) => println!("{}", value),
+        Reply::Integer(value) => println!("{}", value),
+        Reply::Count(count) => println!("{}", count),
+        Reply::Pairs(pairs) => {
+            for (key, value) in pairs {
+                println!("{}\t{}", key, value);
+            }
         }
-        Request::Rm { .. } => {
-            if let Response::Rm { value } = Response::deserialize(&mut reader)? {
-                if value.as_str() != "ok" {
-                    error!("{}", value);
-                    return Err(KvsError::KeyNotFound);
+        Reply::Versions(versions) => {
+            for version in versions {
+                match version.value {
+                    Some(value) => println!("{}\t{}\t{}", version.seq, version.timestamp, value),
+                    None => println!("{}\t{}\t<removed>", version.seq, version.timestamp),
                 }
             }
         }
-        _ => {}
+        Reply::Change(change) => match change.value {
+            Some(value) => println!("{}\t{}\t{}", change.seq, change.key, value),
+            None => println!("{}\t{}\t<removed>", change.seq, change.key),
+        },
+        Reply::Stats(stats) => {
+            for (name, value) in stats {
+                println!("{}\t{}", name, value);
+            }
+        }
+        Reply::Members(members) => {
+            for member in members {
+                println!("{}", member);
+            }
+        }
+        Reply::Replication(_) | Reply::Welcome(_) => return Err(reply.unexpected()),
     }
     Ok(())
 }
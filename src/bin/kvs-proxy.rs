@@ -0,0 +1,289 @@
+use log::error;
+use serde::Serialize;
+use serde_json::Deserializer;
+use std::{
+    io::{BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    ops::Bound,
+    sync::{Arc, RwLock},
+    thread,
+    time::Duration,
+};
+
+use clap::Parser;
+
+use kvs::{
+    parse_request, Capability, HashRing, KvsClient, KvsError, NaiveThreadPool, Reply, Request,
+    Response, Result, ThreadPool, Welcome, DEFAULT_IP_ADDR,
+};
+
+/// How long a request on a key that is being moved waits before checking
+/// again.
+const MOVE_WAIT: Duration = Duration::from_millis(10);
+
+/// Spreads the keys of its clients over several kvs-servers.
+#[derive(Parser)]
+#[clap(version)]
+struct Args {
+    #[clap(long, value_parser, default_value = DEFAULT_IP_ADDR)]
+    addr: SocketAddr,
+    /// kvs-servers holding the keys. Backends added or removed with
+    /// `kvs-client add-node` and `remove-node` must be listed accordingly
+    /// when the proxy restarts.
+    #[clap(long, value_parser, use_value_delimiter = true, required = true)]
+    backends: Vec<SocketAddr>,
+    /// Points each backend owns on the hash ring
+    #[clap(long, value_parser, default_value_t = 64)]
+    vnodes: usize,
+}
+
+fn main() -> Result<()> {
+    env_logger::init();
+    let cli = Args::parse();
+    let mut ring = HashRing::new(cli.vnodes);
+    for backend in &cli.backends {
+        ring.add(&backend.to_string());
+    }
+    error!(
+        "version: {}\n addr: {}\n backends: {}",
+        env!("CARGO_PKG_VERSION"),
+        cli.addr,
+        ring.nodes().collect::<Vec<_>>().join(",")
+    );
+    let routing = Arc::new(RwLock::new(Routing { ring, next: None }));
+    // only for its connections, the proxy routes by its own ring
+    let client = KvsClient::new(cli.backends.iter().copied());
+    let listener = TcpListener::bind(cli.addr)?;
+    let pool = NaiveThreadPool::new(1000)?;
+    for stream_res in listener.incoming() {
+        let stream = match stream_res {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("{}", e);
+                continue;
+            }
+        };
+        let routing = routing.clone();
+        let client = client.clone();
+        pool.spawn(move || {
+            let reader = BufReader::new(&stream);
+            let messages = Deserializer::from_reader(reader).into_iter::<serde_json::Value>();
+            for message in messages {
+                let message = match message {
+                    Ok(message) => message,
+                    Err(e) => {
+                        error!("{}", e);
+                        break;
+                    }
+                };
+                let op = match parse_request(message) {
+                    Ok(op) => op,
+                    Err(rejection) if send(&stream, &rejection).is_ok() => continue,
+                    Err(_) => break,
+                };
+                let response = match op {
+                    Request::Raft { from, .. } => {
+                        error!("raft message from {} sent to a proxy", from);
+                        continue;
+                    }
+                    Request::Hello {
+                        version,
+                        mut capabilities,
+                    } => {
+                        // the proxy speaks JSON only
+                        capabilities.remove(&Capability::BinaryFrames);
+                        Welcome::negotiate(version, &capabilities)
+                            .map(Reply::Welcome)
+                            .into()
+                    }
+                    Request::AddNode { .. } | Request::RemoveNode { .. } => {
+                        rebalance(&client, &routing, &op).map(Reply::Members).into()
+                    }
+                    // rebalancing waits for the requests in flight
+                    op => loop {
+                        let routing = routing.read().unwrap();
+                        if !routing.waits(&op) {
+                            break route(&client, &routing.ring, &op);
+                        }
+                        drop(routing);
+                        thread::sleep(MOVE_WAIT);
+                    },
+                };
+                if send(&stream, &response).is_err() {
+                    break;
+                }
+            }
+        })
+    }
+    Ok(())
+}
+
+/// The ring requests are routed by, and while rebalancing the ring keys are
+/// being moved to.
+struct Routing {
+    ring: HashRing,
+    next: Option<HashRing>,
+}
+
+impl Routing {
+    /// Whether `op` has to wait for keys to move: requests on a key that
+    /// changes owner, and those on several keys.
+    fn waits(&self, op: &Request) -> bool {
+        match (&self.next, op.key()) {
+            (None, _) => false,
+            (Some(next), Some(key)) => next.node(key) != self.ring.node(key),
+            (Some(_), None) => true,
+        }
+    }
+}
+
+/// Sends `op` to the backends it concerns and answers it from theirs.
+fn route(client: &KvsClient, ring: &HashRing, op: &Request) -> Response {
+    if op.namespace().is_some() {
+        return KvsError::Server("namespaces are not sharded".to_owned()).into();
+    }
+    if let Some(key) = op.key() {
+        let Some(backend) = ring.node(key) else {
+            return KvsError::Server("no backends".to_owned()).into();
+        };
+        return call(client, backend, op).unwrap_or_else(Response::from);
+    }
+    match op {
+        Request::RmPrefix { .. } | Request::RmRange { .. } | Request::Scan { .. } => {}
+        Request::Stats { .. } => {}
+        _ => return KvsError::UnsupportedOperation.into(),
+    }
+    match fan_out(client, ring, op) {
+        Ok(responses) => Response::merge(op, responses, ring),
+        Err(e) => e.into(),
+    }
+}
+
+/// Adds the backend of an `AddNode`, or removes that of a `RemoveNode`, and
+/// moves the keys whose owner changes. Only requests on those keys wait
+/// while they move.
+fn rebalance(client: &KvsClient, routing: &RwLock<Routing>, op: &Request) -> Result<Vec<String>> {
+    let (Request::AddNode { addr } | Request::RemoveNode { addr }) = op else {
+        unreachable!("not a membership request");
+    };
+    let add = matches!(op, Request::AddNode { .. });
+    let addr = addr
+        .parse::<SocketAddr>()
+        .map_err(|e| KvsError::Server(format!("invalid backend address: {}", e)))?
+        .to_string();
+    let (sources, new_ring) = {
+        let mut routing = routing.write().unwrap();
+        if routing.next.is_some() {
+            return Err(KvsError::Server(
+                "another rebalance is in progress".to_owned(),
+            ));
+        }
+        let mut new_ring = routing.ring.clone();
+        let changed = if add {
+            new_ring.add(&addr)
+        } else {
+            new_ring.remove(&addr)
+        };
+        if !changed {
+            return Ok(routing.ring.nodes().map(str::to_owned).collect());
+        }
+        if new_ring.nodes().next().is_none() {
+            return Err(KvsError::Server(
+                "cannot remove the last backend".to_owned(),
+            ));
+        }
+        let sources: Vec<String> = if add {
+            routing.ring.nodes().map(str::to_owned).collect()
+        } else {
+            vec![addr]
+        };
+        routing.next = Some(new_ring.clone());
+        (sources, new_ring)
+    };
+    let moved = sources
+        .iter()
+        .try_for_each(|source| move_keys(client, source, &new_ring));
+    let mut routing = routing.write().unwrap();
+    let next = routing.next.take();
+    moved?;
+    routing.ring = next.unwrap();
+    Ok(routing.ring.nodes().map(str::to_owned).collect())
+}
+
+/// Copies the keys of `source` that `ring` assigns elsewhere to their
+/// owner, then removes them from `source`.
+fn move_keys(client: &KvsClient, source: &str, ring: &HashRing) -> Result<()> {
+    let scan = Request::Scan {
+        start: Bound::Unbounded,
+        end: Bound::Unbounded,
+        ns: None,
+    };
+    let pairs = match reply(client, source, &scan)? {
+        Reply::Pairs(pairs) => pairs,
+        reply => return Err(backend_error(source, reply.unexpected())),
+    };
+    let mut moved = Vec::new();
+    for (key, value) in pairs {
+        let owner = ring.node(&key).unwrap_or(source);
+        if owner == source {
+            continue;
+        }
+        let set = Request::Set {
+            key: key.clone(),
+            value,
+            ns: None,
+        };
+        match reply(client, owner, &set)? {
+            Reply::Done => moved.push(key),
+            reply => return Err(backend_error(owner, reply.unexpected())),
+        }
+    }
+    for key in moved {
+        // a key removed from the source meanwhile is gone all the same
+        match call(client, source, &Request::Rm { key, ns: None })?.into_reply() {
+            Ok(Reply::Done) | Err(KvsError::KeyNotFound) => {}
+            Ok(reply) => return Err(backend_error(source, reply.unexpected())),
+            Err(e) => return Err(backend_error(source, e)),
+        }
+    }
+    Ok(())
+}
+
+/// Sends `op` to every backend, returning their responses by backend.
+fn fan_out(client: &KvsClient, ring: &HashRing, op: &Request) -> Result<Vec<(String, Response)>> {
+    thread::scope(|scope| {
+        let calls: Vec<_> = ring
+            .nodes()
+            .map(|backend| (backend, scope.spawn(move || call(client, backend, op))))
+            .collect();
+        calls
+            .into_iter()
+            .map(|(backend, call)| Ok((backend.to_owned(), call.join().unwrap()?)))
+            .collect()
+    })
+}
+
+/// Sends `op` to `backend` and returns its response.
+fn call(client: &KvsClient, backend: &str, op: &Request) -> Result<Response> {
+    client
+        .send(backend, op)
+        .map_err(|e| backend_error(backend, e))
+}
+
+/// Sends `op` to `backend` and returns the reply of its response.
+fn reply(client: &KvsClient, backend: &str, op: &Request) -> Result<Reply> {
+    call(client, backend, op)?
+        .into_reply()
+        .map_err(|e| backend_error(backend, e))
+}
+
+fn backend_error(backend: &str, error: KvsError) -> KvsError {
+    KvsError::Backend(backend.to_owned(), error.to_string())
+}
+
+/// Writes `message` to `stream` in one write, lest Nagle's algorithm hold
+/// back the rest until the client acknowledges the first part.
+fn send(mut stream: &TcpStream, message: &impl Serialize) -> Result<()> {
+    stream.write_all(&serde_json::to_vec(message)?)?;
+    Ok(())
+}
@@ -1,20 +1,53 @@
 use log::error;
+use serde::Serialize;
 use serde_json::Deserializer;
 use std::{
-    env::current_dir,
+    env::{self, current_dir},
     fmt::Display,
     fs,
-    io::{BufReader, BufWriter},
-    net::{SocketAddr, TcpListener},
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener},
+    path::PathBuf,
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use clap::{Parser, ValueEnum};
-use env_logger;
 
+#[cfg(feature = "async")]
+use kvs::asynchronous::{write_frame as write_frame_async, write_json, JsonReader};
+use kvs::auth::{self, Session, Users};
+use kvs::http::HttpServer;
+use kvs::raft::{Cluster, Command, Raft, RaftOptions};
+use kvs::resp::RespServer;
+#[cfg(feature = "async")]
+use kvs::AsyncKvsEngine;
 use kvs::{
-    KvStore, KvsEngine, NaiveThreadPool, Request, Response, Result, SledKvsEngine, ThreadPool,
-    DEFAULT_IP_ADDR,
+    merge, parse_request, prefix_range, read_frame, write_frame, CachePolicy, CachedEngine,
+    Capability, Change, Credentials, KvStore, KvStoreOptions, KvsEngine, KvsError, LsmKvsEngine,
+    LsmOptions, NaiveThreadPool, Replica, ReplicationEvent, Reply, Request, Response, Result,
+    Retention, SharedQueueThreadPool, SledKvsEngine, SledOptions, Stream, ThreadPool,
+    TlsServerConfig, Welcome, DEFAULT_IP_ADDR,
 };
+#[cfg(feature = "async")]
+use tokio::{
+    io::{AsyncReadExt, AsyncWrite},
+    sync::mpsc,
+};
+
+/// Runs each connection, of whichever front end, on a thread of its own.
+static CONNECTIONS: NaiveThreadPool = NaiveThreadPool;
+/// How often a watch without changes checks whether its client hung up.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Pipelined requests of a connection handled at a time. Its next frames
+/// are not read until one of them completes.
+const MAX_IN_FLIGHT: usize = 64;
 
 #[derive(Parser)]
 #[clap(version)]
@@ -23,12 +56,115 @@ struct Args {
     addr: SocketAddr,
     #[clap(long, value_enum)]
     engine: Option<EngineChoice>,
+    /// Target false positive rate of the bloom filters of the sled and lsm engines
+    #[clap(long, value_parser, default_value_t = 0.01)]
+    bloom_fp_rate: f64,
+    /// Bytes of memory the key directory of the kvs engine may use before spilling to disk
+    #[clap(long, value_parser)]
+    index_memory_limit: Option<usize>,
+    /// Serve reads of the kvs engine from a memory map of its log
+    #[clap(long)]
+    mmap: bool,
+    /// Versions of each key the kvs engine keeps for `history`, counting the current one
+    #[clap(long, value_parser, conflicts_with = "history-secs")]
+    history_versions: Option<usize>,
+    /// Seconds the kvs engine keeps old versions of each key for `history`
+    #[clap(long, value_parser)]
+    history_secs: Option<u64>,
+    /// Bytes of values to cache in memory in front of the engine
+    #[clap(long, value_parser)]
+    cache_size: Option<usize>,
+    /// Changes the engine keeps for resuming change streams
+    #[clap(long, value_parser)]
+    change_log: Option<usize>,
+    /// Follow the kvs-server at this address, serving reads and rejecting
+    /// writes. Only the default keyspace is replicated.
+    #[clap(long, value_parser)]
+    replica_of: Option<SocketAddr>,
+    /// Run as a node of a Raft cluster of the kvs-servers at these addresses,
+    /// counting this one. A server not among them joins once added with
+    /// `kvs-client add-node`. Writes to namespaces are not supported.
+    #[clap(
+        long,
+        value_parser,
+        use_value_delimiter = true,
+        conflicts_with = "replica-of"
+    )]
+    cluster: Vec<SocketAddr>,
+    /// Eviction policy of the value cache
+    #[clap(long, value_enum, default_value_t = CachePolicyChoice::Lru)]
+    cache_policy: CachePolicyChoice,
+    /// How connections are served: a thread each, or tasks on tokio for
+    /// servers built with the `async` feature
+    #[clap(long, value_enum, default_value_t = RuntimeChoice::Threads)]
+    runtime: RuntimeChoice,
+    /// Also serve Redis clients at this address, over RESP2. Not with
+    /// --cluster or --replica-of, whose writes go through the native protocol.
+    #[clap(long, value_parser, conflicts_with_all = &["cluster", "replica-of"])]
+    resp_addr: Option<SocketAddr>,
+    /// Also serve HTTP at this address, as a JSON and plain text gateway.
+    /// Not with --cluster or --replica-of, for the same reason as RESP.
+    #[clap(long, value_parser, conflicts_with_all = &["cluster", "replica-of"])]
+    http_addr: Option<SocketAddr>,
+    /// Serve the native protocol over TLS, showing the PEM certificate
+    /// chain in this file. Not with --cluster or --replica-of, whose nodes
+    /// connect to each other in the clear.
+    #[clap(
+        long,
+        value_parser,
+        requires = "tls-key",
+        conflicts_with_all = &["cluster", "replica-of"]
+    )]
+    tls_cert: Option<PathBuf>,
+    /// PEM file of the private key of --tls-cert
+    #[clap(long, value_parser, requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+    /// Require clients to show a certificate signed by the CAs in this PEM
+    /// file
+    #[clap(long, value_parser, requires = "tls-cert")]
+    client_ca: Option<PathBuf>,
+    /// Threads serving the requests of pipelining connections, with the
+    /// threads runtime
+    #[clap(long, value_parser, default_value_t = 16)]
+    workers: u32,
+    /// Require clients to authenticate as one of the users in this JSON
+    /// file, and serve them what their grants allow. Only clients of --addr
+    /// authenticate: --resp-addr and --http-addr serve anyone, so keep them
+    /// private. The nodes of --cluster authenticate to each other with
+    /// --cluster-token, which a user must hold with an admin grant.
+    #[clap(long, value_parser)]
+    auth_file: Option<PathBuf>,
+    /// Token the nodes of --cluster authenticate with under --auth-file, or
+    /// else the KVS_CLUSTER_TOKEN environment variable
+    #[clap(long, value_parser, requires = "cluster")]
+    cluster_token: Option<String>,
+    /// Print the hash of the password read from stdin for the --auth-file,
+    /// and exit
+    #[clap(long, conflicts_with = "hash-token")]
+    hash_password: bool,
+    /// Print the hash of the token read from stdin for the --auth-file, and
+    /// exit
+    #[clap(long)]
+    hash_token: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy)]
+enum RuntimeChoice {
+    Threads,
+    Async,
+}
+
+#[derive(ValueEnum, Clone, Copy)]
+enum CachePolicyChoice {
+    Lru,
+    Lfu,
 }
 
 #[derive(ValueEnum, Clone)]
 pub enum EngineChoice {
     Kvs,
     Sled,
+    Lsm,
 }
 
 impl Display for EngineChoice {
@@ -36,6 +172,7 @@ impl Display for EngineChoice {
         match self {
             EngineChoice::Kvs => write!(f, "Kvs"),
             EngineChoice::Sled => write!(f, "Sled"),
+            EngineChoice::Lsm => write!(f, "Lsm"),
         }
     }
 }
@@ -43,45 +180,39 @@ impl Display for EngineChoice {
 fn main() -> Result<()> {
     env_logger::init();
     let cli = Args::parse();
-    let mut engine = EngineChoice::Kvs;
-    let former_engine = fs::read_to_string("engine").unwrap_or(String::from(""));
-    match former_engine.as_str() {
-        "kvs" => match &cli.engine {
-            Some(EngineChoice::Kvs) | None => {
-                engine = EngineChoice::Kvs;
-            }
-            _ => {
-                error!(
-                    "error engine: former_engine: {}, selected engine kvs",
-                    former_engine
-                );
-            }
-        },
-        "sled" => match &cli.engine {
-            Some(EngineChoice::Sled) | None => {
-                engine = EngineChoice::Sled;
-            }
-            _ => {
-                error!(
-                    "error engine: former_engine: {}, selected engine kvs",
-                    former_engine
-                );
-            }
-        },
-        "" => match &cli.engine {
-            Some(EngineChoice::Kvs) | None => {
-                engine = EngineChoice::Kvs;
-                fs::write("engine", "kvs")?;
-            }
-            Some(EngineChoice::Sled) => {
-                engine = EngineChoice::Sled;
-                fs::write("engine", "sled")?;
-            }
-        },
+    if cli.hash_password || cli.hash_token {
+        let mut secret = String::new();
+        io::stdin().lock().read_line(&mut secret)?;
+        let secret = secret.trim_end_matches(['\r', '\n']);
+        let hash = match cli.hash_password {
+            true => auth::hash_password(secret)?,
+            false => auth::hash_token(secret),
+        };
+        println!("{}", hash);
+        return Ok(());
+    }
+    let former_engine = fs::read_to_string("engine").unwrap_or_default();
+    let engine = match (former_engine.as_str(), cli.engine.clone()) {
+        ("", choice) => {
+            let engine = choice.unwrap_or(EngineChoice::Kvs);
+            fs::write("engine", engine.to_string().to_lowercase())?;
+            engine
+        }
+        ("kvs", Some(EngineChoice::Kvs) | None) => EngineChoice::Kvs,
+        ("sled", Some(EngineChoice::Sled) | None) => EngineChoice::Sled,
+        ("lsm", Some(EngineChoice::Lsm) | None) => EngineChoice::Lsm,
+        (former, Some(selected)) if ["kvs", "sled", "lsm"].contains(&former) => {
+            error!(
+                "error engine: former_engine: {}, selected engine {}",
+                former, selected
+            );
+            process::exit(1);
+        }
         _ => {
             error!("wrong engine name written in file");
+            process::exit(1);
         }
-    }
+    };
 
     error!(
         "version: {}\n engine: {}\n addr: {}",
@@ -90,48 +221,860 @@ fn main() -> Result<()> {
         cli.addr
     );
     match engine {
-        EngineChoice::Kvs => run_with_engine(KvStore::open(current_dir()?)?, cli.addr),
-        EngineChoice::Sled => run_with_engine(SledKvsEngine::open(current_dir()?)?, cli.addr),
+        EngineChoice::Kvs => {
+            let options = KvStoreOptions {
+                index_memory_limit: cli.index_memory_limit,
+                mmap_reads: cli.mmap,
+                retention: match (cli.history_versions, cli.history_secs) {
+                    (Some(n), _) => Some(Retention::Versions(n)),
+                    (None, Some(secs)) => Some(Retention::Age(Duration::from_secs(secs))),
+                    (None, None) => None,
+                },
+                change_log: cli.change_log,
+                ..KvStoreOptions::default()
+            };
+            serve(KvStore::open_with_options(current_dir()?, options)?, &cli)
+        }
+        EngineChoice::Sled => {
+            let options = SledOptions {
+                bloom_false_positive_rate: cli.bloom_fp_rate,
+                change_log: cli.change_log,
+                ..SledOptions::default()
+            };
+            serve(
+                SledKvsEngine::open_with_options(current_dir()?, options)?,
+                &cli,
+            )
+        }
+        EngineChoice::Lsm => {
+            let options = LsmOptions {
+                bloom_false_positive_rate: cli.bloom_fp_rate,
+                change_log: cli.change_log,
+                ..LsmOptions::default()
+            };
+            serve(
+                LsmKvsEngine::open_with_options(current_dir()?, options)?,
+                &cli,
+            )
+        }
     }
 }
 
-fn run_with_engine<E: KvsEngine + Send>(engine: E, addr: SocketAddr) -> Result<()> {
+fn serve<E: KvsEngine>(engine: E, cli: &Args) -> Result<()> {
+    match cli.cache_size {
+        Some(capacity) => {
+            let policy = match cli.cache_policy {
+                CachePolicyChoice::Lru => CachePolicy::Lru,
+                CachePolicyChoice::Lfu => CachePolicy::Lfu,
+            };
+            serve_resp_too(CachedEngine::with_policy(engine, capacity, policy), cli)
+        }
+        None => serve_resp_too(engine, cli),
+    }
+}
+
+/// Serves Redis clients too if asked to, the other front ends serving the
+/// engine of their server so that writes clear the deadlines of keys.
+fn serve_resp_too<E: KvsEngine>(engine: E, cli: &Args) -> Result<()> {
+    let Some(addr) = cli.resp_addr else {
+        return run_with_engine(engine, cli);
+    };
     let listener = TcpListener::bind(addr)?;
-    let pool = NaiveThreadPool::new(1000)?;
+    let server = RespServer::new(engine);
+    let engine = server.engine();
+    thread::spawn(move || serve_resp(server, listener));
+    run_with_engine(engine, cli)
+}
+
+fn run_with_engine<E: KvsEngine + Send>(engine: E, cli: &Args) -> Result<()> {
+    let listener = TcpListener::bind(cli.addr)?;
+    let replica = cli
+        .replica_of
+        .map(|primary| Replica::start(engine.clone(), primary));
+    let cluster = if cli.cluster.is_empty() {
+        None
+    } else {
+        let raft = Raft::open(
+            cli.addr.to_string(),
+            cli.cluster.iter().map(SocketAddr::to_string),
+            engine.clone(),
+            Some(current_dir()?.join("raft")),
+            RaftOptions::default(),
+        )?;
+        let token = cli
+            .cluster_token
+            .clone()
+            .or_else(|| env::var("KVS_CLUSTER_TOKEN").ok());
+        if cli.auth_file.is_some() && token.is_none() {
+            error!("--auth-file with --cluster needs --cluster-token or KVS_CLUSTER_TOKEN");
+            process::exit(1);
+        }
+        Some(Cluster::start_with_credentials(
+            raft,
+            token.map(Credentials::Token),
+        ))
+    };
+    if let Some(addr) = cli.http_addr {
+        let listener = TcpListener::bind(addr)?;
+        let server = HttpServer::new(engine.clone());
+        thread::spawn(move || serve_http(server, listener));
+    }
+    let tls = match (&cli.tls_cert, &cli.tls_key) {
+        (Some(cert), Some(key)) => Some(TlsServerConfig::new(cert, key, cli.client_ca.as_deref())?),
+        _ => None,
+    };
+    let users = match &cli.auth_file {
+        Some(path) => Some(Arc::new(Users::load(path)?)),
+        None => None,
+    };
+    match cli.runtime {
+        RuntimeChoice::Threads => {
+            serve_threads(engine, listener, replica, cluster, tls, users, cli.workers)
+        }
+        #[cfg(feature = "async")]
+        RuntimeChoice::Async if tls.is_some() => {
+            error!("TLS is served by the threads runtime only");
+            process::exit(1);
+        }
+        #[cfg(feature = "async")]
+        RuntimeChoice::Async => serve_async(engine, listener, replica, cluster, users),
+        #[cfg(not(feature = "async"))]
+        RuntimeChoice::Async => {
+            error!("kvs-server was built without the async feature");
+            process::exit(1);
+        }
+    }
+}
+
+/// Serves each connection on a thread of its own. The requests of
+/// pipelining connections are served by `workers` threads shared by all.
+fn serve_threads<E: KvsEngine>(
+    engine: E,
+    listener: TcpListener,
+    replica: Option<Arc<Replica>>,
+    cluster: Option<Cluster<E>>,
+    tls: Option<TlsServerConfig>,
+    users: Option<Arc<Users>>,
+    workers: u32,
+) -> Result<()> {
+    let workers = Arc::new(SharedQueueThreadPool::new(workers)?);
     for stream_res in listener.incoming() {
         let engine = engine.clone();
-        pool.spawn(move || {
-            let stream = stream_res.unwrap();
-            let reader = BufReader::new(&stream);
-            let mut writer = BufWriter::new(&stream);
-            let operations = Deserializer::from_reader(reader).into_iter::<Request>();
-            for op in operations {
-                let op = op.unwrap();
-                match op {
-                    Request::Set { key, value } => {
-                        let value = match engine.set(key, value) {
-                            Ok(..) => "ok".to_string(),
-                            Err(e) => e.to_string(),
-                        };
-                        serde_json::to_writer(&mut writer, &Response::Set { value }).unwrap();
+        let replica = replica.clone();
+        let cluster = cluster.clone();
+        let tls = tls.clone();
+        let mut session = users.clone().map(Session::new);
+        let workers = workers.clone();
+        CONNECTIONS.spawn(move || {
+            // the handshake is done on the thread of the connection
+            let stream = stream_res
+                .map_err(KvsError::from)
+                .and_then(|stream| match &tls {
+                    Some(tls) => tls.accept(stream),
+                    None => Ok(Stream::from(stream)),
+                });
+            // responses of pipelined requests are written as they complete
+            let (stream, writer) = match stream.and_then(|s| Ok((s.try_clone()?, s))) {
+                Ok((stream, writer)) => (stream, Arc::new(Mutex::new(writer))),
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            };
+            let mut reader = BufReader::new(stream);
+            // JSON until a handshake agrees on binary frames
+            let mut framed = false;
+            let mut pipelined = false;
+            let in_flight = Arc::new(InFlight::default());
+            loop {
+                let (op, to) = if framed {
+                    let frame = match read_frame(&mut reader) {
+                        Ok(Some(frame)) => frame,
+                        Ok(None) => break,
+                        Err(e) => {
+                            error!("{}", e);
+                            break;
+                        }
+                    };
+                    let to = Responder::new(&writer, Some(frame.id));
+                    match frame.request() {
+                        Ok(op) => (op, to),
+                        Err(e) => match to.send(&Response::from(e)) {
+                            Ok(()) => continue,
+                            Err(_) => break,
+                        },
                     }
-                    Request::Get { key } => {
-                        let value = match engine.get(key).unwrap() {
-                            Some(value) => value,
-                            None => "Key not found".to_string(),
-                        };
-                        serde_json::to_writer(&stream, &Response::Get { value }).unwrap();
+                } else {
+                    let mut messages = Deserializer::from_reader(&mut reader).into_iter();
+                    let message = match messages.next() {
+                        Some(Ok(message)) => message,
+                        Some(Err(e)) => {
+                            error!("{}", e);
+                            break;
+                        }
+                        None => break,
+                    };
+                    let to = Responder::new(&writer, None);
+                    match parse_request(message) {
+                        Ok(op) => (op, to),
+                        Err(rejection) if to.send(&rejection).is_ok() => continue,
+                        Err(_) => break,
                     }
-                    Request::Rm { key } => {
-                        let value = match engine.remove(key) {
-                            Ok(..) => "ok".to_string(),
-                            Err(e) => e.to_string(),
-                        };
-                        serde_json::to_writer(&stream, &Response::Rm { value }).unwrap();
+                };
+                if let Some(reply) = admit(session.as_mut(), &op) {
+                    match to.send(&Response::from(reply)) {
+                        Ok(()) => continue,
+                        Err(_) => break,
                     }
                 }
+                if let Request::Watch { .. } | Request::Changes { .. } | Request::Replicate = op {
+                    serve_stream(&engine, &op, to, &hang_up(reader));
+                    // ends the read of `hang_up` too
+                    let _ = writer.lock().unwrap().shutdown(Shutdown::Both);
+                    break;
+                }
+                if let Request::Raft { from, message } = op {
+                    let stepped = match &cluster {
+                        Some(cluster) => cluster.step(&from, message),
+                        None => Err(KvsError::UnsupportedOperation),
+                    };
+                    if let Err(e) = stepped {
+                        error!("raft message from {}: {}", from, e);
+                    }
+                    continue;
+                }
+                if pipelined && !matches!(op, Request::Hello { .. }) {
+                    let (engine, replica, cluster) =
+                        (engine.clone(), replica.clone(), cluster.clone());
+                    let (writer, frame) = (writer.clone(), to.frame);
+                    let slot = in_flight.take();
+                    workers.spawn(move || {
+                        let reply = handle(&engine, op, replica.as_deref(), cluster.as_ref());
+                        // the client may hang up without waiting for the response
+                        let _ = Responder::new(&writer, frame).send(&Response::from(reply));
+                        drop(slot);
+                    });
+                    continue;
+                }
+                let reply = handle(&engine, op, replica.as_deref(), cluster.as_ref());
+                let agreed = (
+                    agrees_on(&reply, Capability::BinaryFrames),
+                    agrees_on(&reply, Capability::Pipelining),
+                );
+                // the client may hang up without waiting for the response
+                if to.send(&Response::from(reply)).is_err() {
+                    break;
+                }
+                framed |= agreed.0;
+                pipelined |= agreed.1;
             }
         })
     }
     Ok(())
 }
+
+/// Pipelined requests of a connection being handled.
+#[derive(Default)]
+struct InFlight {
+    count: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl InFlight {
+    /// Waits for fewer than `MAX_IN_FLIGHT` requests to be in flight, and
+    /// counts one more until the slot returned is dropped.
+    fn take(self: &Arc<Self>) -> Slot {
+        let mut count = self.count.lock().unwrap();
+        while *count >= MAX_IN_FLIGHT {
+            count = self.freed.wait(count).unwrap();
+        }
+        *count += 1;
+        Slot(self.clone())
+    }
+}
+
+struct Slot(Arc<InFlight>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        *self.0.count.lock().unwrap() -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+/// Serves the connections of Redis clients, each on a thread of its own.
+fn serve_resp<E: KvsEngine>(server: RespServer<E>, listener: TcpListener) -> Result<()> {
+    for stream_res in listener.incoming() {
+        let server = server.clone();
+        CONNECTIONS.spawn(move || {
+            if let Err(e) = stream_res
+                .map_err(KvsError::from)
+                .and_then(|s| server.serve(s))
+            {
+                error!("{}", e);
+            }
+        })
+    }
+    Ok(())
+}
+
+/// Serves HTTP connections, each on a thread of its own as native ones are.
+fn serve_http<E: KvsEngine>(server: HttpServer<E>, listener: TcpListener) -> Result<()> {
+    for stream_res in listener.incoming() {
+        let server = server.clone();
+        CONNECTIONS.spawn(move || {
+            if let Err(e) = stream_res
+                .map_err(KvsError::from)
+                .and_then(|s| server.serve(s))
+            {
+                error!("{}", e);
+            }
+        })
+    }
+    Ok(())
+}
+
+/// Serves connections as tasks on tokio. Engine calls run on its blocking
+/// threads, while watches and replication streams are served by the tasks
+/// of their connections.
+#[cfg(feature = "async")]
+fn serve_async<E: KvsEngine>(
+    engine: E,
+    listener: TcpListener,
+    replica: Option<Arc<Replica>>,
+    cluster: Option<Cluster<E>>,
+    users: Option<Arc<Users>>,
+) -> Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        listener.set_nonblocking(true)?;
+        let listener = tokio::net::TcpListener::from_std(listener)?;
+        let engine = AsyncKvsEngine::new(engine);
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let engine = engine.clone();
+            let replica = replica.clone();
+            let cluster = cluster.clone();
+            let session = users.clone().map(Session::new);
+            tokio::spawn(serve_connection(engine, stream, replica, cluster, session));
+        }
+    })
+}
+
+#[cfg(feature = "async")]
+async fn serve_connection<E: KvsEngine>(
+    engine: AsyncKvsEngine<E>,
+    stream: tokio::net::TcpStream,
+    replica: Option<Arc<Replica>>,
+    cluster: Option<Cluster<E>>,
+    mut session: Option<Session>,
+) {
+    let mut conn = JsonReader::new(stream);
+    // JSON until a handshake agrees on binary frames
+    let mut framed = false;
+    let mut pipelined = false;
+    // responses of pipelined requests as they complete
+    let (completed, mut responses) = mpsc::channel(MAX_IN_FLIGHT);
+    let mut in_flight = 0;
+    loop {
+        let (op, frame) = if framed {
+            let next = tokio::select! {
+                next = conn.next_frame(), if in_flight < MAX_IN_FLIGHT => next,
+                Some((id, response)) = responses.recv() => {
+                    in_flight -= 1;
+                    match respond(conn.get_mut(), Some(id), &response).await {
+                        Ok(()) => continue,
+                        Err(_) => break,
+                    }
+                }
+            };
+            let frame = match next {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => {
+                    error!("{}", e);
+                    break;
+                }
+            };
+            match frame.request() {
+                Ok(op) => (op, Some(frame.id)),
+                Err(e) => match respond(conn.get_mut(), Some(frame.id), &Response::from(e)).await {
+                    Ok(()) => continue,
+                    Err(_) => break,
+                },
+            }
+        } else {
+            let message = match conn.next::<serde_json::Value>().await {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                Err(e) => {
+                    error!("{}", e);
+                    break;
+                }
+            };
+            match parse_request(message) {
+                Ok(op) => (op, None),
+                Err(rejection) if write_json(conn.get_mut(), &rejection).await.is_ok() => continue,
+                Err(_) => break,
+            }
+        };
+        let admitted = match op {
+            // password hashes take a while to check
+            Request::Auth { .. } => tokio::task::block_in_place(|| admit(session.as_mut(), &op)),
+            _ => admit(session.as_mut(), &op),
+        };
+        if let Some(reply) = admitted {
+            match respond(conn.get_mut(), frame, &Response::from(reply)).await {
+                Ok(()) => continue,
+                Err(_) => break,
+            }
+        }
+        if let Request::Watch { .. } | Request::Changes { .. } | Request::Replicate = op {
+            // the stream takes over the connection once the rest is answered
+            while in_flight > 0 {
+                let Some((id, response)) = responses.recv().await else {
+                    break;
+                };
+                in_flight -= 1;
+                if respond(conn.get_mut(), Some(id), &response).await.is_err() {
+                    return;
+                }
+            }
+            let opened = engine.run(move |engine| Feed::open(engine, &op)).await;
+            serve_stream_async(conn.into_inner(), frame, opened).await;
+            break;
+        }
+        if let Request::Raft { from, message } = op {
+            let stepped = match &cluster {
+                Some(cluster) => cluster.step(&from, message),
+                None => Err(KvsError::UnsupportedOperation),
+            };
+            if let Err(e) = stepped {
+                error!("raft message from {}: {}", from, e);
+            }
+            continue;
+        }
+        let (replica, cluster) = (replica.clone(), cluster.clone());
+        let reply =
+            engine.run(move |engine| handle(engine, op, replica.as_deref(), cluster.as_ref()));
+        if let (true, Some(id)) = (pipelined, frame) {
+            let completed = completed.clone();
+            in_flight += 1;
+            tokio::spawn(async move {
+                let _ = completed.send((id, Response::from(reply.await))).await;
+            });
+            continue;
+        }
+        let reply = reply.await;
+        let agreed = (
+            agrees_on(&reply, Capability::BinaryFrames),
+            agrees_on(&reply, Capability::Pipelining),
+        );
+        // the client may hang up without waiting for the response
+        if respond(conn.get_mut(), frame, &Response::from(reply))
+            .await
+            .is_err()
+        {
+            break;
+        }
+        framed |= agreed.0;
+        pipelined |= agreed.1;
+    }
+}
+
+/// Writes `response` to `writer`, in a frame under the ID of its request if
+/// that came in one.
+#[cfg(feature = "async")]
+async fn respond(
+    writer: &mut (impl AsyncWrite + Unpin),
+    frame: Option<u64>,
+    response: &Response,
+) -> Result<()> {
+    match frame {
+        Some(id) => write_frame_async(writer, id, response).await,
+        None => write_json(writer, response).await,
+    }
+}
+
+/// Serves the stream `Feed::open` opened on `stream` until the client hangs
+/// up, or its changes fall behind. A thread per stream hands its changes
+/// over, so that none of tokio's threads waits on them.
+#[cfg(feature = "async")]
+async fn serve_stream_async(
+    stream: tokio::net::TcpStream,
+    frame: Option<u64>,
+    opened: Result<(Receiver<Change>, Vec<Response>, Feed)>,
+) {
+    let (mut reader, mut writer) = stream.into_split();
+    let (changes, backlog, mut feed) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            let _ = respond(&mut writer, frame, &Response::from(e)).await;
+            return;
+        }
+    };
+    for response in &backlog {
+        if respond(&mut writer, frame, response).await.is_err() {
+            return;
+        }
+    }
+    // a full channel holds changes back in the watch, which drops it once
+    // it falls behind
+    let (forward, mut forwarded) = mpsc::channel(1);
+    thread::spawn(move || loop {
+        let forwarding = match changes.recv_timeout(WATCH_POLL_INTERVAL) {
+            Ok(change) => forward.blocking_send(change).is_ok(),
+            Err(RecvTimeoutError::Timeout) => !forward.is_closed(),
+            Err(RecvTimeoutError::Disconnected) => false,
+        };
+        if !forwarding {
+            return;
+        }
+    });
+    // whatever the client sends meanwhile goes unanswered
+    let hung_up = async move {
+        let mut buf = [0; 4096];
+        while matches!(reader.read(&mut buf).await, Ok(n) if n > 0) {}
+    };
+    tokio::pin!(hung_up);
+    loop {
+        let response = tokio::select! {
+            _ = &mut hung_up => return,
+            change = tokio::time::timeout(WATCH_POLL_INTERVAL, forwarded.recv()) => match change {
+                Ok(Some(change)) => feed.change(change),
+                Ok(None) => {
+                    let _ = respond(&mut writer, frame, &feed.fell_behind()).await;
+                    return;
+                }
+                Err(_) => feed.idle(),
+            },
+        };
+        if let Some(response) = response {
+            if respond(&mut writer, frame, &response).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Answers `Auth` requests, and rejects the requests the user of `session`
+/// may not make. Servers without users let every request through.
+fn admit(session: Option<&mut Session>, op: &Request) -> Option<Result<Reply>> {
+    let session = session?;
+    match op {
+        Request::Auth { credentials } => {
+            Some(session.authenticate(credentials).map(|_| Reply::Done))
+        }
+        op => session.authorize(op).err().map(Err),
+    }
+}
+
+/// Whether `reply` is a `Welcome` agreeing on `capability`.
+fn agrees_on(reply: &Result<Reply>, capability: Capability) -> bool {
+    matches!(reply, Ok(Reply::Welcome(welcome)) if welcome.capabilities.contains(&capability))
+}
+
+/// Where responses to a request go: its connection, in frames under the ID
+/// of the request if that came in one. Writers take turns on the
+/// connection, as pipelined requests complete in any order.
+#[derive(Clone, Copy)]
+struct Responder<'a> {
+    writer: &'a Mutex<Stream>,
+    frame: Option<u64>,
+}
+
+impl<'a> Responder<'a> {
+    fn new(writer: &'a Mutex<Stream>, frame: Option<u64>) -> Responder<'a> {
+        Responder { writer, frame }
+    }
+
+    fn send(&self, message: &impl Serialize) -> Result<()> {
+        let mut stream = self.writer.lock().unwrap();
+        match self.frame {
+            Some(id) => write_frame(&mut *stream, id, message),
+            None => send(&mut *stream, message),
+        }
+    }
+}
+
+/// Writes `message` to `stream` in one write, lest Nagle's algorithm hold
+/// back the rest until the client acknowledges the first part.
+fn send(stream: &mut impl Write, message: &impl Serialize) -> Result<()> {
+    stream.write_all(&serde_json::to_vec(message)?)?;
+    Ok(())
+}
+
+/// Returns the engine of the namespace `op` addresses.
+fn target<E: KvsEngine>(engine: &E, op: &Request) -> Result<E> {
+    // writes create the namespace they address
+    match op.namespace() {
+        None => Ok(engine.clone()),
+        Some(name) => match op {
+            Request::Set { .. } | Request::Incr { .. } | Request::Append { .. } => engine
+                .create_namespace(name)
+                .and_then(|_| engine.namespace(name)),
+            _ => engine.namespace(name),
+        },
+    }
+}
+
+/// What a watch, `Changes` or replication stream sends, on either runtime.
+enum Feed {
+    /// Changes past `last_seq`, of which earlier ones went out in the
+    /// backlog.
+    Changes { last_seq: u64 },
+    /// Changes of the default keyspace after its snapshot, and heartbeats.
+    Replication,
+}
+
+impl Feed {
+    /// Starts the stream `op` asks for. Returns the changes to follow, the
+    /// responses that go out before them, and the feed turning them into
+    /// responses.
+    fn open<E: KvsEngine>(
+        engine: &E,
+        op: &Request,
+    ) -> Result<(Receiver<Change>, Vec<Response>, Feed)> {
+        // watch first so that no change falls between backlog and stream
+        match op {
+            Request::Watch { start, end, .. } => {
+                let changes = target(engine, op)?.watch((start.clone(), end.clone()))?;
+                Ok((changes, Vec::new(), Feed::Changes { last_seq: 0 }))
+            }
+            Request::Changes { since, .. } => {
+                let engine = target(engine, op)?;
+                let changes = engine.watch(..)?;
+                let backlog = engine.changes(*since)?;
+                let last_seq = backlog.last().map_or(*since, |change| change.seq);
+                let backlog = backlog
+                    .into_iter()
+                    .map(|change| Response::Ok(Reply::Change(change)))
+                    .collect();
+                Ok((changes, backlog, Feed::Changes { last_seq }))
+            }
+            Request::Replicate => {
+                let changes = engine.watch(..)?;
+                let snapshot = ReplicationEvent::Snapshot {
+                    pairs: engine.scan(..)?,
+                    timestamp: now(),
+                };
+                let snapshot = Response::Ok(Reply::Replication(snapshot));
+                Ok((changes, vec![snapshot], Feed::Replication))
+            }
+            _ => Err(KvsError::UnsupportedOperation),
+        }
+    }
+
+    /// The response to `change`, unless the backlog had it.
+    fn change(&mut self, change: Change) -> Option<Response> {
+        let reply = match self {
+            Feed::Changes { last_seq } if change.seq <= *last_seq => return None,
+            Feed::Changes { last_seq } => {
+                *last_seq = change.seq;
+                Reply::Change(change)
+            }
+            Feed::Replication => Reply::Replication(ReplicationEvent::Change {
+                change,
+                timestamp: now(),
+            }),
+        };
+        Some(Response::Ok(reply))
+    }
+
+    /// The response to `WATCH_POLL_INTERVAL` without a change, if any.
+    fn idle(&self) -> Option<Response> {
+        match self {
+            Feed::Changes { .. } => None,
+            Feed::Replication => Some(Response::Ok(Reply::Replication(
+                ReplicationEvent::Heartbeat { timestamp: now() },
+            ))),
+        }
+    }
+
+    /// The last response of a stream whose changes fell behind.
+    fn fell_behind(&self) -> Response {
+        Response::from(match self {
+            Feed::Changes { .. } => KvsError::Server("watch fell behind".to_owned()),
+            Feed::Replication => KvsError::Replication("replica fell behind".to_owned()),
+        })
+    }
+}
+
+/// Serves the stream `op` asks for until the client hangs up, or its
+/// changes fall behind.
+fn serve_stream<E: KvsEngine>(engine: &E, op: &Request, to: Responder, hung_up: &AtomicBool) {
+    let (changes, backlog, mut feed) = match Feed::open(engine, op) {
+        Ok(opened) => opened,
+        Err(e) => {
+            let _ = to.send(&Response::from(e));
+            return;
+        }
+    };
+    if backlog.iter().any(|response| to.send(response).is_err()) {
+        return;
+    }
+    loop {
+        let response = match changes.recv_timeout(WATCH_POLL_INTERVAL) {
+            Ok(change) => feed.change(change),
+            Err(RecvTimeoutError::Timeout) if hung_up.load(Ordering::Relaxed) => return,
+            Err(RecvTimeoutError::Timeout) => feed.idle(),
+            Err(RecvTimeoutError::Disconnected) => {
+                let _ = to.send(&feed.fell_behind());
+                return;
+            }
+        };
+        if response.is_some_and(|response| to.send(&response).is_err()) {
+            return;
+        }
+    }
+}
+
+/// Reads `reader` on a thread of its own until the client hangs up, then
+/// sets the flag returned. Whatever the client sends meanwhile, such as
+/// further pipelined frames, goes unanswered.
+fn hang_up(mut reader: impl Read + Send + 'static) -> Arc<AtomicBool> {
+    let hung_up = Arc::new(AtomicBool::new(false));
+    let flag = hung_up.clone();
+    thread::spawn(move || {
+        let mut buf = [0; 4096];
+        while matches!(reader.read(&mut buf), Ok(n) if n > 0) {}
+        flag.store(true, Ordering::Relaxed);
+    });
+    hung_up
+}
+
+/// Milliseconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+fn handle<E: KvsEngine>(
+    engine: &E,
+    op: Request,
+    replica: Option<&Replica>,
+    cluster: Option<&Cluster<E>>,
+) -> Result<Reply> {
+    // replicas only change through replication
+    if let Some(replica) = replica.filter(|_| op.is_write()) {
+        return Err(KvsError::ReadOnlyReplica(replica.primary()));
+    }
+    if let Some(cluster) = cluster {
+        if op.is_write() {
+            return write(cluster, op);
+        }
+        // only a leader a majority still follows has every write applied
+        let replicated = op.namespace().is_none();
+        let reads = matches!(
+            op,
+            Request::Get { .. } | Request::Scan { .. } | Request::History { .. }
+        );
+        if replicated && reads {
+            cluster.read()?;
+        }
+    }
+    if let Request::AddNode { addr } | Request::RemoveNode { addr } = &op {
+        let cluster = cluster.ok_or(KvsError::UnsupportedOperation)?;
+        let add = matches!(op, Request::AddNode { .. });
+        let addr = addr
+            .parse::<SocketAddr>()
+            .map_err(|e| KvsError::Raft(format!("invalid node address: {}", e)))?;
+        let members = cluster.change_membership(&addr.to_string(), add)?;
+        return Ok(Reply::Members(members));
+    }
+    let engine = target(engine, &op)?;
+    match op {
+        Request::Set { key, value, .. } => engine.set(key, value).map(|_| Reply::Done),
+        Request::Get { key, .. } => engine
+            .get(key)?
+            .map(Reply::Value)
+            .ok_or(KvsError::KeyNotFound),
+        Request::Rm { key, .. } => engine.remove(key).map(|_| Reply::Done),
+        Request::History { key, .. } => engine.history(key).map(Reply::Versions),
+        Request::Incr {
+            key,
+            delta,
+            initial,
+            ..
+        } => engine.incr(key, delta, initial).map(Reply::Integer),
+        Request::Append { key, value, .. } => engine.append(key, value).map(|_| Reply::Done),
+        Request::RmPrefix { prefix, .. } => engine.remove_prefix(&prefix).map(Reply::Count),
+        Request::RmRange { start, end, .. } => engine.remove_range((start, end)).map(Reply::Count),
+        Request::Scan { start, end, .. } => engine.scan((start, end)).map(Reply::Pairs),
+        Request::Stats { .. } => {
+            let mut stats = engine.stats()?;
+            if let Some(replica) = replica {
+                replica.report(&mut stats);
+            }
+            if let Some(cluster) = cluster {
+                cluster.report(&mut stats);
+            }
+            Ok(Reply::Stats(stats))
+        }
+        Request::Hello {
+            version,
+            capabilities,
+        } => Welcome::negotiate(version, &capabilities).map(Reply::Welcome),
+        // servers without users take any credentials
+        Request::Auth { .. } => Ok(Reply::Done),
+        Request::Watch { .. } | Request::Changes { .. } | Request::Replicate => {
+            unreachable!("streams are served by watch() and replicate()")
+        }
+        Request::Raft { .. } | Request::AddNode { .. } | Request::RemoveNode { .. } => {
+            unreachable!("cluster requests are served by run_with_engine() and above")
+        }
+    }
+}
+
+/// Commits the write `op` through the cluster. Nodes that do not lead
+/// redirect the client to the leader.
+fn write<E: KvsEngine>(cluster: &Cluster<E>, op: Request) -> Result<Reply> {
+    if op.namespace().is_some() {
+        return Err(KvsError::Raft("namespaces are not replicated".to_owned()));
+    }
+    let command = match &op {
+        Request::Set { key, value, .. } => Command::Set {
+            key: key.clone(),
+            value: value.clone(),
+        },
+        Request::Rm { key, .. } => Command::Remove { key: key.clone() },
+        Request::Incr {
+            key,
+            delta,
+            initial,
+            ..
+        } => Command::Merge {
+            key: key.clone(),
+            operator: merge::COUNTER.to_owned(),
+            operand: format!("[{},{}]", delta, initial),
+        },
+        Request::Append { key, value, .. } => Command::Merge {
+            key: key.clone(),
+            operator: merge::APPEND.to_owned(),
+            operand: value.clone(),
+        },
+        Request::RmPrefix { prefix, .. } => {
+            let (start, end) = prefix_range(prefix);
+            Command::RemoveRange { start, end }
+        }
+        Request::RmRange { start, end, .. } => Command::RemoveRange {
+            start: start.clone(),
+            end: end.clone(),
+        },
+        _ => unreachable!("not a write"),
+    };
+    let value = cluster.write(command)?;
+    match op {
+        Request::Set { .. } | Request::Rm { .. } | Request::Append { .. } => Ok(Reply::Done),
+        Request::Incr { .. } => value
+            .parse()
+            .map(Reply::Integer)
+            .map_err(|_| KvsError::Merge(format!("{:?} is not an integer", value))),
+        Request::RmPrefix { .. } | Request::RmRange { .. } => value
+            .parse()
+            .map(Reply::Count)
+            .map_err(|_| KvsError::Raft(format!("{:?} is not a count", value))),
+        _ => unreachable!("not a write"),
+    }
+}
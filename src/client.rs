@@ -0,0 +1,753 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use serde_json::Deserializer;
+
+use crate::{
+    read_frame, write_frame, Capability, Credentials, Envelope, ErrorCode, HashRing, KvsError,
+    Reply, Request, Response, Result, Stream, TlsClientConfig, Welcome, CAPABILITIES,
+    PROTOCOL_VERSION,
+};
+
+/// Idle connections kept open per server.
+const POOL_SIZE: usize = 4;
+/// How long a server that could not be reached stays out of the ring.
+const RETRY_FAILED_AFTER: Duration = Duration::from_secs(5);
+/// Redirects to follow before giving up on finding the leader of a cluster.
+const MAX_REDIRECTS: usize = 10;
+/// How long to wait for a cluster without a leader to elect one.
+const ELECTION_WAIT: Duration = Duration::from_millis(300);
+/// Points each server owns on the hash ring.
+const VNODES: usize = 64;
+
+/// Timeouts, wire format, encryption and credentials of `KvsClient`.
+#[derive(Debug, Clone)]
+pub struct KvsClientOptions {
+    /// How long opening a connection may take.
+    pub connect_timeout: Duration,
+    /// How long a server may take to answer, `None` to wait for ever.
+    /// Cluster writes wait up to five seconds for a majority.
+    pub read_timeout: Option<Duration>,
+    /// Whether to offer binary frames, spoken instead of JSON with servers
+    /// that agree to them.
+    pub binary_frames: bool,
+    /// TLS to speak with servers, in the clear if `None`.
+    pub tls: Option<TlsClientConfig>,
+    /// What connections authenticate with once open, if anything.
+    pub credentials: Option<Credentials>,
+}
+
+impl Default for KvsClientOptions {
+    fn default() -> Self {
+        KvsClientOptions {
+            connect_timeout: Duration::from_secs(3),
+            read_timeout: Some(Duration::from_secs(6)),
+            binary_frames: true,
+            tls: None,
+            credentials: None,
+        }
+    }
+}
+
+/// Client of one or more kvs-servers.
+///
+/// Requests on a key go to the server a consistent hash ring assigns the key
+/// to. Other requests go to every server, and their responses are merged
+/// like kvs-proxy does. A server that cannot be reached is taken out of the
+/// ring, so that its keys go to the next one, and is tried again after a
+/// while. Cluster nodes that do not lead are followed to their leader.
+#[derive(Clone)]
+pub struct KvsClient {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    options: KvsClientOptions,
+    servers: Mutex<Servers>,
+    /// Idle connections by server address.
+    pools: Mutex<HashMap<String, Vec<Connection>>>,
+    /// Connections shared by all callers, to servers that pipeline.
+    pipelines: Mutex<HashMap<String, Arc<Pipeline>>>,
+}
+
+struct Servers {
+    live: HashRing,
+    /// Servers out of the ring, with when they failed.
+    failed: HashMap<String, Instant>,
+}
+
+struct Connection {
+    writer: BufWriter<Stream>,
+    reader: BufReader<Stream>,
+    /// What the server agreed to when the connection opened.
+    welcome: Welcome,
+    /// ID of the last request sent in a frame.
+    last_id: u64,
+}
+
+/// Connection to a server that pipelines, shared by its callers. Requests
+/// go out as they come, and a thread hands each response to the caller
+/// waiting for its ID.
+struct Pipeline {
+    writer: Mutex<BufWriter<Stream>>,
+    last_id: AtomicU64,
+    /// Callers waiting for a response, by request ID. `None` once the
+    /// connection failed.
+    waiting: Arc<Waiting>,
+    welcome: Welcome,
+    read_timeout: Option<Duration>,
+}
+
+type Waiting = Mutex<Option<HashMap<u64, Sender<Result<Response>>>>>;
+
+/// Request sent over a pipeline, with where its response arrives.
+type Pending = (u64, Receiver<Result<Response>>);
+
+/// Error of a call, with whether the request may have reached the server.
+struct CallError {
+    error: KvsError,
+    sent: bool,
+}
+
+impl CallError {
+    fn unsent(error: KvsError) -> CallError {
+        CallError { error, sent: false }
+    }
+
+    fn sent(error: KvsError) -> CallError {
+        CallError { error, sent: true }
+    }
+
+    /// Whether the request can be sent again, elsewhere or on another
+    /// connection, without risking making it twice.
+    fn retries(&self, op: &Request) -> bool {
+        !self.sent || !op.is_write()
+    }
+}
+
+impl From<CallError> for KvsError {
+    fn from(e: CallError) -> KvsError {
+        e.error
+    }
+}
+
+impl KvsClient {
+    /// Creates a client of `servers`. Connections are opened on demand.
+    pub fn new(servers: impl IntoIterator<Item = SocketAddr>) -> KvsClient {
+        KvsClient::with_options(servers, KvsClientOptions::default())
+    }
+
+    pub fn with_options(
+        servers: impl IntoIterator<Item = SocketAddr>,
+        options: KvsClientOptions,
+    ) -> KvsClient {
+        let mut live = HashRing::new(VNODES);
+        for server in servers {
+            live.add(&server.to_string());
+        }
+        let servers = Servers {
+            live,
+            failed: HashMap::new(),
+        };
+        KvsClient {
+            inner: Arc::new(Inner {
+                options,
+                servers: Mutex::new(servers),
+                pools: Mutex::new(HashMap::new()),
+                pipelines: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Connects to the kvs-server at `addr`. The connection is kept open
+    /// for the requests to come.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<KvsClient> {
+        KvsClient::connect_with_options(addr, KvsClientOptions::default())
+    }
+
+    pub fn connect_with_options(
+        addr: impl ToSocketAddrs,
+        options: KvsClientOptions,
+    ) -> Result<KvsClient> {
+        let addr = addr.to_socket_addrs()?.next().ok_or_else(no_servers)?;
+        let client = KvsClient::with_options([addr], options);
+        let server = addr.to_string();
+        let conn = client.open(&server)?;
+        client.release(&server, conn);
+        Ok(client)
+    }
+
+    /// Gets the value of `key`, `None` if it is missing.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        value(self.request(&Request::Get { key, ns: None })?)
+    }
+
+    /// Sets the value of `key`.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        done(self.request(&Request::Set {
+            key,
+            value,
+            ns: None,
+        })?)
+    }
+
+    /// Removes `key`.
+    ///
+    /// # Errors
+    ///
+    /// `KvsError::KeyNotFound` if the key is missing.
+    pub fn remove(&self, key: String) -> Result<()> {
+        done(self.request(&Request::Rm { key, ns: None })?)
+    }
+
+    /// What `server` agreed to: the protocol version requests to it are
+    /// sent in, and the capabilities both sides support.
+    pub fn welcome(&self, server: &str) -> Result<Welcome> {
+        if let Some(pipeline) = self.pipeline(server) {
+            return Ok(pipeline.welcome.clone());
+        }
+        let conn = match self.idle(server) {
+            Some(conn) => conn,
+            None => self.open(server)?,
+        };
+        let welcome = conn.welcome.clone();
+        self.release(server, conn);
+        Ok(welcome)
+    }
+
+    /// Servers that are in the ring, the others having failed recently.
+    pub fn servers(&self) -> Vec<String> {
+        self.ring().nodes().map(str::to_owned).collect()
+    }
+
+    /// Sends `op` to the servers it concerns and returns their response.
+    /// Streams are read with `stream` instead.
+    pub fn request(&self, op: &Request) -> Result<Response> {
+        if let Some(key) = op.key() {
+            return self.request_key(key, op);
+        }
+        let ring = self.ring();
+        let servers: Vec<&str> = ring.nodes().collect();
+        let fan_out = matches!(
+            op,
+            Request::RmPrefix { .. }
+                | Request::RmRange { .. }
+                | Request::Scan { .. }
+                | Request::Stats { .. }
+        );
+        match servers[..] {
+            [] => Err(no_servers()),
+            [server, ..] if servers.len() == 1 || !fan_out => self.request_server(server, op),
+            _ => {
+                let responses = thread::scope(|scope| {
+                    let calls: Vec<_> = servers
+                        .iter()
+                        .map(|&server| {
+                            (server, scope.spawn(move || self.request_server(server, op)))
+                        })
+                        .collect();
+                    calls
+                        .into_iter()
+                        .map(|(server, call)| Ok((server.to_owned(), call.join().unwrap()?)))
+                        .collect::<Result<Vec<_>>>()
+                })?;
+                Ok(Response::merge(op, responses, &ring))
+            }
+        }
+    }
+
+    /// Sends each of `ops` to the server it concerns without waiting for the
+    /// responses to those before, where servers pipeline, and returns the
+    /// responses in the order of `ops`. Requests not on a single key, and
+    /// those to servers that do not pipeline, are sent like `request` does,
+    /// as are those that could not be written. Those that fail once written
+    /// are not sent again.
+    pub fn request_all(&self, ops: &[Request]) -> Vec<Result<Response>> {
+        let ring = self.ring();
+        let pending: Vec<Option<(Arc<Pipeline>, Pending)>> = ops
+            .iter()
+            .map(|op| {
+                let pipeline = self.pipeline_to(ring.node(op.key()?)?)?;
+                let pending = pipeline.submit(op).ok()?;
+                Some((pipeline, pending))
+            })
+            .collect();
+        ops.iter()
+            .zip(pending)
+            .map(|(op, pending)| {
+                match pending.map(|(pipeline, pending)| pipeline.wait(pending)) {
+                    // redirects, and requests that could not be written, are
+                    // left to `request`
+                    Some(Ok(Response::Error {
+                        code: ErrorCode::NotLeader,
+                        ..
+                    }))
+                    | None => self.request(op),
+                    Some(response) => response,
+                }
+            })
+            .collect()
+    }
+
+    /// Sends the `Watch` or `Changes` request `op` to every server and
+    /// returns the responses they stream, as they arrive.
+    pub fn stream(&self, op: &Request) -> Result<Receiver<Result<Response>>> {
+        let (sender, receiver) = mpsc::channel();
+        for server in self.servers() {
+            let mut conn = self.open(&server)?;
+            conn.writer.get_ref().set_read_timeout(None)?;
+            conn.send(op)?;
+            let sender = sender.clone();
+            thread::spawn(move || loop {
+                let response = conn.receive();
+                let failed = response.is_err();
+                if sender.send(response).is_err() || failed {
+                    return;
+                }
+            });
+        }
+        Ok(receiver)
+    }
+
+    /// Sends `op` to `server`, or to the leader of its cluster, and returns
+    /// its response. Failures do not take `server` out of the ring.
+    pub fn send(&self, server: &str, op: &Request) -> Result<Response> {
+        Ok(self.route(server, op)?)
+    }
+
+    fn route(&self, server: &str, op: &Request) -> std::result::Result<Response, CallError> {
+        let mut addr = server.to_owned();
+        for _ in 0..MAX_REDIRECTS {
+            let response = match self.call(&addr, op) {
+                // the leader we were sent to may have just failed
+                Err(e) if addr != server && e.retries(op) => {
+                    addr = server.to_owned();
+                    thread::sleep(ELECTION_WAIT);
+                    continue;
+                }
+                response => response?,
+            };
+            match response {
+                Response::Error {
+                    code: ErrorCode::NotLeader,
+                    message: leader,
+                } if !leader.is_empty() => addr = leader,
+                Response::Error {
+                    code: ErrorCode::NotLeader,
+                    ..
+                } => thread::sleep(ELECTION_WAIT),
+                response => return Ok(response),
+            }
+        }
+        Err(CallError::unsent(KvsError::NotLeader(None)))
+    }
+
+    /// Sends `op` to the owner of `key`, moving on to the next owner while
+    /// servers cannot be reached. Writes that may have reached a server
+    /// are not sent to another.
+    fn request_key(&self, key: &str, op: &Request) -> Result<Response> {
+        let mut error = None;
+        loop {
+            let Some(server) = self.ring().node(key).map(str::to_owned) else {
+                return Err(error.unwrap_or_else(no_servers));
+            };
+            match self.route(&server, op) {
+                Err(e) if matches!(e.error, KvsError::Io(_) | KvsError::Serde(_)) => {
+                    self.fail(&server);
+                    if !e.retries(op) {
+                        return Err(e.error);
+                    }
+                    error = Some(e.error);
+                }
+                response => return Ok(response?),
+            }
+        }
+    }
+
+    fn request_server(&self, server: &str, op: &Request) -> Result<Response> {
+        let response = self.send(server, op);
+        if let Err(KvsError::Io(_) | KvsError::Serde(_)) = response {
+            self.fail(server);
+        }
+        response
+    }
+
+    /// The ring of live servers, with those that failed long enough ago
+    /// back in it.
+    fn ring(&self) -> HashRing {
+        let mut servers = self.inner.servers.lock().unwrap();
+        let Servers { live, failed } = &mut *servers;
+        failed.retain(|server, since| {
+            let retry = since.elapsed() >= RETRY_FAILED_AFTER;
+            if retry {
+                live.add(server);
+            }
+            !retry
+        });
+        live.clone()
+    }
+
+    fn fail(&self, server: &str) {
+        let mut servers = self.inner.servers.lock().unwrap();
+        if servers.live.remove(server) {
+            servers.failed.insert(server.to_owned(), Instant::now());
+        }
+        self.inner.pools.lock().unwrap().remove(server);
+        self.inner.pipelines.lock().unwrap().remove(server);
+    }
+
+    /// Sends `op` to `addr` over its pipeline or an idle connection, or a
+    /// new one. It is sent on a new connection only if it could not be
+    /// written to the others.
+    fn call(&self, addr: &str, op: &Request) -> std::result::Result<Response, CallError> {
+        if let Some(pipeline) = self.pipeline(addr) {
+            if let Ok(pending) = pipeline.submit(op) {
+                return pipeline.wait(pending).map_err(CallError::sent);
+            }
+        } else if let Some(mut conn) = self.idle(addr) {
+            // the server may have closed the connection while it was idle
+            if !conn.is_stale() && conn.send(op).is_ok() {
+                let response = conn.receive().map_err(CallError::sent)?;
+                self.release(addr, conn);
+                return Ok(response);
+            }
+        }
+        let mut conn = self.open(addr).map_err(CallError::unsent)?;
+        if conn.pipelines() {
+            let pipeline = self.install(addr, conn).map_err(CallError::unsent)?;
+            let pending = pipeline.submit(op).map_err(CallError::unsent)?;
+            return pipeline.wait(pending).map_err(CallError::sent);
+        }
+        conn.send(op).map_err(CallError::unsent)?;
+        let response = conn.receive().map_err(CallError::sent)?;
+        self.release(addr, conn);
+        Ok(response)
+    }
+
+    fn pipeline(&self, addr: &str) -> Option<Arc<Pipeline>> {
+        let pipelines = self.inner.pipelines.lock().unwrap();
+        pipelines
+            .get(addr)
+            .filter(|pipeline| !pipeline.is_closed())
+            .cloned()
+    }
+
+    /// The pipeline to `addr`, opened if need be. `None` if the server does
+    /// not pipeline or cannot be reached.
+    fn pipeline_to(&self, addr: &str) -> Option<Arc<Pipeline>> {
+        if let Some(pipeline) = self.pipeline(addr) {
+            return Some(pipeline);
+        }
+        // connections of servers that pipeline are never idle
+        if let Some(conn) = self.idle(addr) {
+            self.release(addr, conn);
+            return None;
+        }
+        let conn = self.open(addr).ok()?;
+        if !conn.pipelines() {
+            self.release(addr, conn);
+            return None;
+        }
+        self.install(addr, conn).ok()
+    }
+
+    /// Shares `conn` among the callers of `addr`, unless another connection
+    /// already is.
+    fn install(&self, addr: &str, conn: Connection) -> Result<Arc<Pipeline>> {
+        let mut pipelines = self.inner.pipelines.lock().unwrap();
+        if let Some(pipeline) = pipelines.get(addr).filter(|pipeline| !pipeline.is_closed()) {
+            return Ok(pipeline.clone());
+        }
+        let pipeline = Arc::new(Pipeline::start(conn, self.inner.options.read_timeout)?);
+        pipelines.insert(addr.to_owned(), pipeline.clone());
+        Ok(pipeline)
+    }
+
+    fn idle(&self, addr: &str) -> Option<Connection> {
+        self.inner
+            .pools
+            .lock()
+            .unwrap()
+            .get_mut(addr)
+            .and_then(Vec::pop)
+    }
+
+    fn open(&self, addr: &str) -> Result<Connection> {
+        let addr = addr
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Connection::open(&addr, &self.inner.options)
+    }
+
+    fn release(&self, addr: &str, conn: Connection) {
+        if conn.pipelines() {
+            let _ = self.install(addr, conn);
+            return;
+        }
+        let mut pools = self.inner.pools.lock().unwrap();
+        let pool = pools.entry(addr.to_owned()).or_default();
+        if pool.len() < POOL_SIZE {
+            pool.push(conn);
+        }
+    }
+}
+
+impl fmt::Debug for KvsClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KvsClient")
+            .field("servers", &self.servers())
+            .finish()
+    }
+}
+
+impl Connection {
+    fn open(addr: &SocketAddr, options: &KvsClientOptions) -> Result<Connection> {
+        let stream = TcpStream::connect_timeout(addr, options.connect_timeout)?;
+        stream.set_read_timeout(options.read_timeout)?;
+        let stream = match &options.tls {
+            Some(tls) => tls.connect(stream, addr.ip())?,
+            None => Stream::from(stream),
+        };
+        let mut conn = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            welcome: Welcome::unversioned(),
+            last_id: 0,
+        };
+        conn.welcome = conn.hello(offered(options))?;
+        if let Some(credentials) = &options.credentials {
+            let auth = Request::Auth {
+                credentials: credentials.clone(),
+            };
+            done(conn.call(&auth)?)?;
+        }
+        Ok(conn)
+    }
+
+    /// Offers the protocol version and capabilities of this client. Servers
+    /// from before handshakes refuse the offer, and get requests of the
+    /// oldest version instead.
+    fn hello(&mut self, capabilities: BTreeSet<Capability>) -> Result<Welcome> {
+        let hello = Request::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities,
+        };
+        match self.call(&hello)?.into_reply() {
+            Ok(Reply::Welcome(welcome)) => Ok(welcome),
+            Ok(reply) => Err(reply.unexpected()),
+            Err(KvsError::InvalidRequest(_) | KvsError::UnsupportedVersion(_)) => {
+                Ok(Welcome::unversioned())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn call(&mut self, op: &Request) -> Result<Response> {
+        self.send(op)?;
+        self.receive()
+    }
+
+    /// Whether the server closed the connection, or sent what no request
+    /// asked for, while it was idle.
+    fn is_stale(&self) -> bool {
+        if !self.reader.buffer().is_empty() {
+            return true;
+        }
+        let tcp = self.writer.get_ref().tcp();
+        if tcp.set_nonblocking(true).is_err() {
+            return true;
+        }
+        let idle = matches!(tcp.peek(&mut [0]), Err(e) if e.kind() == io::ErrorKind::WouldBlock);
+        tcp.set_nonblocking(false).is_err() || !idle
+    }
+
+    /// Whether the connection speaks binary frames rather than JSON.
+    fn framed(&self) -> bool {
+        self.welcome
+            .capabilities
+            .contains(&Capability::BinaryFrames)
+    }
+
+    /// Whether the server answers requests sent without waiting, over
+    /// frames.
+    fn pipelines(&self) -> bool {
+        self.welcome.capabilities.contains(&Capability::Pipelining)
+    }
+
+    fn send(&mut self, op: &Request) -> Result<()> {
+        let envelope = Envelope {
+            version: self.welcome.version,
+            request: op,
+        };
+        if self.framed() {
+            self.last_id += 1;
+            write_frame(&mut self.writer, self.last_id, &envelope)?;
+        } else {
+            serde_json::to_writer(&mut self.writer, &envelope)?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<Response> {
+        if self.framed() {
+            let frame = read_frame(&mut self.reader)?
+                .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+            if frame.id != self.last_id {
+                return Err(KvsError::Frame(format!(
+                    "response to request {} while awaiting {}",
+                    frame.id, self.last_id
+                )));
+            }
+            return frame.message();
+        }
+        let mut reader = Deserializer::from_reader(&mut self.reader);
+        Response::deserialize(&mut reader).map_err(|e| match e.is_io() {
+            // timeouts and hang-ups
+            true => KvsError::Io(e.into()),
+            false => e.into(),
+        })
+    }
+}
+
+impl Pipeline {
+    fn start(conn: Connection, read_timeout: Option<Duration>) -> Result<Pipeline> {
+        let Connection {
+            writer,
+            mut reader,
+            welcome,
+            last_id,
+        } = conn;
+        // callers time out on their own
+        reader.get_ref().set_read_timeout(None)?;
+        let waiting: Arc<Waiting> = Arc::new(Mutex::new(Some(HashMap::new())));
+        let callers = waiting.clone();
+        thread::spawn(move || {
+            let error = loop {
+                let frame = match read_frame(&mut reader) {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => break io::Error::from(io::ErrorKind::UnexpectedEof),
+                    Err(KvsError::Io(e)) => break e,
+                    Err(e) => break io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+                };
+                // callers that timed out no longer wait
+                let mut callers = callers.lock().unwrap();
+                let caller = callers
+                    .as_mut()
+                    .and_then(|waiting| waiting.remove(&frame.id));
+                if let Some(caller) = caller {
+                    let _ = caller.send(frame.message());
+                }
+            };
+            let waiting = callers.lock().unwrap().take().unwrap_or_default();
+            for caller in waiting.into_values() {
+                let error = io::Error::new(error.kind(), error.to_string());
+                let _ = caller.send(Err(error.into()));
+            }
+        });
+        Ok(Pipeline {
+            writer: Mutex::new(writer),
+            last_id: AtomicU64::new(last_id),
+            waiting,
+            welcome,
+            read_timeout,
+        })
+    }
+
+    /// Sends `op` without waiting for its response.
+    fn submit(&self, op: &Request) -> Result<Pending> {
+        let (sender, receiver) = mpsc::channel();
+        let mut writer = self.writer.lock().unwrap();
+        let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+        match self.waiting.lock().unwrap().as_mut() {
+            Some(waiting) => waiting.insert(id, sender),
+            None => return Err(io::Error::from(io::ErrorKind::NotConnected).into()),
+        };
+        let envelope = Envelope {
+            version: self.welcome.version,
+            request: op,
+        };
+        let sent = write_frame(&mut *writer, id, &envelope).and_then(|_| Ok(writer.flush()?));
+        if let Err(e) = sent {
+            self.forget(id);
+            return Err(e);
+        }
+        Ok((id, receiver))
+    }
+
+    /// Waits for the response to a request sent by `submit`.
+    fn wait(&self, (id, receiver): Pending) -> Result<Response> {
+        let response = match self.read_timeout {
+            Some(timeout) => receiver.recv_timeout(timeout),
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match response {
+            Ok(response) => response,
+            Err(RecvTimeoutError::Timeout) => {
+                self.forget(id);
+                Err(io::Error::from(io::ErrorKind::TimedOut).into())
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(io::Error::from(io::ErrorKind::NotConnected).into())
+            }
+        }
+    }
+
+    fn forget(&self, id: u64) {
+        if let Some(waiting) = self.waiting.lock().unwrap().as_mut() {
+            waiting.remove(&id);
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.waiting.lock().unwrap().is_none()
+    }
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        // ends the thread reading responses
+        if let Ok(writer) = self.writer.get_mut() {
+            let _ = writer.get_ref().shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Value of the response to a `Get`, `None` for a miss.
+pub(crate) fn value(response: Response) -> Result<Option<String>> {
+    match response.into_reply() {
+        Ok(Reply::Value(value)) => Ok(Some(value)),
+        Ok(reply) => Err(reply.unexpected()),
+        Err(KvsError::KeyNotFound) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Outcome of a write of a single key.
+pub(crate) fn done(response: Response) -> Result<()> {
+    match response.into_reply()? {
+        Reply::Done => Ok(()),
+        reply => Err(reply.unexpected()),
+    }
+}
+
+/// Capabilities a client with `options` offers.
+pub(crate) fn offered(options: &KvsClientOptions) -> BTreeSet<Capability> {
+    CAPABILITIES
+        .iter()
+        .filter(|&&capability| options.binary_frames || capability != Capability::BinaryFrames)
+        .copied()
+        .collect()
+}
+
+fn no_servers() -> KvsError {
+    io::Error::new(io::ErrorKind::NotConnected, "no server can be reached").into()
+}
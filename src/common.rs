@@ -1,17 +1,556 @@
+use std::collections::BTreeSet;
+use std::ops::Bound;
+
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::auth::Credentials;
+use crate::raft::Message;
+use crate::{Change, HashRing, KvsError, Result, Stats, Version};
 
 pub const DEFAULT_IP_ADDR: &str = "127.0.0.1:4000";
 
+/// Version of the protocol. Version 3 begins connections with a `Hello`.
+pub const PROTOCOL_VERSION: u32 = 3;
+/// Oldest version servers still answer. Others are rejected with
+/// `KvsError::UnsupportedVersion`.
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+/// Capabilities this version of kvs supports, as client and as server.
+pub const CAPABILITIES: &[Capability] = &[
+    Capability::Scan,
+    Capability::BinaryFrames,
+    Capability::Pipelining,
+];
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    Set { key: String, value: String },
-    Get { key: String },
-    Rm { key: String },
+    Set {
+        key: String,
+        value: String,
+        /// Namespace of the key, the default keyspace if `None`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ns: Option<String>,
+    },
+    Get {
+        key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ns: Option<String>,
+    },
+    Rm {
+        key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ns: Option<String>,
+    },
+    History {
+        key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ns: Option<String>,
+    },
+    /// Adds `delta` to the integer under `key`, starting from `initial`.
+    Incr {
+        key: String,
+        delta: i64,
+        initial: i64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ns: Option<String>,
+    },
+    Append {
+        key: String,
+        value: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ns: Option<String>,
+    },
+    /// Removes every key starting with `prefix`.
+    RmPrefix {
+        prefix: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ns: Option<String>,
+    },
+    /// Removes every key between `start` and `end`.
+    RmRange {
+        start: Bound<String>,
+        end: Bound<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ns: Option<String>,
+    },
+    /// Returns the pairs with keys between `start` and `end`, ordered by
+    /// key.
+    Scan {
+        start: Bound<String>,
+        end: Bound<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ns: Option<String>,
+    },
+    /// Streams the changes to keys between `start` and `end` until the
+    /// client hangs up.
+    Watch {
+        start: Bound<String>,
+        end: Bound<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ns: Option<String>,
+    },
+    /// Streams the retained changes after sequence number `since`, then the
+    /// changes to come until the client hangs up.
+    Changes {
+        since: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ns: Option<String>,
+    },
+    Stats {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ns: Option<String>,
+    },
+    /// Streams a snapshot of the default keyspace, then every change to it,
+    /// to a replica.
+    Replicate,
+    /// Message from another node of the cluster. It is not answered.
+    Raft {
+        from: String,
+        message: Message,
+    },
+    /// Adds the kvs-server at `addr` to the cluster.
+    AddNode {
+        addr: String,
+    },
+    RemoveNode {
+        addr: String,
+    },
+    /// Opens a connection with the highest protocol version and the
+    /// capabilities the client supports, answered with a `Welcome`. Sent in
+    /// an envelope of `MIN_PROTOCOL_VERSION` so that servers from before
+    /// handshakes reject it in a way clients tell.
+    Hello {
+        version: u32,
+        capabilities: BTreeSet<Capability>,
+    },
+    /// Authenticates the connection, whose requests then need a grant of
+    /// the user on servers with users. Answered with `Done`, by servers
+    /// without users too.
+    Auth {
+        credentials: Credentials,
+    },
+}
+
+/// Optional feature of the protocol, used only once both sides offer it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Capability {
+    /// Several requests in one.
+    Batch,
+    /// `Scan` requests.
+    Scan,
+    /// Keys that expire.
+    Ttl,
+    /// Compressed messages.
+    Compression,
+    /// Authenticated clients.
+    Auth,
+    /// Binary frames instead of JSON after the handshake, see `Frame`.
+    BinaryFrames,
+    /// Requests sent without waiting for the responses to those before,
+    /// which are answered as they complete. Only with `BinaryFrames`, whose
+    /// IDs tell the responses apart.
+    Pipelining,
+    /// A capability of a later version, which is never agreed on.
+    #[serde(other)]
+    Unknown,
+}
+
+/// What a server agrees to in answer to a `Hello`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Welcome {
+    /// Version of the protocol the connection speaks.
+    pub version: u32,
+    /// Capabilities both sides offered.
+    pub capabilities: BTreeSet<Capability>,
+}
+
+impl Welcome {
+    /// This server's answer to a client offering protocol versions up to
+    /// `version` and `capabilities`: the highest version both speak, or
+    /// `KvsError::UnsupportedVersion` if there is none.
+    pub fn negotiate(version: u32, capabilities: &BTreeSet<Capability>) -> Result<Welcome> {
+        let version = version.min(PROTOCOL_VERSION);
+        if version < MIN_PROTOCOL_VERSION {
+            return Err(KvsError::UnsupportedVersion(version));
+        }
+        let mut agreed: BTreeSet<Capability> = CAPABILITIES
+            .iter()
+            .filter(|capability| capabilities.contains(capability))
+            .copied()
+            .collect();
+        if !agreed.contains(&Capability::BinaryFrames) {
+            agreed.remove(&Capability::Pipelining);
+        }
+        Ok(Welcome {
+            version,
+            capabilities: agreed,
+        })
+    }
+
+    /// What a server from before handshakes supports.
+    pub fn unversioned() -> Welcome {
+        Welcome {
+            version: MIN_PROTOCOL_VERSION,
+            capabilities: BTreeSet::new(),
+        }
+    }
+}
+
+impl Request {
+    /// Whether the request changes keys.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Request::Set { .. }
+                | Request::Rm { .. }
+                | Request::Incr { .. }
+                | Request::Append { .. }
+                | Request::RmPrefix { .. }
+                | Request::RmRange { .. }
+        )
+    }
+
+    /// Key of a request on a single key.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Request::Set { key, .. }
+            | Request::Get { key, .. }
+            | Request::Rm { key, .. }
+            | Request::History { key, .. }
+            | Request::Incr { key, .. }
+            | Request::Append { key, .. } => Some(key),
+            _ => None,
+        }
+    }
+
+    /// Namespace the request is addressed to.
+    pub fn namespace(&self) -> Option<&str> {
+        match self {
+            Request::Set { ns, .. }
+            | Request::Get { ns, .. }
+            | Request::Rm { ns, .. }
+            | Request::History { ns, .. }
+            | Request::Incr { ns, .. }
+            | Request::Append { ns, .. }
+            | Request::RmPrefix { ns, .. }
+            | Request::RmRange { ns, .. }
+            | Request::Scan { ns, .. }
+            | Request::Watch { ns, .. }
+            | Request::Changes { ns, .. }
+            | Request::Stats { ns } => ns.as_deref(),
+            Request::Replicate
+            | Request::Raft { .. }
+            | Request::AddNode { .. }
+            | Request::RemoveNode { .. }
+            | Request::Hello { .. }
+            | Request::Auth { .. } => None,
+        }
+    }
+}
+
+/// A request as sent over the wire, with the protocol version of its client.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope<R = Request> {
+    pub version: u32,
+    pub request: R,
+}
+
+impl<R> Envelope<R> {
+    /// Envelope of `request` for this version of the protocol.
+    pub fn new(request: R) -> Envelope<R> {
+        Envelope {
+            version: PROTOCOL_VERSION,
+            request,
+        }
+    }
+}
+
+/// Reads the request in `message`, as read off the wire. A message that is
+/// not a request of this version of the protocol gets the returned
+/// rejection instead, in a format its client reads.
+pub fn parse_request(
+    message: serde_json::Value,
+) -> std::result::Result<Request, serde_json::Value> {
+    let reject = |e: KvsError| serde_json::to_value(Response::from(e)).unwrap();
+    let Some(version) = message.get("version") else {
+        return Err(reject_unversioned(&message));
+    };
+    match version.as_u64().map(u32::try_from) {
+        Some(Ok(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION)) => {}
+        Some(Ok(version)) => return Err(reject(KvsError::UnsupportedVersion(version))),
+        _ => {
+            return Err(reject(KvsError::InvalidRequest(format!(
+                "version {}",
+                version
+            ))))
+        }
+    }
+    serde_json::from_value::<Envelope>(message)
+        .map(|envelope| envelope.request)
+        .map_err(|e| reject(KvsError::InvalidRequest(e.to_string())))
+}
+
+/// Rejects a request of a client from before protocol versions, which reads
+/// responses named after its request holding either a message or a
+/// `Result`.
+fn reject_unversioned(message: &serde_json::Value) -> serde_json::Value {
+    let error = KvsError::UnsupportedVersion(1).to_string();
+    let name = match message {
+        serde_json::Value::Object(fields) => fields.keys().next().map(String::as_str),
+        serde_json::Value::String(name) => Some(name.as_str()),
+        _ => None,
+    };
+    let name = name.unwrap_or("Get");
+    let value = match name {
+        "Get" | "Set" | "Rm" | "Append" => json!(error),
+        _ => json!({ "Err": error }),
+    };
+    json!({ name: { "value": value } })
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Response {
-    Get { value: String },
-    Rm { value: String },
-    Set { value: String },
+    Ok(Reply),
+    /// The key of the request does not exist.
+    NotFound,
+    /// The request failed with the `KvsError` `code` names. `message` is
+    /// what the error carries, so that clients can rebuild it: the leader of
+    /// `NotLeader` for one, or the description of errors that carry nothing.
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+/// What a request that succeeded returns.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Reply {
+    /// Writes of a single key return nothing.
+    Done,
+    Value(String),
+    Integer(i64),
+    /// Keys removed.
+    Count(usize),
+    /// Pairs ordered by key.
+    Pairs(Vec<(String, String)>),
+    Versions(Vec<Version>),
+    /// One of the changes streamed for a `Watch` or `Changes`.
+    Change(Change),
+    Stats(Stats),
+    /// One of the events streamed for a `Replicate`.
+    Replication(ReplicationEvent),
+    /// Members of the cluster after an `AddNode` or `RemoveNode`, or
+    /// backends of a proxy.
+    Members(Vec<String>),
+    Welcome(Welcome),
+}
+
+impl Reply {
+    /// Error for a reply of the wrong kind for its request.
+    pub fn unexpected(self) -> KvsError {
+        KvsError::Server(format!("unexpected reply {:?}", self))
+    }
+}
+
+/// `KvsError` of a failed request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    UnsupportedOperation,
+    Corrupted,
+    UnknownMergeOperator,
+    Merge,
+    NamespaceNotFound,
+    InvalidNamespace,
+    ChangesTruncated,
+    ReadOnlyReplica,
+    Replication,
+    NotLeader,
+    Raft,
+    Backend,
+    UnsupportedVersion,
+    InvalidRequest,
+    Unauthorized,
+    /// Failures of the server itself, such as those of its disk.
+    Internal,
+}
+
+impl From<KvsError> for Response {
+    fn from(e: KvsError) -> Response {
+        let (code, message) = match e {
+            KvsError::KeyNotFound => return Response::NotFound,
+            KvsError::UnsupportedOperation => (ErrorCode::UnsupportedOperation, e.to_string()),
+            KvsError::Corrupted(message) => (ErrorCode::Corrupted, message),
+            KvsError::UnknownMergeOperator(name) => (ErrorCode::UnknownMergeOperator, name),
+            KvsError::Merge(message) => (ErrorCode::Merge, message),
+            KvsError::MergeOverflow => (ErrorCode::Merge, "integer overflow".to_owned()),
+            KvsError::NamespaceNotFound(name) => (ErrorCode::NamespaceNotFound, name),
+            KvsError::InvalidNamespace(name) => (ErrorCode::InvalidNamespace, name),
+            KvsError::ChangesTruncated(seq) => (ErrorCode::ChangesTruncated, seq.to_string()),
+            KvsError::ReadOnlyReplica(primary) => (ErrorCode::ReadOnlyReplica, primary.to_string()),
+            KvsError::Replication(message) => (ErrorCode::Replication, message),
+            KvsError::NotLeader(leader) => (ErrorCode::NotLeader, leader.unwrap_or_default()),
+            KvsError::Raft(message) => (ErrorCode::Raft, message),
+            KvsError::Backend(server, message) => {
+                (ErrorCode::Backend, format!("{}: {}", server, message))
+            }
+            KvsError::UnsupportedVersion(version) => {
+                (ErrorCode::UnsupportedVersion, version.to_string())
+            }
+            KvsError::InvalidRequest(message) => (ErrorCode::InvalidRequest, message),
+            KvsError::Unauthorized(message) => (ErrorCode::Unauthorized, message),
+            KvsError::Server(message) => (ErrorCode::Internal, message),
+            KvsError::Frame(_) | KvsError::Tls(_) => (ErrorCode::Internal, e.to_string()),
+            KvsError::Io(ref cause) => (ErrorCode::Internal, format!("{}: {}", e, cause)),
+            KvsError::Serde(ref cause) => (ErrorCode::Internal, format!("{}: {}", e, cause)),
+            KvsError::Sled(ref cause) => (ErrorCode::Internal, format!("{}: {}", e, cause)),
+            KvsError::ThreadPoolBuilderError(_) => (ErrorCode::Internal, e.to_string()),
+        };
+        Response::Error { code, message }
+    }
+}
+
+impl From<Result<Reply>> for Response {
+    fn from(reply: Result<Reply>) -> Response {
+        match reply {
+            Ok(reply) => Response::Ok(reply),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl Response {
+    /// The reply of a request that succeeded, or the error of one that did
+    /// not.
+    pub fn into_reply(self) -> Result<Reply> {
+        match self {
+            Response::Ok(reply) => Ok(reply),
+            Response::NotFound => Err(KvsError::KeyNotFound),
+            Response::Error { code, message } => Err(error(code, message)),
+        }
+    }
+
+    /// Combines the responses of the servers of `ring` to `op`, sent to
+    /// each of them: counts are added up, pairs merged, and stats of each
+    /// server are prefixed with its address.
+    ///
+    /// # Panics
+    ///
+    /// Unless `op` is a `RmPrefix`, `RmRange`, `Scan` or `Stats`.
+    pub fn merge(op: &Request, responses: Vec<(String, Response)>, ring: &HashRing) -> Response {
+        merge(op, responses, ring).into()
+    }
+}
+
+/// The `KvsError` a response with `code` and `message` stands for.
+fn error(code: ErrorCode, message: String) -> KvsError {
+    match code {
+        ErrorCode::UnsupportedOperation => KvsError::UnsupportedOperation,
+        ErrorCode::Corrupted => KvsError::Corrupted(message),
+        ErrorCode::UnknownMergeOperator => KvsError::UnknownMergeOperator(message),
+        ErrorCode::Merge => KvsError::Merge(message),
+        ErrorCode::NamespaceNotFound => KvsError::NamespaceNotFound(message),
+        ErrorCode::InvalidNamespace => KvsError::InvalidNamespace(message),
+        ErrorCode::ChangesTruncated => match message.parse() {
+            Ok(seq) => KvsError::ChangesTruncated(seq),
+            Err(_) => KvsError::Server(message),
+        },
+        ErrorCode::ReadOnlyReplica => match message.parse() {
+            Ok(primary) => KvsError::ReadOnlyReplica(primary),
+            Err(_) => KvsError::Server(message),
+        },
+        ErrorCode::Replication => KvsError::Replication(message),
+        ErrorCode::NotLeader => KvsError::NotLeader(Some(message).filter(|m| !m.is_empty())),
+        ErrorCode::Raft => KvsError::Raft(message),
+        ErrorCode::Backend => match message.split_once(": ") {
+            Some((server, message)) => KvsError::Backend(server.to_owned(), message.to_owned()),
+            None => KvsError::Server(message),
+        },
+        ErrorCode::UnsupportedVersion => match message.parse() {
+            Ok(version) => KvsError::UnsupportedVersion(version),
+            Err(_) => KvsError::Server(message),
+        },
+        ErrorCode::InvalidRequest => KvsError::InvalidRequest(message),
+        ErrorCode::Unauthorized => KvsError::Unauthorized(message),
+        ErrorCode::Internal => KvsError::Server(message),
+    }
+}
+
+fn merge(op: &Request, responses: Vec<(String, Response)>, ring: &HashRing) -> Result<Reply> {
+    let mut replies = Vec::with_capacity(responses.len());
+    for (server, response) in responses {
+        match response.into_reply() {
+            Ok(reply) => replies.push((server, reply)),
+            Err(e) => return Err(KvsError::Backend(server, e.to_string())),
+        }
+    }
+    match op {
+        Request::RmPrefix { .. } | Request::RmRange { .. } => {
+            let mut removed = 0;
+            for (_, reply) in replies {
+                let Reply::Count(count) = reply else {
+                    return Err(reply.unexpected());
+                };
+                removed += count;
+            }
+            Ok(Reply::Count(removed))
+        }
+        Request::Scan { .. } => {
+            let mut pairs = Vec::new();
+            for (server, reply) in replies {
+                let Reply::Pairs(value) = reply else {
+                    return Err(reply.unexpected());
+                };
+                // leftovers of an interrupted rebalancing are not the owner's
+                pairs.extend(
+                    value
+                        .into_iter()
+                        .filter(|(key, _)| ring.node(key) == Some(&server)),
+                );
+            }
+            pairs.sort_unstable();
+            Ok(Reply::Pairs(pairs))
+        }
+        Request::Stats { .. } => {
+            let mut stats = Stats::new();
+            let mut keys = 0;
+            for (server, reply) in replies {
+                let Reply::Stats(value) = reply else {
+                    return Err(reply.unexpected());
+                };
+                for (name, value) in value {
+                    if name == "keys" {
+                        keys += value.parse::<u64>().unwrap_or(0);
+                    }
+                    stats.insert(format!("{}/{}", server, name), value);
+                }
+            }
+            let servers: Vec<&str> = ring.nodes().collect();
+            stats.insert("backends".to_owned(), servers.join(","));
+            stats.insert("keys".to_owned(), keys.to_string());
+            Ok(Reply::Stats(stats))
+        }
+        _ => unreachable!("not a fan-out request"),
+    }
+}
+
+/// Message of the stream from a primary to a replica. Timestamps are the
+/// primary's clock when sending, in milliseconds since the Unix epoch.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ReplicationEvent {
+    /// Every pair as of some point after the stream began. Some of the
+    /// changes that follow may already be part of it, which is harmless as
+    /// they are replayed in order.
+    Snapshot {
+        pairs: Vec<(String, String)>,
+        timestamp: u64,
+    },
+    Change {
+        change: Change,
+        timestamp: u64,
+    },
+    /// Sent while there are no changes, so that replicas can tell an idle
+    /// primary from a lost one.
+    Heartbeat {
+        timestamp: u64,
+    },
 }
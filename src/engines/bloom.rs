@@ -0,0 +1,148 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::Stats;
+
+/// A fixed-size bloom filter over string keys.
+///
+/// The hash is computed in this module rather than with `std`'s hasher so
+/// the filter can be written to disk and read back by another build.
+#[derive(Debug, Clone)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// Creates a filter sized for `keys` entries with `bits_per_key` bits each.
+    pub fn new(keys: usize, bits_per_key: usize) -> Self {
+        let nbits = (keys * bits_per_key).max(64);
+        // k = ln(2) * m / n minimises the false positive rate
+        let hashes = ((bits_per_key as f64) * 0.69).round().clamp(1.0, 30.0) as u32;
+        Self {
+            bits: vec![0; nbits.div_ceil(8)],
+            hashes,
+        }
+    }
+
+    /// Creates a filter holding every key whose `hash` is in `hashes`.
+    pub fn from_hashes(hashes: &[u64], bits_per_key: usize) -> Self {
+        let mut filter = Self::new(hashes.len(), bits_per_key);
+        for hash in hashes {
+            filter.insert_hash(*hash);
+        }
+        filter
+    }
+
+    pub fn insert(&mut self, key: &str) {
+        self.insert_hash(hash(key));
+    }
+
+    fn insert_hash(&mut self, hash: u64) {
+        let nbits = self.bits.len() as u64 * 8;
+        for bit in probes(hash, self.hashes, nbits) {
+            self.bits[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+    }
+
+    /// Returns `false` if `key` was definitely never inserted.
+    pub fn may_contain(&self, key: &str) -> bool {
+        let nbits = self.bits.len() as u64 * 8;
+        probes(hash(key), self.hashes, nbits)
+            .all(|bit| self.bits[(bit / 8) as usize] & (1 << (bit % 8)) != 0)
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.bits.len()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.bits.len() + 4);
+        buf.extend_from_slice(&self.hashes.to_le_bytes());
+        buf.extend_from_slice(&self.bits);
+        buf
+    }
+
+    /// Decodes a filter written by `to_bytes`, returning `None` if `buf` is malformed.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < 5 {
+            return None;
+        }
+        let hashes = u32::from_le_bytes(buf[..4].try_into().unwrap());
+        Some(Self {
+            bits: buf[4..].to_vec(),
+            hashes,
+        })
+    }
+}
+
+/// Bits per key needed to keep the false positive rate at `false_positive_rate`.
+pub fn bits_per_key(false_positive_rate: f64) -> usize {
+    // m / n = -ln(p) / ln(2)^2
+    let rate = false_positive_rate.clamp(1e-9, 0.5);
+    (-rate.ln() / (2f64.ln() * 2f64.ln())).ceil() as usize
+}
+
+/// Outcome counters of the filters of an engine, for its stats.
+#[derive(Debug, Default)]
+pub struct FilterCounters {
+    negatives: AtomicU64,
+    false_positives: AtomicU64,
+}
+
+impl FilterCounters {
+    /// Records a lookup the filter answered with "definitely absent".
+    pub fn negative(&self) {
+        self.negatives.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a lookup the filter let through for a key that was absent.
+    pub fn false_positive(&self) {
+        self.false_positives.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn report(&self, stats: &mut Stats, false_positive_rate: f64) {
+        let negatives = self.negatives.load(Ordering::Relaxed);
+        let false_positives = self.false_positives.load(Ordering::Relaxed);
+        let observed = if negatives + false_positives == 0 {
+            0.0
+        } else {
+            false_positives as f64 / (negatives + false_positives) as f64
+        };
+        stats.insert(
+            "bloom_false_positive_rate".to_owned(),
+            false_positive_rate.to_string(),
+        );
+        stats.insert("bloom_negatives".to_owned(), negatives.to_string());
+        stats.insert(
+            "bloom_false_positives".to_owned(),
+            false_positives.to_string(),
+        );
+        stats.insert(
+            "bloom_observed_false_positive_rate".to_owned(),
+            format!("{:.6}", observed),
+        );
+    }
+}
+
+fn probes(h: u64, hashes: u32, nbits: u64) -> impl Iterator<Item = u64> {
+    // double hashing: g_i(x) = h1(x) + i * h2(x)
+    let h1 = h & 0xffff_ffff;
+    let h2 = (h >> 32) | 1;
+    (0..hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % nbits)
+}
+
+/// Stable 64-bit hash of `key`, usable with `BloomFilter::from_hashes`.
+pub fn hash(key: &str) -> u64 {
+    // FNV-1a
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in key.as_bytes() {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    // murmur3 finaliser, spreads the entropy into the high bits
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
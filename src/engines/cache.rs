@@ -0,0 +1,298 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::RangeBounds;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+
+use crate::{Change, KvsEngine, Result, Stats, Version};
+
+/// Per entry bookkeeping bytes counted against the cache capacity.
+const ENTRY_OVERHEAD: usize = 64;
+
+/// Which entry `CachedEngine` evicts when it is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// Least recently used.
+    Lru,
+    /// Least frequently used, ties broken by recency.
+    Lfu,
+}
+
+/// Read-through value cache in front of another engine.
+///
+/// Values read from the inner engine are kept up to a byte budget and served
+/// from memory afterwards. Writes go to the inner engine first and then drop
+/// the cached value of their key. Namespaces of the inner engine share the
+/// cache of the handle they were opened from.
+#[derive(Debug, Clone)]
+pub struct CachedEngine<E: KvsEngine> {
+    inner: E,
+    cache: Arc<Mutex<ValueCache>>,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+    namespace: Option<Arc<str>>,
+}
+
+#[derive(Debug)]
+struct ValueCache {
+    policy: CachePolicy,
+    capacity: usize,
+    size: usize,
+    clock: u64,
+    /// Bumped by every write. A value read from the inner engine is only
+    /// cached if no write happened while it was being read.
+    epoch: u64,
+    entries: HashMap<String, CacheEntry>,
+    /// Eviction order, smallest rank first.
+    ranks: BTreeMap<(u64, u64), String>,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    value: String,
+    rank: (u64, u64),
+    uses: u64,
+}
+
+impl<E: KvsEngine> CachedEngine<E> {
+    /// Wraps `inner` with an LRU cache of `capacity` bytes.
+    pub fn new(inner: E, capacity: usize) -> Self {
+        Self::with_policy(inner, capacity, CachePolicy::Lru)
+    }
+
+    pub fn with_policy(inner: E, capacity: usize, policy: CachePolicy) -> Self {
+        CachedEngine {
+            inner,
+            cache: Arc::new(Mutex::new(ValueCache {
+                policy,
+                capacity,
+                size: 0,
+                clock: 0,
+                epoch: 0,
+                entries: HashMap::new(),
+                ranks: BTreeMap::new(),
+            })),
+            hits: Arc::new(AtomicU64::new(0)),
+            misses: Arc::new(AtomicU64::new(0)),
+            namespace: None,
+        }
+    }
+
+    /// Returns the wrapped engine.
+    pub fn inner(&self) -> &E {
+        &self.inner
+    }
+
+    fn invalidate(&self, key: &str) {
+        let mut cache = self.cache.lock().unwrap();
+        cache.epoch += 1;
+        cache.remove(&self.cache_key(key));
+    }
+
+    /// Key of `key` in the shared cache, unique across namespaces.
+    fn cache_key(&self, key: &str) -> String {
+        self.key_prefix() + key
+    }
+
+    /// Prefix of the cache keys of this handle's keyspace.
+    fn key_prefix(&self) -> String {
+        match &self.namespace {
+            Some(name) => namespace_prefix(name),
+            None => "/".to_owned(),
+        }
+    }
+}
+
+fn namespace_prefix(name: &str) -> String {
+    format!("{}/{}/", name.len(), name)
+}
+
+impl ValueCache {
+    fn get(&mut self, key: &str) -> Option<String> {
+        self.clock += 1;
+        let clock = self.clock;
+        let policy = self.policy;
+        let entry = self.entries.get_mut(key)?;
+        entry.uses += 1;
+        let rank = rank(policy, entry.uses, clock);
+        let key = self.ranks.remove(&entry.rank).unwrap();
+        entry.rank = rank;
+        self.ranks.insert(rank, key);
+        Some(entry.value.clone())
+    }
+
+    fn insert(&mut self, key: String, value: String) {
+        let size = entry_size(&key, &value);
+        if size > self.capacity {
+            return;
+        }
+        self.remove(&key);
+        while self.size + size > self.capacity {
+            let (_, victim) = self.ranks.pop_first().unwrap();
+            let entry = self.entries.remove(&victim).unwrap();
+            self.size -= entry_size(&victim, &entry.value);
+        }
+        self.clock += 1;
+        let rank = rank(self.policy, 1, self.clock);
+        self.ranks.insert(rank, key.clone());
+        self.entries.insert(
+            key,
+            CacheEntry {
+                value,
+                rank,
+                uses: 1,
+            },
+        );
+        self.size += size;
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.ranks.remove(&entry.rank);
+            self.size -= entry_size(key, &entry.value);
+        }
+    }
+
+    /// Removes every entry whose key satisfies `predicate`.
+    fn remove_matching(&mut self, predicate: impl Fn(&str) -> bool) {
+        let keys: Vec<String> = self
+            .entries
+            .keys()
+            .filter(|key| predicate(key))
+            .cloned()
+            .collect();
+        for key in keys {
+            self.remove(&key);
+        }
+    }
+}
+
+fn rank(policy: CachePolicy, uses: u64, clock: u64) -> (u64, u64) {
+    match policy {
+        CachePolicy::Lru => (0, clock),
+        CachePolicy::Lfu => (uses, clock),
+    }
+}
+
+fn entry_size(key: &str, value: &str) -> usize {
+    key.len() + value.len() + ENTRY_OVERHEAD
+}
+
+impl<E: KvsEngine> KvsEngine for CachedEngine<E> {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let cache_key = self.cache_key(&key);
+        let epoch = {
+            let mut cache = self.cache.lock().unwrap();
+            if let Some(value) = cache.get(&cache_key) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(Some(value));
+            }
+            cache.epoch
+        };
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let value = self.inner.get(key)?;
+        if let Some(value) = &value {
+            let mut cache = self.cache.lock().unwrap();
+            if cache.epoch == epoch {
+                cache.insert(cache_key, value.clone());
+            }
+        }
+        Ok(value)
+    }
+    fn remove(&self, key: String) -> Result<()> {
+        let rv = self.inner.remove(key.clone());
+        self.invalidate(&key);
+        rv
+    }
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let rv = self.inner.set(key.clone(), value);
+        self.invalidate(&key);
+        rv
+    }
+    fn merge(&self, key: String, operator: &str, operand: String) -> Result<String> {
+        let rv = self.inner.merge(key.clone(), operator, operand);
+        self.invalidate(&key);
+        rv
+    }
+    fn remove_range<R: RangeBounds<String>>(&self, range: R) -> Result<usize> {
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        let rv = self.inner.remove_range(range);
+        let prefix = self.key_prefix();
+        let mut cache = self.cache.lock().unwrap();
+        cache.epoch += 1;
+        cache.remove_matching(|key| {
+            key.strip_prefix(&prefix)
+                .is_some_and(|key| bounds.contains(&key.to_owned()))
+        });
+        rv
+    }
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        self.inner.scan(range)
+    }
+    fn scan_limit<R: RangeBounds<String>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        self.inner.scan_limit(range, limit)
+    }
+    fn create_namespace(&self, name: &str) -> Result<()> {
+        self.inner.create_namespace(name)
+    }
+    fn namespace(&self, name: &str) -> Result<Self> {
+        Ok(CachedEngine {
+            inner: self.inner.namespace(name)?,
+            cache: self.cache.clone(),
+            hits: self.hits.clone(),
+            misses: self.misses.clone(),
+            namespace: Some(name.into()),
+        })
+    }
+    fn drop_namespace(&self, name: &str) -> Result<()> {
+        let rv = self.inner.drop_namespace(name);
+        let prefix = namespace_prefix(name);
+        let mut cache = self.cache.lock().unwrap();
+        cache.epoch += 1;
+        cache.remove_matching(|key| key.starts_with(&prefix));
+        rv
+    }
+    fn namespaces(&self) -> Result<Vec<String>> {
+        self.inner.namespaces()
+    }
+    fn watch<R: RangeBounds<String>>(&self, range: R) -> Result<Receiver<Change>> {
+        self.inner.watch(range)
+    }
+    fn changes(&self, since: u64) -> Result<Vec<Change>> {
+        self.inner.changes(since)
+    }
+    fn get_version(&self, key: String, seq: u64) -> Result<Option<String>> {
+        self.inner.get_version(key, seq)
+    }
+    fn history(&self, key: String) -> Result<Vec<Version>> {
+        self.inner.history(key)
+    }
+    fn stats(&self) -> Result<Stats> {
+        let mut stats = self.inner.stats()?;
+        let cache = self.cache.lock().unwrap();
+        let policy = match cache.policy {
+            CachePolicy::Lru => "lru",
+            CachePolicy::Lfu => "lfu",
+        };
+        stats.insert("cache_policy".to_owned(), policy.to_owned());
+        stats.insert(
+            "cache_capacity_bytes".to_owned(),
+            cache.capacity.to_string(),
+        );
+        stats.insert("cache_bytes".to_owned(), cache.size.to_string());
+        stats.insert("cache_entries".to_owned(), cache.entries.len().to_string());
+        stats.insert(
+            "cache_hits".to_owned(),
+            self.hits.load(Ordering::Relaxed).to_string(),
+        );
+        stats.insert(
+            "cache_misses".to_owned(),
+            self.misses.load(Ordering::Relaxed).to_string(),
+        );
+        Ok(stats)
+    }
+}
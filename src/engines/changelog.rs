@@ -0,0 +1,133 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use serde_json::Deserializer;
+
+use crate::{Change, KvsError, Result};
+
+/// The most recent changes of a store, in a file of their own.
+///
+/// The file holds JSON `Change` records in commit order. Once it holds twice
+/// as many changes as it retains, the older half is cut off.
+#[derive(Debug)]
+pub struct ChangeLog {
+    path: PathBuf,
+    /// Changes kept after a trim.
+    capacity: usize,
+    file: File,
+    /// Sequence number and file offset of every change in the file.
+    offsets: VecDeque<(u64, u64)>,
+    len: u64,
+    last_seq: u64,
+}
+
+impl ChangeLog {
+    /// Opens the change log at `path`, dropping a record the last process
+    /// did not finish writing.
+    pub fn open(path: PathBuf, capacity: usize) -> Result<ChangeLog> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+        let mut offsets = VecDeque::new();
+        let mut stream = Deserializer::from_reader(BufReader::new(&mut file)).into_iter::<Change>();
+        let mut len = 0;
+        while let Some(Ok(change)) = stream.next() {
+            offsets.push_back((change.seq, len));
+            len = stream.byte_offset() as u64;
+        }
+        if file.metadata()?.len() > len {
+            file.set_len(len)?;
+        }
+        let last_seq = offsets.back().map_or(0, |&(seq, _)| seq);
+        Ok(ChangeLog {
+            path,
+            capacity: capacity.max(1),
+            file,
+            offsets,
+            len,
+            last_seq,
+        })
+    }
+
+    /// Sequence number of the last change written.
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    /// Appends the changes of one write.
+    pub fn append(&mut self, seq: u64, changes: &[(&str, Option<&str>)]) -> Result<()> {
+        let mut buf = Vec::new();
+        for (key, value) in changes {
+            let change = Change {
+                seq,
+                key: (*key).to_owned(),
+                value: value.map(str::to_owned),
+            };
+            self.offsets.push_back((seq, self.len + buf.len() as u64));
+            serde_json::to_writer(&mut buf, &change)?;
+        }
+        self.file.write_all(&buf)?;
+        self.len += buf.len() as u64;
+        self.last_seq = self.last_seq.max(seq);
+        if self.offsets.len() >= 2 * self.capacity {
+            self.trim()?;
+        }
+        Ok(())
+    }
+
+    /// Returns every retained change after `since`, oldest first. Fails if
+    /// some of them were already cut off.
+    pub fn since(&self, since: u64) -> Result<Vec<Change>> {
+        let first = self.offsets.partition_point(|&(seq, _)| seq <= since);
+        let pos = match self.offsets.get(first) {
+            // sequence numbers of a store without gaps are consecutive
+            Some(&(seq, _)) if first == 0 && seq > since.saturating_add(1) => {
+                return Err(KvsError::ChangesTruncated(seq))
+            }
+            Some(&(_, pos)) => pos,
+            None => return Ok(Vec::new()),
+        };
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(pos))?;
+        let reader = BufReader::new(file.take(self.len - pos));
+        Deserializer::from_reader(reader)
+            .into_iter::<Change>()
+            .map(|change| Ok(change?))
+            .collect()
+    }
+
+    /// Cuts off all but the last `capacity` changes, keeping the changes of
+    /// one write together.
+    fn trim(&mut self) -> Result<()> {
+        let mut cut = self.offsets.len() - self.capacity;
+        while cut > 0 && cut < self.offsets.len() && self.offsets[cut].0 == self.offsets[cut - 1].0
+        {
+            cut += 1;
+        }
+        let Some(&(_, start)) = self.offsets.get(cut) else {
+            return Ok(());
+        };
+        let mut buf = Vec::new();
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(start))?;
+        file.read_to_end(&mut buf)?;
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, &buf)?;
+        fs::rename(&tmp_path, &self.path)?;
+        self.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        self.offsets.drain(..cut);
+        for (_, pos) in self.offsets.iter_mut() {
+            *pos -= start;
+        }
+        self.len -= start;
+        Ok(())
+    }
+}
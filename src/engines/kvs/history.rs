@@ -0,0 +1,125 @@
+//! Old versions of the keys of `KvStore` that are kept in the log, and where.
+//!
+//! Every record of the log carries a sequence number. Without a retention
+//! policy compaction keeps only the live value of each key. With one, the
+//! versions it covers are tracked here and compaction copies them into the
+//! new log too, oldest first, so replaying the log yields the same history.
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::keydir::ValueLocation;
+use crate::Result;
+
+/// Which old versions of a key `KvStore` keeps around.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retention {
+    /// The last `n` versions, counting the current one.
+    Versions(usize),
+    /// Versions written within the duration, plus the current one.
+    Age(Duration),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct VersionEntry {
+    pub seq: u64,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub location: ValueLocation,
+    /// The version is a removal of the key.
+    pub removed: bool,
+}
+
+#[derive(Debug)]
+pub struct History {
+    retention: Retention,
+    keys: HashMap<String, Vec<VersionEntry>>,
+    len: usize,
+}
+
+impl History {
+    pub fn new(retention: Retention) -> History {
+        History {
+            retention,
+            keys: HashMap::new(),
+            len: 0,
+        }
+    }
+
+    /// Number of retained versions over all keys.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Retained versions of `key`, oldest first.
+    pub fn versions(&self, key: &str) -> &[VersionEntry] {
+        self.keys.get(key).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// Records a new version of `key`, returning the log bytes of the
+    /// versions that are no longer retained.
+    pub fn push(&mut self, key: &str, entry: VersionEntry) -> u64 {
+        let versions = self.keys.entry(key.to_owned()).or_default();
+        versions.push(entry);
+        self.len += 1;
+        self.prune(key, now())
+    }
+
+    /// Drops versions that fell out of the retention window and moves the
+    /// rest through `relocate` in sequence order, for when the log is
+    /// rewritten by compaction.
+    pub fn rewrite(
+        &mut self,
+        mut relocate: impl FnMut(ValueLocation) -> Result<ValueLocation>,
+    ) -> Result<()> {
+        let now = now();
+        let keys: Vec<String> = self.keys.keys().cloned().collect();
+        for key in &keys {
+            self.prune(key, now);
+        }
+        let mut entries: Vec<&mut VersionEntry> = self.keys.values_mut().flatten().collect();
+        entries.sort_unstable_by_key(|entry| entry.seq);
+        for entry in entries {
+            entry.location = relocate(entry.location)?;
+        }
+        Ok(())
+    }
+
+    fn prune(&mut self, key: &str, now: u64) -> u64 {
+        let versions = match self.keys.get_mut(key) {
+            Some(versions) => versions,
+            None => return 0,
+        };
+        let keep = match self.retention {
+            Retention::Versions(n) => n.max(1),
+            Retention::Age(age) => {
+                let cutoff = now.saturating_sub(age.as_millis() as u64);
+                versions
+                    .iter()
+                    .filter(|entry| entry.timestamp >= cutoff)
+                    .count()
+                    .max(1)
+            }
+        };
+        let drop = versions.len().saturating_sub(keep);
+        let mut freed: u64 = versions.drain(..drop).map(|entry| entry.location.len).sum();
+        self.len -= drop;
+        // a lone removal tells nothing a missing key does not
+        if let [entry] = versions.as_slice() {
+            if entry.removed {
+                freed += entry.location.len;
+                self.len -= 1;
+                self.keys.remove(key);
+            }
+        }
+        freed
+    }
+}
+
+/// Milliseconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
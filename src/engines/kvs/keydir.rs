@@ -0,0 +1,392 @@
+//! The key directory of `KvStore`: where in the log the live value of each
+//! key starts.
+//!
+//! Without a memory limit every entry lives in a `HashMap`. With a limit, the
+//! map only holds recently written and recently read entries. Once it grows
+//! past the limit, entries not yet on disk are spilled into a new sorted run
+//! (an SSTable mapping keys to encoded locations, newest run first) and the
+//! least recently used half of the map is evicted. Lookups that miss the map
+//! consult the runs through their bloom filters and cache what they find.
+//! Runs are rebuilt from the log on open, so they are never read back across
+//! restarts.
+
+use std::collections::HashMap;
+use std::fs;
+use std::mem;
+use std::ops::RangeBounds;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+use crate::engines::bloom;
+use crate::engines::sstable::{Lookup, MergeIter, SsTable, SsTableBuilder};
+use crate::{KvsError, Result};
+
+/// Number of runs that triggers merging them into one.
+const RUN_MERGE_TRIGGER: usize = 4;
+const RUN_BLOCK_SIZE: usize = 4 * 1024;
+const RUN_FALSE_POSITIVE_RATE: f64 = 0.01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValueLocation {
+    pub pos: u64,
+    pub len: u64,
+}
+
+impl ValueLocation {
+    fn encode(&self) -> String {
+        format!("{}:{}", self.pos, self.len)
+    }
+
+    fn decode(s: &str) -> Result<ValueLocation> {
+        let corrupted = || KvsError::Corrupted(format!("key directory entry {}", s));
+        let (pos, len) = s.split_once(':').ok_or_else(corrupted)?;
+        Ok(ValueLocation {
+            pos: pos.parse().map_err(|_| corrupted())?,
+            len: len.parse().map_err(|_| corrupted())?,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct HotEntry {
+    /// `None` shadows an older entry of the key in the runs.
+    location: Option<ValueLocation>,
+    /// Not yet written to a run.
+    dirty: bool,
+    /// Bumped by `peek` under a shared borrow.
+    last_used: AtomicU64,
+}
+
+#[derive(Debug)]
+pub struct KeyDir {
+    dir: PathBuf,
+    limit: Option<usize>,
+    hot: HashMap<String, HotEntry>,
+    hot_bytes: usize,
+    runs: Vec<SsTable>,
+    next_run: u64,
+    clock: AtomicU64,
+    len: usize,
+}
+
+impl KeyDir {
+    /// Creates an empty directory spilling into `dir` once it holds more
+    /// than `limit` bytes. Runs left in `dir` by an earlier open are
+    /// deleted, as the store rebuilds its directory from the log.
+    pub fn new(dir: PathBuf, limit: Option<usize>) -> Result<KeyDir> {
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        if limit.is_some() {
+            fs::create_dir_all(&dir)?;
+        }
+        Ok(KeyDir {
+            dir,
+            limit,
+            hot: HashMap::new(),
+            hot_bytes: 0,
+            runs: Vec::new(),
+            next_run: 0,
+            clock: AtomicU64::new(0),
+            len: 0,
+        })
+    }
+
+    /// Number of live keys.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Number of spilled runs on disk.
+    pub fn runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    /// Approximate bytes of memory held, including run indexes and filters.
+    pub fn memory_usage(&self) -> usize {
+        self.hot_bytes + self.runs.iter().map(SsTable::memory_usage).sum::<usize>()
+    }
+
+    /// The location of `key` if it can be told without reading the runs,
+    /// which takes `get`.
+    pub fn peek(&self, key: &str) -> Option<Option<ValueLocation>> {
+        match self.hot.get(key) {
+            Some(entry) => {
+                let now = self.clock.fetch_add(1, Ordering::Relaxed) + 1;
+                entry.last_used.fetch_max(now, Ordering::Relaxed);
+                Some(entry.location)
+            }
+            None if self.runs.is_empty() => Some(None),
+            None => None,
+        }
+    }
+
+    pub fn get(&mut self, key: &str) -> Result<Option<ValueLocation>> {
+        if let Some(location) = self.peek(key) {
+            return Ok(location);
+        }
+        *self.clock.get_mut() += 1;
+        let location = self.get_cold(key)?;
+        if location.is_some() {
+            self.admit(key.to_owned(), location, false)?;
+        }
+        Ok(location)
+    }
+
+    /// Points `key` at `location`, returning where its previous value was.
+    pub fn insert(
+        &mut self,
+        key: String,
+        location: ValueLocation,
+    ) -> Result<Option<ValueLocation>> {
+        let previous = self.previous(&key)?;
+        if previous.is_none() {
+            self.len += 1;
+        }
+        *self.clock.get_mut() += 1;
+        self.admit(key, Some(location), true)?;
+        Ok(previous)
+    }
+
+    /// Drops `key`, returning where its value was.
+    pub fn remove(&mut self, key: &str) -> Result<Option<ValueLocation>> {
+        let previous = self.previous(key)?;
+        if previous.is_none() {
+            return Ok(None);
+        }
+        self.len -= 1;
+        if self.runs.is_empty() {
+            if let Some(entry) = self.hot.remove(key) {
+                self.hot_bytes -= entry_size(key, &entry);
+            }
+        } else {
+            *self.clock.get_mut() += 1;
+            self.admit(key.to_owned(), None, true)?;
+        }
+        Ok(previous)
+    }
+
+    /// Returns the live entries whose key falls in `range`, in key order.
+    pub fn range<R: RangeBounds<String>>(&self, range: &R) -> Result<Vec<(String, ValueLocation)>> {
+        let mut hot: Vec<(String, Option<ValueLocation>)> = self
+            .hot
+            .iter()
+            .filter(|(key, _)| range.contains(*key))
+            .map(|(key, entry)| (key.clone(), entry.location))
+            .collect();
+        hot.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        let mut sources = vec![hot.into_iter().map(Ok).collect::<Vec<_>>()];
+        for run in &self.runs {
+            let entries = run.scan(range)?.into_iter();
+            sources.push(entries.map(|entry| decode_entry(Ok(entry))).collect());
+        }
+        MergeIter::new(sources.into_iter().map(Vec::into_iter))
+            .filter_map(|entry| match entry {
+                Ok((key, Some(location))) => Some(Ok((key, location))),
+                Ok((_, None)) => None,
+                Err(e) => Some(Err(e)),
+            })
+            .collect()
+    }
+
+    /// Moves every live entry in key order through `relocate`, for when the
+    /// log is rewritten by compaction. Entries in the map stay resident at
+    /// their new locations, while the spilled ones end up in one new run.
+    pub fn rewrite(
+        &mut self,
+        mut relocate: impl FnMut(ValueLocation) -> Result<ValueLocation>,
+    ) -> Result<()> {
+        let mut hot: Vec<(String, Option<ValueLocation>)> = self
+            .hot
+            .iter()
+            .map(|(key, entry)| (key.clone(), entry.location))
+            .collect();
+        hot.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        let runs = mem::take(&mut self.runs);
+        type Source<'a> = Box<dyn Iterator<Item = Result<(String, Option<ValueLocation>)>> + 'a>;
+        let mut sources: Vec<Source> = vec![Box::new(hot.into_iter().map(Ok))];
+        for run in &runs {
+            sources.push(Box::new(run.iter().map(decode_entry)));
+        }
+        let entries = MergeIter::new(sources);
+
+        let id = self.next_run;
+        let mut builder = match runs.is_empty() {
+            true => None,
+            false => {
+                self.next_run += 1;
+                Some(self.run_builder(id)?)
+            }
+        };
+        for entry in entries {
+            let (key, location) = entry?;
+            match (self.hot.get_mut(&key), location) {
+                // no run holds the entries of the map any more
+                (Some(entry), Some(location)) => {
+                    entry.location = Some(relocate(location)?);
+                    entry.dirty = true;
+                }
+                // nothing older than the new run is left to shadow
+                (Some(_), None) => {
+                    if let Some(entry) = self.hot.remove(&key) {
+                        self.hot_bytes -= entry_size(&key, &entry);
+                    }
+                }
+                (None, Some(location)) => {
+                    if let Some(builder) = builder.as_mut() {
+                        builder.add(&key, Some(&relocate(location)?.encode()))?;
+                    }
+                }
+                (None, None) => {}
+            }
+        }
+        match builder {
+            Some(builder) if !builder.is_empty() => self.runs.push(builder.finish(id)?),
+            Some(_) => fs::remove_file(self.run_path(id))?,
+            None => {}
+        }
+        for run in runs {
+            fs::remove_file(run.path())?;
+        }
+        Ok(())
+    }
+
+    fn previous(&self, key: &str) -> Result<Option<ValueLocation>> {
+        match self.hot.get(key) {
+            Some(entry) => Ok(entry.location),
+            None => self.get_cold(key),
+        }
+    }
+
+    fn get_cold(&self, key: &str) -> Result<Option<ValueLocation>> {
+        for run in &self.runs {
+            match run.get(key)? {
+                Lookup::Found(Some(location)) => return ValueLocation::decode(&location).map(Some),
+                Lookup::Found(None) => return Ok(None),
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    fn admit(&mut self, key: String, location: Option<ValueLocation>, dirty: bool) -> Result<()> {
+        let entry = HotEntry {
+            location,
+            dirty,
+            last_used: AtomicU64::new(*self.clock.get_mut()),
+        };
+        self.hot_bytes += entry_size(&key, &entry);
+        if let Some(old) = self.hot.insert(key.clone(), entry) {
+            self.hot_bytes -= entry_size(&key, &old);
+        }
+        match self.limit {
+            Some(limit) if self.hot_bytes > limit => self.spill(limit),
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes dirty entries into a new run and evicts the least recently
+    /// used entries until the map is down to half of `limit`.
+    fn spill(&mut self, limit: usize) -> Result<()> {
+        let mut dirty: Vec<(&String, &HotEntry)> =
+            self.hot.iter().filter(|(_, entry)| entry.dirty).collect();
+        if !dirty.is_empty() {
+            dirty.sort_unstable_by(|a, b| a.0.cmp(b.0));
+            let id = self.next_run;
+            let mut builder = self.run_builder(id)?;
+            for (key, entry) in dirty {
+                builder.add(key, entry.location.map(|l| l.encode()).as_deref())?;
+            }
+            self.runs.insert(0, builder.finish(id)?);
+            self.next_run += 1;
+        }
+
+        let mut by_age: Vec<(u64, usize)> = self
+            .hot
+            .iter()
+            .map(|(key, entry)| {
+                (
+                    entry.last_used.load(Ordering::Relaxed),
+                    entry_size(key, entry),
+                )
+            })
+            .collect();
+        by_age.sort_unstable_by_key(|(last_used, _)| std::cmp::Reverse(*last_used));
+        let mut kept = 0;
+        let mut cutoff = 0;
+        for (last_used, size) in by_age {
+            if kept + size > limit / 2 {
+                cutoff = last_used;
+                break;
+            }
+            kept += size;
+        }
+        self.hot
+            .retain(|_, entry| *entry.last_used.get_mut() > cutoff);
+        for entry in self.hot.values_mut() {
+            entry.dirty = false;
+        }
+        self.hot_bytes = self
+            .hot
+            .iter()
+            .map(|(key, entry)| entry_size(key, entry))
+            .sum();
+
+        if self.runs.len() > RUN_MERGE_TRIGGER {
+            self.merge_runs()?;
+        }
+        Ok(())
+    }
+
+    /// Merges all runs into one. Tombstones are dropped since nothing older
+    /// than the merged run remains.
+    fn merge_runs(&mut self) -> Result<()> {
+        let id = self.next_run;
+        self.next_run += 1;
+        let mut builder = self.run_builder(id)?;
+        for entry in MergeIter::new(self.runs.iter().map(SsTable::iter)) {
+            if let (key, Some(location)) = entry? {
+                builder.add(&key, Some(&location))?;
+            }
+        }
+        let merged = if builder.is_empty() {
+            fs::remove_file(self.run_path(id))?;
+            Vec::new()
+        } else {
+            vec![builder.finish(id)?]
+        };
+        for run in mem::replace(&mut self.runs, merged) {
+            fs::remove_file(run.path())?;
+        }
+        Ok(())
+    }
+
+    fn run_builder(&self, id: u64) -> Result<SsTableBuilder> {
+        SsTableBuilder::new(
+            self.run_path(id),
+            RUN_BLOCK_SIZE,
+            bloom::bits_per_key(RUN_FALSE_POSITIVE_RATE),
+        )
+    }
+
+    fn run_path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{}.run", id))
+    }
+}
+
+fn decode_entry(
+    entry: Result<(String, Option<String>)>,
+) -> Result<(String, Option<ValueLocation>)> {
+    let (key, location) = entry?;
+    let location = location
+        .map(|location| ValueLocation::decode(&location))
+        .transpose()?;
+    Ok((key, location))
+}
+
+/// Approximate heap and table footprint of one entry.
+fn entry_size(key: &str, entry: &HotEntry) -> usize {
+    key.len() + mem::size_of::<String>() + mem::size_of_val(entry) + 16
+}
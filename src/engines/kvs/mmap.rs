@@ -0,0 +1,84 @@
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use arc_swap::ArcSwapOption;
+use memmap2::Mmap;
+
+use super::keydir::ValueLocation;
+use crate::{KvsError, Result};
+
+const REMAP_STEP: usize = 1024 * 1024;
+
+/// Read path of `KvStore` that serves values straight out of a memory map of
+/// the log.
+///
+/// The log is only ever appended to, so the mapped prefix never changes and
+/// readers share one map without locking. The first read maps the file.
+/// Records appended since are left to the buffered reader of the store until
+/// `REMAP_STEP` bytes have piled up past the map, so that reads of fresh
+/// writes do not remap the file each time. Compaction, which swaps in a new log file, drops
+/// the map so that the next read maps the new file.
+#[derive(Debug)]
+pub struct MmapReader {
+    path: PathBuf,
+    map: ArcSwapOption<Mmap>,
+    remap: Mutex<()>,
+}
+
+impl MmapReader {
+    pub fn new(path: PathBuf) -> MmapReader {
+        MmapReader {
+            path,
+            map: ArcSwapOption::empty(),
+            remap: Mutex::new(()),
+        }
+    }
+
+    /// Calls `f` with the bytes at `location`, `None` if they are left to
+    /// the buffered reader.
+    pub fn read<T>(
+        &self,
+        location: ValueLocation,
+        f: impl FnOnce(&[u8]) -> Result<T>,
+    ) -> Result<Option<T>> {
+        let start = location.pos as usize;
+        let end = start + location.len as usize;
+        let map = match self.map.load_full() {
+            Some(map) if map.len() >= end => map,
+            Some(map) if end - map.len() <= REMAP_STEP => return Ok(None),
+            _ => self.remap(end)?,
+        };
+        match map.get(start..end) {
+            Some(buf) => f(buf).map(Some),
+            None => Err(KvsError::Corrupted(self.path.display().to_string())),
+        }
+    }
+
+    /// Bytes of the log mapped so far.
+    pub fn mapped_len(&self) -> usize {
+        self.map.load().as_ref().map_or(0, |map| map.len())
+    }
+
+    /// Forgets the current map, e.g. after the log file was replaced.
+    pub fn reset(&self) {
+        let _remap = self.remap.lock().unwrap();
+        self.map.store(None);
+    }
+
+    fn remap(&self, min_len: usize) -> Result<Arc<Mmap>> {
+        let _remap = self.remap.lock().unwrap();
+        // another reader may have remapped while we waited
+        if let Some(map) = self.map.load_full() {
+            if map.len() >= min_len {
+                return Ok(map);
+            }
+        }
+        let file = File::open(&self.path)?;
+        // SAFETY: the log is only appended to by this process and replaced,
+        // never truncated, by compaction, so the mapped bytes stay valid.
+        let map = Arc::new(unsafe { Mmap::map(&file)? });
+        self.map.store(Some(map.clone()));
+        Ok(map)
+    }
+}
@@ -0,0 +1,850 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::{Bound, DerefMut, RangeBounds};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+
+use crate::engines::changelog::ChangeLog;
+use crate::engines::namespace::{self, Link};
+use crate::engines::watch::Watchers;
+use crate::engines::MergeOperators;
+use crate::{Change, KvsEngine, KvsError, Result, Stats, Version};
+
+use self::history::{History, VersionEntry};
+use self::keydir::{KeyDir, ValueLocation};
+use self::mmap::MmapReader;
+
+pub use self::history::Retention;
+
+mod history;
+mod keydir;
+mod mmap;
+
+const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+/// Length of a chain of merge records at which a merge writes the resolved
+/// value instead, bounding the records a read has to visit.
+const MAX_MERGE_DEPTH: u32 = 16;
+/// Serialized merge records start with their variant name.
+const MERGE_TAG: &[u8] = b"{\"Merge\"";
+
+/// Tuning knobs of `KvStore`.
+#[derive(Debug, Clone, Default)]
+pub struct KvStoreOptions {
+    /// Bytes of memory the key directory may use before spilling entries to
+    /// sorted runs on disk. `None` keeps every key in memory.
+    pub index_memory_limit: Option<usize>,
+    /// Serve reads from a memory map of the log instead of seeking a shared
+    /// buffered reader.
+    pub mmap_reads: bool,
+    /// Old versions of each key kept through compaction and served by
+    /// `get_version` and `history`. `None` keeps only the live value.
+    pub retention: Option<Retention>,
+    /// Operators `merge` may name. They also resolve merge records already
+    /// in the log, so every operator used before must stay registered.
+    pub merge_operators: MergeOperators,
+    /// Changes kept in a change log next to the data for `changes`. `None`
+    /// keeps no change log.
+    pub change_log: Option<usize>,
+}
+
+/// KvStore struct
+#[derive(Debug, Clone)]
+pub struct KvStore {
+    path: Arc<PathBuf>,
+    options: Arc<KvStoreOptions>,
+    index: Arc<RwLock<KeyDir>>,
+    // held shared while reading records and exclusively by compaction,
+    // which moves them
+    gate: Arc<RwLock<()>>,
+    writer: Arc<Mutex<BufWriterWithPos>>,
+    reader: Arc<Mutex<BufReaderWithPos>>,
+    mmap: Option<Arc<MmapReader>>,
+    history: Option<Arc<Mutex<History>>>,
+    // sequence number of the next write
+    seq: Arc<AtomicU64>,
+    // uncompacted data bytes
+    uncompacted: Arc<Mutex<u64>>,
+    namespaces: Link<KvStore>,
+    watchers: Arc<Watchers>,
+    changes: Option<Arc<Mutex<ChangeLog>>>,
+}
+
+#[derive(Debug)]
+pub struct BufWriterWithPos {
+    writer: BufWriter<File>,
+    pos: u64,
+}
+
+#[derive(Debug)]
+pub struct BufReaderWithPos {
+    reader: BufReader<File>,
+    pos: u64,
+}
+
+impl BufReaderWithPos {
+    fn new(mut inner: File) -> Result<Self> {
+        let pos = inner.stream_position()?;
+        Ok(Self {
+            reader: BufReader::new(inner),
+            pos,
+        })
+    }
+    pub fn get_mut(&mut self) -> &mut File {
+        self.reader.get_mut()
+    }
+}
+
+impl Read for BufReaderWithPos {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for BufReaderWithPos {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.reader.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+impl BufWriterWithPos {
+    fn new(mut inner: File) -> Result<Self> {
+        let pos = inner.seek(SeekFrom::End(0))?;
+        let writer = BufWriter::new(inner);
+        Ok(Self { writer, pos })
+    }
+}
+
+impl Write for BufWriterWithPos {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Seek for BufWriterWithPos {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.writer.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Operation {
+    Set {
+        key: String,
+        value: String,
+        #[serde(default)]
+        seq: u64,
+        #[serde(default)]
+        timestamp: u64,
+    },
+    Rm {
+        key: String,
+        #[serde(default)]
+        seq: u64,
+        #[serde(default)]
+        timestamp: u64,
+    },
+    /// Removes every key in the range.
+    RmRange {
+        start: Bound<String>,
+        end: Bound<String>,
+        seq: u64,
+        timestamp: u64,
+    },
+    /// An operand to fold into the value of the record at `prev`.
+    Merge {
+        key: String,
+        operator: String,
+        operand: String,
+        prev: Option<ValueLocation>,
+        /// Number of merge records in the chain ending here.
+        depth: u32,
+        seq: u64,
+        timestamp: u64,
+    },
+    /// Keeps the sequence number of the last write when compaction drops
+    /// its record.
+    Seq { seq: u64 },
+}
+
+impl KvsEngine for KvStore {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let _gate = self.gate.read().unwrap();
+        // keys in memory are looked up without excluding other readers
+        let hot = self.index.read().unwrap().peek(&key);
+        let location = match hot {
+            Some(location) => location,
+            None => self.index.write().unwrap().get(&key)?,
+        };
+        location.map(|l| self.read_value(l)).transpose()
+    }
+    fn remove(&self, key: String) -> Result<()> {
+        let mut writer_guard = self.writer.lock().unwrap();
+        let mut uncompacted_guard = self.uncompacted.lock().unwrap();
+        let mut index_guard = self.index.write().unwrap();
+        if index_guard.remove(&key)?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        };
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst);
+        let timestamp = history::now();
+        let pos = writer_guard.pos;
+        let row = Operation::Rm {
+            key: key.clone(),
+            seq,
+            timestamp,
+        };
+        serde_json::to_writer(writer_guard.deref_mut(), &row)?;
+        writer_guard.flush()?;
+        if let Some(history) = &self.history {
+            let entry = VersionEntry {
+                seq,
+                timestamp,
+                location: ValueLocation {
+                    pos,
+                    len: writer_guard.pos - pos,
+                },
+                removed: true,
+            };
+            *uncompacted_guard += history.lock().unwrap().push(&key, entry);
+        }
+        self.publish(seq, &[(&key, None)])?;
+        Ok(())
+    }
+    fn remove_range<R: RangeBounds<String>>(&self, range: R) -> Result<usize> {
+        let mut writer_guard = self.writer.lock().unwrap();
+        let mut uncompacted_guard = self.uncompacted.lock().unwrap();
+        let mut index_guard = self.index.write().unwrap();
+        let keys: Vec<String> = index_guard
+            .range(&range)?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst);
+        let timestamp = history::now();
+        let pos = writer_guard.pos;
+        let row = Operation::RmRange {
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            seq,
+            timestamp,
+        };
+        serde_json::to_writer(writer_guard.deref_mut(), &row)?;
+        writer_guard.flush()?;
+        let location = ValueLocation {
+            pos,
+            len: writer_guard.pos - pos,
+        };
+        let mut history_guard = self.history.as_ref().map(|h| h.lock().unwrap());
+        for key in &keys {
+            let previous = index_guard.remove(key)?;
+            match history_guard.as_deref_mut() {
+                Some(history) => {
+                    let entry = VersionEntry {
+                        seq,
+                        timestamp,
+                        location,
+                        removed: true,
+                    };
+                    *uncompacted_guard += history.push(key, entry);
+                }
+                None => *uncompacted_guard += previous.map_or(0, |v| v.len),
+            }
+        }
+        let changes: Vec<(&str, Option<&str>)> =
+            keys.iter().map(|key| (key.as_str(), None)).collect();
+        self.publish(seq, &changes)?;
+        Ok(keys.len())
+    }
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        self.scan_limit(range, usize::MAX)
+    }
+    fn scan_limit<R: RangeBounds<String>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let _gate = self.gate.read().unwrap();
+        let locations = self.index.read().unwrap().range(&range)?;
+        let mut pairs = Vec::new();
+        // values are only read up to the limit
+        for (key, value_location) in locations.into_iter().take(limit) {
+            pairs.push((key, self.read_value(value_location)?));
+        }
+        Ok(pairs)
+    }
+    fn stats(&self) -> Result<Stats> {
+        let mut stats = Stats::new();
+        let index_guard = self.index.read().unwrap();
+        stats.insert("keys".to_owned(), index_guard.len().to_string());
+        stats.insert(
+            "index_memory_bytes".to_owned(),
+            index_guard.memory_usage().to_string(),
+        );
+        if let Some(limit) = self.options.index_memory_limit {
+            stats.insert("index_memory_limit".to_owned(), limit.to_string());
+        }
+        stats.insert("index_runs".to_owned(), index_guard.runs().to_string());
+        drop(index_guard);
+        if let Some(history) = &self.history {
+            stats.insert(
+                "history_versions".to_owned(),
+                history.lock().unwrap().len().to_string(),
+            );
+        }
+        stats.insert(
+            "uncompacted_bytes".to_owned(),
+            self.uncompacted.lock().unwrap().to_string(),
+        );
+        if let Some(mmap) = &self.mmap {
+            stats.insert("mmap_bytes".to_owned(), mmap.mapped_len().to_string());
+        }
+        Ok(stats)
+    }
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let mut writer_guard = self.writer.lock().unwrap();
+        let mut uncompacted_guard = self.uncompacted.lock().unwrap();
+        let mut index_guard = self.index.write().unwrap();
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst);
+        let timestamp = history::now();
+        let row = Operation::Set {
+            key: key.clone(),
+            value,
+            seq,
+            timestamp,
+        };
+        let pos = writer_guard.pos;
+        serde_json::to_writer(writer_guard.deref_mut(), &row)?;
+        writer_guard.flush()?;
+        let location = ValueLocation {
+            pos,
+            len: writer_guard.pos - pos,
+        };
+        let previous = index_guard.insert(key.clone(), location)?;
+        match &self.history {
+            Some(history) => {
+                let entry = VersionEntry {
+                    seq,
+                    timestamp,
+                    location,
+                    removed: false,
+                };
+                *uncompacted_guard += history.lock().unwrap().push(&key, entry);
+            }
+            None => *uncompacted_guard += previous.map_or(0, |v| v.len),
+        }
+        if let Operation::Set { value, .. } = &row {
+            self.publish(seq, &[(&key, Some(value))])?;
+        }
+        let need_compaction = *uncompacted_guard >= COMPACTION_THRESHOLD;
+        drop(index_guard);
+        drop(uncompacted_guard);
+        drop(writer_guard);
+        if need_compaction {
+            self.compact()?;
+        }
+        Ok(())
+    }
+    fn merge(&self, key: String, operator: &str, operand: String) -> Result<String> {
+        let mut writer_guard = self.writer.lock().unwrap();
+        let mut uncompacted_guard = self.uncompacted.lock().unwrap();
+        let mut index_guard = self.index.write().unwrap();
+        let prev = index_guard.get(&key)?;
+        let (existing, depth) = match prev {
+            Some(location) => {
+                let (value, depth) = self.resolve(location, |l| self.read_record(l))?;
+                (Some(value), depth)
+            }
+            None => (None, 0),
+        };
+        let value =
+            self.options
+                .merge_operators
+                .merge(operator, &key, existing.as_deref(), &operand)?;
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst);
+        let timestamp = history::now();
+        let row = if depth < MAX_MERGE_DEPTH {
+            Operation::Merge {
+                key: key.clone(),
+                operator: operator.to_owned(),
+                operand,
+                prev,
+                depth: depth + 1,
+                seq,
+                timestamp,
+            }
+        } else {
+            Operation::Set {
+                key: key.clone(),
+                value: value.clone(),
+                seq,
+                timestamp,
+            }
+        };
+        let pos = writer_guard.pos;
+        serde_json::to_writer(writer_guard.deref_mut(), &row)?;
+        writer_guard.flush()?;
+        let location = ValueLocation {
+            pos,
+            len: writer_guard.pos - pos,
+        };
+        index_guard.insert(key.clone(), location)?;
+        match &self.history {
+            Some(history) => {
+                let entry = VersionEntry {
+                    seq,
+                    timestamp,
+                    location,
+                    removed: false,
+                };
+                *uncompacted_guard += history.lock().unwrap().push(&key, entry);
+            }
+            // the chain is folded by the next compaction
+            None => *uncompacted_guard += prev.map_or(0, |v| v.len),
+        }
+        self.publish(seq, &[(&key, Some(&value))])?;
+        let need_compaction = *uncompacted_guard >= COMPACTION_THRESHOLD;
+        drop(index_guard);
+        drop(uncompacted_guard);
+        drop(writer_guard);
+        if need_compaction {
+            self.compact()?;
+        }
+        Ok(value)
+    }
+    fn create_namespace(&self, name: &str) -> Result<()> {
+        self.open_namespace(name, true).map(|_| ())
+    }
+    fn namespace(&self, name: &str) -> Result<Self> {
+        self.open_namespace(name, false)
+    }
+    fn watch<R: RangeBounds<String>>(&self, range: R) -> Result<Receiver<Change>> {
+        Ok(self.watchers.subscribe(range))
+    }
+    fn changes(&self, since: u64) -> Result<Vec<Change>> {
+        match &self.changes {
+            Some(changes) => changes.lock().unwrap().since(since),
+            None => Err(KvsError::UnsupportedOperation),
+        }
+    }
+    fn drop_namespace(&self, name: &str) -> Result<()> {
+        namespace::validate(name)?;
+        let registry = self.namespaces.registry()?;
+        let mut handles = registry.handles.lock().unwrap();
+        let dir = registry.dir.join(name);
+        if !dir.is_dir() {
+            return Err(KvsError::NamespaceNotFound(name.to_owned()));
+        }
+        handles.remove(name);
+        // the rename drops the namespace at once, whatever it holds
+        let trash = registry.dir.join(format!(".{}.dropped", name));
+        if trash.exists() {
+            fs::remove_dir_all(&trash)?;
+        }
+        fs::rename(&dir, &trash)?;
+        fs::remove_dir_all(&trash)?;
+        Ok(())
+    }
+    fn namespaces(&self) -> Result<Vec<String>> {
+        let registry = self.namespaces.registry()?;
+        let _handles = registry.handles.lock().unwrap();
+        let entries = match fs::read_dir(&registry.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.file_type()?.is_dir() && !name.starts_with('.') {
+                names.push(name);
+            }
+        }
+        names.sort_unstable();
+        Ok(names)
+    }
+    fn get_version(&self, key: String, seq: u64) -> Result<Option<String>> {
+        let history = self
+            .history
+            .as_ref()
+            .ok_or(KvsError::UnsupportedOperation)?;
+        let history_guard = history.lock().unwrap();
+        let version = history_guard
+            .versions(&key)
+            .iter()
+            .rev()
+            .find(|entry| entry.seq <= seq);
+        match version {
+            Some(entry) if !entry.removed => self.read_value(entry.location).map(Some),
+            _ => Ok(None),
+        }
+    }
+    fn history(&self, key: String) -> Result<Vec<Version>> {
+        let history = self
+            .history
+            .as_ref()
+            .ok_or(KvsError::UnsupportedOperation)?;
+        let history_guard = history.lock().unwrap();
+        let mut versions = Vec::new();
+        for entry in history_guard.versions(&key) {
+            let value = match entry.removed {
+                true => None,
+                false => Some(self.read_value(entry.location)?),
+            };
+            versions.push(Version {
+                seq: entry.seq,
+                timestamp: entry.timestamp,
+                value,
+            });
+        }
+        Ok(versions)
+    }
+}
+
+impl KvStore {
+    /// Reads the value of the record at `value_location`. The caller must
+    /// hold the gate, the index or the history lock so compaction cannot
+    /// move the record meanwhile.
+    fn read_value(&self, value_location: ValueLocation) -> Result<String> {
+        self.resolve(value_location, |l| self.read_record(l))
+            .map(|(value, _)| value)
+    }
+
+    fn read_record(&self, location: ValueLocation) -> Result<Operation> {
+        if let Some(mmap) = &self.mmap {
+            if let Some(op) = mmap.read(location, |buf| Ok(serde_json::from_slice(buf)?))? {
+                return Ok(op);
+            }
+        }
+        let mut reader = self.reader.lock().unwrap();
+        Ok(serde_json::from_slice(&read_at(
+            reader.deref_mut(),
+            location,
+        )?)?)
+    }
+
+    /// Returns the value of the set or merge record at `location` and the
+    /// number of merge records it took to resolve it.
+    fn resolve(
+        &self,
+        location: ValueLocation,
+        mut read: impl FnMut(ValueLocation) -> Result<Operation>,
+    ) -> Result<(String, u32)> {
+        let mut operands = Vec::new();
+        let mut next = Some(location);
+        let mut value = None;
+        while let Some(location) = next {
+            match read(location)? {
+                Operation::Set { value: v, .. } => {
+                    value = Some(v);
+                    break;
+                }
+                Operation::Merge {
+                    key,
+                    operator,
+                    operand,
+                    prev,
+                    ..
+                } => {
+                    operands.push((key, operator, operand));
+                    next = prev;
+                }
+                Operation::Rm { .. } | Operation::RmRange { .. } | Operation::Seq { .. } => {
+                    return Err(KvsError::Corrupted(format!(
+                        "value record expected at {}",
+                        location.pos
+                    )))
+                }
+            }
+        }
+        let depth = operands.len() as u32;
+        for (key, operator, operand) in operands.into_iter().rev() {
+            value = Some(self.options.merge_operators.merge(
+                &operator,
+                &key,
+                value.as_deref(),
+                &operand,
+            )?);
+        }
+        // a chain holds at least one record
+        Ok((value.unwrap_or_default(), depth))
+    }
+
+    fn load(&self) -> Result<()> {
+        let mut reader_guard = self.reader.lock().unwrap();
+        let mut index_guard = self.index.write().unwrap();
+        let mut uncompacted_guard = self.uncompacted.lock().unwrap();
+        let mut history_guard = self.history.as_ref().map(|h| h.lock().unwrap());
+        let mut stream =
+            Deserializer::from_reader(reader_guard.deref_mut()).into_iter::<Operation>();
+        let mut pos: u64 = 0;
+        let mut last_seq: u64 = 0;
+        while let Some(op) = stream.next() {
+            let new_pos = stream.byte_offset() as u64;
+            let location = ValueLocation {
+                pos,
+                len: new_pos - pos,
+            };
+            let (seq, timestamp, changes) = match op? {
+                Operation::Set {
+                    key,
+                    seq,
+                    timestamp,
+                    ..
+                }
+                | Operation::Merge {
+                    key,
+                    seq,
+                    timestamp,
+                    ..
+                } => {
+                    let previous = index_guard.insert(key.clone(), location)?;
+                    (seq, timestamp, vec![(key, false, previous)])
+                }
+                Operation::Rm {
+                    key,
+                    seq,
+                    timestamp,
+                } => {
+                    let previous = index_guard.remove(&key)?;
+                    (seq, timestamp, vec![(key, true, previous)])
+                }
+                Operation::RmRange {
+                    start,
+                    end,
+                    seq,
+                    timestamp,
+                } => {
+                    let mut changes = Vec::new();
+                    for (key, _) in index_guard.range(&(start, end))? {
+                        let previous = index_guard.remove(&key)?;
+                        changes.push((key, true, previous));
+                    }
+                    (seq, timestamp, changes)
+                }
+                Operation::Seq { seq } => (seq, 0, Vec::new()),
+            };
+            // records written before sequence numbers existed lack one
+            let seq = if seq == 0 { last_seq + 1 } else { seq };
+            last_seq = last_seq.max(seq);
+            for (key, removed, previous) in changes {
+                match history_guard.as_deref_mut() {
+                    Some(history) => {
+                        let entry = VersionEntry {
+                            seq,
+                            timestamp,
+                            location,
+                            removed,
+                        };
+                        *uncompacted_guard += history.push(&key, entry);
+                    }
+                    None => *uncompacted_guard += previous.map_or(0, |v| v.len),
+                }
+            }
+            pos = new_pos;
+        }
+        if let Some(changes) = &self.changes {
+            last_seq = last_seq.max(changes.lock().unwrap().last_seq());
+        }
+        self.seq.store(last_seq + 1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Records the changes of the write `seq` in the change log and passes
+    /// them to watchers, all at once so that readers of the change log never
+    /// see part of a write.
+    fn publish(&self, seq: u64, changes: &[(&str, Option<&str>)]) -> Result<()> {
+        let mut log_guard = self.changes.as_ref().map(|c| c.lock().unwrap());
+        if let Some(log) = log_guard.as_deref_mut() {
+            log.append(seq, changes)?;
+        }
+        for (key, value) in changes {
+            self.watchers.notify(seq, key, *value);
+        }
+        Ok(())
+    }
+    fn compact(&self) -> Result<()> {
+        let mut writer_guard = self.writer.lock().unwrap();
+        let _gate = self.gate.write().unwrap();
+        let mut index_guard = self.index.write().unwrap();
+        let mut history_guard = self.history.as_ref().map(|h| h.lock().unwrap());
+        let mut reader_guard = self.reader.lock().unwrap();
+        writer_guard.flush()?;
+        let mut archive_path: PathBuf = self.path.to_path_buf();
+        archive_path.push(format!("db.archive.{:?}", SystemTime::now()));
+        let mut current_path = self.path.to_path_buf();
+        current_path.push("db");
+        fs::copy(&current_path, &archive_path)?;
+        fs::remove_file(&current_path)?;
+        let mut writer = BufWriterWithPos::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&current_path)?,
+        )?;
+        let mut reader = BufReaderWithPos::new(OpenOptions::new().read(true).open(&archive_path)?)?;
+        let mut new_pos = 0;
+        let mut copy = |v: ValueLocation| {
+            let mut buf = read_at(reader.get_mut(), v)?;
+            // merge chains are folded into plain set records
+            if buf.starts_with(MERGE_TAG) {
+                if let Operation::Merge {
+                    key,
+                    seq,
+                    timestamp,
+                    ..
+                } = serde_json::from_slice(&buf)?
+                {
+                    let (value, _) = self.resolve(v, |l| {
+                        Ok(serde_json::from_slice(&read_at(reader.get_mut(), l)?)?)
+                    })?;
+                    let row = Operation::Set {
+                        key,
+                        value,
+                        seq,
+                        timestamp,
+                    };
+                    buf = serde_json::to_vec(&row)?;
+                }
+            }
+            writer.write_all(&buf)?;
+            let len = buf.len() as u64;
+            let location = ValueLocation { pos: new_pos, len };
+            new_pos += len;
+            Ok(location)
+        };
+        // retained versions go first, the live ones among them are not
+        // copied again
+        let mut moved = HashMap::new();
+        if let Some(history) = history_guard.as_deref_mut() {
+            history.rewrite(|v| {
+                // a range removal is a version of every key it removed
+                if let Some(location) = moved.get(&v.pos) {
+                    return Ok(*location);
+                }
+                let location = copy(v)?;
+                moved.insert(v.pos, location);
+                Ok(location)
+            })?;
+        }
+        index_guard.rewrite(|v| match moved.get(&v.pos) {
+            Some(location) => Ok(*location),
+            None => copy(v),
+        })?;
+        let row = Operation::Seq {
+            seq: self.seq.load(Ordering::SeqCst) - 1,
+        };
+        serde_json::to_writer(&mut writer, &row)?;
+        writer.flush()?;
+        *writer_guard = writer;
+        *reader_guard = BufReaderWithPos::new(OpenOptions::new().read(true).open(&current_path)?)?;
+        if let Some(mmap) = &self.mmap {
+            mmap.reset();
+        }
+        *self.uncompacted.lock().unwrap() = 0;
+        fs::remove_file(&archive_path)?;
+        Ok(())
+    }
+
+    /// Each namespace is a store of its own in `ns/<name>`.
+    fn open_namespace(&self, name: &str, create: bool) -> Result<KvStore> {
+        namespace::validate(name)?;
+        let registry = self.namespaces.registry()?;
+        let mut handles = registry.handles.lock().unwrap();
+        if let Some(store) = handles.get(name) {
+            return Ok(store.clone());
+        }
+        let dir = registry.dir.join(name);
+        if !dir.is_dir() {
+            if !create {
+                return Err(KvsError::NamespaceNotFound(name.to_owned()));
+            }
+            fs::create_dir_all(&dir)?;
+        }
+        let store = Self::open_at(dir, (*self.options).clone(), self.namespaces.child())?;
+        handles.insert(name.to_owned(), store.clone());
+        Ok(store)
+    }
+
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        Self::open_with_options(path, KvStoreOptions::default())
+    }
+
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        let dir = path.into();
+        let namespaces = Link::root(dir.join("ns"));
+        Self::open_at(dir, options, namespaces)
+    }
+
+    /// Opens the store in `dir`, the root of a store or one of its
+    /// namespaces.
+    fn open_at(
+        dir: PathBuf,
+        options: KvStoreOptions,
+        namespaces: Link<KvStore>,
+    ) -> Result<KvStore> {
+        let mut db_path: PathBuf = dir.clone();
+        db_path.push("db");
+        let index = KeyDir::new(dir.join("index"), options.index_memory_limit)?;
+        let mmap = options
+            .mmap_reads
+            .then(|| Arc::new(MmapReader::new(db_path.clone())));
+        let history = options
+            .retention
+            .map(|retention| Arc::new(Mutex::new(History::new(retention))));
+        let changes = options
+            .change_log
+            .map(|capacity| ChangeLog::open(dir.join("changes"), capacity))
+            .transpose()?
+            .map(|log| Arc::new(Mutex::new(log)));
+        let kvs = KvStore {
+            path: Arc::new(dir),
+            options: Arc::new(options),
+            index: Arc::new(RwLock::new(index)),
+            gate: Arc::new(RwLock::new(())),
+            writer: Arc::new(Mutex::new(BufWriterWithPos::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&db_path)?,
+            )?)),
+            reader: Arc::new(Mutex::new(BufReaderWithPos::new(
+                OpenOptions::new().read(true).open(&db_path)?,
+            )?)),
+            mmap,
+            history,
+            seq: Arc::new(AtomicU64::new(1)),
+            uncompacted: Arc::new(Mutex::new(0)),
+            namespaces,
+            watchers: Arc::new(Watchers::default()),
+            changes,
+        };
+        kvs.load()?;
+        Ok(kvs)
+    }
+}
+
+fn read_at(file: &mut (impl Read + Seek), location: ValueLocation) -> Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(location.pos))?;
+    let mut buf = vec![0; location.len as usize];
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
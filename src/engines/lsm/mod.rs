@@ -0,0 +1,681 @@
+//! A log-structured merge-tree engine.
+//!
+//! Writes go to a write-ahead log and an in-memory sorted memtable. A full
+//! memtable is frozen, still read but no longer written, and a fresh one and
+//! log take the writes while the frozen one is flushed into an immutable
+//! SSTable in level 0. Levels are merged downwards by leveled compaction:
+//! level 0 is compacted as a whole once it holds `l0_compaction_trigger`
+//! tables, deeper levels whenever they outgrow their size budget. Flushes
+//! and compactions run on a background thread, which streams their input
+//! tables into the output tables. The set of live tables is recorded in
+//! `MANIFEST`, which is replaced atomically after every flush and compaction.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::mem;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, RwLock, Weak};
+use std::thread::{self, JoinHandle};
+
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::engines::bloom::{self, FilterCounters};
+use crate::engines::changelog::ChangeLog;
+use crate::engines::sstable::{Lookup, MergeIter, SsTable, SsTableBuilder};
+use crate::engines::watch::Watchers;
+use crate::engines::MergeOperators;
+use crate::{Change, KvsEngine, KvsError, Result, Stats};
+
+use self::wal::Wal;
+
+mod wal;
+
+const MANIFEST: &str = "MANIFEST";
+const CHANGES_FILE: &str = "changes";
+
+/// Tuning knobs of `LsmKvsEngine`.
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    /// Memtable size in bytes that triggers a flush to level 0.
+    pub memtable_size: usize,
+    /// Target size of an SSTable data block.
+    pub block_size: usize,
+    /// Target size of an SSTable produced by compaction.
+    pub table_size: u64,
+    /// Number of level 0 tables that triggers a level 0 compaction.
+    pub l0_compaction_trigger: usize,
+    /// Size budget of level 1, each deeper level gets `level_multiplier` times more.
+    pub level_base_size: u64,
+    pub level_multiplier: u64,
+    pub max_levels: usize,
+    /// Target false positive rate of the per-table bloom filters.
+    pub bloom_false_positive_rate: f64,
+    /// Operators `merge` may name.
+    pub merge_operators: MergeOperators,
+    /// Changes kept in a change log next to the data for `changes`. `None`
+    /// keeps no change log.
+    pub change_log: Option<usize>,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        LsmOptions {
+            memtable_size: 4 * 1024 * 1024,
+            block_size: 4 * 1024,
+            table_size: 2 * 1024 * 1024,
+            l0_compaction_trigger: 4,
+            level_base_size: 10 * 1024 * 1024,
+            level_multiplier: 10,
+            max_levels: 7,
+            bloom_false_positive_rate: 0.01,
+            merge_operators: MergeOperators::default(),
+            change_log: None,
+        }
+    }
+}
+
+/// LsmKvsEngine struct
+#[derive(Debug, Clone)]
+pub struct LsmKvsEngine {
+    path: Arc<PathBuf>,
+    options: Arc<LsmOptions>,
+    state: Arc<RwLock<State>>,
+    counters: Arc<FilterCounters>,
+    compactor: Arc<Compactor>,
+    watchers: Arc<Watchers>,
+}
+
+type Memtable = BTreeMap<String, Option<String>>;
+
+#[derive(Debug)]
+struct State {
+    memtable: Memtable,
+    memtable_size: usize,
+    /// Full memtable being flushed, which reads consult after `memtable`.
+    frozen: Option<Arc<Memtable>>,
+    wal: Wal,
+    /// Oldest log not yet flushed. It and every later log are replayed on
+    /// open.
+    oldest_wal: u64,
+    /// Level 0 is ordered newest first, deeper levels by key.
+    levels: Vec<Vec<Arc<SsTable>>>,
+    next_id: u64,
+    /// Largest key of the last table compacted out of each level.
+    compact_pointers: Vec<String>,
+    changes: Option<ChangeLog>,
+    /// Sequence number of the last write, restarting from 0 on open unless
+    /// a change log is kept.
+    seq: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    next_id: u64,
+    wal: u64,
+    levels: Vec<Vec<u64>>,
+}
+
+impl LsmKvsEngine {
+    pub fn open(path: impl Into<PathBuf>) -> Result<LsmKvsEngine> {
+        Self::open_with_options(path, LsmOptions::default())
+    }
+
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        options: LsmOptions,
+    ) -> Result<LsmKvsEngine> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+        let manifest = match fs::read(path.join(MANIFEST)) {
+            Ok(buf) => serde_json::from_slice(&buf)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Manifest::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut levels = vec![Vec::new(); options.max_levels];
+        for (level, ids) in manifest.levels.iter().enumerate() {
+            for id in ids {
+                let table = SsTable::open(table_path(&path, *id), *id)?;
+                levels[level].push(Arc::new(table));
+            }
+        }
+
+        // a crash may have left a frozen memtable unflushed, in the logs
+        // before the last one
+        let wals: Vec<u64> = wal_ids(&path)?
+            .into_iter()
+            .filter(|id| *id >= manifest.wal)
+            .collect();
+        let mut memtable = BTreeMap::new();
+        let mut memtable_size = 0;
+        for id in &wals {
+            memtable_size += Wal::replay(&wal_path(&path, *id), &mut memtable)?;
+        }
+        let wal = wals.last().copied().unwrap_or(manifest.wal);
+        remove_stale_files(&path, &manifest)?;
+        let changes = options
+            .change_log
+            .map(|capacity| ChangeLog::open(path.join(CHANGES_FILE), capacity))
+            .transpose()?;
+
+        let state = State {
+            memtable,
+            memtable_size,
+            frozen: None,
+            wal: Wal::open(wal_path(&path, wal), wal)?,
+            oldest_wal: manifest.wal,
+            levels,
+            next_id: manifest.next_id.max(wal + 1),
+            compact_pointers: vec![String::new(); options.max_levels],
+            seq: changes.as_ref().map_or(0, ChangeLog::last_seq),
+            changes,
+        };
+        let path = Arc::new(path);
+        let options = Arc::new(options);
+        let state = Arc::new(RwLock::new(state));
+        let compactor = Compactor::start(path.clone(), options.clone(), Arc::downgrade(&state));
+        // levels left over budget by a crash
+        compactor.wake();
+        Ok(LsmKvsEngine {
+            path,
+            options,
+            state,
+            counters: Arc::new(FilterCounters::default()),
+            compactor: Arc::new(compactor),
+            watchers: Arc::new(Watchers::default()),
+        })
+    }
+
+    fn write(&self, key: String, value: Option<String>) -> Result<()> {
+        let mut state = self.state.write().unwrap();
+        if value.is_none() && lookup(&state, &key, &self.counters)?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.write_locked(&mut state, key.clone(), value.clone())?;
+        self.publish(&mut state, &[(&key, value.as_deref())])
+    }
+
+    fn write_locked(&self, state: &mut State, key: String, value: Option<String>) -> Result<()> {
+        state.wal.append(&key, value.as_deref())?;
+        state.memtable_size += key.len() + value.as_ref().map_or(0, String::len);
+        state.memtable.insert(key, value);
+        if state.memtable_size >= self.options.memtable_size {
+            if state.frozen.is_none() {
+                self.freeze(state)?;
+            }
+            // also retries a flush that failed
+            self.compactor.wake();
+        }
+        Ok(())
+    }
+
+    /// Passes the changes of a write to the change log and to watchers under
+    /// the next sequence number.
+    fn publish(&self, state: &mut State, changes: &[(&str, Option<&str>)]) -> Result<()> {
+        state.seq += 1;
+        if let Some(log) = &mut state.changes {
+            log.append(state.seq, changes)?;
+        }
+        for (key, value) in changes {
+            self.watchers.notify(state.seq, key, *value);
+        }
+        Ok(())
+    }
+
+    /// Sets the memtable aside for the compactor thread to flush, and starts
+    /// a fresh one and log. Until that flush is done, the fresh memtable
+    /// takes writes past its size.
+    fn freeze(&self, state: &mut State) -> Result<()> {
+        let id = state.next_id;
+        state.wal = Wal::open(wal_path(&self.path, id), id)?;
+        sync_dir(&self.path)?;
+        state.next_id = id + 1;
+        state.frozen = Some(Arc::new(mem::take(&mut state.memtable)));
+        state.memtable_size = 0;
+        Ok(())
+    }
+}
+
+/// Thread flushing and compacting the tables of an engine in the background,
+/// joined once the last handle of the engine is dropped.
+#[derive(Debug)]
+struct Compactor {
+    wake: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Compactor {
+    fn start(dir: Arc<PathBuf>, options: Arc<LsmOptions>, state: Weak<RwLock<State>>) -> Compactor {
+        let (wake, woken) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            while woken.recv().is_ok() {
+                while woken.try_recv().is_ok() {}
+                let Some(state) = state.upgrade() else {
+                    return;
+                };
+                if let Err(e) = flush(&dir, &options, &state) {
+                    error!("flush of {} failed: {}", dir.display(), e);
+                }
+                if let Err(e) = compact(&dir, &options, &state) {
+                    error!("compaction of {} failed: {}", dir.display(), e);
+                }
+            }
+        });
+        Compactor {
+            wake: Some(wake),
+            thread: Some(thread),
+        }
+    }
+
+    fn wake(&self) {
+        if let Some(wake) = &self.wake {
+            let _ = wake.send(());
+        }
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        // the compaction under way is finished first
+        self.wake.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Writes the frozen memtable, if any, to a new level 0 table, then drops it
+/// and the logs it was replayed from. Like compactions, flushes only lock
+/// the state to take an ID and to swap in their table.
+fn flush(dir: &Path, options: &LsmOptions, state: &RwLock<State>) -> Result<()> {
+    let Some(frozen) = state.read().unwrap().frozen.clone() else {
+        return Ok(());
+    };
+    let id = {
+        let mut state = state.write().unwrap();
+        state.next_id += 1;
+        state.next_id - 1
+    };
+    let mut builder = SsTableBuilder::new(
+        table_path(dir, id),
+        options.block_size,
+        bloom::bits_per_key(options.bloom_false_positive_rate),
+    )?;
+    for (key, value) in frozen.iter() {
+        builder.add(key, value.as_deref())?;
+    }
+    let table = builder.finish(id)?;
+    let oldest_wal = {
+        let mut state = state.write().unwrap();
+        state.levels[0].insert(0, Arc::new(table));
+        state.frozen = None;
+        // no second memtable is frozen before this one is flushed
+        state.oldest_wal = state.wal.id;
+        save_manifest(dir, &state)?;
+        state.oldest_wal
+    };
+    for id in wal_ids(dir)? {
+        if id < oldest_wal {
+            fs::remove_file(wal_path(dir, id))?;
+        }
+    }
+    Ok(())
+}
+
+/// Tables merged by one compaction: tables of `level` and the overlapping
+/// tables of the next level.
+struct Compaction {
+    level: usize,
+    /// Newest first, so that their entries win over those of `lower`.
+    upper: Vec<Arc<SsTable>>,
+    lower: Vec<Arc<SsTable>>,
+    largest: String,
+    /// Whether no level below holds the keys, so tombstones can go.
+    bottom: bool,
+}
+
+/// Runs compactions until every level is within its budget. The state is
+/// only locked to pick the tables of each compaction and to swap in its
+/// output, and readers and writers carry on while tables are merged.
+fn compact(dir: &Path, options: &LsmOptions, state: &RwLock<State>) -> Result<()> {
+    loop {
+        let Some(compaction) = pick(options, &state.read().unwrap()) else {
+            return Ok(());
+        };
+        let outputs = merge_tables(dir, options, state, &compaction)?;
+        install(dir, state, compaction, outputs)?;
+    }
+}
+
+/// Picks the next compaction, `None` if every level is within its budget.
+fn pick(options: &LsmOptions, state: &State) -> Option<Compaction> {
+    let level = if state.levels[0].len() >= options.l0_compaction_trigger {
+        0
+    } else {
+        (1..options.max_levels - 1)
+            .find(|level| level_size(&state.levels[*level]) > level_budget(options, *level))?
+    };
+    let upper: Vec<Arc<SsTable>> = if level == 0 {
+        state.levels[0].clone()
+    } else {
+        // round robin over the key space of the level
+        let pointer = &state.compact_pointers[level];
+        let tables = &state.levels[level];
+        let table = tables
+            .iter()
+            .find(|table| &table.smallest > pointer)
+            .unwrap_or(&tables[0]);
+        vec![table.clone()]
+    };
+    let smallest = upper.iter().map(|t| t.smallest.clone()).min().unwrap();
+    let largest = upper.iter().map(|t| t.largest.clone()).max().unwrap();
+    let lower = state.levels[level + 1]
+        .iter()
+        .filter(|table| table.overlaps(&smallest, &largest))
+        .cloned()
+        .collect();
+    Some(Compaction {
+        level,
+        upper,
+        lower,
+        largest,
+        bottom: state.levels[level + 2..].iter().all(Vec::is_empty),
+    })
+}
+
+/// Streams the entries of the tables of `compaction` into new tables of at
+/// most `table_size` bytes.
+fn merge_tables(
+    dir: &Path,
+    options: &LsmOptions,
+    state: &RwLock<State>,
+    compaction: &Compaction,
+) -> Result<Vec<Arc<SsTable>>> {
+    let sources = compaction
+        .upper
+        .iter()
+        .chain(&compaction.lower)
+        .map(|table| table.iter());
+    let mut outputs = Vec::new();
+    let mut builder: Option<(u64, SsTableBuilder)> = None;
+    for entry in MergeIter::new(sources) {
+        let (key, value) = entry?;
+        if value.is_none() && compaction.bottom {
+            continue;
+        }
+        let (id, current) = match &mut builder {
+            Some(builder) => builder,
+            None => {
+                let id = {
+                    let mut state = state.write().unwrap();
+                    state.next_id += 1;
+                    state.next_id - 1
+                };
+                let current = SsTableBuilder::new(
+                    table_path(dir, id),
+                    options.block_size,
+                    bloom::bits_per_key(options.bloom_false_positive_rate),
+                )?;
+                builder.insert((id, current))
+            }
+        };
+        current.add(&key, value.as_deref())?;
+        if current.estimated_size() >= options.table_size {
+            let id = *id;
+            let (_, current) = builder.take().unwrap();
+            outputs.push(Arc::new(current.finish(id)?));
+        }
+    }
+    if let Some((id, current)) = builder {
+        if !current.is_empty() {
+            outputs.push(Arc::new(current.finish(id)?));
+        }
+    }
+    Ok(outputs)
+}
+
+/// Replaces the tables of `compaction` with `outputs`.
+fn install(
+    dir: &Path,
+    state: &RwLock<State>,
+    compaction: Compaction,
+    outputs: Vec<Arc<SsTable>>,
+) -> Result<()> {
+    let Compaction {
+        level,
+        upper,
+        lower,
+        largest,
+        ..
+    } = compaction;
+    let obsolete: Vec<u64> = upper.iter().chain(lower.iter()).map(|t| t.id).collect();
+    {
+        let mut state = state.write().unwrap();
+        state.levels[level].retain(|table| !obsolete.contains(&table.id));
+        state.levels[level + 1].retain(|table| !obsolete.contains(&table.id));
+        state.levels[level + 1].extend(outputs);
+        state.levels[level + 1].sort_by(|a, b| a.smallest.cmp(&b.smallest));
+        state.compact_pointers[level] = largest;
+        save_manifest(dir, &state)?;
+    }
+    // readers still holding the tables keep their files open
+    for table in upper.iter().chain(lower.iter()) {
+        fs::remove_file(table.path())?;
+    }
+    Ok(())
+}
+
+fn level_budget(options: &LsmOptions, level: usize) -> u64 {
+    options.level_base_size * options.level_multiplier.pow(level as u32 - 1)
+}
+
+fn save_manifest(dir: &Path, state: &State) -> Result<()> {
+    let manifest = Manifest {
+        next_id: state.next_id,
+        wal: state.oldest_wal,
+        levels: state
+            .levels
+            .iter()
+            .map(|tables| tables.iter().map(|table| table.id).collect())
+            .collect(),
+    };
+    let tmp = dir.join(format!("{}.tmp", MANIFEST));
+    let mut file = File::create(&tmp)?;
+    file.write_all(&serde_json::to_vec(&manifest)?)?;
+    file.sync_all()?;
+    fs::rename(tmp, dir.join(MANIFEST))?;
+    sync_dir(dir)
+}
+
+/// Makes the files created in, renamed into or removed from `dir` durable.
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+impl KvsEngine for LsmKvsEngine {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let state = self.state.read().unwrap();
+        lookup(&state, &key, &self.counters)
+    }
+    fn remove(&self, key: String) -> Result<()> {
+        self.write(key, None)
+    }
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.write(key, Some(value))
+    }
+    fn merge(&self, key: String, operator: &str, operand: String) -> Result<String> {
+        // resolved on write, the write lock makes read and write atomic
+        let mut state = self.state.write().unwrap();
+        let existing = lookup(&state, &key, &self.counters)?;
+        let value =
+            self.options
+                .merge_operators
+                .merge(operator, &key, existing.as_deref(), &operand)?;
+        self.write_locked(&mut state, key.clone(), Some(value.clone()))?;
+        self.publish(&mut state, &[(&key, Some(&value))])?;
+        Ok(value)
+    }
+    fn stats(&self) -> Result<Stats> {
+        let state = self.state.read().unwrap();
+        let mut stats = Stats::new();
+        stats.insert("memtable_bytes".to_owned(), state.memtable_size.to_string());
+        for (level, tables) in state.levels.iter().enumerate() {
+            stats.insert(format!("level{}_tables", level), tables.len().to_string());
+            stats.insert(
+                format!("level{}_bytes", level),
+                level_size(tables).to_string(),
+            );
+        }
+        self.counters
+            .report(&mut stats, self.options.bloom_false_positive_rate);
+        Ok(stats)
+    }
+    fn remove_range<R: RangeBounds<String>>(&self, range: R) -> Result<usize> {
+        let mut state = self.state.write().unwrap();
+        let pairs = scan(&state, &range)?;
+        for (key, _) in &pairs {
+            self.write_locked(&mut state, key.clone(), None)?;
+        }
+        if !pairs.is_empty() {
+            let changes: Vec<(&str, Option<&str>)> =
+                pairs.iter().map(|(key, _)| (key.as_str(), None)).collect();
+            self.publish(&mut state, &changes)?;
+        }
+        Ok(pairs.len())
+    }
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        let state = self.state.read().unwrap();
+        scan(&state, &range)
+    }
+    fn watch<R: RangeBounds<String>>(&self, range: R) -> Result<Receiver<Change>> {
+        Ok(self.watchers.subscribe(range))
+    }
+    fn changes(&self, since: u64) -> Result<Vec<Change>> {
+        match &self.state.read().unwrap().changes {
+            Some(changes) => changes.since(since),
+            None => Err(KvsError::UnsupportedOperation),
+        }
+    }
+}
+
+/// Returns the live pairs in `range`, ordered by key.
+fn scan<R: RangeBounds<String>>(state: &State, range: &R) -> Result<Vec<(String, String)>> {
+    // apply oldest to newest so that newer entries win
+    let mut merged = BTreeMap::new();
+    for tables in state.levels[1..].iter().rev() {
+        for table in tables {
+            merged.extend(table.scan(range)?);
+        }
+    }
+    for table in state.levels[0].iter().rev() {
+        merged.extend(table.scan(range)?);
+    }
+    let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+    if !is_empty_range(&bounds) {
+        for memtable in state.frozen.as_deref().into_iter().chain([&state.memtable]) {
+            merged.extend(
+                memtable
+                    .range::<String, _>(bounds.clone())
+                    .map(|(k, v)| (k.clone(), v.clone())),
+            );
+        }
+    }
+    Ok(merged
+        .into_iter()
+        .filter_map(|(key, value)| value.map(|value| (key, value)))
+        .collect())
+}
+
+/// Finds the newest entry of `key`, from the memtables down to the last
+/// level.
+fn lookup(state: &State, key: &str, counters: &FilterCounters) -> Result<Option<String>> {
+    for memtable in [&state.memtable].into_iter().chain(state.frozen.as_deref()) {
+        if let Some(value) = memtable.get(key) {
+            return Ok(value.clone());
+        }
+    }
+    let candidates = state.levels[0]
+        .iter()
+        .chain(state.levels[1..].iter().filter_map(|tables| {
+            let i = tables.partition_point(|table| table.largest.as_str() < key);
+            tables.get(i)
+        }));
+    for table in candidates {
+        match table.get(key)? {
+            Lookup::Found(value) => return Ok(value),
+            Lookup::Filtered => counters.negative(),
+            Lookup::Missing => counters.false_positive(),
+            Lookup::OutOfRange => {}
+        }
+    }
+    Ok(None)
+}
+
+fn level_size(tables: &[Arc<SsTable>]) -> u64 {
+    tables.iter().map(|table| table.size).sum()
+}
+
+/// `BTreeMap::range` panics on inverted bounds, which callers may well pass.
+fn is_empty_range(bounds: &(Bound<String>, Bound<String>)) -> bool {
+    match bounds {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.sst", id))
+}
+
+fn wal_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.wal", id))
+}
+
+/// IDs of the logs in `dir`, oldest first.
+fn wal_ids(dir: &Path) -> Result<Vec<u64>> {
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("wal") {
+            continue;
+        }
+        if let Some(id) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<u64>().ok())
+        {
+            ids.push(id);
+        }
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+/// Deletes tables and logs left behind by a crash between writing the
+/// manifest and removing the files it no longer references.
+fn remove_stale_files(dir: &Path, manifest: &Manifest) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let id = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<u64>().ok());
+        let stale = match (id, path.extension().and_then(|ext| ext.to_str())) {
+            (Some(id), Some("sst")) => !manifest.levels.iter().any(|ids| ids.contains(&id)),
+            (Some(id), Some("wal")) => id < manifest.wal,
+            _ => false,
+        };
+        if stale {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,66 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+
+use crate::Result;
+
+/// Write-ahead log of the current memtable.
+#[derive(Debug)]
+pub struct Wal {
+    pub id: u64,
+    writer: BufWriter<File>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    key: String,
+    value: Option<String>,
+}
+
+impl Wal {
+    pub fn open(path: impl AsRef<Path>, id: u64) -> Result<Wal> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Wal {
+            id,
+            writer: BufWriter::new(file),
+        })
+    }
+
+    /// Appends a write, on disk once this returns.
+    pub fn append(&mut self, key: &str, value: Option<&str>) -> Result<()> {
+        let record = Record {
+            key: key.to_owned(),
+            value: value.map(str::to_owned),
+        };
+        serde_json::to_writer(&mut self.writer, &record)?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    /// Replays a log into `memtable`, returning the number of bytes replayed.
+    ///
+    /// A torn record at the tail, left by a crash in the middle of a write,
+    /// ends the replay instead of failing it, and is cut off so that the
+    /// records appended after it are replayed next time.
+    pub fn replay(path: &Path, memtable: &mut BTreeMap<String, Option<String>>) -> Result<usize> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut stream = Deserializer::from_reader(BufReader::new(&mut file)).into_iter::<Record>();
+        let mut size = 0;
+        let mut len = 0;
+        while let Some(Ok(record)) = stream.next() {
+            size += record.key.len() + record.value.as_ref().map_or(0, String::len);
+            memtable.insert(record.key, record.value);
+            len = stream.byte_offset() as u64;
+        }
+        if file.metadata()?.len() > len {
+            file.set_len(len)?;
+            file.sync_data()?;
+        }
+        Ok(size)
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use crate::{KvsError, Result};
+
+/// Name of the built-in operator behind `KvsEngine::incr`. Its operand is a
+/// JSON array `[delta, initial]`.
+pub const COUNTER: &str = "incr";
+/// Name of the built-in operator behind `KvsEngine::append`.
+pub const APPEND: &str = "append";
+/// Name of the built-in operator keeping the numerically larger value.
+pub const MAX: &str = "max";
+/// Name of the built-in operator adding the elements of a JSON array to the
+/// JSON array stored under a key, skipping elements it already holds.
+pub const UNION: &str = "union";
+
+/// Combines the current value of a key with an operand into its new value.
+///
+/// Engines may apply an operand long after it was written, or several times
+/// when replaying their log, so operators must be deterministic.
+pub trait MergeOperator: Send + Sync {
+    fn merge(&self, key: &str, existing: Option<&str>, operand: &str) -> Result<String>;
+}
+
+impl<F> MergeOperator for F
+where
+    F: Fn(&str, Option<&str>, &str) -> Result<String> + Send + Sync,
+{
+    fn merge(&self, key: &str, existing: Option<&str>, operand: &str) -> Result<String> {
+        self(key, existing, operand)
+    }
+}
+
+/// Merge operators by name. The built-in ones are always present.
+#[derive(Clone)]
+pub struct MergeOperators {
+    operators: HashMap<String, Arc<dyn MergeOperator>>,
+}
+
+impl MergeOperators {
+    /// Registers `operator` as `name`, replacing any operator of that name.
+    pub fn register(&mut self, name: impl Into<String>, operator: impl MergeOperator + 'static) {
+        self.operators.insert(name.into(), Arc::new(operator));
+    }
+
+    /// Applies the operator registered as `name`.
+    pub fn merge(
+        &self,
+        name: &str,
+        key: &str,
+        existing: Option<&str>,
+        operand: &str,
+    ) -> Result<String> {
+        let operator = self
+            .operators
+            .get(name)
+            .ok_or_else(|| KvsError::UnknownMergeOperator(name.to_owned()))?;
+        operator.merge(key, existing, operand)
+    }
+}
+
+impl Default for MergeOperators {
+    fn default() -> Self {
+        let mut operators = MergeOperators {
+            operators: HashMap::new(),
+        };
+        operators.register(COUNTER, counter);
+        operators.register(APPEND, append);
+        operators.register(MAX, max);
+        operators.register(UNION, union);
+        operators
+    }
+}
+
+impl fmt::Debug for MergeOperators {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names: Vec<&String> = self.operators.keys().collect();
+        names.sort();
+        f.debug_set().entries(names).finish()
+    }
+}
+
+fn counter(_key: &str, existing: Option<&str>, operand: &str) -> Result<String> {
+    let (delta, initial): (i64, i64) = serde_json::from_str(operand)?;
+    let current = match existing {
+        Some(value) => value
+            .parse::<i64>()
+            .map_err(|_| KvsError::Merge(format!("{:?} is not an integer", value)))?,
+        None => initial,
+    };
+    current
+        .checked_add(delta)
+        .map(|value| value.to_string())
+        .ok_or(KvsError::MergeOverflow)
+}
+
+fn append(_key: &str, existing: Option<&str>, operand: &str) -> Result<String> {
+    Ok(existing.unwrap_or_default().to_owned() + operand)
+}
+
+fn max(_key: &str, existing: Option<&str>, operand: &str) -> Result<String> {
+    let number = |s: &str| {
+        s.parse::<f64>()
+            .map_err(|_| KvsError::Merge(format!("{:?} is not a number", s)))
+    };
+    let candidate = number(operand)?;
+    match existing {
+        Some(value) if number(value)? >= candidate => Ok(value.to_owned()),
+        _ => Ok(operand.to_owned()),
+    }
+}
+
+fn union(_key: &str, existing: Option<&str>, operand: &str) -> Result<String> {
+    let array = |s: &str| match serde_json::from_str(s)? {
+        Value::Array(values) => Ok(values),
+        _ => Err(KvsError::Merge(format!("{:?} is not a JSON array", s))),
+    };
+    let mut values = existing.map(array).transpose()?.unwrap_or_default();
+    for value in array(operand)? {
+        if !values.contains(&value) {
+            values.push(value);
+        }
+    }
+    Ok(Value::Array(values).to_string())
+}
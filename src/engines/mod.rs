@@ -1,13 +1,153 @@
-use crate::Result;
+use std::collections::BTreeMap;
+use std::ops::{Bound, RangeBounds};
+use std::sync::mpsc::Receiver;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsError, Result};
+
+/// Engine specific counters and settings, keyed by name.
+pub type Stats = BTreeMap<String, String>;
+
+/// One retained version of a key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Version {
+    /// Sequence number of the write, increasing over the whole store.
+    pub seq: u64,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    /// `None` if the write removed the key.
+    pub value: Option<String>,
+}
+
+/// A committed write to a key, as seen by watchers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Change {
+    /// Sequence number of the write, increasing over the whole store.
+    pub seq: u64,
+    pub key: String,
+    /// `None` if the write removed the key.
+    pub value: Option<String>,
+}
 
 pub trait KvsEngine: Clone + Send + 'static {
     fn get(&self, key: String) -> Result<Option<String>>;
     fn remove(&self, key: String) -> Result<()>;
     fn set(&self, key: String, value: String) -> Result<()>;
+    /// Returns all key/value pairs whose key falls in `range`, ordered by key.
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>>;
+    /// Returns the first `limit` pairs `scan` would, stopping there where
+    /// the engine can.
+    fn scan_limit<R: RangeBounds<String>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let mut pairs = self.scan(range)?;
+        pairs.truncate(limit);
+        Ok(pairs)
+    }
+    fn stats(&self) -> Result<Stats>;
+    /// Removes every key in `range`, returning how many there were.
+    fn remove_range<R: RangeBounds<String>>(&self, _range: R) -> Result<usize> {
+        Err(KvsError::UnsupportedOperation)
+    }
+    /// Removes every key starting with `prefix`, returning how many there
+    /// were.
+    fn remove_prefix(&self, prefix: &str) -> Result<usize> {
+        self.remove_range(prefix_range(prefix))
+    }
+    /// Returns the value `key` had right after the write with sequence number
+    /// `seq`, as far as the retained history goes.
+    fn get_version(&self, _key: String, _seq: u64) -> Result<Option<String>> {
+        Err(KvsError::UnsupportedOperation)
+    }
+    /// Returns the retained versions of `key`, oldest first.
+    fn history(&self, _key: String) -> Result<Vec<Version>> {
+        Err(KvsError::UnsupportedOperation)
+    }
+    /// Atomically folds `operand` into the value of `key` with the merge
+    /// operator registered as `operator`, returning the new value.
+    fn merge(&self, _key: String, _operator: &str, _operand: String) -> Result<String> {
+        Err(KvsError::UnsupportedOperation)
+    }
+    /// Atomically adds `delta` to the integer stored under `key`, starting
+    /// from `initial` if the key is missing, and returns the result.
+    fn incr(&self, key: String, delta: i64, initial: i64) -> Result<i64> {
+        let operand = serde_json::to_string(&(delta, initial))?;
+        let value = self.merge(key, merge::COUNTER, operand)?;
+        value
+            .parse()
+            .map_err(|_| KvsError::Merge(format!("{:?} is not an integer", value)))
+    }
+    /// Atomically subtracts `delta`, see `incr`.
+    fn decr(&self, key: String, delta: i64, initial: i64) -> Result<i64> {
+        let delta = delta.checked_neg().ok_or(KvsError::MergeOverflow)?;
+        self.incr(key, delta, initial)
+    }
+    /// Atomically appends `value` to the value of `key`.
+    fn append(&self, key: String, value: String) -> Result<()> {
+        self.merge(key, merge::APPEND, value).map(|_| ())
+    }
+    /// Creates the namespace `name` unless it exists.
+    fn create_namespace(&self, _name: &str) -> Result<()> {
+        Err(KvsError::UnsupportedOperation)
+    }
+    /// Returns a handle to namespace `name`, whose keys are independent of
+    /// those of the store and of every other namespace.
+    fn namespace(&self, _name: &str) -> Result<Self> {
+        Err(KvsError::UnsupportedOperation)
+    }
+    /// Drops namespace `name` and all its keys. Handles to it must not be
+    /// used afterwards.
+    fn drop_namespace(&self, _name: &str) -> Result<()> {
+        Err(KvsError::UnsupportedOperation)
+    }
+    /// Returns the names of all namespaces, sorted.
+    fn namespaces(&self) -> Result<Vec<String>> {
+        Err(KvsError::UnsupportedOperation)
+    }
+    /// Subscribes to the writes to keys in `range`, in the order they are
+    /// committed. The receiver is disconnected if it falls too far behind.
+    fn watch<R: RangeBounds<String>>(&self, _range: R) -> Result<Receiver<Change>> {
+        Err(KvsError::UnsupportedOperation)
+    }
+    /// Returns the retained changes after sequence number `since`, oldest
+    /// first. Together with `watch` this lets a consumer resume where it
+    /// left off.
+    fn changes(&self, _since: u64) -> Result<Vec<Change>> {
+        Err(KvsError::UnsupportedOperation)
+    }
+}
+
+/// Returns the range of the keys starting with `prefix`.
+pub fn prefix_range(prefix: &str) -> (Bound<String>, Bound<String>) {
+    let start = Bound::Included(prefix.to_owned());
+    // the smallest string after all keys with the prefix: the prefix with
+    // its last character incremented, dropping characters that cannot be
+    let mut end: Vec<char> = prefix.chars().collect();
+    while let Some(c) = end.pop() {
+        if let Some(next) = (c as u32 + 1..=char::MAX as u32).find_map(char::from_u32) {
+            end.push(next);
+            return (start, Bound::Excluded(end.into_iter().collect()));
+        }
+    }
+    (start, Bound::Unbounded)
 }
 
+pub(crate) mod bloom;
+mod cache;
+mod changelog;
 mod kvs;
+mod lsm;
+pub mod merge;
+mod namespace;
 mod sled;
+mod sstable;
+mod watch;
 
-pub use self::kvs::KvStore;
-pub use self::sled::SledKvsEngine;
+pub use self::cache::{CachePolicy, CachedEngine};
+pub use self::kvs::{KvStore, KvStoreOptions, Retention};
+pub use self::lsm::{LsmKvsEngine, LsmOptions};
+pub use self::merge::{MergeOperator, MergeOperators};
+pub use self::sled::{SledKvsEngine, SledOptions};
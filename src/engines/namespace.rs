@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::{KvsError, Result};
+
+/// Checks that `name` can name a namespace, which must be usable as a file
+/// name on its own.
+pub fn validate(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        return Err(KvsError::InvalidNamespace(name.to_owned()));
+    }
+    Ok(())
+}
+
+/// Open namespaces of one store, shared by all of its handles.
+#[derive(Debug)]
+pub struct Registry<E> {
+    /// Where the store keeps its namespaces.
+    pub dir: PathBuf,
+    pub handles: Mutex<HashMap<String, E>>,
+}
+
+/// Link from an engine handle to the namespaces of its store. Root handles
+/// keep the registry alive, while namespace handles, which the registry
+/// holds, only refer to it.
+#[derive(Debug, Clone)]
+pub enum Link<E> {
+    Root(Arc<Registry<E>>),
+    Namespace(Weak<Registry<E>>),
+}
+
+impl<E> Link<E> {
+    pub fn root(dir: PathBuf) -> Link<E> {
+        Link::Root(Arc::new(Registry {
+            dir,
+            handles: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// Link for a namespace of the same store.
+    pub fn child(&self) -> Link<E> {
+        match self {
+            Link::Root(registry) => Link::Namespace(Arc::downgrade(registry)),
+            Link::Namespace(registry) => Link::Namespace(registry.clone()),
+        }
+    }
+
+    /// Fails once every root handle of the store is gone.
+    pub fn registry(&self) -> Result<Arc<Registry<E>>> {
+        match self {
+            Link::Root(registry) => Ok(registry.clone()),
+            Link::Namespace(registry) => registry.upgrade().ok_or(KvsError::UnsupportedOperation),
+        }
+    }
+}
@@ -1,33 +1,486 @@
-use crate::{KvsEngine, KvsError, Result};
-use sled::{self, Db};
-use std::path::PathBuf;
+use crate::engines::bloom::{self, BloomFilter, FilterCounters};
+use crate::engines::changelog::ChangeLog;
+use crate::engines::namespace::{self, Link};
+use crate::engines::watch::Watchers;
+use crate::engines::MergeOperators;
+use crate::{Change, KvsEngine, KvsError, Result, Stats};
+use sled::{self, Db, Tree};
+use std::fs;
+use std::io;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::thread;
+use std::time::Duration;
+
+/// File next to the sled data holding the bloom filter of a cleanly closed store.
+const FILTER_FILE: &str = "bloom.filter";
+/// Sidecar file holding the recent changes, as sled keeps no such log.
+const CHANGES_FILE: &str = "changes";
+const MIN_FILTER_CAPACITY: usize = 1024;
+/// How long `open` waits for a store closed just before to release its lock.
+const LOCK_RETRIES: usize = 100;
+const LOCK_RETRY_DELAY: Duration = Duration::from_millis(10);
+/// Prefix of the names of the trees holding namespaces.
+const NAMESPACE_PREFIX: &str = "ns/";
+
+/// Tuning knobs of `SledKvsEngine`.
+#[derive(Debug, Clone)]
+pub struct SledOptions {
+    /// Target false positive rate of the bloom filter consulted before reads.
+    pub bloom_false_positive_rate: f64,
+    /// Operators `merge` may name.
+    pub merge_operators: MergeOperators,
+    /// Changes kept in a change log next to the data for `changes`. `None`
+    /// keeps no change log.
+    pub change_log: Option<usize>,
+}
+
+impl Default for SledOptions {
+    fn default() -> Self {
+        SledOptions {
+            bloom_false_positive_rate: 0.01,
+            merge_operators: MergeOperators::default(),
+            change_log: None,
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct SledKvsEngine {
     db: Db,
+    /// The default tree, or the tree of a namespace.
+    tree: Tree,
+    options: Arc<SledOptions>,
+    filter: Arc<KeyFilter>,
+    namespaces: Link<SledKvsEngine>,
+    watchers: Arc<Watchers>,
+    changes: Option<Arc<Mutex<ChangeLog>>>,
+}
+
+/// Bloom filter over every key of the tree, so that misses skip sled.
+///
+/// The filter is only written out when the last handle is dropped, and the
+/// file is deleted again on open. A store that was not closed cleanly thus
+/// has no filter file and gets its filter rebuilt from the keys in sled.
+#[derive(Debug)]
+struct KeyFilter {
+    path: PathBuf,
+    false_positive_rate: f64,
+    /// Held shared by writers from filter insert to sled insert, and
+    /// exclusively while rebuilding so no in-flight key is lost.
+    gate: RwLock<()>,
+    state: RwLock<FilterState>,
+    counters: FilterCounters,
+    /// Set once the namespace of the filter is dropped, so it is not saved.
+    dropped: AtomicBool,
+}
+
+#[derive(Debug)]
+struct FilterState {
+    bloom: BloomFilter,
+    capacity: usize,
+    inserted: usize,
 }
 
 impl SledKvsEngine {
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with_options(path, SledOptions::default())
+    }
+
+    pub fn open_with_options(path: impl Into<PathBuf>, options: SledOptions) -> Result<Self> {
+        let path = path.into();
+        let db = open_db(&path)?;
+        let tree = (*db).clone();
+        let namespaces = Link::root(path.clone());
+        Self::open_tree(db, tree, &path, None, Arc::new(options), namespaces)
+    }
+
+    /// Opens `tree`, which keeps its sidecar files in `dir`, suffixed with
+    /// the name of its namespace if any.
+    fn open_tree(
+        db: Db,
+        tree: Tree,
+        dir: &Path,
+        name: Option<&str>,
+        options: Arc<SledOptions>,
+        namespaces: Link<SledKvsEngine>,
+    ) -> Result<Self> {
+        let filter = KeyFilter::open(sidecar_path(dir, FILTER_FILE, name), &tree, &options)?;
+        let changes = options
+            .change_log
+            .map(|capacity| ChangeLog::open(sidecar_path(dir, CHANGES_FILE, name), capacity))
+            .transpose()?
+            .map(|log| Arc::new(Mutex::new(log)));
         Ok(Self {
-            db: sled::open(path.into())?,
+            db,
+            tree,
+            options,
+            filter: Arc::new(filter),
+            namespaces,
+            watchers: Arc::new(Watchers::default()),
+            changes,
+        })
+    }
+
+    /// Serializes writes while a change log is kept, so that it orders them
+    /// like sled does.
+    fn lock_changes(&self) -> Option<MutexGuard<'_, ChangeLog>> {
+        self.changes.as_ref().map(|changes| changes.lock().unwrap())
+    }
+
+    /// Passes the changes of a write to the change log and to watchers under
+    /// the next sequence number, which the change log assigns if kept.
+    fn publish(
+        &self,
+        mut log: Option<MutexGuard<'_, ChangeLog>>,
+        changes: &[(&str, Option<&str>)],
+    ) -> Result<()> {
+        let seq = match log.as_deref_mut() {
+            Some(log) => {
+                let seq = log.last_seq() + 1;
+                log.append(seq, changes)?;
+                seq
+            }
+            None => self.db.generate_id()?,
+        };
+        for (key, value) in changes {
+            self.watchers.notify(seq, key, *value);
+        }
+        Ok(())
+    }
+
+    /// Each namespace is a tree of its own, with a filter of its own.
+    fn open_namespace(&self, name: &str, create: bool) -> Result<SledKvsEngine> {
+        namespace::validate(name)?;
+        let registry = self.namespaces.registry()?;
+        let mut handles = registry.handles.lock().unwrap();
+        if let Some(store) = handles.get(name) {
+            return Ok(store.clone());
+        }
+        let tree_name = format!("{}{}", NAMESPACE_PREFIX, name);
+        if !create
+            && !self
+                .db
+                .tree_names()
+                .iter()
+                .any(|n| n == tree_name.as_bytes())
+        {
+            return Err(KvsError::NamespaceNotFound(name.to_owned()));
+        }
+        let store = Self::open_tree(
+            self.db.clone(),
+            self.db.open_tree(tree_name)?,
+            &registry.dir,
+            Some(name),
+            self.options.clone(),
+            self.namespaces.child(),
+        )?;
+        handles.insert(name.to_owned(), store.clone());
+        Ok(store)
+    }
+}
+
+impl KeyFilter {
+    fn open(path: PathBuf, db: &Tree, options: &SledOptions) -> Result<KeyFilter> {
+        let state = match fs::read(&path) {
+            Ok(buf) => FilterState::decode(&buf, options.bloom_false_positive_rate),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        let state = match state {
+            Some(state) => {
+                fs::remove_file(&path)?;
+                state
+            }
+            None => FilterState::build(db, options.bloom_false_positive_rate)?,
+        };
+        Ok(KeyFilter {
+            path,
+            false_positive_rate: options.bloom_false_positive_rate,
+            gate: RwLock::new(()),
+            state: RwLock::new(state),
+            counters: FilterCounters::default(),
+            dropped: AtomicBool::new(false),
+        })
+    }
+
+    fn may_contain(&self, key: &str) -> bool {
+        self.state.read().unwrap().bloom.may_contain(key)
+    }
+
+    /// Adds `key`, then runs `insert` before any rebuild may start.
+    fn insert<T>(&self, db: &Tree, key: &str, insert: impl FnOnce() -> Result<T>) -> Result<T> {
+        let (rv, full) = {
+            let _gate = self.gate.read().unwrap();
+            let full = {
+                let mut state = self.state.write().unwrap();
+                state.bloom.insert(key);
+                state.inserted += 1;
+                state.inserted > state.capacity
+            };
+            (insert()?, full)
+        };
+        if full {
+            // rebuild with room for as many keys again as the tree holds
+            let _gate = self.gate.write().unwrap();
+            let mut state = self.state.write().unwrap();
+            if state.inserted > state.capacity {
+                *state = FilterState::build(db, self.false_positive_rate)?;
+            }
+        }
+        Ok(rv)
+    }
+}
+
+impl FilterState {
+    fn build(db: &Tree, false_positive_rate: f64) -> Result<FilterState> {
+        let capacity = (db.len() * 2).max(MIN_FILTER_CAPACITY);
+        let mut bloom = BloomFilter::new(capacity, bloom::bits_per_key(false_positive_rate));
+        let mut inserted = 0;
+        for key in db.iter().keys() {
+            bloom.insert(&String::from_utf8_lossy(&key?));
+            inserted += 1;
+        }
+        Ok(FilterState {
+            bloom,
+            capacity,
+            inserted,
+        })
+    }
+
+    fn encode(&self, false_positive_rate: f64) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&false_positive_rate.to_le_bytes());
+        buf.extend_from_slice(&(self.capacity as u64).to_le_bytes());
+        buf.extend_from_slice(&(self.inserted as u64).to_le_bytes());
+        buf.extend_from_slice(&self.bloom.to_bytes());
+        buf
+    }
+
+    /// Decodes a saved filter, returning `None` if it is malformed or was
+    /// built for another false positive rate.
+    fn decode(buf: &[u8], false_positive_rate: f64) -> Option<FilterState> {
+        let word = |i: usize| {
+            Some(u64::from_le_bytes(
+                buf.get(i * 8..i * 8 + 8)?.try_into().ok()?,
+            ))
+        };
+        if f64::from_bits(word(0)?) != false_positive_rate {
+            return None;
+        }
+        Some(FilterState {
+            capacity: word(1)? as usize,
+            inserted: word(2)? as usize,
+            bloom: BloomFilter::from_bytes(buf.get(24..)?)?,
         })
     }
 }
 
+impl Drop for KeyFilter {
+    fn drop(&mut self) {
+        if self.dropped.load(Ordering::SeqCst) {
+            return;
+        }
+        let state = self.state.read().unwrap();
+        let _ = fs::write(&self.path, state.encode(self.false_positive_rate));
+    }
+}
+
 impl KvsEngine for SledKvsEngine {
     fn get(&self, key: String) -> Result<Option<String>> {
-        let rv = self.db.get(key)?;
-        Ok(rv.map(|s| String::from_utf8(AsRef::<[u8]>::as_ref(&s).to_vec()).unwrap()))
+        if !self.filter.may_contain(&key) {
+            self.filter.counters.negative();
+            return Ok(None);
+        }
+        let rv = self.tree.get(key)?;
+        if rv.is_none() {
+            self.filter.counters.false_positive();
+        }
+        rv.as_deref().map(utf8).transpose()
     }
     fn remove(&self, key: String) -> Result<()> {
-        self.db.remove(key)?.ok_or(KvsError::KeyNotFound)?;
-        self.db.flush()?;
-        Ok(())
+        let log = self.lock_changes();
+        self.tree
+            .remove(key.as_str())?
+            .ok_or(KvsError::KeyNotFound)?;
+        self.tree.flush()?;
+        self.publish(log, &[(&key, None)])
+    }
+    fn remove_range<R: RangeBounds<String>>(&self, range: R) -> Result<usize> {
+        let log = self.lock_changes();
+        let mut batch = sled::Batch::default();
+        let mut keys = Vec::new();
+        for key in self.tree.range(byte_range(&range)).keys() {
+            let key = key?;
+            batch.remove(key.clone());
+            keys.push(key);
+        }
+        self.tree.apply_batch(batch)?;
+        self.tree.flush()?;
+        let keys: Vec<String> = keys.iter().map(|key| utf8(key)).collect::<Result<_>>()?;
+        let changes: Vec<(&str, Option<&str>)> =
+            keys.iter().map(|key| (key.as_str(), None)).collect();
+        self.publish(log, &changes)?;
+        Ok(keys.len())
+    }
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        self.scan_limit(range, usize::MAX)
+    }
+    fn scan_limit<R: RangeBounds<String>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for pair in self.tree.range(byte_range(&range)).take(limit) {
+            let (key, value) = pair?;
+            pairs.push((utf8(&key)?, utf8(&value)?));
+        }
+        Ok(pairs)
     }
     fn set(&self, key: String, value: String) -> Result<()> {
-        self.db.insert(key.as_str(), value.as_str())?;
-        self.db.flush()?;
+        let log = self.lock_changes();
+        self.filter.insert(&self.tree, &key, || {
+            self.tree.insert(key.as_str(), value.as_str())?;
+            Ok(())
+        })?;
+        self.tree.flush()?;
+        self.publish(log, &[(&key, Some(&value))])
+    }
+    fn merge(&self, key: String, operator: &str, operand: String) -> Result<String> {
+        let log = self.lock_changes();
+        let value = self.filter.insert(&self.tree, &key, || {
+            // not `Tree::merge`, as a tree has one merge operator while
+            // requests name theirs, and sled's operators cannot fail while
+            // ours keep the existing value and report why. sled may call the
+            // update again if the value changed meanwhile
+            let mut error = None;
+            let value = self.tree.update_and_fetch(key.as_str(), |old| {
+                let merged = old.map(utf8).transpose().and_then(|existing| {
+                    self.options.merge_operators.merge(
+                        operator,
+                        &key,
+                        existing.as_deref(),
+                        &operand,
+                    )
+                });
+                match merged {
+                    Ok(value) => {
+                        error = None;
+                        Some(value.into_bytes())
+                    }
+                    Err(e) => {
+                        error = Some(e);
+                        old.map(<[u8]>::to_vec)
+                    }
+                }
+            })?;
+            match error {
+                Some(e) => Err(e),
+                None => Ok(value),
+            }
+        })?;
+        self.tree.flush()?;
+        let value = value.as_deref().map(utf8).transpose()?.unwrap_or_default();
+        self.publish(log, &[(&key, Some(&value))])?;
+        Ok(value)
+    }
+    fn create_namespace(&self, name: &str) -> Result<()> {
+        self.open_namespace(name, true).map(|_| ())
+    }
+    fn namespace(&self, name: &str) -> Result<Self> {
+        self.open_namespace(name, false)
+    }
+    fn drop_namespace(&self, name: &str) -> Result<()> {
+        namespace::validate(name)?;
+        let registry = self.namespaces.registry()?;
+        let mut handles = registry.handles.lock().unwrap();
+        if let Some(store) = handles.remove(name) {
+            store.filter.dropped.store(true, Ordering::SeqCst);
+        }
+        if !self.db.drop_tree(format!("{}{}", NAMESPACE_PREFIX, name))? {
+            return Err(KvsError::NamespaceNotFound(name.to_owned()));
+        }
+        for file in [FILTER_FILE, CHANGES_FILE] {
+            match fs::remove_file(sidecar_path(&registry.dir, file, Some(name))) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
         Ok(())
     }
+    fn watch<R: RangeBounds<String>>(&self, range: R) -> Result<Receiver<Change>> {
+        Ok(self.watchers.subscribe(range))
+    }
+    fn changes(&self, since: u64) -> Result<Vec<Change>> {
+        match &self.changes {
+            Some(changes) => changes.lock().unwrap().since(since),
+            None => Err(KvsError::UnsupportedOperation),
+        }
+    }
+    fn namespaces(&self) -> Result<Vec<String>> {
+        let mut names: Vec<String> = self
+            .db
+            .tree_names()
+            .iter()
+            .filter_map(|name| name.strip_prefix(NAMESPACE_PREFIX.as_bytes()))
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect();
+        names.sort_unstable();
+        Ok(names)
+    }
+    fn stats(&self) -> Result<Stats> {
+        let mut stats = Stats::new();
+        stats.insert(
+            "size_on_disk".to_owned(),
+            self.db.size_on_disk()?.to_string(),
+        );
+        let state = self.filter.state.read().unwrap();
+        stats.insert("bloom_capacity".to_owned(), state.capacity.to_string());
+        stats.insert("bloom_inserted".to_owned(), state.inserted.to_string());
+        drop(state);
+        self.filter
+            .counters
+            .report(&mut stats, self.filter.false_positive_rate);
+        Ok(stats)
+    }
+}
+
+fn sidecar_path(dir: &Path, file: &str, name: Option<&str>) -> PathBuf {
+    match name {
+        Some(name) => dir.join(format!("{}.{}", file, name)),
+        None => dir.join(file),
+    }
+}
+
+/// Opens the sled database in `path`. Background writes of a handle that was
+/// just dropped may hold the file lock for a moment, so a held lock, which
+/// sled reports as an I/O error of kind `Other`, is retried for a while.
+fn open_db(path: &Path) -> Result<Db> {
+    let mut retries = 0;
+    loop {
+        match sled::open(path) {
+            Err(sled::Error::Io(e))
+                if retries < LOCK_RETRIES && e.kind() == io::ErrorKind::Other =>
+            {
+                retries += 1;
+                thread::sleep(LOCK_RETRY_DELAY);
+            }
+            rv => return Ok(rv?),
+        }
+    }
+}
+
+fn utf8(bytes: &[u8]) -> Result<String> {
+    String::from_utf8(bytes.to_vec())
+        .map_err(|_| KvsError::Corrupted("key or value is not UTF-8".to_owned()))
+}
+
+fn byte_range<R: RangeBounds<String>>(range: &R) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let start = range.start_bound().map(|key| key.as_bytes().to_vec());
+    let end = range.end_bound().map(|key| key.as_bytes().to_vec());
+    (start, end)
 }
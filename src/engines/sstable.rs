@@ -0,0 +1,446 @@
+//! Immutable sorted string tables, shared by the LSM engine and the spilled
+//! key directory of `KvStore`.
+//!
+//! Layout of a table file:
+//!
+//! ```text
+//! | data block | ... | data block | index block | bloom filter | footer |
+//! ```
+//!
+//! A data block is a run of entries, each encoded as
+//! `key_len: u32 | key | tag: u8 | value_len: u32 | value`, where a tag of 0
+//! marks a tombstone. The index block starts with the smallest key of the
+//! table followed by one `last_key` / `offset` / `len` handle per data block.
+//! The footer is five little-endian `u64`s: index offset and length, bloom
+//! offset and length, and `MAGIC`.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::engines::bloom::{self, BloomFilter};
+use crate::{KvsError, Result};
+
+const MAGIC: u64 = 0x6b76_735f_7373_7401;
+const FOOTER_LEN: u64 = 40;
+
+/// A key and its value, `None` being a tombstone.
+pub type Entry = (String, Option<String>);
+
+#[derive(Debug)]
+struct BlockHandle {
+    last_key: String,
+    offset: u64,
+    len: u64,
+}
+
+/// Outcome of looking a key up in one table.
+#[derive(Debug)]
+pub enum Lookup {
+    /// The table holds an entry for the key, `None` being a tombstone.
+    Found(Option<String>),
+    /// The key is outside the key range of the table.
+    OutOfRange,
+    /// The bloom filter ruled the key out.
+    Filtered,
+    /// The bloom filter let the key through but the table has no entry.
+    Missing,
+}
+
+/// An open table. Index and bloom filter are kept in memory, data blocks are
+/// read on demand.
+#[derive(Debug)]
+pub struct SsTable {
+    pub id: u64,
+    pub smallest: String,
+    pub largest: String,
+    pub size: u64,
+    path: PathBuf,
+    file: Mutex<File>,
+    index: Vec<BlockHandle>,
+    bloom: BloomFilter,
+}
+
+impl SsTable {
+    pub fn open(path: impl Into<PathBuf>, id: u64) -> Result<SsTable> {
+        let path = path.into();
+        let mut file = File::open(&path)?;
+        let size = file.seek(SeekFrom::End(0))?;
+        if size < FOOTER_LEN {
+            return Err(corrupted(&path));
+        }
+        let footer = read_at(&mut file, size - FOOTER_LEN, FOOTER_LEN)?;
+        let mut footer = footer.as_slice();
+        let index_offset = get_u64(&mut footer).ok_or_else(|| corrupted(&path))?;
+        let index_len = get_u64(&mut footer).ok_or_else(|| corrupted(&path))?;
+        let bloom_offset = get_u64(&mut footer).ok_or_else(|| corrupted(&path))?;
+        let bloom_len = get_u64(&mut footer).ok_or_else(|| corrupted(&path))?;
+        if get_u64(&mut footer) != Some(MAGIC) {
+            return Err(corrupted(&path));
+        }
+        // what the file claims is only read within its body
+        let body = size - FOOTER_LEN;
+        let within = |offset: u64, len: u64| offset.checked_add(len).is_some_and(|end| end <= body);
+        if !within(index_offset, index_len) || !within(bloom_offset, bloom_len) {
+            return Err(corrupted(&path));
+        }
+
+        let buf = read_at(&mut file, index_offset, index_len)?;
+        let mut buf = buf.as_slice();
+        let smallest = get_string(&mut buf).ok_or_else(|| corrupted(&path))?;
+        let mut index = Vec::new();
+        while !buf.is_empty() {
+            let handle = BlockHandle {
+                last_key: get_string(&mut buf).ok_or_else(|| corrupted(&path))?,
+                offset: get_u64(&mut buf).ok_or_else(|| corrupted(&path))?,
+                len: get_u64(&mut buf).ok_or_else(|| corrupted(&path))?,
+            };
+            if !within(handle.offset, handle.len) {
+                return Err(corrupted(&path));
+            }
+            index.push(handle);
+        }
+        let largest = index
+            .last()
+            .map(|handle| handle.last_key.clone())
+            .ok_or_else(|| corrupted(&path))?;
+        let bloom = BloomFilter::from_bytes(&read_at(&mut file, bloom_offset, bloom_len)?)
+            .ok_or_else(|| corrupted(&path))?;
+
+        Ok(SsTable {
+            id,
+            smallest,
+            largest,
+            size,
+            path,
+            file: Mutex::new(file),
+            index,
+            bloom,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get(&self, key: &str) -> Result<Lookup> {
+        if key < self.smallest.as_str() || key > self.largest.as_str() {
+            return Ok(Lookup::OutOfRange);
+        }
+        if !self.bloom.may_contain(key) {
+            return Ok(Lookup::Filtered);
+        }
+        let block = self
+            .index
+            .partition_point(|handle| handle.last_key.as_str() < key);
+        let entries = self.read_block(block)?;
+        Ok(entries
+            .binary_search_by(|(k, _)| k.as_str().cmp(key))
+            .map_or(Lookup::Missing, |i| Lookup::Found(entries[i].1.clone())))
+    }
+
+    pub fn overlaps(&self, smallest: &str, largest: &str) -> bool {
+        self.smallest.as_str() <= largest && self.largest.as_str() >= smallest
+    }
+
+    /// Returns the entries whose key falls in `range`, tombstones included.
+    pub fn scan<R: RangeBounds<String>>(&self, range: &R) -> Result<Vec<Entry>> {
+        let first = match range.start_bound() {
+            Bound::Included(start) | Bound::Excluded(start) => self
+                .index
+                .partition_point(|handle| &handle.last_key < start),
+            Bound::Unbounded => 0,
+        };
+        let mut entries = Vec::new();
+        for block in first..self.index.len() {
+            for (key, value) in self.read_block(block)? {
+                if range.contains(&key) {
+                    entries.push((key, value));
+                } else if !before_start(range, &key) {
+                    return Ok(entries);
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Streams the entries of the table in key order, one block at a time.
+    pub fn iter(&self) -> TableIter<'_> {
+        TableIter {
+            table: self,
+            block: 0,
+            entries: Vec::new().into_iter(),
+        }
+    }
+
+    /// Bytes of memory held for the block index and bloom filter.
+    pub fn memory_usage(&self) -> usize {
+        let index: usize = self
+            .index
+            .iter()
+            .map(|handle| handle.last_key.len() + std::mem::size_of::<BlockHandle>())
+            .sum();
+        index + self.smallest.len() + self.bloom.memory_usage()
+    }
+
+    fn read_block(&self, block: usize) -> Result<Vec<Entry>> {
+        let handle = match self.index.get(block) {
+            Some(handle) => handle,
+            None => return Ok(Vec::new()),
+        };
+        let buf = read_at(&mut self.file.lock().unwrap(), handle.offset, handle.len)?;
+        let mut buf = buf.as_slice();
+        let mut entries = Vec::new();
+        while !buf.is_empty() {
+            entries.push(get_entry(&mut buf).ok_or_else(|| corrupted(&self.path))?);
+        }
+        Ok(entries)
+    }
+}
+
+pub struct TableIter<'a> {
+    table: &'a SsTable,
+    block: usize,
+    entries: std::vec::IntoIter<Entry>,
+}
+
+impl Iterator for TableIter<'_> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(Ok(entry));
+            }
+            if self.block >= self.table.index.len() {
+                return None;
+            }
+            match self.table.read_block(self.block) {
+                Ok(entries) => self.entries = entries.into_iter(),
+                Err(e) => return Some(Err(e)),
+            }
+            self.block += 1;
+        }
+    }
+}
+
+/// Merges sorted entry streams into one, yielding each key once. When several
+/// sources hold the same key, the entry of the earliest source wins.
+pub struct MergeIter<V, I: Iterator<Item = Result<(String, V)>>> {
+    sources: Vec<std::iter::Peekable<I>>,
+}
+
+impl<V, I: Iterator<Item = Result<(String, V)>>> MergeIter<V, I> {
+    pub fn new(sources: impl IntoIterator<Item = I>) -> Self {
+        MergeIter {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+        }
+    }
+}
+
+impl<V, I: Iterator<Item = Result<(String, V)>>> Iterator for MergeIter<V, I> {
+    type Item = Result<(String, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut winner: Option<(usize, String)> = None;
+        for i in 0..self.sources.len() {
+            match self.sources[i].peek() {
+                None => {}
+                // surface errors as soon as they are seen
+                Some(Err(_)) => return self.sources[i].next(),
+                Some(Ok((key, _))) if winner.as_ref().is_none_or(|(_, best)| key < best) => {
+                    winner = Some((i, key.clone()));
+                }
+                Some(Ok(_)) => {}
+            }
+        }
+        let (key, value) = match self.sources[winner?.0].next()? {
+            Ok(entry) => entry,
+            Err(e) => return Some(Err(e)),
+        };
+        for source in &mut self.sources {
+            while let Some(Ok((other, _))) = source.peek() {
+                if *other != key {
+                    break;
+                }
+                source.next();
+            }
+        }
+        Some(Ok((key, value)))
+    }
+}
+
+/// Writes entries, which must be added in strictly increasing key order,
+/// into a new table file.
+pub struct SsTableBuilder {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    block_size: usize,
+    bits_per_key: usize,
+    block: Vec<u8>,
+    offset: u64,
+    smallest: Option<String>,
+    last_key: String,
+    index: Vec<BlockHandle>,
+    hashes: Vec<u64>,
+}
+
+impl SsTableBuilder {
+    pub fn new(path: impl Into<PathBuf>, block_size: usize, bits_per_key: usize) -> Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        Ok(SsTableBuilder {
+            path,
+            writer: BufWriter::new(file),
+            block_size,
+            bits_per_key,
+            block: Vec::new(),
+            offset: 0,
+            smallest: None,
+            last_key: String::new(),
+            index: Vec::new(),
+            hashes: Vec::new(),
+        })
+    }
+
+    pub fn add(&mut self, key: &str, value: Option<&str>) -> Result<()> {
+        if self.smallest.is_none() {
+            self.smallest = Some(key.to_owned());
+        }
+        put_entry(&mut self.block, key, value);
+        self.hashes.push(bloom::hash(key));
+        self.last_key.clear();
+        self.last_key.push_str(key);
+        if self.block.len() >= self.block_size {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// Bytes written so far, used to cut compaction output into tables.
+    pub fn estimated_size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.smallest.is_none()
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        self.writer.write_all(&self.block)?;
+        self.index.push(BlockHandle {
+            last_key: self.last_key.clone(),
+            offset: self.offset,
+            len: self.block.len() as u64,
+        });
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Writes index, bloom filter and footer, syncs the file and opens it.
+    pub fn finish(mut self, id: u64) -> Result<SsTable> {
+        self.finish_block()?;
+        let mut index = Vec::new();
+        put_string(&mut index, self.smallest.as_deref().unwrap_or_default());
+        for handle in &self.index {
+            put_string(&mut index, &handle.last_key);
+            index.extend_from_slice(&handle.offset.to_le_bytes());
+            index.extend_from_slice(&handle.len.to_le_bytes());
+        }
+        let bloom = BloomFilter::from_hashes(&self.hashes, self.bits_per_key).to_bytes();
+
+        let index_offset = self.offset;
+        let bloom_offset = index_offset + index.len() as u64;
+        self.writer.write_all(&index)?;
+        self.writer.write_all(&bloom)?;
+        for word in [
+            index_offset,
+            index.len() as u64,
+            bloom_offset,
+            bloom.len() as u64,
+            MAGIC,
+        ] {
+            self.writer.write_all(&word.to_le_bytes())?;
+        }
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        SsTable::open(self.path, id)
+    }
+}
+
+fn before_start<R: RangeBounds<String>>(range: &R, key: &String) -> bool {
+    match range.start_bound() {
+        Bound::Included(start) => key < start,
+        Bound::Excluded(start) => key <= start,
+        Bound::Unbounded => false,
+    }
+}
+
+fn corrupted(path: &Path) -> KvsError {
+    KvsError::Corrupted(path.display().to_string())
+}
+
+fn read_at(file: &mut File, offset: u64, len: u64) -> Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buf = vec![0; len as usize];
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn put_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn put_entry(buf: &mut Vec<u8>, key: &str, value: Option<&str>) {
+    put_string(buf, key);
+    match value {
+        Some(value) => {
+            buf.push(1);
+            put_string(buf, value);
+        }
+        None => buf.push(0),
+    }
+}
+
+fn get_u32(buf: &mut &[u8]) -> Option<u32> {
+    let (word, rest) = buf.split_first_chunk::<4>()?;
+    *buf = rest;
+    Some(u32::from_le_bytes(*word))
+}
+
+fn get_u64(buf: &mut &[u8]) -> Option<u64> {
+    let (word, rest) = buf.split_first_chunk::<8>()?;
+    *buf = rest;
+    Some(u64::from_le_bytes(*word))
+}
+
+fn get_string(buf: &mut &[u8]) -> Option<String> {
+    let len = get_u32(buf)? as usize;
+    if buf.len() < len {
+        return None;
+    }
+    let (s, rest) = buf.split_at(len);
+    *buf = rest;
+    String::from_utf8(s.to_vec()).ok()
+}
+
+fn get_entry(buf: &mut &[u8]) -> Option<Entry> {
+    let key = get_string(buf)?;
+    let (tag, rest) = buf.split_first()?;
+    *buf = rest;
+    match tag {
+        0 => Some((key, None)),
+        1 => Some((key, Some(get_string(buf)?))),
+        _ => None,
+    }
+}
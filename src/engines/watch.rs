@@ -0,0 +1,53 @@
+use std::ops::{Bound, RangeBounds};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+
+use crate::engines::Change;
+
+/// Changes a watcher may fall behind by before it is dropped.
+const WATCH_BUFFER: usize = 1024;
+
+/// Watchers of the keys of one store or namespace.
+#[derive(Debug, Default)]
+pub struct Watchers {
+    watchers: Mutex<Vec<Watcher>>,
+}
+
+#[derive(Debug)]
+struct Watcher {
+    range: (Bound<String>, Bound<String>),
+    sender: SyncSender<Change>,
+}
+
+impl Watchers {
+    /// Returns the receiving end of the changes to keys in `range`.
+    pub fn subscribe<R: RangeBounds<String>>(&self, range: R) -> Receiver<Change> {
+        let (sender, receiver) = mpsc::sync_channel(WATCH_BUFFER);
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        self.watchers
+            .lock()
+            .unwrap()
+            .push(Watcher { range, sender });
+        receiver
+    }
+
+    /// Sends the change to every watcher of `key`. Watchers that hung up or
+    /// fell too far behind are dropped, which disconnects their receiver.
+    pub fn notify(&self, seq: u64, key: &str, value: Option<&str>) {
+        let mut watchers = self.watchers.lock().unwrap();
+        watchers.retain(|watcher| {
+            if !watcher.range.contains(&key.to_owned()) {
+                return true;
+            }
+            let change = Change {
+                seq,
+                key: key.to_owned(),
+                value: value.map(str::to_owned),
+            };
+            match watcher.sender.try_send(change) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_) | TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
@@ -1,5 +1,3 @@
-use serde_json;
-use sled;
 use std::io;
 use thiserror::Error;
 
@@ -18,6 +16,63 @@ pub enum KvsError {
     /// unexpected command type error
     #[error("Unsupported operation")]
     UnsupportedOperation,
+    /// data file failed validation
+    #[error("corrupted data file: {0}")]
+    Corrupted(String),
+    /// no merge operator registered under the name
+    #[error("unknown merge operator: {0}")]
+    UnknownMergeOperator(String),
+    /// merge operator rejected its operand or the existing value
+    #[error("merge failed: {0}")]
+    Merge(String),
+    /// counter merged past the range of an `i64`
+    #[error("merge failed: integer overflow")]
+    MergeOverflow,
+    /// namespace does not exist
+    #[error("namespace not found: {0}")]
+    NamespaceNotFound(String),
+    /// name not allowed for a namespace
+    #[error("invalid namespace name: {0:?}")]
+    InvalidNamespace(String),
+    /// changes asked for were already dropped from the change log
+    #[error("changes before sequence number {0} are no longer retained")]
+    ChangesTruncated(u64),
+    /// write sent to a replica
+    #[error("read-only replica of {0}")]
+    ReadOnlyReplica(std::net::SocketAddr),
+    /// primary sent something a replica cannot follow
+    #[error("replication failed: {0}")]
+    Replication(String),
+    /// write sent to a cluster node that does not lead, with the leader
+    /// if known
+    #[error("not the leader, try {}", .0.as_deref().unwrap_or("again later"))]
+    NotLeader(Option<String>),
+    /// cluster could not take a write or membership change
+    #[error("raft error: {0}")]
+    Raft(String),
+    /// server a request was sharded to failed or could not be reached
+    #[error("backend {0}: {1}")]
+    Backend(String, String),
+    /// client speaks a version of the protocol the server does not
+    #[error("protocol version {0} is not supported")]
+    UnsupportedVersion(u32),
+    /// message is not a request of the protocol
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+    /// connection is not authenticated, or its user may not make the
+    /// request
+    #[error("unauthorized: {0}")]
+    Unauthorized(String),
+    /// bytes received are not a binary frame, or its payload not the
+    /// message expected
+    #[error("invalid frame: {0}")]
+    Frame(String),
+    /// certificates or keys could not be loaded, or a TLS session failed
+    #[error("tls error: {0}")]
+    Tls(String),
+    /// server failed a request for a reason of its own, with its message
+    #[error("server error: {0}")]
+    Server(String),
     #[error("sled error")]
     Sled(#[from] sled::Error),
     #[error("rayon thread pool build error")]
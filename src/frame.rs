@@ -0,0 +1,165 @@
+//! Length-prefixed binary frames, which connections switch to from JSON once
+//! the handshake agrees on `Capability::BinaryFrames`.
+//!
+//! A frame is the length of the rest of it as a big-endian `u32`, the ID of
+//! a request as a big-endian `u64`, then a MessagePack payload. Responses
+//! carry the ID of their request, those streamed for a watch the ID of the
+//! watch.
+use std::io::{self, Read, Write};
+
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::Serialize;
+
+use crate::{Envelope, KvsError, Request, Result, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+
+/// Bytes of the length prefix.
+const LEN_SIZE: usize = 4;
+/// Bytes of the request ID.
+const ID_SIZE: usize = 8;
+/// Largest frame accepted, not counting its length prefix. Larger lengths
+/// are taken for corruption rather than buffered.
+pub const MAX_FRAME_LEN: usize = 64 << 20;
+
+/// A message with the ID of the request it is or answers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub id: u64,
+    /// MessagePack encoding of the message.
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Frame of `message` under `id`.
+    pub fn new<T: Serialize>(id: u64, message: &T) -> Result<Frame> {
+        // named fields, as skipped ones would shift those after them
+        let payload =
+            rmp_serde::to_vec_named(message).map_err(|e| KvsError::Frame(e.to_string()))?;
+        if ID_SIZE + payload.len() > MAX_FRAME_LEN {
+            return Err(KvsError::Frame(format!(
+                "{} bytes is too long",
+                ID_SIZE + payload.len()
+            )));
+        }
+        Ok(Frame { id, payload })
+    }
+
+    /// The message in the payload.
+    pub fn message<T: DeserializeOwned>(&self) -> Result<T> {
+        rmp_serde::from_slice(&self.payload).map_err(|e| KvsError::Frame(e.to_string()))
+    }
+
+    /// The request in the payload, checked like `parse_request` checks JSON
+    /// messages.
+    pub fn request(&self) -> Result<Request> {
+        let invalid = |e: KvsError| KvsError::InvalidRequest(e.to_string());
+        // the version first, as requests of later ones may not read
+        let envelope: Envelope<IgnoredAny> = self.message().map_err(invalid)?;
+        match envelope.version {
+            MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION => {}
+            version => return Err(KvsError::UnsupportedVersion(version)),
+        }
+        let envelope: Envelope = self.message().map_err(invalid)?;
+        Ok(envelope.request)
+    }
+
+    /// The frame as sent over the wire.
+    pub fn encode(&self) -> Vec<u8> {
+        let len = (ID_SIZE + self.payload.len()) as u32;
+        let mut bytes = Vec::with_capacity(LEN_SIZE + len as usize);
+        bytes.extend_from_slice(&len.to_be_bytes());
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+}
+
+/// Splits bytes received in arbitrary pieces into frames.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    /// Bytes received past the last frame.
+    buf: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> FrameDecoder {
+        FrameDecoder::default()
+    }
+
+    /// Adds bytes received.
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Takes the next frame, or `None` until all of it has been received.
+    ///
+    /// # Errors
+    ///
+    /// `KvsError::Frame` if the bytes are not a frame. The stream cannot be
+    /// read past them, and the same error is returned from then on.
+    pub fn next_frame(&mut self) -> Result<Option<Frame>> {
+        take_frame(&mut self.buf)
+    }
+
+    /// Whether no part of a frame is buffered.
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+}
+
+/// Takes the first frame off `buf`, or `None` if it is not all there.
+pub(crate) fn take_frame(buf: &mut Vec<u8>) -> Result<Option<Frame>> {
+    let Some(header) = buf.get(..LEN_SIZE) else {
+        return Ok(None);
+    };
+    let len = frame_len(header.try_into().unwrap())?;
+    if buf.len() < LEN_SIZE + len {
+        return Ok(None);
+    }
+    let mut frame: Vec<u8> = buf.drain(..LEN_SIZE + len).skip(LEN_SIZE).collect();
+    let payload = frame.split_off(ID_SIZE);
+    let id = u64::from_be_bytes(frame.try_into().unwrap());
+    Ok(Some(Frame { id, payload }))
+}
+
+/// Length of the frame after `header`, checked.
+fn frame_len(header: [u8; LEN_SIZE]) -> Result<usize> {
+    let len = u32::from_be_bytes(header) as usize;
+    if len < ID_SIZE {
+        return Err(KvsError::Frame(format!("{} bytes is too short", len)));
+    }
+    if len > MAX_FRAME_LEN {
+        return Err(KvsError::Frame(format!("{} bytes is too long", len)));
+    }
+    Ok(len)
+}
+
+/// Reads the next frame, or `None` if the stream ends before one starts.
+pub fn read_frame(reader: &mut impl Read) -> Result<Option<Frame>> {
+    let mut header = [0; LEN_SIZE];
+    let mut read = 0;
+    while read < LEN_SIZE {
+        match reader.read(&mut header[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let len = frame_len(header)?;
+    // grown as the bytes arrive, not to the length a peer claims
+    let mut frame = Vec::new();
+    if reader.take(len as u64).read_to_end(&mut frame)? < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    let payload = frame.split_off(ID_SIZE);
+    let id = u64::from_be_bytes(frame.try_into().unwrap());
+    Ok(Some(Frame { id, payload }))
+}
+
+/// Writes `message` in a frame under `id`, in one write lest Nagle's
+/// algorithm hold back part of it.
+pub fn write_frame(writer: &mut impl Write, id: u64, message: &impl Serialize) -> Result<()> {
+    writer.write_all(&Frame::new(id, message)?.encode())?;
+    Ok(())
+}
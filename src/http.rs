@@ -0,0 +1,520 @@
+//! HTTP/1.1 gateway, for scripts and browsers without a kvs client.
+//!
+//! | Route                         | Answer                                          |
+//! |-------------------------------|-------------------------------------------------|
+//! | `GET /keys/{key}`             | the value as text, 404 if missing               |
+//! | `PUT /keys/{key}`             | sets the value to the body, 204                 |
+//! | `DELETE /keys/{key}`          | 204, 404 if missing                             |
+//! | `GET /keys?start=&end=&limit=`| `{"pairs": [{"key", "value"}], "next"}`, JSON   |
+//! | `GET /keys?prefix=&limit=`    | the same, of the keys starting with the prefix  |
+//! | `POST /batch/get`             | `["k1", "k2"]` to `{"k1": "v1", "k2": null}`     |
+//! | `POST /batch/set`             | `{"k1": "v1"}`, 204                             |
+//! | `POST /batch/delete`          | `["k1", "k2"]` to `{"removed": 1}`              |
+//! | `GET /stats`                  | engine statistics, JSON                         |
+//! | `GET /health`                 | `{"status": "ok"}`                              |
+//!
+//! Routes of keys take an `ns` parameter naming the namespace to use
+//! instead of the default keyspace. Keys are percent-decoded, and listings
+//! run from `start` included to `end` excluded; `next` is where the next
+//! page starts, if `limit` cut this one short. Errors are
+//! `{"error": message}`.
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::ops::Bound;
+
+use log::error;
+use serde_json::{json, Value};
+
+use crate::{prefix_range, KvsEngine, KvsError, Result};
+
+/// Longest request line or header line.
+const MAX_LINE_LEN: usize = 8 << 10;
+/// Most headers of a request.
+const MAX_HEADERS: usize = 100;
+/// Largest body of a request.
+const MAX_BODY_LEN: usize = 64 << 20;
+
+/// An HTTP request, as read off a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequest {
+    pub method: String,
+    /// Path of the target, still percent-encoded.
+    pub path: String,
+    /// Decoded parameters of the query string, in order.
+    pub query: Vec<(String, String)>,
+    /// Headers, their names in lower case.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Whether the client keeps the connection open after the response.
+    pub keep_alive: bool,
+}
+
+impl HttpRequest {
+    /// Value of the header `name`, given in lower case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Value of the query parameter `name`.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// An HTTP response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status: u16,
+    /// Headers besides `Content-Length` and `Connection`.
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn empty(status: u16) -> HttpResponse {
+        HttpResponse {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    fn json(status: u16, body: &Value) -> HttpResponse {
+        HttpResponse {
+            status,
+            headers: vec![("Content-Type", "application/json".to_owned())],
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    fn text(body: String) -> HttpResponse {
+        HttpResponse {
+            status: 200,
+            headers: vec![("Content-Type", "text/plain; charset=utf-8".to_owned())],
+            body: body.into_bytes(),
+        }
+    }
+
+    fn error(status: u16, message: &str) -> HttpResponse {
+        HttpResponse::json(status, &json!({ "error": message }))
+    }
+
+    /// Appends the response as sent over the wire to `out`, without its
+    /// body for a `HEAD` request.
+    pub fn encode(&self, keep_alive: bool, head: bool, out: &mut Vec<u8>) {
+        let mut header = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for (name, value) in &self.headers {
+            header.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        header.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        if !keep_alive {
+            header.push_str("Connection: close\r\n");
+        }
+        header.push_str("\r\n");
+        out.extend_from_slice(header.as_bytes());
+        if !head {
+            out.extend_from_slice(&self.body);
+        }
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        _ => "",
+    }
+}
+
+/// Reads the next request, or `None` if the stream ends before one starts.
+/// Bodies are read by `Content-Length` or in chunks.
+///
+/// # Errors
+///
+/// `KvsError::InvalidRequest` if the bytes are not a request. The stream
+/// cannot be read past them.
+pub fn read_request(reader: &mut impl BufRead) -> Result<Option<HttpRequest>> {
+    // empty lines may come before a request
+    let line = loop {
+        match read_line(reader)? {
+            Some(line) if line.is_empty() => continue,
+            Some(line) => break line,
+            None => return Ok(None),
+        }
+    };
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(bad_request("invalid request line"));
+    };
+    let minor = match version {
+        "HTTP/1.1" => 1,
+        "HTTP/1.0" => 0,
+        _ => return Err(bad_request("unsupported HTTP version")),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    if !path.starts_with('/') {
+        return Err(bad_request("invalid request target"));
+    }
+    let query = query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            Ok((decode(name, true)?, decode(value, true)?))
+        })
+        .collect::<Result<_>>()?;
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader)?.ok_or_else(|| bad_request("unexpected end"))?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(bad_request("too many headers"));
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| bad_request("invalid header"))?;
+        headers.push((name.trim().to_ascii_lowercase(), value.trim().to_owned()));
+    }
+    let mut request = HttpRequest {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        query,
+        headers,
+        body: Vec::new(),
+        keep_alive: false,
+    };
+    let connection = request.header("connection").map(str::to_ascii_lowercase);
+    request.keep_alive = match minor {
+        1 => connection.as_deref() != Some("close"),
+        _ => connection.as_deref() == Some("keep-alive"),
+    };
+    request.body = match (
+        request.header("transfer-encoding"),
+        request.header("content-length"),
+    ) {
+        (Some(coding), _) if coding.eq_ignore_ascii_case("chunked") => read_chunked(reader)?,
+        (Some(_), _) => return Err(bad_request("unsupported transfer encoding")),
+        (None, Some(len)) => {
+            let len: usize = len
+                .parse()
+                .map_err(|_| bad_request("invalid content length"))?;
+            if len > MAX_BODY_LEN {
+                return Err(bad_request("body too large"));
+            }
+            // grown as the bytes arrive, not to the length a client claims
+            let mut body = Vec::new();
+            if reader.take(len as u64).read_to_end(&mut body)? < len {
+                return Err(bad_request("unexpected end"));
+            }
+            body
+        }
+        (None, None) => Vec::new(),
+    };
+    Ok(Some(request))
+}
+
+/// Reads a body sent in chunks, and the trailers after it.
+fn read_chunked(reader: &mut impl BufRead) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(reader)?.ok_or_else(|| bad_request("unexpected end"))?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size =
+            usize::from_str_radix(size, 16).map_err(|_| bad_request("invalid chunk size"))?;
+        if size == 0 {
+            break;
+        }
+        if size > MAX_BODY_LEN - body.len() {
+            return Err(bad_request("body too large"));
+        }
+        let start = body.len();
+        if reader.take(size as u64 + 2).read_to_end(&mut body)? < size + 2 {
+            return Err(bad_request("unexpected end"));
+        }
+        if !body.ends_with(b"\r\n") {
+            return Err(bad_request("chunk not followed by CRLF"));
+        }
+        body.truncate(start + size);
+    }
+    // trailers, up to the empty line
+    while !read_line(reader)?
+        .ok_or_else(|| bad_request("unexpected end"))?
+        .is_empty()
+    {}
+    Ok(body)
+}
+
+/// Reads a line without its line break, or `None` at the end of the stream.
+fn read_line(reader: &mut impl BufRead) -> Result<Option<String>> {
+    let mut line = Vec::new();
+    reader
+        .take(MAX_LINE_LEN as u64 + 1)
+        .read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(match line.len() < MAX_LINE_LEN {
+            true => bad_request("unexpected end"),
+            false => bad_request("line too long"),
+        });
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| bad_request("line is not UTF-8"))
+}
+
+/// Percent-decodes `s`, and `+` into a space in query strings.
+fn decode(s: &str, query: bool) -> Result<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&c, tail)) = rest.split_first() {
+        rest = tail;
+        match c {
+            b'%' => {
+                let hex = rest
+                    .get(..2)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| bad_request("invalid percent-encoding"))?;
+                bytes.push(hex);
+                rest = &rest[2..];
+            }
+            b'+' if query => bytes.push(b' '),
+            c => bytes.push(c),
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| bad_request("percent-encoded bytes are not UTF-8"))
+}
+
+fn bad_request(message: &str) -> KvsError {
+    KvsError::InvalidRequest(message.to_owned())
+}
+
+/// Serves an engine over HTTP.
+#[derive(Clone)]
+pub struct HttpServer<E: KvsEngine> {
+    engine: E,
+}
+
+impl<E: KvsEngine> HttpServer<E> {
+    pub fn new(engine: E) -> HttpServer<E> {
+        HttpServer { engine }
+    }
+
+    /// Answers the requests of `stream` until either side closes it.
+    pub fn serve(&self, stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(&stream);
+        let mut writer = BufWriter::new(&stream);
+        loop {
+            let (response, keep_alive, head) = match read_request(&mut reader) {
+                Ok(Some(request)) => (
+                    self.handle(&request),
+                    request.keep_alive,
+                    request.method == "HEAD",
+                ),
+                Ok(None) => return Ok(()),
+                Err(KvsError::InvalidRequest(message)) => {
+                    (HttpResponse::error(400, &message), false, false)
+                }
+                Err(e) => return Err(e),
+            };
+            let mut out = Vec::new();
+            response.encode(keep_alive, head, &mut out);
+            writer.write_all(&out)?;
+            writer.flush()?;
+            if !keep_alive {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Routes `request` and returns its response.
+    pub fn handle(&self, request: &HttpRequest) -> HttpResponse {
+        let method = match request.method.as_str() {
+            "HEAD" => "GET",
+            method => method,
+        };
+        let path = request.path.as_str();
+        let (allowed, response) = match path {
+            "/health" => (
+                "GET",
+                (method == "GET").then(|| Ok(HttpResponse::json(200, &json!({ "status": "ok" })))),
+            ),
+            "/stats" => ("GET", (method == "GET").then(|| self.stats())),
+            "/keys" | "/keys/" => ("GET", (method == "GET").then(|| self.list(request))),
+            "/batch/get" => ("POST", (method == "POST").then(|| self.batch_get(request))),
+            "/batch/set" => ("POST", (method == "POST").then(|| self.batch_set(request))),
+            "/batch/delete" => (
+                "POST",
+                (method == "POST").then(|| self.batch_delete(request)),
+            ),
+            _ => match path.strip_prefix("/keys/") {
+                Some(key) => {
+                    let response = match method {
+                        "GET" | "PUT" | "DELETE" => {
+                            Some(decode(key, false).and_then(|key| self.key(method, &key, request)))
+                        }
+                        _ => None,
+                    };
+                    ("GET, PUT, DELETE", response)
+                }
+                None => return HttpResponse::error(404, "no such route"),
+            },
+        };
+        match response {
+            Some(Ok(response)) => response,
+            Some(Err(e)) => error_response(e),
+            None => {
+                let mut response = HttpResponse::error(405, "method not allowed");
+                response.headers.push(("Allow", allowed.to_owned()));
+                response
+            }
+        }
+    }
+
+    /// The engine, or its namespace named by the `ns` parameter.
+    fn keyspace(&self, request: &HttpRequest) -> Result<E> {
+        match request.param("ns") {
+            Some(name) => self.engine.namespace(name),
+            None => Ok(self.engine.clone()),
+        }
+    }
+
+    fn key(&self, method: &str, key: &str, request: &HttpRequest) -> Result<HttpResponse> {
+        let engine = self.keyspace(request)?;
+        match method {
+            "GET" => engine
+                .get(key.to_owned())?
+                .map(HttpResponse::text)
+                .ok_or(KvsError::KeyNotFound),
+            "PUT" => {
+                let value = String::from_utf8(request.body.clone())
+                    .map_err(|_| bad_request("value is not UTF-8"))?;
+                engine.set(key.to_owned(), value)?;
+                Ok(HttpResponse::empty(204))
+            }
+            _ => {
+                engine.remove(key.to_owned())?;
+                Ok(HttpResponse::empty(204))
+            }
+        }
+    }
+
+    fn list(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        let engine = self.keyspace(request)?;
+        let range = match (
+            request.param("prefix"),
+            request.param("start"),
+            request.param("end"),
+        ) {
+            (Some(_), Some(_), _) | (Some(_), _, Some(_)) => {
+                return Err(bad_request("prefix is not given with start or end"))
+            }
+            (Some(prefix), None, None) => prefix_range(prefix),
+            (None, start, end) => (
+                start.map_or(Bound::Unbounded, |start| Bound::Included(start.to_owned())),
+                end.map_or(Bound::Unbounded, |end| Bound::Excluded(end.to_owned())),
+            ),
+        };
+        let limit = match request.param("limit") {
+            Some(limit) => limit
+                .parse()
+                .map_err(|_| bad_request("limit is not a number"))?,
+            None => usize::MAX,
+        };
+        // one past the limit tells where the next page starts
+        let mut pairs = engine.scan_limit(range, limit.saturating_add(1))?;
+        let next = match pairs.len() > limit {
+            true => Some(pairs[limit].0.clone()),
+            false => None,
+        };
+        pairs.truncate(limit);
+        let pairs: Vec<Value> = pairs
+            .into_iter()
+            .map(|(key, value)| json!({ "key": key, "value": value }))
+            .collect();
+        Ok(HttpResponse::json(
+            200,
+            &json!({ "pairs": pairs, "next": next }),
+        ))
+    }
+
+    fn batch_get(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        let engine = self.keyspace(request)?;
+        let keys: Vec<String> = body(request)?;
+        let values = keys
+            .into_iter()
+            .map(|key| Ok((key.clone(), engine.get(key)?)))
+            .collect::<Result<BTreeMap<_, _>>>()?;
+        Ok(HttpResponse::json(200, &json!(values)))
+    }
+
+    fn batch_set(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        let engine = self.keyspace(request)?;
+        let pairs: BTreeMap<String, String> = body(request)?;
+        for (key, value) in pairs {
+            engine.set(key, value)?;
+        }
+        Ok(HttpResponse::empty(204))
+    }
+
+    fn batch_delete(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        let engine = self.keyspace(request)?;
+        let keys: Vec<String> = body(request)?;
+        let mut removed = 0;
+        for key in keys {
+            match engine.remove(key) {
+                Ok(()) => removed += 1,
+                Err(KvsError::KeyNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(HttpResponse::json(200, &json!({ "removed": removed })))
+    }
+
+    fn stats(&self) -> Result<HttpResponse> {
+        Ok(HttpResponse::json(200, &json!(self.engine.stats()?)))
+    }
+}
+
+/// The JSON body of `request`.
+fn body<T: serde::de::DeserializeOwned>(request: &HttpRequest) -> Result<T> {
+    serde_json::from_slice(&request.body).map_err(|e| bad_request(&e.to_string()))
+}
+
+fn error_response(e: KvsError) -> HttpResponse {
+    let status = match e {
+        KvsError::KeyNotFound | KvsError::NamespaceNotFound(_) => 404,
+        KvsError::InvalidRequest(_) | KvsError::InvalidNamespace(_) => 400,
+        KvsError::UnsupportedOperation => 501,
+        _ => 500,
+    };
+    if status == 500 {
+        error!("{}: {:?}", e, e);
+    }
+    match e {
+        KvsError::InvalidRequest(message) => HttpResponse::error(status, &message),
+        e => HttpResponse::error(status, &e.to_string()),
+    }
+}
@@ -1,10 +1,36 @@
 //! A simple key/value store.
-pub use common::{Request, Response, DEFAULT_IP_ADDR};
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+#[cfg(feature = "async")]
+pub use asynchronous::{AsyncKvsClient, AsyncKvsEngine};
+pub use auth::Credentials;
+pub use client::{KvsClient, KvsClientOptions};
+pub use common::{
+    parse_request, Capability, Envelope, ErrorCode, ReplicationEvent, Reply, Request, Response,
+    Welcome, CAPABILITIES, DEFAULT_IP_ADDR, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+pub use engines::{
+    merge, prefix_range, CachePolicy, CachedEngine, Change, KvStore, KvStoreOptions, KvsEngine,
+    LsmKvsEngine, LsmOptions, MergeOperator, MergeOperators, Retention, SledKvsEngine, SledOptions,
+    Stats, Version,
+};
 pub use error::{KvsError, Result};
+pub use frame::{read_frame, write_frame, Frame, FrameDecoder, MAX_FRAME_LEN};
+pub use replica::Replica;
+pub use ring::HashRing;
 pub use thread_pool::{NaiveThreadPool, SharedQueueThreadPool, ThreadPool};
+pub use tls::{Stream, TlsClientConfig, TlsServerConfig, TlsStream};
 
+#[cfg(feature = "async")]
+pub mod asynchronous;
+pub mod auth;
+mod client;
 mod common;
 mod engines;
 mod error;
+mod frame;
+pub mod http;
+pub mod raft;
+mod replica;
+pub mod resp;
+mod ring;
 pub mod thread_pool;
+mod tls;
@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, error};
+
+use super::{Command, Message, NodeId, Raft, Role};
+use crate::{Credentials, Envelope, KvsEngine, KvsError, Request, Result, Stats};
+
+/// Time between ticks of the node.
+const TICK: Duration = Duration::from_millis(50);
+/// How long a write waits to be committed, or a read for the leadership of
+/// the node to be confirmed, before giving up.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(200);
+/// Messages queued for a peer, past which they are dropped. Raft recovers
+/// lost messages by itself.
+const PEER_BUFFER: usize = 256;
+
+/// Runs a `Raft` node with a `kvs-server`: ticks it, sends its messages to
+/// the other servers as `Request::Raft`, and waits for writes to commit.
+#[derive(Debug)]
+pub struct Cluster<E: KvsEngine> {
+    node: Arc<Mutex<Node<E>>>,
+}
+
+#[derive(Debug)]
+struct Node<E: KvsEngine> {
+    raft: Raft<E>,
+    /// Writes waiting to be applied, by log index, with the term they were
+    /// proposed in.
+    waiters: HashMap<u64, (u64, SyncSender<Result<String>>)>,
+    /// Reads waiting to be confirmed, by read id.
+    reads: HashMap<u64, SyncSender<Result<()>>>,
+    peers: HashMap<NodeId, SyncSender<Message>>,
+    /// What the connections to peers authenticate with, if anything.
+    credentials: Option<Credentials>,
+}
+
+impl<E: KvsEngine> Clone for Cluster<E> {
+    fn clone(&self) -> Self {
+        Cluster {
+            node: self.node.clone(),
+        }
+    }
+}
+
+impl<E: KvsEngine> Cluster<E> {
+    /// Starts ticking `raft` in the background.
+    pub fn start(raft: Raft<E>) -> Cluster<E> {
+        Self::start_with_credentials(raft, None)
+    }
+
+    /// Starts ticking `raft` in the background, authenticating with
+    /// `credentials` to peers that require it.
+    pub fn start_with_credentials(raft: Raft<E>, credentials: Option<Credentials>) -> Cluster<E> {
+        let node = Node {
+            raft,
+            waiters: HashMap::new(),
+            reads: HashMap::new(),
+            peers: HashMap::new(),
+            credentials,
+        };
+        let cluster = Cluster {
+            node: Arc::new(Mutex::new(node)),
+        };
+        let ticker = cluster.clone();
+        thread::spawn(move || loop {
+            thread::sleep(TICK);
+            let mut node = ticker.node.lock().unwrap();
+            if let Err(e) = node.raft.tick() {
+                error!("raft tick failed: {}", e);
+            }
+            node.flush();
+        });
+        cluster
+    }
+
+    /// Handles a message from another node.
+    pub fn step(&self, from: &str, message: Message) -> Result<()> {
+        let mut node = self.node.lock().unwrap();
+        let rv = node.raft.step(from, message);
+        node.flush();
+        rv
+    }
+
+    /// Commits `command` and returns what applying it returned. Fails with
+    /// `NotLeader` unless this node leads.
+    pub fn write(&self, command: Command) -> Result<String> {
+        self.commit(|raft| raft.propose(command))
+    }
+
+    /// Waits until the engine holds every write committed before the call,
+    /// so that reading it is not stale. Fails with `NotLeader` unless this
+    /// node leads and a majority confirms so.
+    pub fn read(&self) -> Result<()> {
+        let confirmed = {
+            let mut node = self.node.lock().unwrap();
+            let id = node.raft.read_index()?;
+            let (sender, receiver) = mpsc::sync_channel(1);
+            node.reads.insert(id, sender);
+            node.flush();
+            receiver
+        };
+        confirmed
+            .recv_timeout(WRITE_TIMEOUT)
+            .map_err(|_| KvsError::Raft("timed out confirming the leader for a read".to_owned()))?
+    }
+
+    /// Adds `node` to the members, or removes it, and returns the members
+    /// once that is committed.
+    pub fn change_membership(&self, node: &str, add: bool) -> Result<Vec<NodeId>> {
+        self.commit(|raft| raft.change_membership(node, add))?;
+        let members = self.node.lock().unwrap().raft.members().clone();
+        Ok(members.into_iter().collect())
+    }
+
+    /// The leader of the current term, if known.
+    pub fn leader(&self) -> Option<NodeId> {
+        self.node.lock().unwrap().raft.leader().map(str::to_owned)
+    }
+
+    /// Adds the state of the node to `stats`.
+    pub fn report(&self, stats: &mut Stats) {
+        let node = self.node.lock().unwrap();
+        let raft = &node.raft;
+        let role = match raft.role() {
+            Role::Follower => "follower",
+            Role::Candidate => "candidate",
+            Role::Leader => "leader",
+        };
+        let members: Vec<&str> = raft.members().iter().map(String::as_str).collect();
+        stats.insert("raft_role".to_owned(), role.to_owned());
+        stats.insert("raft_term".to_owned(), raft.term().to_string());
+        stats.insert(
+            "raft_leader".to_owned(),
+            raft.leader().unwrap_or_default().to_owned(),
+        );
+        stats.insert(
+            "raft_commit_index".to_owned(),
+            raft.commit_index().to_string(),
+        );
+        stats.insert(
+            "raft_applied_index".to_owned(),
+            raft.last_applied().to_string(),
+        );
+        stats.insert("raft_members".to_owned(), members.join(","));
+    }
+
+    fn commit<F>(&self, propose: F) -> Result<String>
+    where
+        F: FnOnce(&mut Raft<E>) -> Result<(u64, u64)>,
+    {
+        let applied = {
+            let mut node = self.node.lock().unwrap();
+            let (index, term) = propose(&mut node.raft)?;
+            let (sender, receiver) = mpsc::sync_channel(1);
+            node.waiters.insert(index, (term, sender));
+            node.flush();
+            receiver
+        };
+        applied
+            .recv_timeout(WRITE_TIMEOUT)
+            .map_err(|_| KvsError::Raft("timed out waiting for the write to commit".to_owned()))?
+    }
+}
+
+impl<E: KvsEngine> Node<E> {
+    /// Answers the writes that were applied and the reads that were
+    /// confirmed, and sends the pending messages.
+    fn flush(&mut self) {
+        for (id, result) in self.raft.take_reads() {
+            if let Some(waiter) = self.reads.remove(&id) {
+                let _ = waiter.try_send(result);
+            }
+        }
+        for applied in self.raft.take_applied() {
+            if let Some((term, waiter)) = self.waiters.remove(&applied.index) {
+                // another leader's entry took the place of the write
+                let result = if term == applied.term {
+                    applied.result
+                } else {
+                    Err(KvsError::NotLeader(self.raft.leader().map(str::to_owned)))
+                };
+                let _ = waiter.try_send(result);
+            }
+        }
+        for (to, message) in self.raft.take_messages() {
+            let from = self.raft.id();
+            let credentials = &self.credentials;
+            let peer = self
+                .peers
+                .entry(to.clone())
+                .or_insert_with(|| connect(from.to_owned(), to, credentials.clone()));
+            let _ = peer.try_send(message);
+        }
+    }
+}
+
+/// Starts sending messages to node `to` over a connection of their own.
+fn connect(from: NodeId, to: NodeId, credentials: Option<Credentials>) -> SyncSender<Message> {
+    let (sender, receiver) = mpsc::sync_channel(PEER_BUFFER);
+    thread::spawn(move || {
+        let mut stream: Option<TcpStream> = None;
+        for message in receiver {
+            if stream.is_none() {
+                stream = open(&to, credentials.as_ref())
+                    .map_err(|e| debug!("cannot reach {}: {}", to, e))
+                    .ok();
+            }
+            let Some(mut conn) = stream.as_ref() else {
+                continue;
+            };
+            let request = Envelope::new(Request::Raft {
+                from: from.clone(),
+                message,
+            });
+            let sent = serde_json::to_vec(&request)
+                .map_err(KvsError::from)
+                .and_then(|buf| Ok(conn.write_all(&buf)?));
+            if sent.is_err() {
+                stream = None;
+            }
+        }
+    });
+    sender
+}
+
+/// Connects to the node at `addr`. Its answer to `credentials` goes unread,
+/// like those to messages: a peer turning them away ignores the messages.
+fn open(addr: &str, credentials: Option<&Credentials>) -> Result<TcpStream> {
+    let addr: SocketAddr = addr
+        .parse()
+        .map_err(|_| KvsError::Raft(format!("invalid node address {:?}", addr)))?;
+    let mut stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+    stream.set_write_timeout(Some(CONNECT_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+    if let Some(credentials) = credentials {
+        let auth = Envelope::new(Request::Auth {
+            credentials: credentials.clone(),
+        });
+        stream.write_all(&serde_json::to_vec(&auth)?)?;
+    }
+    Ok(stream)
+}
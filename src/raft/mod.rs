@@ -0,0 +1,873 @@
+//! Raft consensus over a `KvsEngine`.
+//!
+//! `Raft` is the state machine of one node. It does no I/O besides its
+//! storage and the engine it applies committed writes to: time passes by
+//! calls to `tick`, messages from other nodes are passed to `step`, and the
+//! messages it sends are collected with `take_messages`. `Cluster` drives a
+//! node over TCP for `kvs-server`.
+//!
+//! Reads are served by the leader once it knows it still leads: each read
+//! starts a heartbeat round, and waits for a majority to answer it and for
+//! the commit index as of its start to be applied.
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::ops::Bound;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::replica::restore;
+use crate::{KvsEngine, KvsError, Result};
+
+use self::storage::Storage;
+
+mod cluster;
+mod storage;
+
+pub use self::cluster::Cluster;
+
+/// Nodes are named by the address they serve clients on.
+pub type NodeId = String;
+
+/// Tuning knobs of `Raft`, in ticks where they are durations.
+#[derive(Debug, Clone)]
+pub struct RaftOptions {
+    /// Ticks without hearing from a leader before a node starts an election,
+    /// at the least. Each node waits up to twice as long, at random.
+    pub election_ticks: u32,
+    /// Ticks between the heartbeats of a leader.
+    pub heartbeat_ticks: u32,
+    /// Applied entries after which the log is compacted into a snapshot.
+    pub snapshot_entries: u64,
+    /// Entries sent in one message at most.
+    pub max_batch: usize,
+}
+
+impl Default for RaftOptions {
+    fn default() -> Self {
+        RaftOptions {
+            election_ticks: 10,
+            heartbeat_ticks: 3,
+            snapshot_entries: 1000,
+            max_batch: 100,
+        }
+    }
+}
+
+/// A write replicated through the log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Command {
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+    RemoveRange {
+        start: Bound<String>,
+        end: Bound<String>,
+    },
+    Merge {
+        key: String,
+        operator: String,
+        operand: String,
+    },
+    /// Makes these nodes the members of the cluster, from the moment the
+    /// entry is appended to a log.
+    Config(BTreeSet<NodeId>),
+    /// Written by a new leader to commit the entries of earlier terms.
+    Noop,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub term: u64,
+    pub index: u64,
+    pub command: Command,
+}
+
+/// The applied state up to and including entry `index`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub index: u64,
+    pub term: u64,
+    pub config: BTreeSet<NodeId>,
+    pub pairs: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Message {
+    RequestVote {
+        term: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    AppendEntries {
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+        /// Heartbeat round of the leader, echoed in the response.
+        #[serde(default)]
+        round: u64,
+    },
+    /// Answers `AppendEntries` and `InstallSnapshot`. On failure
+    /// `match_index` is the last entry the follower may share.
+    AppendResponse {
+        term: u64,
+        success: bool,
+        match_index: u64,
+        #[serde(default)]
+        round: u64,
+    },
+    InstallSnapshot {
+        term: u64,
+        snapshot: Snapshot,
+    },
+}
+
+impl Message {
+    fn term(&self) -> u64 {
+        match self {
+            Message::RequestVote { term, .. }
+            | Message::Vote { term, .. }
+            | Message::AppendEntries { term, .. }
+            | Message::AppendResponse { term, .. }
+            | Message::InstallSnapshot { term, .. } => *term,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// Outcome of applying the entry at `index`, written in `term`: the value or
+/// count the write returns, or its error.
+#[derive(Debug)]
+pub struct Applied {
+    pub index: u64,
+    pub term: u64,
+    pub result: Result<String>,
+}
+
+/// One node of a Raft group.
+#[derive(Debug)]
+pub struct Raft<E: KvsEngine> {
+    id: NodeId,
+    engine: E,
+    options: RaftOptions,
+    storage: Storage,
+    role: Role,
+    term: u64,
+    voted_for: Option<NodeId>,
+    leader: Option<NodeId>,
+    /// Entries after the snapshot.
+    log: Vec<Entry>,
+    snapshot_index: u64,
+    snapshot_term: u64,
+    snapshot_config: BTreeSet<NodeId>,
+    /// Members as of the last config entry in the log.
+    config: BTreeSet<NodeId>,
+    commit_index: u64,
+    last_applied: u64,
+    elapsed: u32,
+    timeout: u32,
+    votes: BTreeSet<NodeId>,
+    /// Followers that answered since the last election timeout, so that a
+    /// leader cut off from a majority steps down.
+    active: BTreeSet<NodeId>,
+    next_index: HashMap<NodeId, u64>,
+    match_index: HashMap<NodeId, u64>,
+    /// Index of the first entry of the term of this leader.
+    term_start: u64,
+    /// Heartbeat round, bumped by every read.
+    round: u64,
+    /// Last round each follower answered in this term.
+    acked: HashMap<NodeId, u64>,
+    /// Rounds and read indexes of the reads not yet let go, oldest first.
+    reads: VecDeque<(u64, u64)>,
+    ready_reads: Vec<(u64, Result<()>)>,
+    messages: Vec<(NodeId, Message)>,
+    applied: Vec<Applied>,
+    rng: u64,
+}
+
+impl<E: KvsEngine> Raft<E> {
+    /// Opens node `id` with its state in `dir`, or in memory if `None`.
+    /// `members` are the members of a new cluster, used until the log
+    /// holds a config of its own. The engine is reset to the last snapshot
+    /// and catches up as entries are committed.
+    pub fn open(
+        id: impl Into<NodeId>,
+        members: impl IntoIterator<Item = NodeId>,
+        engine: E,
+        dir: Option<PathBuf>,
+        options: RaftOptions,
+    ) -> Result<Raft<E>> {
+        let id = id.into();
+        let (storage, state) = Storage::open(dir)?;
+        let mut snapshot = state.snapshot.unwrap_or_default();
+        if snapshot.index == 0 {
+            snapshot.config = members.into_iter().collect();
+        }
+        restore(&engine, std::mem::take(&mut snapshot.pairs))?;
+        let rng = id.bytes().fold(0x9e37_79b9_7f4a_7c15, |h: u64, b| {
+            (h ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+        });
+        let mut raft = Raft {
+            id,
+            engine,
+            options,
+            storage,
+            role: Role::Follower,
+            term: state.term,
+            voted_for: state.voted_for,
+            leader: None,
+            log: state.log,
+            snapshot_index: snapshot.index,
+            snapshot_term: snapshot.term,
+            snapshot_config: snapshot.config,
+            config: BTreeSet::new(),
+            commit_index: snapshot.index,
+            last_applied: snapshot.index,
+            elapsed: 0,
+            timeout: 0,
+            votes: BTreeSet::new(),
+            active: BTreeSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            term_start: 0,
+            round: 0,
+            acked: HashMap::new(),
+            reads: VecDeque::new(),
+            ready_reads: Vec::new(),
+            messages: Vec::new(),
+            applied: Vec::new(),
+            rng,
+        };
+        raft.log.retain(|entry| entry.index > snapshot.index);
+        raft.update_config();
+        raft.reset_timeout();
+        Ok(raft)
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    /// The leader of the current term, if known.
+    pub fn leader(&self) -> Option<&str> {
+        self.leader.as_deref()
+    }
+
+    pub fn members(&self) -> &BTreeSet<NodeId> {
+        &self.config
+    }
+
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+
+    pub fn last_applied(&self) -> u64 {
+        self.last_applied
+    }
+
+    /// Index of the last entry compacted into the snapshot.
+    pub fn snapshot_index(&self) -> u64 {
+        self.snapshot_index
+    }
+
+    pub fn engine(&self) -> &E {
+        &self.engine
+    }
+
+    /// Messages to send since the last call, with their destination.
+    pub fn take_messages(&mut self) -> Vec<(NodeId, Message)> {
+        std::mem::take(&mut self.messages)
+    }
+
+    /// Entries applied since the last call.
+    pub fn take_applied(&mut self) -> Vec<Applied> {
+        std::mem::take(&mut self.applied)
+    }
+
+    /// Reads confirmed or failed since the last call, by the id `read_index`
+    /// returned.
+    pub fn take_reads(&mut self) -> Vec<(u64, Result<()>)> {
+        std::mem::take(&mut self.ready_reads)
+    }
+
+    /// Starts a read if this node leads, returning its id. Once
+    /// `take_reads` yields the id without an error, the engine holds every
+    /// write committed before the read started.
+    pub fn read_index(&mut self) -> Result<u64> {
+        if self.role != Role::Leader {
+            return Err(KvsError::NotLeader(self.leader.clone()));
+        }
+        self.round += 1;
+        // what earlier leaders committed is only known to be committed here
+        // once the first entry of this term is
+        let index = self.commit_index.max(self.term_start);
+        self.reads.push_back((self.round, index));
+        self.broadcast_append();
+        self.advance_reads();
+        Ok(self.round)
+    }
+
+    /// Appends `command` to the log if this node leads, returning the index
+    /// and term it will be applied under.
+    pub fn propose(&mut self, command: Command) -> Result<(u64, u64)> {
+        if self.role != Role::Leader {
+            return Err(KvsError::NotLeader(self.leader.clone()));
+        }
+        if let Command::Config(_) = command {
+            let pending = self
+                .log
+                .iter()
+                .any(|e| e.index > self.commit_index && matches!(e.command, Command::Config(_)));
+            if pending {
+                return Err(KvsError::Raft(
+                    "another membership change is in progress".to_owned(),
+                ));
+            }
+        }
+        let index = self.last_index() + 1;
+        let entry = Entry {
+            term: self.term,
+            index,
+            command,
+        };
+        self.storage.append(std::slice::from_ref(&entry))?;
+        self.log.push(entry);
+        self.update_config();
+        self.advance_commit()?;
+        self.broadcast_append();
+        Ok((index, self.term))
+    }
+
+    /// Adds `node` to the members, or removes it, one node at a time.
+    pub fn change_membership(&mut self, node: &str, add: bool) -> Result<(u64, u64)> {
+        let mut config = self.config.clone();
+        if add {
+            config.insert(node.to_owned());
+        } else {
+            config.remove(node);
+        }
+        self.propose(Command::Config(config))
+    }
+
+    /// Advances time by one tick.
+    pub fn tick(&mut self) -> Result<()> {
+        self.elapsed += 1;
+        match self.role {
+            Role::Leader => {
+                if self.elapsed.is_multiple_of(self.options.heartbeat_ticks) {
+                    self.broadcast_append();
+                }
+                if self.elapsed >= self.options.election_ticks {
+                    // step down unless a majority answered since last time
+                    let active = self
+                        .config
+                        .iter()
+                        .filter(|&node| *node == self.id || self.active.contains(node))
+                        .count();
+                    if active < self.quorum() {
+                        self.become_follower(self.term, None)?;
+                    }
+                    self.elapsed = 0;
+                    self.active.clear();
+                }
+            }
+            Role::Follower | Role::Candidate => {
+                if self.elapsed >= self.timeout && self.config.contains(&self.id) {
+                    self.campaign()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles a message from node `from`.
+    pub fn step(&mut self, from: &str, message: Message) -> Result<()> {
+        let term = message.term();
+        if term > self.term {
+            let leader = match message {
+                Message::AppendEntries { .. } | Message::InstallSnapshot { .. } => {
+                    Some(from.to_owned())
+                }
+                _ => None,
+            };
+            self.become_follower(term, leader)?;
+        }
+        if term < self.term {
+            // tell a stale leader of the new term
+            if let Message::AppendEntries { .. } | Message::InstallSnapshot { .. } = message {
+                self.send(
+                    from,
+                    Message::AppendResponse {
+                        term: self.term,
+                        success: false,
+                        match_index: 0,
+                        round: 0,
+                    },
+                );
+            }
+            return Ok(());
+        }
+        match message {
+            Message::RequestVote {
+                last_log_index,
+                last_log_term,
+                ..
+            } => {
+                let up_to_date =
+                    (last_log_term, last_log_index) >= (self.last_term(), self.last_index());
+                let free = self.voted_for.as_deref().is_none_or(|v| v == from);
+                let granted = up_to_date && free && self.role == Role::Follower;
+                if granted {
+                    self.voted_for = Some(from.to_owned());
+                    self.storage
+                        .save_state(self.term, self.voted_for.as_deref())?;
+                    self.elapsed = 0;
+                }
+                self.send(
+                    from,
+                    Message::Vote {
+                        term: self.term,
+                        granted,
+                    },
+                );
+            }
+            Message::Vote { granted, .. } => {
+                if self.role == Role::Candidate && granted {
+                    self.votes.insert(from.to_owned());
+                    if self.votes.len() >= self.quorum() {
+                        self.become_leader()?;
+                    }
+                }
+            }
+            Message::AppendEntries {
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+                round,
+                ..
+            } => {
+                self.follow(from)?;
+                let prev = (prev_log_index, prev_log_term);
+                self.append_entries(from, prev, entries, leader_commit, round)?;
+            }
+            Message::InstallSnapshot { snapshot, .. } => {
+                self.follow(from)?;
+                self.install_snapshot(from, snapshot)?;
+            }
+            Message::AppendResponse {
+                success,
+                match_index,
+                round,
+                ..
+            } => {
+                if self.role == Role::Leader {
+                    self.active.insert(from.to_owned());
+                    let acked = self.acked.entry(from.to_owned()).or_default();
+                    *acked = (*acked).max(round);
+                    self.advance_reads();
+                    if success {
+                        let matched = self.match_index.entry(from.to_owned()).or_default();
+                        *matched = (*matched).max(match_index);
+                        self.next_index.insert(from.to_owned(), match_index + 1);
+                        self.advance_commit()?;
+                        if match_index < self.last_index() {
+                            self.send_append(from);
+                        }
+                    } else {
+                        let next = self.next_index.get(from).copied().unwrap_or(1);
+                        let next = next.saturating_sub(1).min(match_index + 1).max(1);
+                        self.next_index.insert(from.to_owned(), next);
+                        self.send_append(from);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn follow(&mut self, leader: &str) -> Result<()> {
+        if self.role != Role::Follower || self.leader.as_deref() != Some(leader) {
+            self.become_follower(self.term, Some(leader.to_owned()))?;
+        }
+        self.elapsed = 0;
+        Ok(())
+    }
+
+    fn append_entries(
+        &mut self,
+        from: &str,
+        (prev_log_index, prev_log_term): (u64, u64),
+        entries: Vec<Entry>,
+        leader_commit: u64,
+        round: u64,
+    ) -> Result<()> {
+        let reject = |raft: &mut Self, match_index| {
+            raft.send(
+                from,
+                Message::AppendResponse {
+                    term: raft.term,
+                    success: false,
+                    match_index,
+                    round,
+                },
+            );
+        };
+        if prev_log_index > self.last_index() {
+            reject(self, self.last_index());
+            return Ok(());
+        }
+        // entries up to the snapshot are committed, so they match
+        if prev_log_index >= self.snapshot_index && self.term_at(prev_log_index) != prev_log_term {
+            reject(self, prev_log_index - 1);
+            return Ok(());
+        }
+        let last_new = prev_log_index + entries.len() as u64;
+        let mut appended = Vec::new();
+        for entry in entries {
+            if entry.index <= self.snapshot_index {
+                continue;
+            }
+            if entry.index <= self.last_index() {
+                if self.term_at(entry.index) == entry.term {
+                    continue;
+                }
+                self.log
+                    .truncate((entry.index - self.snapshot_index - 1) as usize);
+                self.storage.truncate(&self.log)?;
+            }
+            appended.push(entry);
+        }
+        if !appended.is_empty() {
+            self.storage.append(&appended)?;
+            self.log.extend(appended);
+        }
+        self.update_config();
+        let commit_index = leader_commit.min(last_new);
+        if commit_index > self.commit_index {
+            self.commit_index = commit_index;
+            self.apply()?;
+        }
+        self.send(
+            from,
+            Message::AppendResponse {
+                term: self.term,
+                success: true,
+                match_index: last_new,
+                round,
+            },
+        );
+        Ok(())
+    }
+
+    fn install_snapshot(&mut self, from: &str, snapshot: Snapshot) -> Result<()> {
+        if snapshot.index > self.commit_index {
+            restore(&self.engine, snapshot.pairs.clone())?;
+            self.log.clear();
+            self.snapshot_index = snapshot.index;
+            self.snapshot_term = snapshot.term;
+            self.snapshot_config = snapshot.config.clone();
+            self.commit_index = snapshot.index;
+            self.last_applied = snapshot.index;
+            self.storage.save_snapshot(&snapshot, &self.log)?;
+            self.update_config();
+        }
+        self.send(
+            from,
+            Message::AppendResponse {
+                term: self.term,
+                success: true,
+                match_index: self.commit_index,
+                round: 0,
+            },
+        );
+        Ok(())
+    }
+
+    fn campaign(&mut self) -> Result<()> {
+        self.role = Role::Candidate;
+        self.term += 1;
+        self.voted_for = Some(self.id.clone());
+        self.leader = None;
+        self.storage
+            .save_state(self.term, self.voted_for.as_deref())?;
+        self.votes = BTreeSet::from([self.id.clone()]);
+        self.reset_timeout();
+        if self.votes.len() >= self.quorum() {
+            return self.become_leader();
+        }
+        let message = Message::RequestVote {
+            term: self.term,
+            last_log_index: self.last_index(),
+            last_log_term: self.last_term(),
+        };
+        for peer in self.peers() {
+            self.send(&peer, message.clone());
+        }
+        Ok(())
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) -> Result<()> {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.storage.save_state(self.term, None)?;
+        }
+        for (round, _) in self.reads.drain(..) {
+            let e = KvsError::NotLeader(leader.clone());
+            self.ready_reads.push((round, Err(e)));
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.reset_timeout();
+        Ok(())
+    }
+
+    fn become_leader(&mut self) -> Result<()> {
+        self.role = Role::Leader;
+        self.leader = Some(self.id.clone());
+        self.elapsed = 0;
+        self.active.clear();
+        self.next_index.clear();
+        self.match_index.clear();
+        self.acked.clear();
+        self.term_start = self.last_index() + 1;
+        // entries of earlier terms only commit along with one of this term
+        self.propose(Command::Noop)?;
+        Ok(())
+    }
+
+    fn broadcast_append(&mut self) {
+        for peer in self.peers() {
+            self.send_append(&peer);
+        }
+    }
+
+    /// Sends `peer` the entries it misses, or the snapshot if they were
+    /// compacted.
+    fn send_append(&mut self, peer: &str) {
+        let last_index = self.last_index();
+        let next = *self
+            .next_index
+            .entry(peer.to_owned())
+            .or_insert(last_index + 1);
+        if next <= self.snapshot_index {
+            let snapshot = Snapshot {
+                index: self.snapshot_index,
+                term: self.snapshot_term,
+                config: self.snapshot_config.clone(),
+                pairs: self.storage.snapshot_pairs(),
+            };
+            let message = Message::InstallSnapshot {
+                term: self.term,
+                snapshot,
+            };
+            self.send(peer, message);
+            return;
+        }
+        let start = (next - self.snapshot_index - 1) as usize;
+        let end = self.log.len().min(start + self.options.max_batch);
+        let message = Message::AppendEntries {
+            term: self.term,
+            prev_log_index: next - 1,
+            prev_log_term: self.term_at(next - 1),
+            entries: self.log[start..end].to_vec(),
+            leader_commit: self.commit_index,
+            round: self.round,
+        };
+        self.send(peer, message);
+    }
+
+    /// Commits the entries of this term a majority holds.
+    fn advance_commit(&mut self) -> Result<()> {
+        if self.role != Role::Leader {
+            return Ok(());
+        }
+        let mut index = self.last_index();
+        while index > self.commit_index && self.term_at(index) == self.term {
+            let holders = self
+                .config
+                .iter()
+                .filter(|&node| {
+                    *node == self.id || self.match_index.get(node).is_some_and(|&m| m >= index)
+                })
+                .count();
+            if holders >= self.quorum() {
+                self.commit_index = index;
+                return self.apply();
+            }
+            index -= 1;
+        }
+        Ok(())
+    }
+
+    /// Applies the committed entries to the engine.
+    fn apply(&mut self) -> Result<()> {
+        while self.last_applied < self.commit_index {
+            let index = self.last_applied + 1;
+            let entry = &self.log[(index - self.snapshot_index - 1) as usize];
+            let result = match &entry.command {
+                Command::Set { key, value } => self
+                    .engine
+                    .set(key.clone(), value.clone())
+                    .map(|_| "ok".to_owned()),
+                Command::Remove { key } => self.engine.remove(key.clone()).map(|_| "ok".to_owned()),
+                Command::RemoveRange { start, end } => self
+                    .engine
+                    .remove_range((start.clone(), end.clone()))
+                    .map(|removed| removed.to_string()),
+                Command::Merge {
+                    key,
+                    operator,
+                    operand,
+                } => self.engine.merge(key.clone(), operator, operand.clone()),
+                Command::Config(_) | Command::Noop => Ok(String::new()),
+            };
+            self.applied.push(Applied {
+                index,
+                term: entry.term,
+                result,
+            });
+            self.last_applied = index;
+        }
+        // a leader removed from the cluster leaves once that is committed
+        if self.role == Role::Leader && !self.config.contains(&self.id) {
+            let config_committed = !self
+                .log
+                .iter()
+                .any(|e| e.index > self.commit_index && matches!(e.command, Command::Config(_)));
+            if config_committed {
+                self.become_follower(self.term, None)?;
+            }
+        }
+        if self.last_applied - self.snapshot_index >= self.options.snapshot_entries {
+            self.compact()?;
+        }
+        self.advance_reads();
+        Ok(())
+    }
+
+    /// Lets go the reads whose round a majority answered and whose read
+    /// index is applied.
+    fn advance_reads(&mut self) {
+        while let Some(&(round, index)) = self.reads.front() {
+            let answered = self
+                .config
+                .iter()
+                .filter(|&node| {
+                    *node == self.id || self.acked.get(node).is_some_and(|&r| r >= round)
+                })
+                .count();
+            if answered < self.quorum() || self.last_applied < index {
+                return;
+            }
+            self.reads.pop_front();
+            self.ready_reads.push((round, Ok(())));
+        }
+    }
+
+    /// Replaces the applied entries with a snapshot of the engine.
+    fn compact(&mut self) -> Result<()> {
+        let index = self.last_applied;
+        let term = self.term_at(index);
+        let applied = (index - self.snapshot_index) as usize;
+        let config = self.log[..applied]
+            .iter()
+            .rev()
+            .find_map(|e| match &e.command {
+                Command::Config(config) => Some(config.clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| self.snapshot_config.clone());
+        let snapshot = Snapshot {
+            index,
+            term,
+            config: config.clone(),
+            pairs: self.engine.scan(..)?,
+        };
+        self.log.drain(..applied);
+        self.storage.save_snapshot(&snapshot, &self.log)?;
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+        self.snapshot_config = config;
+        Ok(())
+    }
+
+    fn update_config(&mut self) {
+        self.config = self
+            .log
+            .iter()
+            .rev()
+            .find_map(|e| match &e.command {
+                Command::Config(config) => Some(config.clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| self.snapshot_config.clone());
+    }
+
+    fn peers(&self) -> Vec<NodeId> {
+        self.config
+            .iter()
+            .filter(|node| **node != self.id)
+            .cloned()
+            .collect()
+    }
+
+    fn quorum(&self) -> usize {
+        self.config.len() / 2 + 1
+    }
+
+    fn last_index(&self) -> u64 {
+        self.snapshot_index + self.log.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.term_at(self.last_index())
+    }
+
+    fn term_at(&self, index: u64) -> u64 {
+        if index <= self.snapshot_index {
+            return self.snapshot_term;
+        }
+        self.log[(index - self.snapshot_index - 1) as usize].term
+    }
+
+    fn send(&mut self, to: &str, message: Message) {
+        self.messages.push((to.to_owned(), message));
+    }
+
+    fn reset_timeout(&mut self) {
+        // xorshift, good enough to spread elections apart
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let ticks = self.options.election_ticks;
+        self.timeout = ticks + (self.rng % ticks as u64) as u32;
+        self.elapsed = 0;
+    }
+}
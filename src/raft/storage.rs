@@ -0,0 +1,165 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+
+use super::{Entry, Snapshot};
+use crate::Result;
+
+const STATE_FILE: &str = "raft.state";
+const LOG_FILE: &str = "raft.log";
+const SNAPSHOT_FILE: &str = "raft.snapshot";
+
+/// What a node must not forget across restarts.
+#[derive(Debug, Default)]
+pub struct State {
+    pub term: u64,
+    pub voted_for: Option<String>,
+    /// Entries after the snapshot, though a crash while compacting may
+    /// leave some it already covers.
+    pub log: Vec<Entry>,
+    pub snapshot: Option<Snapshot>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct HardState {
+    term: u64,
+    voted_for: Option<String>,
+}
+
+/// Persists the state of a node in a directory, or nowhere.
+///
+/// The term and vote and the snapshot are rewritten whole, through a
+/// temporary file. The log is a stream of JSON entries that is appended to,
+/// and rewritten when it loses entries. Writes are synced to disk, and so is
+/// the directory after a rename, before they return.
+#[derive(Debug)]
+pub struct Storage {
+    dir: Option<PathBuf>,
+    log: Option<File>,
+    /// Pairs of the last snapshot, sent to followers that lag behind it.
+    pairs: Vec<(String, String)>,
+}
+
+impl Storage {
+    pub fn open(dir: Option<PathBuf>) -> Result<(Storage, State)> {
+        let Some(dir) = dir else {
+            let storage = Storage {
+                dir: None,
+                log: None,
+                pairs: Vec::new(),
+            };
+            return Ok((storage, State::default()));
+        };
+        fs::create_dir_all(&dir)?;
+        let mut state = State::default();
+        if let Some(hard) = read::<HardState>(&dir.join(STATE_FILE))? {
+            state.term = hard.term;
+            state.voted_for = hard.voted_for;
+        }
+        state.snapshot = read::<Snapshot>(&dir.join(SNAPSHOT_FILE))?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(dir.join(LOG_FILE))?;
+        let mut stream = Deserializer::from_reader(BufReader::new(&mut file)).into_iter::<Entry>();
+        let mut len = 0;
+        while let Some(Ok(entry)) = stream.next() {
+            state.log.push(entry);
+            len = stream.byte_offset() as u64;
+        }
+        // drop an entry the last process did not finish writing
+        if file.metadata()?.len() > len {
+            file.set_len(len)?;
+        }
+        let pairs = state
+            .snapshot
+            .as_ref()
+            .map_or_else(Vec::new, |snapshot| snapshot.pairs.clone());
+        let storage = Storage {
+            dir: Some(dir),
+            log: Some(file),
+            pairs,
+        };
+        Ok((storage, state))
+    }
+
+    pub fn save_state(&mut self, term: u64, voted_for: Option<&str>) -> Result<()> {
+        if let Some(dir) = &self.dir {
+            let state = HardState {
+                term,
+                voted_for: voted_for.map(str::to_owned),
+            };
+            write(&dir.join(STATE_FILE), &state)?;
+        }
+        Ok(())
+    }
+
+    pub fn append(&mut self, entries: &[Entry]) -> Result<()> {
+        if let Some(file) = &mut self.log {
+            let mut buf = Vec::new();
+            for entry in entries {
+                serde_json::to_writer(&mut buf, entry)?;
+            }
+            file.write_all(&buf)?;
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the log with `log`.
+    pub fn truncate(&mut self, log: &[Entry]) -> Result<()> {
+        if let Some(dir) = &self.dir {
+            let mut buf = Vec::new();
+            for entry in log {
+                serde_json::to_writer(&mut buf, entry)?;
+            }
+            let path = dir.join(LOG_FILE);
+            replace(&path, &buf)?;
+            self.log = Some(OpenOptions::new().append(true).open(&path)?);
+        }
+        Ok(())
+    }
+
+    /// Saves `snapshot`, then replaces the log with the entries after it.
+    pub fn save_snapshot(&mut self, snapshot: &Snapshot, log: &[Entry]) -> Result<()> {
+        if let Some(dir) = &self.dir {
+            write(&dir.join(SNAPSHOT_FILE), snapshot)?;
+        }
+        self.pairs = snapshot.pairs.clone();
+        self.truncate(log)
+    }
+
+    pub fn snapshot_pairs(&self) -> Vec<(String, String)> {
+        self.pairs.clone()
+    }
+}
+
+fn read<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<Option<T>> {
+    match fs::read(path) {
+        Ok(buf) => Ok(Some(serde_json::from_slice(&buf)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    replace(path, &serde_json::to_vec(value)?)
+}
+
+/// Replaces the file at `path` with `buf` through a temporary file.
+fn replace(path: &Path, buf: &[u8]) -> Result<()> {
+    let mut tmp_path = path.to_owned().into_os_string();
+    tmp_path.push(".tmp");
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(buf)?;
+    tmp.sync_data()?;
+    fs::rename(&tmp_path, path)?;
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
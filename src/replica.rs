@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::error;
+use serde::Deserialize;
+use serde_json::Deserializer;
+
+use crate::{
+    Envelope, KvsEngine, KvsError, ReplicationEvent, Reply, Request, Response, Result, Stats,
+};
+
+/// How long a replica waits before reconnecting to its primary.
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+/// Silence after which a replica gives up on its primary. Primaries send
+/// heartbeats well within it.
+const PRIMARY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Keeps an engine a copy of the default keyspace of a primary
+/// `kvs-server`.
+#[derive(Debug)]
+pub struct Replica {
+    primary: SocketAddr,
+    status: Mutex<Status>,
+}
+
+#[derive(Debug, Default)]
+struct Status {
+    connected: bool,
+    /// Sequence number on the primary of the last change applied.
+    applied_seq: u64,
+    /// Time from the primary sending the last event to applying it.
+    lag_ms: u64,
+}
+
+impl Replica {
+    /// Starts following `primary` into `engine` in the background,
+    /// reconnecting whenever the connection is lost.
+    pub fn start<E: KvsEngine>(engine: E, primary: SocketAddr) -> Arc<Replica> {
+        let replica = Arc::new(Replica {
+            primary,
+            status: Mutex::new(Status::default()),
+        });
+        let follower = replica.clone();
+        thread::spawn(move || loop {
+            if let Err(e) = follower.follow(&engine) {
+                error!("replication from {} stopped: {}", primary, e);
+            }
+            follower.status.lock().unwrap().connected = false;
+            thread::sleep(RECONNECT_DELAY);
+        });
+        replica
+    }
+
+    /// Address of the primary.
+    pub fn primary(&self) -> SocketAddr {
+        self.primary
+    }
+
+    /// Adds the state of replication to `stats`.
+    pub fn report(&self, stats: &mut Stats) {
+        let status = self.status.lock().unwrap();
+        stats.insert("replica_of".to_owned(), self.primary.to_string());
+        stats.insert("replica_connected".to_owned(), status.connected.to_string());
+        stats.insert(
+            "replica_applied_seq".to_owned(),
+            status.applied_seq.to_string(),
+        );
+        stats.insert("replica_lag_ms".to_owned(), status.lag_ms.to_string());
+    }
+
+    /// Bootstraps from a snapshot of the primary, then applies its changes
+    /// until the connection is lost.
+    fn follow<E: KvsEngine>(&self, engine: &E) -> Result<()> {
+        let stream = TcpStream::connect(self.primary)?;
+        stream.set_read_timeout(Some(PRIMARY_TIMEOUT))?;
+        serde_json::to_writer(&stream, &Envelope::new(Request::Replicate))?;
+        let mut reader = Deserializer::from_reader(BufReader::new(&stream));
+        loop {
+            let event = match Response::deserialize(&mut reader)?.into_reply() {
+                Ok(Reply::Replication(event)) => event,
+                Ok(reply) => return Err(KvsError::Replication(reply.unexpected().to_string())),
+                Err(e) => return Err(KvsError::Replication(e.to_string())),
+            };
+            let timestamp = match event {
+                ReplicationEvent::Snapshot { pairs, timestamp } => {
+                    restore(engine, pairs)?;
+                    self.status.lock().unwrap().connected = true;
+                    timestamp
+                }
+                ReplicationEvent::Change { change, timestamp } => {
+                    match change.value {
+                        Some(value) => engine.set(change.key, value)?,
+                        None => match engine.remove(change.key) {
+                            Err(KvsError::KeyNotFound) => {}
+                            rv => rv?,
+                        },
+                    }
+                    self.status.lock().unwrap().applied_seq = change.seq;
+                    timestamp
+                }
+                ReplicationEvent::Heartbeat { timestamp } => timestamp,
+            };
+            self.status.lock().unwrap().lag_ms = now().saturating_sub(timestamp);
+        }
+    }
+}
+
+/// Makes the pairs of `engine` those of the snapshot.
+pub(crate) fn restore<E: KvsEngine>(engine: &E, pairs: Vec<(String, String)>) -> Result<()> {
+    let mut pairs: HashMap<String, String> = pairs.into_iter().collect();
+    for (key, value) in engine.scan(..)? {
+        match pairs.get(&key) {
+            Some(new_value) if *new_value == value => {
+                pairs.remove(&key);
+            }
+            Some(_) => {}
+            None => engine.remove(key)?,
+        }
+    }
+    for (key, value) in pairs {
+        engine.set(key, value)?;
+    }
+    Ok(())
+}
+
+/// Milliseconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
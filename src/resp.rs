@@ -0,0 +1,712 @@
+//! Redis serialization protocol (RESP2) front end, for tools that speak it.
+//!
+//! Commands map onto `KvsEngine` calls on the default keyspace. Keys set
+//! with `EX` or `PX` expire through deadlines the listener keeps in memory.
+//! Other front ends serve the engine `RespServer::engine` returns, whose
+//! writes clear the deadlines of their keys as `SET` without `EX` does.
+//! Deadlines are lost on restart, and their keys kept.
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::ops::RangeBounds;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::error;
+
+use crate::{Change, KvsEngine, KvsError, Result, Stats, Version};
+
+/// Longest line of a request, as Redis allows for inline commands.
+const MAX_LINE_LEN: usize = 64 << 10;
+/// Longest bulk string of a request.
+const MAX_BULK_LEN: usize = 64 << 20;
+/// Most arguments of a command.
+const MAX_ARGS: usize = 1 << 20;
+/// Keys a `SCAN` returns unless given a `COUNT`.
+const SCAN_COUNT: usize = 10;
+/// How often keys past their deadline are removed without being read.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A RESP2 reply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Simple(String),
+    /// Error line, starting with its kind such as `ERR`.
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    /// The null bulk string, for missing keys.
+    Nil,
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn ok() -> Value {
+        Value::Simple("OK".to_owned())
+    }
+
+    /// Appends the reply as sent over the wire to `out`.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Simple(s) => out.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            Value::Error(e) => out.extend_from_slice(format!("-{}\r\n", e).as_bytes()),
+            Value::Integer(n) => out.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+            Value::Bulk(bytes) => {
+                out.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+                out.extend_from_slice(bytes);
+                out.extend_from_slice(b"\r\n");
+            }
+            Value::Nil => out.extend_from_slice(b"$-1\r\n"),
+            Value::Array(values) => {
+                out.extend_from_slice(format!("*{}\r\n", values.len()).as_bytes());
+                for value in values {
+                    value.encode(out);
+                }
+            }
+        }
+    }
+}
+
+/// Reads the next command, an array of bulk strings or an inline line of
+/// words, or `None` if the stream ends before one starts. Empty commands
+/// are empty.
+///
+/// # Errors
+///
+/// `KvsError::InvalidRequest` if the bytes are not a command. The stream
+/// cannot be read past them.
+pub fn read_command(reader: &mut impl BufRead) -> Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(reader)? else {
+        return Ok(None);
+    };
+    let Some(count) = line.strip_prefix(b"*") else {
+        let words = line.split(u8::is_ascii_whitespace);
+        return Ok(Some(
+            words
+                .filter(|word| !word.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect(),
+        ));
+    };
+    let count = match parse_len(count, MAX_ARGS)? {
+        Some(count) => count,
+        None => return Ok(Some(Vec::new())),
+    };
+    let mut args = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or_else(|| protocol_error("unexpected end"))?;
+        let len = line
+            .strip_prefix(b"$")
+            .ok_or_else(|| protocol_error("expected '$'"))?;
+        let len =
+            parse_len(len, MAX_BULK_LEN)?.ok_or_else(|| protocol_error("invalid bulk length"))?;
+        // grown as the bytes arrive, not to the length a client claims
+        let mut arg = Vec::new();
+        reader.take(len as u64 + 2).read_to_end(&mut arg)?;
+        if arg.len() < len + 2 {
+            return Err(protocol_error("unexpected end"));
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string not followed by CRLF"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Reads a line without its line break, or `None` at the end of the stream.
+fn read_line(reader: &mut impl BufRead) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    reader
+        .take(MAX_LINE_LEN as u64 + 1)
+        .read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(match line.len() < MAX_LINE_LEN {
+            true => protocol_error("unexpected end"),
+            false => protocol_error("too big inline request"),
+        });
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+/// Length of an array or bulk string, `None` if negative.
+fn parse_len(digits: &[u8], max: usize) -> Result<Option<usize>> {
+    let len: i64 = std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .ok_or_else(|| protocol_error("invalid length"))?;
+    match usize::try_from(len) {
+        Ok(len) if len > max => Err(protocol_error("length too big")),
+        Ok(len) => Ok(Some(len)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn protocol_error(message: &str) -> KvsError {
+    KvsError::InvalidRequest(message.to_owned())
+}
+
+/// Serves an engine to Redis clients.
+#[derive(Clone)]
+pub struct RespServer<E: KvsEngine> {
+    engine: E,
+    deadlines: Arc<Deadlines>,
+}
+
+/// Deadlines of expiring keys. Held over writes, so that they and expiry do
+/// not interleave: shared by writes of keys without a deadline, and alone
+/// by those of keys with one and by expiry.
+type Deadlines = RwLock<HashMap<String, Instant>>;
+
+impl<E: KvsEngine> RespServer<E> {
+    /// Serves `engine`, removing keys past their deadline in the background
+    /// for as long as the server, a clone of it or its `engine` lives.
+    pub fn new(engine: E) -> RespServer<E> {
+        let server = RespServer {
+            engine,
+            deadlines: Arc::new(RwLock::new(HashMap::new())),
+        };
+        let deadlines = Arc::downgrade(&server.deadlines);
+        let engine = server.engine.clone();
+        thread::spawn(move || sweep(engine, deadlines));
+        server
+    }
+
+    /// The engine for other front ends to serve, so that their writes clear
+    /// deadlines and their reads miss keys past theirs.
+    pub fn engine(&self) -> ExpiringEngine<E> {
+        ExpiringEngine {
+            inner: self.engine.clone(),
+            deadlines: self.deadlines.clone(),
+            namespaced: false,
+        }
+    }
+
+    /// Answers the commands of `stream` until the client hangs up. Commands
+    /// sent together are answered together.
+    pub fn serve(&self, stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(&stream);
+        let mut writer = BufWriter::new(&stream);
+        loop {
+            let reply = match read_command(&mut reader) {
+                Ok(Some(command)) if command.is_empty() => continue,
+                Ok(Some(command)) => self.execute(&command),
+                Ok(None) => return Ok(()),
+                Err(KvsError::InvalidRequest(message)) => {
+                    let mut out = Vec::new();
+                    Value::Error(format!("ERR Protocol error: {}", message)).encode(&mut out);
+                    writer.write_all(&out)?;
+                    writer.flush()?;
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+            let mut out = Vec::new();
+            reply.encode(&mut out);
+            writer.write_all(&out)?;
+            if reader.buffer().is_empty() {
+                writer.flush()?;
+            }
+        }
+    }
+
+    /// Runs `command`, whose first word names it, and returns its reply.
+    pub fn execute(&self, command: &[Vec<u8>]) -> Value {
+        let args: Option<Vec<&str>> = command
+            .iter()
+            .map(|arg| std::str::from_utf8(arg).ok())
+            .collect();
+        let Some(args) = args else {
+            return Value::Error("ERR keys and values must be UTF-8".to_owned());
+        };
+        let Some((name, args)) = args.split_first() else {
+            return Value::Error("ERR empty command".to_owned());
+        };
+        let name = name.to_ascii_lowercase();
+        let arity_ok = match name.as_str() {
+            "ping" => args.len() <= 1,
+            "info" => args.len() <= 1,
+            "get" | "incr" => args.len() == 1,
+            "set" => args.len() >= 2,
+            "del" | "exists" | "mget" | "scan" => !args.is_empty(),
+            "mset" => !args.is_empty() && args.len() % 2 == 0,
+            _ => return Value::Error(format!("ERR unknown command '{}'", name)),
+        };
+        if !arity_ok {
+            return Value::Error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name
+            ));
+        }
+        let reply = match name.as_str() {
+            "ping" => Ok(match args.first() {
+                Some(message) => Value::Bulk(message.as_bytes().to_vec()),
+                None => Value::Simple("PONG".to_owned()),
+            }),
+            "get" => self.live(args[0]).map(bulk),
+            "set" => self.set(args),
+            "del" => self.del(args),
+            "exists" => args
+                .iter()
+                .map(|key| Ok(self.live(key)?.is_some() as i64))
+                .sum::<Result<i64>>()
+                .map(Value::Integer),
+            "incr" => self.incr(args[0]),
+            "mget" => args
+                .iter()
+                .map(|key| self.live(key).map(bulk))
+                .collect::<Result<_>>()
+                .map(Value::Array),
+            "mset" => self.mset(args),
+            "scan" => self.scan(args),
+            "info" => self.info(),
+            _ => unreachable!("unknown commands are rejected above"),
+        };
+        reply.unwrap_or_else(|e| match e {
+            KvsError::InvalidRequest(message) => Value::Error(format!("ERR {}", message)),
+            e => Value::Error(format!("ERR {}", e)),
+        })
+    }
+
+    /// Value of `key`, removing it if past its deadline.
+    fn live(&self, key: &str) -> Result<Option<String>> {
+        if expired(&self.engine, &self.deadlines, key)? {
+            return Ok(None);
+        }
+        self.engine.get(key.to_owned())
+    }
+
+    /// `SET key value [EX seconds | PX milliseconds] [NX | XX]`
+    fn set(&self, args: &[&str]) -> Result<Value> {
+        let (key, value) = (args[0], args[1]);
+        let (mut deadline, mut nx, mut xx) = (None, false, false);
+        let mut options = args[2..].iter();
+        while let Some(option) = options.next() {
+            match option.to_ascii_uppercase().as_str() {
+                "NX" if !xx => nx = true,
+                "XX" if !nx => xx = true,
+                unit @ ("EX" | "PX") if deadline.is_none() => {
+                    let amount: i64 = options
+                        .next()
+                        .ok_or_else(syntax_error)?
+                        .parse()
+                        .map_err(|_| not_an_integer())?;
+                    if amount <= 0 {
+                        return Err(invalid_expire_time());
+                    }
+                    let amount = amount as u64;
+                    let ttl = match unit {
+                        "EX" => Duration::from_secs(amount),
+                        _ => Duration::from_millis(amount),
+                    };
+                    // deadlines past what an `Instant` holds are refused
+                    // before anything is written
+                    deadline = Some(
+                        Instant::now()
+                            .checked_add(ttl)
+                            .ok_or_else(invalid_expire_time)?,
+                    );
+                }
+                _ => return Err(syntax_error()),
+            }
+        }
+        if deadline.is_none() && !nx && !xx {
+            write(&self.engine, &self.deadlines, key, false, |_| {
+                self.engine.set(key.to_owned(), value.to_owned())
+            })?;
+            return Ok(Value::ok());
+        }
+        let mut deadlines = self.deadlines.write().unwrap();
+        if nx || xx {
+            let exists = match deadlines.get(key) {
+                Some(&at) if at <= Instant::now() => {
+                    expire(&self.engine, &mut deadlines, key)?;
+                    false
+                }
+                _ => self.engine.get(key.to_owned())?.is_some(),
+            };
+            if exists != xx {
+                return Ok(Value::Nil);
+            }
+        }
+        self.engine.set(key.to_owned(), value.to_owned())?;
+        match deadline {
+            Some(at) => deadlines.insert(key.to_owned(), at),
+            None => deadlines.remove(key),
+        };
+        Ok(Value::ok())
+    }
+
+    fn mset(&self, args: &[&str]) -> Result<Value> {
+        for pair in args.chunks(2) {
+            write(&self.engine, &self.deadlines, pair[0], false, |_| {
+                self.engine.set(pair[0].to_owned(), pair[1].to_owned())
+            })?;
+        }
+        Ok(Value::ok())
+    }
+
+    fn del(&self, args: &[&str]) -> Result<Value> {
+        let mut removed = 0;
+        for &key in args {
+            let remove = |expired| match expired {
+                true => Ok(false),
+                false => match self.engine.remove(key.to_owned()) {
+                    Ok(()) => Ok(true),
+                    Err(KvsError::KeyNotFound) => Ok(false),
+                    Err(e) => Err(e),
+                },
+            };
+            if write(&self.engine, &self.deadlines, key, false, remove)? {
+                removed += 1;
+            }
+        }
+        Ok(Value::Integer(removed))
+    }
+
+    fn incr(&self, key: &str) -> Result<Value> {
+        let incr = |_| self.engine.incr(key.to_owned(), 1, 0);
+        match write(&self.engine, &self.deadlines, key, true, incr) {
+            Ok(value) => Ok(Value::Integer(value)),
+            Err(KvsError::MergeOverflow) => Err(KvsError::InvalidRequest(
+                "increment or decrement would overflow".to_owned(),
+            )),
+            Err(KvsError::Merge(_)) => Err(not_an_integer()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// `SCAN cursor [MATCH pattern] [COUNT count]`. The cursor counts the
+    /// keys before it, so keys added or removed meanwhile shift it.
+    fn scan(&self, args: &[&str]) -> Result<Value> {
+        let cursor: usize = args[0]
+            .parse()
+            .map_err(|_| KvsError::InvalidRequest("invalid cursor".to_owned()))?;
+        let (mut pattern, mut count) = (None, SCAN_COUNT);
+        let mut options = args[1..].iter();
+        while let Some(option) = options.next() {
+            let value = options.next().ok_or_else(syntax_error)?;
+            match option.to_ascii_uppercase().as_str() {
+                "MATCH" => pattern = Some(*value),
+                "COUNT" => match value.parse() {
+                    Ok(n) if n > 0 => count = n,
+                    Ok(_) => return Err(syntax_error()),
+                    Err(_) => return Err(not_an_integer()),
+                },
+                _ => return Err(syntax_error()),
+            }
+        }
+        let pairs = self.engine.scan::<std::ops::RangeFull>(..)?;
+        let now = Instant::now();
+        let keys: Vec<String> = {
+            let deadlines = self.deadlines.read().unwrap();
+            pairs
+                .into_iter()
+                .map(|(key, _)| key)
+                .filter(|key| deadlines.get(key).is_none_or(|&at| at > now))
+                .collect()
+        };
+        let end = cursor.saturating_add(count);
+        let next = if end < keys.len() { end } else { 0 };
+        let page = keys
+            .into_iter()
+            .skip(cursor)
+            .take(count)
+            .filter(|key| pattern.is_none_or(|pattern| glob(pattern.as_bytes(), key.as_bytes())))
+            .map(|key| Value::Bulk(key.into_bytes()))
+            .collect();
+        Ok(Value::Array(vec![
+            Value::Bulk(next.to_string().into_bytes()),
+            Value::Array(page),
+        ]))
+    }
+
+    fn info(&self) -> Result<Value> {
+        let keys = self.engine.scan::<std::ops::RangeFull>(..)?.len();
+        let expires = self.deadlines.read().unwrap().len();
+        let mut info = format!(
+            "# Server\r\nkvs_version:{}\r\n\r\n# Keyspace\r\ndb0:keys={},expires={}\r\n\r\n# Engine\r\n",
+            env!("CARGO_PKG_VERSION"),
+            keys,
+            expires
+        );
+        for (name, value) in self.engine.stats()? {
+            info.push_str(&format!("{}:{}\r\n", name, value));
+        }
+        Ok(Value::Bulk(info.into_bytes()))
+    }
+}
+
+/// Removes `key` if it is past its deadline.
+fn expire<E: KvsEngine>(
+    engine: &E,
+    deadlines: &mut HashMap<String, Instant>,
+    key: &str,
+) -> Result<()> {
+    // the key may have been written again since its deadline was read
+    if deadlines.get(key).is_none_or(|&at| at > Instant::now()) {
+        return Ok(());
+    }
+    deadlines.remove(key);
+    match engine.remove(key.to_owned()) {
+        Ok(()) | Err(KvsError::KeyNotFound) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether `key` is past its deadline, removing it if so.
+fn expired<E: KvsEngine>(engine: &E, deadlines: &Deadlines, key: &str) -> Result<bool> {
+    let past = |deadlines: &HashMap<String, Instant>| {
+        deadlines.get(key).is_some_and(|&at| at <= Instant::now())
+    };
+    if !past(&deadlines.read().unwrap()) {
+        return Ok(false);
+    }
+    let mut deadlines = deadlines.write().unwrap();
+    let expired = past(&deadlines);
+    expire(engine, &mut deadlines, key)?;
+    Ok(expired)
+}
+
+/// Runs `write` of `key`, telling it whether the key was removed first for
+/// being past its deadline. Writes of keys without a deadline share the
+/// lock, so that only those of keys with one take turns. The deadline is
+/// then cleared unless `keep`.
+fn write<E: KvsEngine, T>(
+    engine: &E,
+    deadlines: &Deadlines,
+    key: &str,
+    keep: bool,
+    write: impl FnOnce(bool) -> Result<T>,
+) -> Result<T> {
+    {
+        let shared = deadlines.read().unwrap();
+        if !shared.contains_key(key) {
+            return write(false);
+        }
+    }
+    let mut deadlines = deadlines.write().unwrap();
+    let expired = deadlines.get(key).is_some_and(|&at| at <= Instant::now());
+    expire(engine, &mut deadlines, key)?;
+    let written = write(expired)?;
+    if !keep {
+        deadlines.remove(key);
+    }
+    Ok(written)
+}
+
+/// Removes keys past their deadline until the server is dropped.
+fn sweep<E: KvsEngine>(engine: E, deadlines: Weak<Deadlines>) {
+    loop {
+        thread::sleep(SWEEP_INTERVAL);
+        let Some(deadlines) = deadlines.upgrade() else {
+            return;
+        };
+        let mut deadlines = deadlines.write().unwrap();
+        let now = Instant::now();
+        let expired: Vec<String> = deadlines
+            .iter()
+            .filter(|(_, &at)| at <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            if let Err(e) = expire(&engine, &mut deadlines, &key) {
+                error!("expiring {}: {}", key, e);
+            }
+        }
+    }
+}
+
+/// Engine of a `RespServer` as other front ends see it. Writes clear the
+/// deadlines of their keys, `merge` aside as Redis keeps them over `INCR`,
+/// and reads miss keys past their deadline. Keys of namespaces never expire.
+#[derive(Clone)]
+pub struct ExpiringEngine<E: KvsEngine> {
+    inner: E,
+    deadlines: Arc<Deadlines>,
+    namespaced: bool,
+}
+
+impl<E: KvsEngine> KvsEngine for ExpiringEngine<E> {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        if !self.namespaced && expired(&self.inner, &self.deadlines, &key)? {
+            return Ok(None);
+        }
+        self.inner.get(key)
+    }
+    fn remove(&self, key: String) -> Result<()> {
+        if self.namespaced {
+            return self.inner.remove(key);
+        }
+        write(
+            &self.inner,
+            &self.deadlines,
+            &key,
+            false,
+            |expired| match expired {
+                true => Err(KvsError::KeyNotFound),
+                false => self.inner.remove(key.clone()),
+            },
+        )
+    }
+    fn set(&self, key: String, value: String) -> Result<()> {
+        if self.namespaced {
+            return self.inner.set(key, value);
+        }
+        write(&self.inner, &self.deadlines, &key, false, |_| {
+            self.inner.set(key.clone(), value)
+        })
+    }
+    fn merge(&self, key: String, operator: &str, operand: String) -> Result<String> {
+        if self.namespaced {
+            return self.inner.merge(key, operator, operand);
+        }
+        write(&self.inner, &self.deadlines, &key, true, |_| {
+            self.inner.merge(key.clone(), operator, operand)
+        })
+    }
+    fn remove_range<R: RangeBounds<String>>(&self, range: R) -> Result<usize> {
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        if self.namespaced {
+            return self.inner.remove_range(bounds);
+        }
+        {
+            let shared = self.deadlines.read().unwrap();
+            if !shared.keys().any(|key| bounds.contains(key)) {
+                return self.inner.remove_range(bounds);
+            }
+        }
+        let mut deadlines = self.deadlines.write().unwrap();
+        let removed = self.inner.remove_range(range)?;
+        deadlines.retain(|key, _| !bounds.contains(key));
+        Ok(removed)
+    }
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        let mut pairs = self.inner.scan(range)?;
+        if !self.namespaced {
+            let now = Instant::now();
+            let deadlines = self.deadlines.read().unwrap();
+            pairs.retain(|(key, _)| deadlines.get(key).is_none_or(|&at| at > now));
+        }
+        Ok(pairs)
+    }
+    fn create_namespace(&self, name: &str) -> Result<()> {
+        self.inner.create_namespace(name)
+    }
+    fn namespace(&self, name: &str) -> Result<Self> {
+        Ok(ExpiringEngine {
+            inner: self.inner.namespace(name)?,
+            deadlines: self.deadlines.clone(),
+            namespaced: true,
+        })
+    }
+    fn drop_namespace(&self, name: &str) -> Result<()> {
+        self.inner.drop_namespace(name)
+    }
+    fn namespaces(&self) -> Result<Vec<String>> {
+        self.inner.namespaces()
+    }
+    fn watch<R: RangeBounds<String>>(&self, range: R) -> Result<Receiver<Change>> {
+        self.inner.watch(range)
+    }
+    fn changes(&self, since: u64) -> Result<Vec<Change>> {
+        self.inner.changes(since)
+    }
+    fn get_version(&self, key: String, seq: u64) -> Result<Option<String>> {
+        self.inner.get_version(key, seq)
+    }
+    fn history(&self, key: String) -> Result<Vec<Version>> {
+        self.inner.history(key)
+    }
+    fn stats(&self) -> Result<Stats> {
+        self.inner.stats()
+    }
+}
+
+fn bulk(value: Option<String>) -> Value {
+    value.map_or(Value::Nil, |value| Value::Bulk(value.into_bytes()))
+}
+
+fn invalid_expire_time() -> KvsError {
+    KvsError::InvalidRequest("invalid expire time in 'set' command".to_owned())
+}
+
+fn syntax_error() -> KvsError {
+    KvsError::InvalidRequest("syntax error".to_owned())
+}
+
+fn not_an_integer() -> KvsError {
+    KvsError::InvalidRequest("value is not an integer or out of range".to_owned())
+}
+
+/// Whether `text` matches the glob-style `pattern` of `SCAN MATCH`, with
+/// `*`, `?`, `[...]` classes and `\` escapes.
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // where the last `*` resumes the pattern, and the text it took up to
+    let mut star = None;
+    while t < text.len() {
+        let step = match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p + 1, t));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(1),
+            Some(b'[') => class(&pattern[p..], text[t]),
+            Some(b'\\') if p + 1 < pattern.len() => (pattern[p + 1] == text[t]).then_some(2),
+            Some(&c) => (c == text[t]).then_some(1),
+            None => None,
+        };
+        match (step, star) {
+            (Some(step), _) => {
+                p += step;
+                t += 1;
+            }
+            (None, Some((resume, taken))) => {
+                p = resume;
+                t = taken + 1;
+                star = Some((resume, taken + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Length of the `[...]` class `pattern` starts with if it matches `c`. An
+/// unterminated class is a literal `[`.
+fn class(pattern: &[u8], c: u8) -> Option<usize> {
+    let negate = pattern.get(1) == Some(&b'^');
+    let mut i = if negate { 2 } else { 1 };
+    let mut matched = false;
+    while i < pattern.len() && pattern[i] != b']' {
+        if pattern[i] == b'\\' && i + 1 < pattern.len() {
+            matched |= pattern[i + 1] == c;
+            i += 2;
+        } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' {
+            let (lo, hi) = (
+                pattern[i].min(pattern[i + 2]),
+                pattern[i].max(pattern[i + 2]),
+            );
+            matched |= (lo..=hi).contains(&c);
+            i += 3;
+        } else {
+            matched |= pattern[i] == c;
+            i += 1;
+        }
+    }
+    if i >= pattern.len() {
+        return (c == b'[').then_some(1);
+    }
+    (matched != negate).then_some(i + 1)
+}
@@ -0,0 +1,65 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::engines::bloom::hash;
+
+/// Consistent hash ring assigning keys to nodes.
+///
+/// Every node owns `vnodes` points of the ring and a key belongs to the node
+/// of the first point at or after its hash, so adding or removing a node
+/// only moves the keys of its points.
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    vnodes: usize,
+    points: BTreeMap<u64, String>,
+    nodes: BTreeSet<String>,
+}
+
+impl HashRing {
+    /// Creates an empty ring placing `vnodes` points per node.
+    pub fn new(vnodes: usize) -> HashRing {
+        HashRing {
+            vnodes: vnodes.max(1),
+            points: BTreeMap::new(),
+            nodes: BTreeSet::new(),
+        }
+    }
+
+    /// Adds `node`, returning whether it was new.
+    pub fn add(&mut self, node: &str) -> bool {
+        if !self.nodes.insert(node.to_owned()) {
+            return false;
+        }
+        for i in 0..self.vnodes {
+            self.points
+                .insert(hash(&format!("{}#{}", node, i)), node.to_owned());
+        }
+        true
+    }
+
+    /// Removes `node`, returning whether it was there.
+    pub fn remove(&mut self, node: &str) -> bool {
+        if !self.nodes.remove(node) {
+            return false;
+        }
+        self.points.retain(|_, owner| owner != node);
+        true
+    }
+
+    /// The node owning `key`, `None` if the ring is empty.
+    pub fn node(&self, key: &str) -> Option<&str> {
+        let h = hash(key);
+        self.points
+            .range(h..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, node)| node.as_str())
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &str> {
+        self.nodes.iter().map(String::as_str)
+    }
+
+    pub fn contains(&self, node: &str) -> bool {
+        self.nodes.contains(node)
+    }
+}
@@ -40,7 +40,7 @@ impl ThreadPool for SharedQueueThreadPool {
 }
 
 impl ThreadReceiver {
-    fn iter(&self) -> Iter<Job> {
+    fn iter(&self) -> Iter<'_, Job> {
         self.0.iter()
     }
 }
@@ -0,0 +1,336 @@
+//! TLS for connections of the native protocol, on rustls.
+//!
+//! A TLS connection is shared by its handles like a `TcpStream` is by its
+//! clones, so that one thread may read responses while others write
+//! requests. Records are read off the socket without holding the session,
+//! which only locks to decrypt and encrypt them.
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{IpAddr, Shutdown, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConnection, Connection, RootCertStore, ServerConnection};
+
+use crate::{KvsError, Result};
+
+/// Bytes read off the socket at a time.
+const RECORDS_LEN: usize = 16 << 10;
+
+/// What a client trusts servers by, and the certificate it shows servers
+/// that ask for one.
+#[derive(Clone)]
+pub struct TlsClientConfig {
+    config: Arc<rustls::ClientConfig>,
+    server_name: Option<ServerName<'static>>,
+}
+
+impl TlsClientConfig {
+    /// Trusts the servers whose certificate the CAs in the PEM file `ca`
+    /// signed, and shows the certificate chain and key in the PEM files of
+    /// `identity` to servers that ask for one.
+    pub fn new(ca: &Path, identity: Option<(&Path, &Path)>) -> Result<TlsClientConfig> {
+        let builder = rustls::ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?
+            .with_root_certificates(roots(ca)?);
+        let config = match identity {
+            Some((cert, key)) => builder
+                .with_client_auth_cert(certs(cert)?, private_key(key)?)
+                .map_err(tls_error)?,
+            None => builder.with_no_client_auth(),
+        };
+        Ok(TlsClientConfig {
+            config: Arc::new(config),
+            server_name: None,
+        })
+    }
+
+    /// Expects servers to show a certificate for `name` instead of the IP
+    /// address connected to.
+    pub fn with_server_name(mut self, name: &str) -> Result<TlsClientConfig> {
+        let name = ServerName::try_from(name.to_owned()).map_err(tls_error)?;
+        self.server_name = Some(name);
+        Ok(self)
+    }
+
+    /// Opens a session over `stream` to the server at `ip`, completing its
+    /// handshake.
+    pub fn connect(&self, stream: TcpStream, ip: IpAddr) -> Result<Stream> {
+        let name = self
+            .server_name
+            .clone()
+            .unwrap_or(ServerName::IpAddress(ip.into()));
+        let conn = ClientConnection::new(self.config.clone(), name).map_err(tls_error)?;
+        TlsStream::handshake(conn.into(), stream).map(Stream::Tls)
+    }
+}
+
+impl fmt::Debug for TlsClientConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsClientConfig")
+            .field("server_name", &self.server_name)
+            .finish()
+    }
+}
+
+/// The certificate a server shows, and the CAs it trusts clients by if it
+/// asks them for one.
+#[derive(Clone)]
+pub struct TlsServerConfig {
+    config: Arc<rustls::ServerConfig>,
+}
+
+impl TlsServerConfig {
+    /// Shows the certificate chain and key in the PEM files `cert` and
+    /// `key`. With `client_ca`, clients must show a certificate the CAs in
+    /// that PEM file signed.
+    pub fn new(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<TlsServerConfig> {
+        let builder = rustls::ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?;
+        let builder = match client_ca {
+            Some(ca) => {
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots(ca)?), provider())
+                        .build()
+                        .map_err(tls_error)?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder
+            .with_single_cert(certs(cert)?, private_key(key)?)
+            .map_err(tls_error)?;
+        Ok(TlsServerConfig {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Opens a session over `stream` to a client that connected, completing
+    /// its handshake.
+    pub fn accept(&self, stream: TcpStream) -> Result<Stream> {
+        let conn = ServerConnection::new(self.config.clone()).map_err(tls_error)?;
+        TlsStream::handshake(conn.into(), stream).map(Stream::Tls)
+    }
+}
+
+impl fmt::Debug for TlsServerConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsServerConfig").finish_non_exhaustive()
+    }
+}
+
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs(path)? {
+        roots.add(cert).map_err(tls_error)?;
+    }
+    Ok(roots)
+}
+
+fn certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(KvsError::Tls(format!(
+            "no certificate in {}",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+fn private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut BufReader::new(File::open(path)?))?
+        .ok_or_else(|| KvsError::Tls(format!("no private key in {}", path.display())))
+}
+
+fn tls_error(e: impl fmt::Display) -> KvsError {
+    KvsError::Tls(e.to_string())
+}
+
+/// Connection of the native protocol, in the clear or over TLS.
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    Tls(TlsStream),
+}
+
+impl Stream {
+    /// Another handle to the connection.
+    pub fn try_clone(&self) -> Result<Stream> {
+        Ok(match self {
+            Stream::Tcp(stream) => Stream::Tcp(stream.try_clone()?),
+            Stream::Tls(stream) => Stream::Tls(stream.try_clone()?),
+        })
+    }
+
+    /// The socket underneath, which reads encrypted records over TLS.
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Tcp(stream) => stream,
+            Stream::Tls(stream) => &stream.sock,
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        Ok(self.tcp().set_read_timeout(timeout)?)
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> Result<()> {
+        Ok(self.tcp().shutdown(how)?)
+    }
+}
+
+impl From<TcpStream> for Stream {
+    fn from(stream: TcpStream) -> Stream {
+        Stream::Tcp(stream)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// A TLS session over a socket, shared by the handles `try_clone` returns.
+///
+/// A peer closing the connection without notifying reads as the end of
+/// the stream: messages of the protocol are delimited, so a truncated one
+/// is an error of its own.
+pub struct TlsStream {
+    conn: Arc<Mutex<Connection>>,
+    /// Held while records go out, so that records sealed in turn by
+    /// different handles leave in that order.
+    sending: Arc<Mutex<()>>,
+    sock: TcpStream,
+    /// Records read off the socket that the session has no room for yet.
+    records: Vec<u8>,
+}
+
+impl TlsStream {
+    fn handshake(mut conn: Connection, mut sock: TcpStream) -> Result<TlsStream> {
+        while conn.is_handshaking() {
+            // certificates either side rejects fail as invalid data
+            conn.complete_io(&mut sock).map_err(|e| match e.kind() {
+                io::ErrorKind::InvalidData => KvsError::Tls(e.to_string()),
+                _ => KvsError::Io(e),
+            })?;
+        }
+        Ok(TlsStream {
+            conn: Arc::new(Mutex::new(conn)),
+            sending: Arc::new(Mutex::new(())),
+            sock,
+            records: Vec::new(),
+        })
+    }
+
+    /// Another handle to the session.
+    pub fn try_clone(&self) -> Result<TlsStream> {
+        Ok(TlsStream {
+            conn: self.conn.clone(),
+            sending: self.sending.clone(),
+            sock: self.sock.try_clone()?,
+            records: Vec::new(),
+        })
+    }
+
+    /// Sends the records `conn` has sealed, letting go of it first.
+    fn send_records(&self, mut conn: MutexGuard<Connection>) -> io::Result<()> {
+        let mut records = Vec::new();
+        while conn.wants_write() {
+            conn.write_tls(&mut records)?;
+        }
+        if records.is_empty() {
+            return Ok(());
+        }
+        let _sending = self.sending.lock().unwrap();
+        drop(conn);
+        (&self.sock).write_all(&records)
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut conn = self.conn.lock().unwrap();
+            match conn.reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                read => return read,
+            }
+            if self.records.is_empty() {
+                drop(conn);
+                self.records.resize(RECORDS_LEN, 0);
+                match (&self.sock).read(&mut self.records) {
+                    Ok(n) => self.records.truncate(n),
+                    Err(e) => {
+                        self.records.clear();
+                        return Err(e);
+                    }
+                }
+                conn = self.conn.lock().unwrap();
+            }
+            // the session holds so much plaintext at a time, so records are
+            // handed to it as what they decrypt to is read
+            let mut rest = &self.records[..];
+            conn.read_tls(&mut rest)?;
+            let consumed = self.records.len() - rest.len();
+            self.records.drain(..consumed);
+            conn.process_new_packets()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            // alerts, and answers to key updates
+            self.send_records(conn)?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let n = conn.writer().write(buf)?;
+        self.send_records(conn)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl fmt::Debug for TlsStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsStream")
+            .field("sock", &self.sock)
+            .finish_non_exhaustive()
+    }
+}
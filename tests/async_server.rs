@@ -0,0 +1,183 @@
+use assert_cmd::prelude::*;
+use std::process::Command;
+use tempfile::TempDir;
+
+const ADDR: &str = "127.0.0.1:4028";
+
+// Without the async feature the async runtime is refused.
+#[cfg(not(feature = "async"))]
+#[test]
+fn server_async_runtime_unavailable() {
+    let dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", ADDR, "--runtime", "async"])
+        .current_dir(dir.path())
+        .assert()
+        .failure();
+}
+
+#[cfg(feature = "async")]
+mod runtime {
+    use super::*;
+    use kvs::{
+        AsyncKvsClient, AsyncKvsEngine, KvStore, KvsClient, KvsEngine, KvsError, Reply, Request,
+        Response, MAX_FRAME_LEN,
+    };
+    use std::io::Write;
+    use std::net::TcpStream;
+    use std::ops::Bound;
+    use std::path::Path;
+    use std::process::Child;
+    use std::thread;
+    use std::time::Duration;
+    use tokio::runtime::Runtime;
+
+    /// Process, killed when dropped so that a failing test frees its port.
+    struct Server(Child);
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    fn server(dir: &Path, args: &[&str]) -> Server {
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(args)
+            .current_dir(dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_millis(500));
+        assert!(child.try_wait().unwrap().is_none(), "kvs-server exited");
+        Server(child)
+    }
+
+    // Many clients, most of them idle, share the tasks of one async server,
+    // and blocking clients and watches work as with threads. Requests may
+    // arrive over many reads, up to the length of a frame.
+    #[test]
+    fn server_async_runtime() {
+        let dir = TempDir::new().unwrap();
+        let _server = server(dir.path(), &["--addr", ADDR, "--runtime", "async"]);
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on(async {
+            let mut idle = Vec::new();
+            for _ in 0..1000 {
+                idle.push(AsyncKvsClient::connect(ADDR).await.unwrap());
+            }
+            let writers: Vec<_> = (0..20)
+                .map(|i| {
+                    tokio::spawn(async move {
+                        let mut client = AsyncKvsClient::connect(ADDR).await.unwrap();
+                        for j in 0..50 {
+                            let key = format!("key{}-{}", i, j);
+                            client.set(key, format!("value{}", j)).await.unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for writer in writers {
+                writer.await.unwrap();
+            }
+            let client = idle.last_mut().unwrap();
+            assert_eq!(
+                client.get("key3-7".to_owned()).await.unwrap(),
+                Some("value7".to_owned())
+            );
+            client.remove("key3-7".to_owned()).await.unwrap();
+            assert_eq!(client.get("key3-7".to_owned()).await.unwrap(), None);
+            assert!(matches!(
+                client.remove("key3-7".to_owned()).await,
+                Err(KvsError::KeyNotFound)
+            ));
+        });
+
+        let client = KvsClient::connect(ADDR).unwrap();
+        let watch = Request::Watch {
+            start: Bound::Included("watched".to_owned()),
+            end: Bound::Included("watched".to_owned()),
+            ns: None,
+        };
+        let changes = client.stream(&watch).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        client.set("watched".to_owned(), "1".to_owned()).unwrap();
+        match changes
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .unwrap()
+        {
+            Response::Ok(Reply::Change(change)) => assert_eq!(change.value.as_deref(), Some("1")),
+            response => panic!("unexpected response {:?}", response),
+        }
+        let scan = Request::Scan {
+            start: Bound::Unbounded,
+            end: Bound::Unbounded,
+            ns: None,
+        };
+        match client.request(&scan).unwrap() {
+            Response::Ok(Reply::Pairs(pairs)) => assert_eq!(pairs.len(), 20 * 50),
+            response => panic!("unexpected response {:?}", response),
+        }
+
+        // pipelined requests, answered as they complete
+        let sets: Vec<Request> = (0..500)
+            .map(|i| Request::Set {
+                key: format!("bulk{}", i),
+                value: i.to_string(),
+                ns: None,
+            })
+            .collect();
+        for response in client.request_all(&sets) {
+            assert!(matches!(response.unwrap(), Response::Ok(Reply::Done)));
+        }
+        let gets: Vec<Request> = (0..500)
+            .map(|i| Request::Get {
+                key: format!("bulk{}", i),
+                ns: None,
+            })
+            .collect();
+        for (i, response) in client.request_all(&gets).into_iter().enumerate() {
+            assert!(
+                matches!(response.unwrap(), Response::Ok(Reply::Value(v)) if v == i.to_string())
+            );
+        }
+
+        let large = "\"{[\\".repeat(1 << 18);
+        client.set("large".to_owned(), large.clone()).unwrap();
+        assert_eq!(client.get("large".to_owned()).unwrap(), Some(large));
+        let mut stream = TcpStream::connect(ADDR).unwrap();
+        stream.write_all(b"{\"key\":\"").unwrap();
+        let chunk = vec![b'x'; 1 << 20];
+        let chunks = 2 * MAX_FRAME_LEN / chunk.len();
+        assert!((0..chunks).any(|_| stream.write_all(&chunk).is_err()));
+    }
+
+    #[test]
+    fn async_engine() {
+        let dir = TempDir::new().unwrap();
+        let engine = AsyncKvsEngine::new(KvStore::open(dir.path()).unwrap());
+        Runtime::new().unwrap().block_on(async {
+            engine
+                .set("key1".to_owned(), "value1".to_owned())
+                .await
+                .unwrap();
+            engine
+                .set("key2".to_owned(), "value2".to_owned())
+                .await
+                .unwrap();
+            assert_eq!(
+                engine.get("key1".to_owned()).await.unwrap(),
+                Some("value1".to_owned())
+            );
+            engine.remove("key1".to_owned()).await.unwrap();
+            assert_eq!(engine.get("key1".to_owned()).await.unwrap(), None);
+            let pairs = engine.scan(..).await.unwrap();
+            assert_eq!(pairs, vec![("key2".to_owned(), "value2".to_owned())]);
+            let keys = engine.run(|engine| engine.scan(..).map(|pairs| pairs.len()));
+            assert_eq!(keys.await.unwrap(), 1);
+        });
+    }
+}
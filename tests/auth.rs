@@ -0,0 +1,280 @@
+use assert_cmd::prelude::*;
+use kvs::auth::{hash_password, hash_token};
+use kvs::{
+    prefix_range, Credentials, ErrorCode, KvStore, KvsClient, KvsClientOptions, KvsEngine,
+    KvsError, Reply, Request, Response,
+};
+use predicates::str::contains;
+use serde_json::json;
+use std::fs;
+use std::ops::Bound;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const ADDR: &str = "127.0.0.1:4044";
+
+/// Process, killed when dropped so that a failing test frees its port.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn server(dir: &Path, args: &[&str]) -> Server {
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(args)
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+    assert!(child.try_wait().unwrap().is_none(), "kvs-server exited");
+    Server(child)
+}
+
+fn client(credentials: Option<Credentials>) -> kvs::Result<KvsClient> {
+    let options = KvsClientOptions {
+        read_timeout: Some(Duration::from_secs(5)),
+        credentials,
+        ..KvsClientOptions::default()
+    };
+    KvsClient::connect_with_options(ADDR, options)
+}
+
+fn token(token: &str) -> Option<Credentials> {
+    Some(Credentials::Token(token.to_owned()))
+}
+
+fn unauthorized(response: kvs::Result<Response>) -> bool {
+    matches!(
+        response,
+        Ok(Response::Error {
+            code: ErrorCode::Unauthorized,
+            ..
+        })
+    )
+}
+
+// Clients authenticate with a password or a token, and are served what
+// the grants of their user allow.
+#[test]
+fn auth() {
+    let dir = TempDir::new().unwrap();
+    KvStore::open(dir.path())
+        .unwrap()
+        .create_namespace("users")
+        .unwrap();
+    let users = dir.path().join("users.json");
+    let users_file = json!({
+        "alice": {
+            "password": hash_password("secret").unwrap(),
+            "grants": [{ "permission": "admin" }],
+        },
+        "app": {
+            "token": hash_token("app-token"),
+            "grants": [
+                { "prefix": "app/", "permission": "write" },
+                { "namespace": "users", "permission": "read" },
+            ],
+        },
+    });
+    fs::write(&users, users_file.to_string()).unwrap();
+    let _server = server(
+        dir.path(),
+        &["--addr", ADDR, "--auth-file", users.to_str().unwrap()],
+    );
+
+    // clients that do not authenticate only get to say hello
+    let anonymous = client(None).unwrap();
+    assert!(matches!(
+        anonymous.get("key1".to_owned()),
+        Err(KvsError::Unauthorized(message)) if message == "authentication required"
+    ));
+    let wrong = Credentials::Password {
+        user: "alice".to_owned(),
+        password: "guess".to_owned(),
+    };
+    assert!(matches!(
+        client(Some(wrong)),
+        Err(KvsError::Unauthorized(_))
+    ));
+    assert!(matches!(
+        client(token("stolen")),
+        Err(KvsError::Unauthorized(_))
+    ));
+
+    let alice = Credentials::Password {
+        user: "alice".to_owned(),
+        password: "secret".to_owned(),
+    };
+    let admin = client(Some(alice)).unwrap();
+    admin.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    admin
+        .request(&Request::Set {
+            key: "user1".to_owned(),
+            value: "name1".to_owned(),
+            ns: Some("users".to_owned()),
+        })
+        .unwrap();
+    assert!(matches!(
+        admin.request(&Request::Stats { ns: None }),
+        Ok(Response::Ok(Reply::Stats(_)))
+    ));
+
+    let app = client(token("app-token")).unwrap();
+    app.set("app/a".to_owned(), "1".to_owned()).unwrap();
+    assert_eq!(app.get("app/a".to_owned()).unwrap().as_deref(), Some("1"));
+    assert!(matches!(
+        app.get("key1".to_owned()),
+        Err(KvsError::Unauthorized(message)) if message == r#"app may not read key "key1""#
+    ));
+    assert!(matches!(
+        app.set("key1".to_owned(), "mine".to_owned()),
+        Err(KvsError::Unauthorized(_))
+    ));
+    // requests on several keys need a grant covering them all
+    let (start, end) = prefix_range("app/");
+    assert!(matches!(
+        app.request(&Request::Scan { start, end, ns: None }),
+        Ok(Response::Ok(Reply::Pairs(pairs))) if pairs.len() == 1
+    ));
+    let everything = Request::Scan {
+        start: Bound::Unbounded,
+        end: Bound::Unbounded,
+        ns: None,
+    };
+    assert!(unauthorized(app.request(&everything)));
+    assert!(unauthorized(app.request(&Request::RmPrefix {
+        prefix: "ap".to_owned(),
+        ns: None,
+    })));
+    assert!(unauthorized(app.request(&Request::Stats { ns: None })));
+    assert!(unauthorized(app.request(&Request::AddNode {
+        addr: "127.0.0.1:4999".to_owned(),
+    })));
+    // grants on a namespace
+    let user1 = |value: Option<&str>| match value {
+        Some(value) => Request::Set {
+            key: "user1".to_owned(),
+            value: value.to_owned(),
+            ns: Some("users".to_owned()),
+        },
+        None => Request::Get {
+            key: "user1".to_owned(),
+            ns: Some("users".to_owned()),
+        },
+    };
+    assert!(matches!(
+        app.request(&user1(None)),
+        Ok(Response::Ok(Reply::Value(value))) if value == "name1"
+    ));
+    assert!(unauthorized(app.request(&user1(Some("name2")))));
+    // pipelined requests are checked one by one
+    let responses = app.request_all(&[
+        Request::Set {
+            key: "app/b".to_owned(),
+            value: "2".to_owned(),
+            ns: None,
+        },
+        Request::Get {
+            key: "key1".to_owned(),
+            ns: None,
+        },
+    ]);
+    assert!(matches!(responses[0], Ok(Response::Ok(Reply::Done))));
+    assert!(matches!(
+        responses[1],
+        Ok(Response::Error {
+            code: ErrorCode::Unauthorized,
+            ..
+        })
+    ));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "app/b", "--addr", ADDR, "--token", "app-token"])
+        .current_dir(dir.path())
+        .assert()
+        .success()
+        .stdout("2\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", ADDR, "--user", "alice"])
+        .env("KVS_PASSWORD", "secret")
+        .current_dir(dir.path())
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", ADDR, "--token", "app-token"])
+        .current_dir(dir.path())
+        .assert()
+        .failure()
+        .stderr(contains("app may not read"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", ADDR])
+        .current_dir(dir.path())
+        .assert()
+        .failure()
+        .stderr(contains("authentication required"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", ADDR, "--user", "alice"])
+        .env_remove("KVS_PASSWORD")
+        .current_dir(dir.path())
+        .assert()
+        .failure()
+        .stderr(contains("KVS_PASSWORD"));
+}
+
+// kvs-server prints the hashes of the users file, refuses a file it cannot
+// read, and clusters whose nodes have no token to authenticate with.
+#[test]
+fn auth_file() {
+    let dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("--hash-token")
+        .with_stdin()
+        .buffer("app-token\n")
+        .assert()
+        .success()
+        .stdout(format!("{}\n", hash_token("app-token")));
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("--hash-password")
+        .with_stdin()
+        .buffer("secret\n")
+        .assert()
+        .success()
+        .stdout(contains("$argon2id$"));
+
+    let users = dir.path().join("users.json");
+    fs::write(&users, r#"{ "nobody": { "grants": [] } }"#).unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4045", "--auth-file"])
+        .arg(&users)
+        .current_dir(dir.path())
+        .assert()
+        .failure()
+        .stderr(contains("neither password nor token"));
+    fs::write(&users, "{}").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4046", "--auth-file", "users.json"])
+        .args(["--cluster", "127.0.0.1:4046"])
+        .env_remove("KVS_CLUSTER_TOKEN")
+        .current_dir(dir.path())
+        .assert()
+        .failure()
+        .stderr(contains("--cluster-token"));
+}
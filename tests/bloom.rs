@@ -0,0 +1,83 @@
+use kvs::{KvsEngine, LsmKvsEngine, LsmOptions, Result, SledKvsEngine, SledOptions};
+use tempfile::TempDir;
+
+fn stat(stats: &kvs::Stats, name: &str) -> f64 {
+    stats[name].parse().unwrap()
+}
+
+// Misses should mostly be answered by the filter, and keys inserted past the
+// initial filter capacity must never be reported missing.
+#[test]
+fn sled_filter_short_circuits_misses() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = SledOptions {
+        bloom_false_positive_rate: 0.01,
+        ..SledOptions::default()
+    };
+    let store = SledKvsEngine::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..5000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..5000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        assert_eq!(store.get(format!("missing{}", i))?, None);
+    }
+    let stats = store.stats()?;
+    assert_eq!(stats["bloom_false_positive_rate"], "0.01");
+    assert!(stat(&stats, "bloom_negatives") > 4500.0);
+    assert!(stat(&stats, "bloom_observed_false_positive_rate") < 0.05);
+
+    // the filter is saved on close and loaded on open
+    drop(store);
+    assert!(temp_dir.path().join("bloom.filter").exists());
+    let store = SledKvsEngine::open_with_options(temp_dir.path(), options)?;
+    assert!(!temp_dir.path().join("bloom.filter").exists());
+    for i in 0..5000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.get("missing".to_owned())?, None);
+    Ok(())
+}
+
+// A filter built for another false positive rate is rebuilt on open.
+#[test]
+fn sled_filter_rebuilt_for_new_rate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let options = SledOptions {
+        bloom_false_positive_rate: 0.001,
+        ..SledOptions::default()
+    };
+    let store = SledKvsEngine::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.stats()?["bloom_false_positive_rate"], "0.001");
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn lsm_filter_short_circuits_misses() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = LsmOptions {
+        memtable_size: 4 * 1024,
+        bloom_false_positive_rate: 0.01,
+        ..LsmOptions::default()
+    };
+    let store = LsmKvsEngine::open_with_options(temp_dir.path(), options)?;
+    for i in 0..2000 {
+        store.set(format!("key{:05}", i), format!("value{}", i))?;
+    }
+    for i in 0..2000 {
+        assert_eq!(
+            store.get(format!("key{:05}", i))?,
+            Some(format!("value{}", i))
+        );
+        assert_eq!(store.get(format!("key{:05}x", i))?, None);
+    }
+    let stats = store.stats()?;
+    assert!(stat(&stats, "bloom_negatives") > 0.0);
+    assert!(stat(&stats, "bloom_observed_false_positive_rate") < 0.05);
+    Ok(())
+}
@@ -0,0 +1,162 @@
+use std::ops::Bound;
+
+use kvs::{
+    prefix_range, CachedEngine, KvStore, KvStoreOptions, KvsEngine, LsmKvsEngine, Result,
+    Retention, SledKvsEngine,
+};
+use tempfile::TempDir;
+
+fn keys(store: &impl KvsEngine) -> Result<Vec<String>> {
+    Ok(store.scan(..)?.into_iter().map(|(key, _)| key).collect())
+}
+
+// Bulk deletes remove exactly the keys in range and return their count, and
+// scans up to a limit stop at the first keys left.
+fn bulk_delete(store: impl KvsEngine) -> Result<()> {
+    for key in ["a", "user", "user:1", "user:2", "user:3", "userx", "z"] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+    // read through any cache before removing
+    assert_eq!(store.get("user:2".to_owned())?, Some("value".to_owned()));
+
+    assert_eq!(store.remove_prefix("user:")?, 3);
+    assert_eq!(store.remove_prefix("user:")?, 0);
+    assert_eq!(store.get("user:2".to_owned())?, None);
+    assert_eq!(keys(&store)?, vec!["a", "user", "userx", "z"]);
+    let first = store.scan_limit("b".to_owned().., 2)?;
+    assert_eq!(
+        first.iter().map(|(key, _)| key).collect::<Vec<_>>(),
+        ["user", "userx"]
+    );
+
+    assert_eq!(store.remove_range("b".to_owned().."userx".to_owned())?, 1);
+    assert_eq!(keys(&store)?, vec!["a", "userx", "z"]);
+
+    store.set("user:1".to_owned(), "again".to_owned())?;
+    assert_eq!(store.get("user:1".to_owned())?, Some("again".to_owned()));
+    assert_eq!(store.remove_range(..)?, 4);
+    assert!(keys(&store)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn kvs_bulk_delete() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    bulk_delete(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_bulk_delete() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    bulk_delete(SledKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn lsm_bulk_delete() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    bulk_delete(LsmKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn cached_bulk_delete() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    bulk_delete(CachedEngine::new(
+        KvStore::open(temp_dir.path())?,
+        1024 * 1024,
+    ))
+}
+
+#[test]
+fn prefix_ranges() {
+    assert_eq!(
+        prefix_range("ab"),
+        (
+            Bound::Included("ab".to_owned()),
+            Bound::Excluded("ac".to_owned())
+        )
+    );
+    assert_eq!(
+        prefix_range(""),
+        (Bound::Included(String::new()), Bound::Unbounded)
+    );
+    // the last char has no successor, so the one before it is bumped
+    assert_eq!(
+        prefix_range("a\u{10ffff}"),
+        (
+            Bound::Included("a\u{10ffff}".to_owned()),
+            Bound::Excluded("b".to_owned())
+        )
+    );
+    // skips the surrogate code points
+    assert_eq!(
+        prefix_range("\u{d7ff}"),
+        (
+            Bound::Included("\u{d7ff}".to_owned()),
+            Bound::Excluded("\u{e000}".to_owned())
+        )
+    );
+}
+
+// The range tombstone of KvStore survives reopening and compaction without
+// removing keys written after it.
+#[test]
+fn kvs_range_tombstone_persists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{:03}", i), "value".to_owned())?;
+    }
+    assert_eq!(store.remove_prefix("key0")?, 100);
+    store.set("key001".to_owned(), "new".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(keys(&store)?, vec!["key001"]);
+    assert_eq!(store.get("key001".to_owned())?, Some("new".to_owned()));
+
+    // overwrite another key until compaction runs
+    for iter in 0..2000 {
+        store.set("other".to_owned(), format!("{:0>1000}", iter))?;
+    }
+    drop(store);
+    let log_size = temp_dir.path().join("db").metadata()?.len();
+    assert!(log_size < 2 * 1024 * 1024, "no compaction detected");
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(keys(&store)?, vec!["key001", "other"]);
+    assert_eq!(store.get("key001".to_owned())?, Some("new".to_owned()));
+    Ok(())
+}
+
+// With history on, the keys removed by a range keep their older versions.
+#[test]
+fn kvs_range_tombstone_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        retention: Some(Retention::Versions(10)),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.remove_prefix("key")?, 2);
+    let history = store.history("key1".to_owned())?;
+    let values: Vec<Option<String>> = history.iter().map(|v| v.value.clone()).collect();
+    assert_eq!(values, vec![Some("value1".to_owned()), None]);
+
+    // overwrite another key until compaction runs
+    for iter in 0..2000 {
+        store.set("other".to_owned(), format!("{:0>1000}", iter))?;
+    }
+    assert_eq!(store.history("key1".to_owned())?, history);
+    drop(store);
+    let log_size = temp_dir.path().join("db").metadata()?.len();
+    assert!(log_size < 2 * 1024 * 1024, "no compaction detected");
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.history("key1".to_owned())?, history);
+    assert_eq!(store.history("key2".to_owned())?.len(), 2);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
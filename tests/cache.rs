@@ -0,0 +1,116 @@
+use std::sync::{Arc, Barrier};
+use std::thread;
+
+use kvs::{CachePolicy, CachedEngine, KvStore, KvsEngine, Result, SledKvsEngine};
+use tempfile::TempDir;
+
+fn stat(stats: &kvs::Stats, name: &str) -> u64 {
+    stats[name].parse().unwrap()
+}
+
+// Repeated reads of a key should be served from the cache.
+#[test]
+fn counts_hits_and_misses() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = CachedEngine::new(KvStore::open(temp_dir.path())?, 1024 * 1024);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    for _ in 0..10 {
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+    assert_eq!(store.get("missing".to_owned())?, None);
+
+    let stats = store.stats()?;
+    assert_eq!(stat(&stats, "cache_hits"), 9);
+    assert_eq!(stat(&stats, "cache_misses"), 2);
+    assert_eq!(stat(&stats, "cache_entries"), 1);
+    assert_eq!(stats["cache_policy"], "lru");
+    // stats of the inner engine are passed through
+    assert_eq!(stats["keys"], "1");
+    Ok(())
+}
+
+// Writes must never leave a stale value in the cache.
+#[test]
+fn writes_invalidate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = CachedEngine::new(SledKvsEngine::open(temp_dir.path())?, 1024 * 1024);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
+    Ok(())
+}
+
+#[test]
+fn lru_evicts_least_recently_used() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // room for about ten entries
+    let store = CachedEngine::new(KvStore::open(temp_dir.path())?, 10 * 80);
+    for i in 0..20 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        store.get(format!("key{}", i))?;
+    }
+    let stats = store.stats()?;
+    assert!(stat(&stats, "cache_bytes") <= stat(&stats, "cache_capacity_bytes"));
+
+    // the latest keys are cached, the first ones were evicted
+    store.get("key19".to_owned())?;
+    assert_eq!(stat(&store.stats()?, "cache_hits"), 1);
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(stat(&store.stats()?, "cache_hits"), 1);
+    Ok(())
+}
+
+#[test]
+fn lfu_keeps_frequently_used() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store =
+        CachedEngine::with_policy(KvStore::open(temp_dir.path())?, 10 * 80, CachePolicy::Lfu);
+    store.set("hot".to_owned(), "value".to_owned())?;
+    for _ in 0..5 {
+        store.get("hot".to_owned())?;
+    }
+    for i in 0..50 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        store.get(format!("key{}", i))?;
+    }
+    let hits = stat(&store.stats()?, "cache_hits");
+    assert_eq!(store.get("hot".to_owned())?, Some("value".to_owned()));
+    assert_eq!(stat(&store.stats()?, "cache_hits"), hits + 1);
+    Ok(())
+}
+
+// Readers racing a writer must end up seeing the last written value.
+#[test]
+fn concurrent_reads_and_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = CachedEngine::new(KvStore::open(temp_dir.path())?, 1024 * 1024);
+    store.set("key".to_owned(), "0".to_owned())?;
+    let barrier = Arc::new(Barrier::new(5));
+    let mut handles = Vec::new();
+    for _ in 0..4 {
+        let store = store.clone();
+        let barrier = barrier.clone();
+        handles.push(thread::spawn(move || {
+            barrier.wait();
+            for _ in 0..1000 {
+                store.get("key".to_owned()).unwrap();
+            }
+        }));
+    }
+    barrier.wait();
+    for i in 1..=1000 {
+        store.set("key".to_owned(), i.to_string())?;
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("key".to_owned())?, Some("1000".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,142 @@
+use kvs::{
+    Change, KvStore, KvStoreOptions, KvsEngine, KvsError, LsmKvsEngine, LsmOptions, Result,
+    SledKvsEngine, SledOptions,
+};
+use tempfile::TempDir;
+
+fn keys(changes: &[Change]) -> Vec<(&str, Option<&str>)> {
+    changes
+        .iter()
+        .map(|c| (c.key.as_str(), c.value.as_deref()))
+        .collect()
+}
+
+// Every write gets the next sequence number, and a consumer can resume
+// after any of them, also after reopening, until it is cut off.
+fn changes<E: KvsEngine>(open: impl Fn() -> Result<E>) -> Result<()> {
+    let store = open()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.append("key1".to_owned(), "+".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.remove_prefix("key")?, 1);
+
+    let all = store.changes(0)?;
+    assert_eq!(
+        keys(&all),
+        vec![
+            ("key1", Some("value1")),
+            ("key2", Some("value2")),
+            ("key1", Some("value1+")),
+            ("key2", None),
+            ("key1", None),
+        ]
+    );
+    assert!(all.windows(2).all(|w| w[0].seq + 1 == w[1].seq));
+    assert_eq!(store.changes(all[2].seq)?, all[3..]);
+    assert!(store.changes(all[4].seq)?.is_empty());
+    drop(store);
+
+    let store = open()?;
+    assert_eq!(store.changes(0)?, all);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let resumed = store.changes(all[4].seq)?;
+    assert_eq!(keys(&resumed), vec![("key3", Some("value3"))]);
+    assert_eq!(resumed[0].seq, all[4].seq + 1);
+
+    // only the last 100 changes are sure to be kept
+    for i in 0..300 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    assert!(matches!(
+        store.changes(0),
+        Err(KvsError::ChangesTruncated(_))
+    ));
+    let last = store.changes(resumed[0].seq + 200)?;
+    assert_eq!(last.len(), 100);
+    assert_eq!(last[99].key, "key299");
+    Ok(())
+}
+
+#[test]
+fn kvs_changes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        change_log: Some(100),
+        ..KvStoreOptions::default()
+    };
+    changes(|| KvStore::open_with_options(temp_dir.path(), options.clone()))
+}
+
+#[test]
+fn sled_changes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = SledOptions {
+        change_log: Some(100),
+        ..SledOptions::default()
+    };
+    changes(|| SledKvsEngine::open_with_options(temp_dir.path(), options.clone()))
+}
+
+#[test]
+fn lsm_changes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = LsmOptions {
+        change_log: Some(100),
+        ..LsmOptions::default()
+    };
+    changes(|| LsmKvsEngine::open_with_options(temp_dir.path(), options.clone()))
+}
+
+#[test]
+fn sled_namespace_changes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = SledOptions {
+        change_log: Some(100),
+        ..SledOptions::default()
+    };
+    let store = SledKvsEngine::open_with_options(temp_dir.path(), options)?;
+    store.create_namespace("users")?;
+    let users = store.namespace("users")?;
+    store.set("key1".to_owned(), "root".to_owned())?;
+    users.set("key1".to_owned(), "user".to_owned())?;
+    assert_eq!(keys(&users.changes(0)?), vec![("key1", Some("user"))]);
+    assert_eq!(keys(&store.changes(0)?), vec![("key1", Some("root"))]);
+    Ok(())
+}
+
+#[test]
+fn changes_need_change_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(
+        store.changes(0),
+        Err(KvsError::UnsupportedOperation)
+    ));
+    Ok(())
+}
+
+// Sequence numbers keep increasing across compaction and reopening.
+#[test]
+fn kvs_seq_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let changes = store.watch(..)?;
+    store.set("key2".to_owned(), "value".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let last_seq = changes.try_iter().last().unwrap().seq;
+    drop(changes);
+    // compact, dropping both records of key2
+    for iter in 0..2000 {
+        store.set("key1".to_owned(), format!("{:0>1000}", iter))?;
+    }
+    drop(store);
+    let log_size = temp_dir.path().join("db").metadata()?.len();
+    assert!(log_size < 2 * 1024 * 1024, "no compaction detected");
+
+    let store = KvStore::open(temp_dir.path())?;
+    let changes = store.watch(..)?;
+    store.set("key3".to_owned(), "value".to_owned())?;
+    assert_eq!(changes.try_recv().unwrap().seq, last_seq + 2001);
+    Ok(())
+}
@@ -1,7 +1,10 @@
+#![allow(clippy::needless_borrows_for_generic_args)]
+
 use assert_cmd::prelude::*;
-use predicates::str::{contains, is_empty};
+use predicates::str::{contains, is_empty, is_match};
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -164,6 +167,7 @@ fn cli_log_configuration() {
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().unwrap();
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -184,6 +188,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
@@ -203,6 +208,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
@@ -224,6 +230,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -305,6 +312,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -335,3 +343,293 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_access_server_lsm_engine() {
+    cli_access_server("lsm", "127.0.0.1:4006");
+}
+
+#[test]
+fn cli_history() {
+    let addr = "127.0.0.1:4007";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--history-versions", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    for value in ["value1", "value2", "value3"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "key1", value, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rm", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["history", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_match(r"^3\t\d+\tvalue3\n4\t\d+\t<removed>\n$").unwrap());
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_incr_append() {
+    let addr = "127.0.0.1:4008";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+        cmd
+    };
+    client(&["incr", "counter"])
+        .assert()
+        .success()
+        .stdout("1\n");
+    client(&["incr", "counter", "5"])
+        .assert()
+        .success()
+        .stdout("6\n");
+    client(&["decr", "counter", "2"])
+        .assert()
+        .success()
+        .stdout("4\n");
+    client(&["decr", "other", "--initial", "10"])
+        .assert()
+        .success()
+        .stdout("9\n");
+
+    client(&["append", "log", "a"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    client(&["append", "log", "b"]).assert().success();
+    client(&["get", "log"]).assert().success().stdout("ab\n");
+    client(&["incr", "log"]).assert().failure();
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_namespaces() {
+    let addr = "127.0.0.1:4009";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "sled", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+        cmd
+    };
+    client(&["set", "key1", "root"]).assert().success();
+    client(&["set", "key1", "users", "--ns", "users"])
+        .assert()
+        .success();
+    client(&["--ns", "users", "get", "key1"])
+        .assert()
+        .success()
+        .stdout("users\n");
+    client(&["get", "key1"]).assert().success().stdout("root\n");
+    client(&["get", "key1", "--ns", "orders"])
+        .assert()
+        .failure()
+        .stderr(contains("namespace not found"));
+    client(&["rm", "key1", "--ns", "users"]).assert().success();
+    client(&["rm", "key1", "--ns", "users"]).assert().failure();
+    client(&["get", "key1"]).assert().success().stdout("root\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_rm_prefix() {
+    let addr = "127.0.0.1:4010";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+        cmd
+    };
+    for key in ["user:1", "user:2", "user:3", "order:1"] {
+        client(&["set", key, "value"]).assert().success();
+    }
+    client(&["rm", "key1", "--prefix", "user:"])
+        .assert()
+        .failure();
+    client(&["rm", "--prefix", "user:"])
+        .assert()
+        .success()
+        .stdout("3\n");
+    client(&["rm", "--prefix", "user:"])
+        .assert()
+        .success()
+        .stdout("0\n");
+    client(&["get", "user:2"])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    client(&["get", "order:1"])
+        .assert()
+        .success()
+        .stdout("value\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_watch() {
+    let addr = "127.0.0.1:4011";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+        cmd
+    };
+    let mut watcher = client(&["watch", "--prefix", "user:"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+    client(&["set", "user:1", "value1"]).assert().success();
+    client(&["set", "order:1", "value2"]).assert().success();
+    client(&["rm", "user:1"]).assert().success();
+
+    let mut lines = BufReader::new(watcher.stdout.take().unwrap()).lines();
+    let line = lines.next().unwrap().unwrap();
+    assert!(line.ends_with("\tuser:1\tvalue1"), "{}", line);
+    let line = lines.next().unwrap().unwrap();
+    assert!(line.ends_with("\tuser:1\t<removed>"), "{}", line);
+    watcher.kill().unwrap();
+    watcher.wait().unwrap();
+
+    // the server is still serving after the watcher hung up
+    thread::sleep(Duration::from_secs(1));
+    client(&["set", "user:2", "value3"]).assert().success();
+    client(&["get", "user:2"])
+        .assert()
+        .success()
+        .stdout("value3\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_changes() {
+    let addr = "127.0.0.1:4012";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "sled", "--change-log", "100", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+        cmd
+    };
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["set", "key2", "value2"]).assert().success();
+
+    // resume after the first change, then follow new ones
+    let mut consumer = client(&["changes", "--since", "1"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut lines = BufReader::new(consumer.stdout.take().unwrap()).lines();
+    assert_eq!(lines.next().unwrap().unwrap(), "2\tkey2\tvalue2");
+    client(&["rm", "key1"]).assert().success();
+    assert_eq!(lines.next().unwrap().unwrap(), "3\tkey1\t<removed>");
+    consumer.kill().unwrap();
+    consumer.wait().unwrap();
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
@@ -0,0 +1,381 @@
+use assert_cmd::prelude::*;
+use kvs::{
+    read_frame, Capability, Envelope, ErrorCode, Frame, KvsClient, KvsClientOptions, KvsError,
+    Reply, Request, Response, Welcome, PROTOCOL_VERSION,
+};
+use predicates::str::contains;
+use serde::Deserialize;
+use serde_json::Deserializer;
+use std::collections::BTreeSet;
+use std::io::{BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::ops::Bound;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+const SERVERS: [&str; 3] = ["127.0.0.1:4023", "127.0.0.1:4024", "127.0.0.1:4025"];
+const SERVER: &str = "127.0.0.1:4026";
+const SILENT: &str = "127.0.0.1:4027";
+const PIPELINED: &str = "127.0.0.1:4033";
+const UNANSWERING: [&str; 2] = ["127.0.0.1:4047", "127.0.0.1:4048"];
+const UNANSWERING_PIPELINED: &str = "127.0.0.1:4049";
+
+/// Process, killed when dropped so that a failing test frees its port.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn server(dir: &Path, addr: &str) -> Server {
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+    assert!(child.try_wait().unwrap().is_none(), "kvs-server exited");
+    Server(child)
+}
+
+fn scan() -> Request {
+    Request::Scan {
+        start: Bound::Unbounded,
+        end: Bound::Unbounded,
+        ns: None,
+    }
+}
+
+fn pairs(response: Response) -> Vec<(String, String)> {
+    match response {
+        Response::Ok(Reply::Pairs(pairs)) => pairs,
+        response => panic!("unexpected response {:?}", response),
+    }
+}
+
+fn get(client: &KvsClient, key: &str) -> String {
+    let op = Request::Get {
+        key: key.to_owned(),
+        ns: None,
+    };
+    match client.request(&op).unwrap() {
+        Response::Ok(Reply::Value(value)) => value,
+        response => panic!("unexpected response {:?}", response),
+    }
+}
+
+// The client spreads keys over its servers, merges scans of all of them,
+// and moves on to the next server when one goes away.
+#[test]
+fn client_shards_keys() {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let mut servers: Vec<Server> = SERVERS
+        .iter()
+        .zip(&dirs)
+        .map(|(addr, dir)| server(dir.path(), addr))
+        .collect();
+    let client = KvsClient::new(
+        SERVERS
+            .iter()
+            .map(|addr| addr.parse::<SocketAddr>().unwrap()),
+    );
+
+    let keys: BTreeSet<String> = (0..60).map(|i| format!("key{:02}", i)).collect();
+    for key in &keys {
+        let op = Request::Set {
+            key: key.clone(),
+            value: format!("value-{}", key),
+            ns: None,
+        };
+        assert!(matches!(
+            client.request(&op).unwrap(),
+            Response::Ok(Reply::Done)
+        ));
+    }
+
+    let mut stored = BTreeSet::new();
+    for addr in SERVERS {
+        let held = pairs(client.send(addr, &scan()).unwrap());
+        assert!(!held.is_empty(), "{} holds no keys", addr);
+        for (key, _) in held {
+            assert!(stored.insert(key.clone()), "{} is on two servers", key);
+        }
+    }
+    assert_eq!(stored, keys);
+    let all = pairs(client.request(&scan()).unwrap());
+    assert_eq!(all.len(), keys.len());
+    for (key, value) in &all {
+        assert_eq!(value, &format!("value-{}", key));
+    }
+    assert_eq!(get(&client, "key07"), "value-key07");
+
+    // the keys of the dead server are written to the others instead
+    drop(servers.remove(1));
+    for key in &keys {
+        let op = Request::Set {
+            key: key.clone(),
+            value: "again".to_owned(),
+            ns: None,
+        };
+        assert!(matches!(
+            client.request(&op).unwrap(),
+            Response::Ok(Reply::Done)
+        ));
+    }
+    assert!(!client.servers().contains(&SERVERS[1].to_owned()));
+    assert_eq!(client.servers().len(), 2);
+    for key in keys.iter().step_by(7) {
+        assert_eq!(get(&client, key), "again");
+    }
+    let all = pairs(client.request(&scan()).unwrap());
+    assert_eq!(all.len(), keys.len());
+    assert!(all.iter().all(|(_, value)| value == "again"));
+
+    // the binary reaches them through the same client
+    let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+    cmd.args(["get", "key07", "--addr", &SERVERS.join(",")])
+        .assert()
+        .success()
+        .stdout(contains("again"));
+}
+
+// Typed requests over one connection: misses are `None` or `KeyNotFound`.
+#[test]
+fn client_typed_requests() {
+    let dir = TempDir::new().unwrap();
+    let _server = server(dir.path(), SERVER);
+    let client = KvsClient::connect(SERVER).unwrap();
+
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    client.remove("key1".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(
+        client.get("key2".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+}
+
+// Connecting fails at once without a server, and after the read timeout
+// with a server that does not answer the handshake.
+#[test]
+fn client_timeouts() {
+    assert!(matches!(KvsClient::connect(SILENT), Err(KvsError::Io(_))));
+
+    let _listener = TcpListener::bind(SILENT).unwrap();
+    let options = KvsClientOptions {
+        connect_timeout: Duration::from_secs(1),
+        read_timeout: Some(Duration::from_millis(200)),
+        ..KvsClientOptions::default()
+    };
+    let start = Instant::now();
+    assert!(matches!(
+        KvsClient::connect_with_options(SILENT, options),
+        Err(KvsError::Io(_))
+    ));
+    assert!(start.elapsed() < Duration::from_secs(2));
+}
+
+/// Server that answers handshakes, like servers from before them unless it
+/// `pipelines`, and no request after. Counts the requests it gets in
+/// `received`.
+fn unanswering(addr: &str, pipelines: bool, received: Arc<AtomicUsize>) {
+    let listener = TcpListener::bind(addr).unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let received = received.clone();
+            thread::spawn(move || {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let hello = {
+                    let mut reader = Deserializer::from_reader(&mut reader);
+                    serde_json::Value::deserialize(&mut reader)
+                };
+                if hello.is_err() {
+                    return;
+                }
+                let welcome = match pipelines {
+                    true => Response::Ok(Reply::Welcome(Welcome {
+                        version: PROTOCOL_VERSION,
+                        capabilities: [Capability::BinaryFrames, Capability::Pipelining].into(),
+                    })),
+                    false => Response::Error {
+                        code: ErrorCode::InvalidRequest,
+                        message: "unknown request".to_owned(),
+                    },
+                };
+                let _ = stream.write_all(&serde_json::to_vec(&welcome).unwrap());
+                loop {
+                    let request = match pipelines {
+                        true => read_frame(&mut reader).ok().flatten().is_some(),
+                        false => {
+                            let mut reader = Deserializer::from_reader(&mut reader);
+                            serde_json::Value::deserialize(&mut reader).is_ok()
+                        }
+                    };
+                    if !request {
+                        return;
+                    }
+                    received.fetch_add(1, Ordering::SeqCst);
+                }
+            });
+        }
+    });
+}
+
+// Writes that time out are not sent again, to the same server or another,
+// while reads move on to the next server.
+#[test]
+fn client_does_not_resend_writes() {
+    let received = Arc::new(AtomicUsize::new(0));
+    for addr in UNANSWERING {
+        unanswering(addr, false, received.clone());
+    }
+    let options = KvsClientOptions {
+        read_timeout: Some(Duration::from_millis(200)),
+        ..KvsClientOptions::default()
+    };
+    let servers = || UNANSWERING.iter().map(|addr| addr.parse().unwrap());
+
+    let client = KvsClient::with_options(servers(), options.clone());
+    assert!(matches!(
+        client.set("key1".to_owned(), "value1".to_owned()),
+        Err(KvsError::Io(_))
+    ));
+    assert_eq!(received.load(Ordering::SeqCst), 1);
+
+    let client = KvsClient::with_options(servers(), options);
+    assert!(matches!(
+        client.get("key1".to_owned()),
+        Err(KvsError::Io(_))
+    ));
+    assert_eq!(received.load(Ordering::SeqCst), 3);
+
+    // nor are pipelined ones
+    let received = Arc::new(AtomicUsize::new(0));
+    unanswering(UNANSWERING_PIPELINED, true, received.clone());
+    let options = KvsClientOptions {
+        read_timeout: Some(Duration::from_millis(200)),
+        ..KvsClientOptions::default()
+    };
+    let client = KvsClient::with_options([UNANSWERING_PIPELINED.parse().unwrap()], options);
+    let responses = client.request_all(&[set(1), set(2)]);
+    assert!(responses
+        .iter()
+        .all(|response| matches!(response, Err(KvsError::Io(_)))));
+    assert_eq!(received.load(Ordering::SeqCst), 2);
+}
+
+fn set(i: usize) -> Request {
+    Request::Set {
+        key: format!("key{}", i),
+        value: format!("value{}", i),
+        ns: None,
+    }
+}
+
+// Requests go out without waiting for the responses to those before, which
+// find their callers whatever order the server answers them in.
+#[test]
+fn client_pipelining() {
+    let dir = TempDir::new().unwrap();
+    let _server = server(dir.path(), PIPELINED);
+    let client = KvsClient::connect(PIPELINED).unwrap();
+    let welcome = client.welcome(PIPELINED).unwrap();
+    assert!(welcome.capabilities.contains(&Capability::Pipelining));
+
+    let sets: Vec<Request> = (0..2000).map(set).collect();
+    for response in client.request_all(&sets) {
+        assert!(matches!(response.unwrap(), Response::Ok(Reply::Done)));
+    }
+    let gets: Vec<Request> = (0..2000)
+        .map(|i| Request::Get {
+            key: format!("key{}", i),
+            ns: None,
+        })
+        .collect();
+    for (i, response) in client.request_all(&gets).into_iter().enumerate() {
+        assert!(matches!(
+            response.unwrap(),
+            Response::Ok(Reply::Value(value)) if value == format!("value{}", i)
+        ));
+    }
+
+    // callers on several threads share the one connection
+    thread::scope(|scope| {
+        for t in 0..8 {
+            let client = &client;
+            scope.spawn(move || {
+                for i in (t..2000).step_by(8) {
+                    assert_eq!(
+                        client.get(format!("key{}", i)).unwrap(),
+                        Some(format!("value{}", i))
+                    );
+                }
+            });
+        }
+    });
+
+    // clients that do not pipeline send one request at a time
+    let options = KvsClientOptions {
+        binary_frames: false,
+        ..KvsClientOptions::default()
+    };
+    let json_client = KvsClient::connect_with_options(PIPELINED, options).unwrap();
+    let welcome = json_client.welcome(PIPELINED).unwrap();
+    assert!(!welcome.capabilities.contains(&Capability::Pipelining));
+    let responses = json_client.request_all(&gets[..10]);
+    assert!(matches!(
+        &responses[9],
+        Ok(Response::Ok(Reply::Value(value))) if value == "value9"
+    ));
+
+    // every request in flight on a connection is answered once, by its ID
+    let mut stream = TcpStream::connect(PIPELINED).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let hello = serde_json::json!({ "version": 2, "request": { "Hello": {
+        "version": PROTOCOL_VERSION,
+        "capabilities": ["BinaryFrames", "Pipelining"],
+    } } });
+    stream
+        .write_all(&serde_json::to_vec(&hello).unwrap())
+        .unwrap();
+    let welcome = Response::deserialize(&mut Deserializer::from_reader(&mut reader)).unwrap();
+    assert!(matches!(welcome, Response::Ok(Reply::Welcome(_))));
+    let frames: Vec<u8> = (1..=500u64)
+        .flat_map(|id| {
+            Frame::new(id, &Envelope::new(set(id as usize)))
+                .unwrap()
+                .encode()
+        })
+        .collect();
+    stream.write_all(&frames).unwrap();
+    let mut answered = BTreeSet::new();
+    for _ in 1..=500 {
+        let frame = read_frame(&mut reader).unwrap().unwrap();
+        assert!(matches!(
+            frame.message::<Response>().unwrap(),
+            Response::Ok(Reply::Done)
+        ));
+        assert!(answered.insert(frame.id));
+    }
+    assert_eq!(answered, (1..=500).collect());
+}
@@ -0,0 +1,192 @@
+use assert_cmd::prelude::*;
+use kvs::auth::hash_token;
+use predicates::prelude::PredicateBooleanExt;
+use predicates::str::contains;
+use serde_json::json;
+use std::fs;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const NODES: [&str; 3] = ["127.0.0.1:4015", "127.0.0.1:4016", "127.0.0.1:4017"];
+const JOINING: &str = "127.0.0.1:4018";
+const AUTH_NODES: [&str; 3] = ["127.0.0.1:4052", "127.0.0.1:4053", "127.0.0.1:4054"];
+
+/// Server process, killed when dropped so that a failing test frees its
+/// port.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn server(dir: &Path, args: &[&str]) -> Server {
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(args)
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+    assert!(child.try_wait().unwrap().is_none(), "server exited");
+    Server(child)
+}
+
+fn client(addr: &str, args: &[&str]) -> Command {
+    let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+    cmd.args(args).args(["--addr", addr]);
+    cmd
+}
+
+fn stdout(addr: &str, args: &[&str]) -> String {
+    let output = client(addr, args).output().unwrap();
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+/// Runs `args` against `addr` until it prints `expected`.
+fn wait_for(addr: &str, args: &[&str], expected: &str) {
+    for _ in 0..100 {
+        if stdout(addr, args).contains(expected) {
+            return;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("{:?} on {} never printed {:?}", args, addr, expected);
+}
+
+fn leader(addr: &str) -> String {
+    wait_for(addr, &["stats"], "raft_leader\t127.0.0.1:");
+    let stats = stdout(addr, &["stats"]);
+    let line = stats
+        .lines()
+        .find(|line| line.starts_with("raft_leader\t"))
+        .unwrap();
+    line["raft_leader\t".len()..].to_owned()
+}
+
+// Writes sent to any node are committed by the leader and reach every node,
+// a new node joins with the data so far, and the cluster goes on after its
+// leader fails.
+#[test]
+fn cluster() {
+    let members = NODES.join(",");
+    let dirs: Vec<TempDir> = (0..4).map(|_| TempDir::new().unwrap()).collect();
+    let mut servers: Vec<(&str, Server)> = NODES
+        .iter()
+        .zip(&dirs)
+        .map(|(addr, dir)| {
+            let args = ["--addr", addr, "--cluster", &members];
+            (*addr, server(dir.path(), &args))
+        })
+        .collect();
+    let leader = leader(NODES[0]);
+    assert!(NODES.contains(&leader.as_str()));
+
+    for (i, addr) in NODES.iter().enumerate() {
+        client(addr, &["set", &format!("key{}", i), "value"])
+            .assert()
+            .success();
+    }
+    client(NODES[1], &["incr", "counter", "5"])
+        .assert()
+        .success()
+        .stdout("5\n");
+    for addr in NODES {
+        wait_for(addr, &["get", "key0"], "value");
+        wait_for(addr, &["get", "key2"], "value");
+        wait_for(addr, &["get", "counter"], "5");
+    }
+    client(NODES[2], &["set", "key", "value", "--ns", "ns"])
+        .assert()
+        .failure()
+        .stderr(contains("namespaces are not replicated"));
+    client(NODES[0], &["stats"])
+        .assert()
+        .success()
+        .stdout(contains(format!("raft_members\t{}", members)));
+
+    // a new server joins once added
+    let joining = server(dirs[3].path(), &["--addr", JOINING, "--cluster", &members]);
+    client(NODES[2], &["add-node", JOINING])
+        .assert()
+        .success()
+        .stdout(contains(JOINING));
+    wait_for(JOINING, &["get", "key1"], "value");
+    wait_for(JOINING, &["stats"], &format!("raft_leader\t{}", leader));
+    servers.push((JOINING, joining));
+
+    // the others elect a new leader when it fails
+    servers.retain(|(addr, _)| *addr != leader);
+    let survivor = servers[0].0;
+    client(survivor, &["set", "key3", "value"])
+        .assert()
+        .success();
+    let new_leader = self::leader(survivor);
+    assert_ne!(new_leader, leader);
+    for (addr, _) in &servers {
+        wait_for(addr, &["get", "key3"], "value");
+    }
+    client(survivor, &["remove-node", &leader])
+        .assert()
+        .success()
+        .stdout(contains(JOINING))
+        .stdout(contains(leader.as_str()).not());
+}
+
+// Under an auth file the nodes authenticate to each other with the cluster
+// token.
+#[test]
+fn cluster_auth() {
+    let members = AUTH_NODES.join(",");
+    let users = json!({
+        "node": {
+            "token": hash_token("cluster-token"),
+            "grants": [{ "permission": "admin" }],
+        },
+        "app": {
+            "token": hash_token("app-token"),
+            "grants": [{ "permission": "write" }],
+        },
+    });
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let _servers: Vec<Server> = AUTH_NODES
+        .iter()
+        .zip(&dirs)
+        .map(|(addr, dir)| {
+            fs::write(dir.path().join("users.json"), users.to_string()).unwrap();
+            let args = ["--addr", addr, "--cluster", &members];
+            let auth = [
+                "--auth-file",
+                "users.json",
+                "--cluster-token",
+                "cluster-token",
+            ];
+            server(dir.path(), &[&args[..], &auth].concat())
+        })
+        .collect();
+
+    let admin = ["--token", "cluster-token"];
+    wait_for(
+        AUTH_NODES[0],
+        &[&["stats"], &admin[..]].concat(),
+        "raft_leader\t127.0.0.1:",
+    );
+    client(
+        AUTH_NODES[1],
+        &["set", "key", "value", "--token", "app-token"],
+    )
+    .assert()
+    .success();
+    for addr in AUTH_NODES {
+        wait_for(addr, &["get", "key", "--token", "app-token"], "value");
+    }
+    client(AUTH_NODES[2], &["get", "key"])
+        .assert()
+        .failure()
+        .stderr(contains("authentication required"));
+}
@@ -0,0 +1,177 @@
+use kvs::{read_frame, Envelope, Frame, FrameDecoder, KvsError, Request, Response, MAX_FRAME_LEN};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use std::io::{Cursor, ErrorKind};
+use std::ops::Bound;
+
+fn frames() -> Vec<Frame> {
+    let requests = [
+        Request::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+            ns: None,
+        },
+        Request::Get {
+            key: "key1".to_owned(),
+            ns: Some("ns".to_owned()),
+        },
+        Request::Scan {
+            start: Bound::Included("a".to_owned()),
+            end: Bound::Unbounded,
+            ns: None,
+        },
+        Request::Set {
+            key: String::new(),
+            value: "v".repeat(100_000),
+            ns: None,
+        },
+    ];
+    requests
+        .iter()
+        .enumerate()
+        .map(|(id, op)| Frame::new(id as u64 * 1000, &Envelope::new(op)).unwrap())
+        .collect()
+}
+
+/// Feeds `bytes` to a decoder in random pieces and returns what it decodes,
+/// stopping at the first error.
+fn decode(rng: &mut SmallRng, bytes: &[u8]) -> (Vec<Frame>, Option<KvsError>) {
+    let mut decoder = FrameDecoder::new();
+    let mut decoded = Vec::new();
+    let mut rest = bytes;
+    loop {
+        loop {
+            match decoder.next_frame() {
+                Ok(Some(frame)) => decoded.push(frame),
+                Ok(None) => break,
+                Err(e) => return (decoded, Some(e)),
+            }
+        }
+        if rest.is_empty() {
+            return (decoded, None);
+        }
+        let n = rng.gen_range(1..=rest.len().min(5000));
+        decoder.extend(&rest[..n]);
+        rest = &rest[n..];
+    }
+}
+
+// Frames come out of the decoder as they went in, however the bytes are
+// split, and their requests read back.
+#[test]
+fn frame_round_trip() {
+    let mut rng = SmallRng::seed_from_u64(45);
+    let frames = frames();
+    let bytes: Vec<u8> = frames.iter().flat_map(Frame::encode).collect();
+    for _ in 0..100 {
+        let (decoded, error) = decode(&mut rng, &bytes);
+        assert!(error.is_none());
+        assert_eq!(decoded, frames);
+    }
+    let mut reader = Cursor::new(&bytes);
+    for frame in &frames {
+        assert_eq!(&read_frame(&mut reader).unwrap().unwrap(), frame);
+    }
+    assert!(read_frame(&mut reader).unwrap().is_none());
+
+    assert!(matches!(
+        frames[1].request().unwrap(),
+        Request::Get { key, ns: Some(ns) } if key == "key1" && ns == "ns"
+    ));
+    let response = Frame::new(3, &Response::NotFound).unwrap();
+    assert!(matches!(
+        response.message::<Response>().unwrap(),
+        Response::NotFound
+    ));
+}
+
+// Lengths out of bounds are errors as soon as the length is in, and a frame
+// cut short is waited for by the decoder and an error for readers.
+#[test]
+fn frame_decoder_rejects() {
+    for len in [0u32, 7, MAX_FRAME_LEN as u32 + 1, u32::MAX] {
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&len.to_be_bytes());
+        assert!(matches!(decoder.next_frame(), Err(KvsError::Frame(_))));
+        // the decoder stays stuck on it
+        decoder.extend(&[0; 16]);
+        assert!(matches!(decoder.next_frame(), Err(KvsError::Frame(_))));
+        let mut reader = Cursor::new(len.to_be_bytes());
+        assert!(matches!(read_frame(&mut reader), Err(KvsError::Frame(_))));
+    }
+
+    let bytes = frames()[0].encode();
+    for cut in 1..bytes.len() {
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&bytes[..cut]);
+        assert!(decoder.next_frame().unwrap().is_none());
+        assert!(!decoder.is_empty());
+        let mut reader = Cursor::new(&bytes[..cut]);
+        assert!(matches!(
+            read_frame(&mut reader),
+            Err(KvsError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof
+        ));
+    }
+
+    let frame = Frame::new(1, &"not a request").unwrap();
+    assert!(matches!(frame.request(), Err(KvsError::InvalidRequest(_))));
+    let frame = Frame::new(
+        1,
+        &Envelope {
+            version: 9,
+            request: "x",
+        },
+    )
+    .unwrap();
+    assert!(matches!(
+        frame.request(),
+        Err(KvsError::UnsupportedVersion(9))
+    ));
+}
+
+// Random bytes, and valid frames with bytes flipped, cut or spliced in, never
+// panic the decoder nor the payload parser: every frame decoded is either
+// a request or an error.
+#[test]
+fn frame_decoder_fuzz() {
+    let mut rng = SmallRng::seed_from_u64(4545);
+    let valid: Vec<u8> = frames().iter().flat_map(Frame::encode).collect();
+    for round in 0..2000 {
+        let bytes = match round % 4 {
+            0 => {
+                let len = rng.gen_range(0..256);
+                (0..len).map(|_| rng.gen()).collect()
+            }
+            1 => {
+                let mut bytes = valid.clone();
+                for _ in 0..rng.gen_range(1..8) {
+                    let i = rng.gen_range(0..bytes.len());
+                    bytes[i] ^= 1 << rng.gen_range(0..8);
+                }
+                bytes
+            }
+            2 => {
+                let end = rng.gen_range(0..valid.len());
+                let start = rng.gen_range(0..=end);
+                valid[start..end].to_vec()
+            }
+            _ => {
+                // a small frame whose payload is random
+                let len = rng.gen_range(0..64);
+                let payload: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+                Frame {
+                    id: rng.gen(),
+                    payload,
+                }
+                .encode()
+            }
+        };
+        let (decoded, _) = decode(&mut rng, &bytes);
+        let mut reader = Cursor::new(&bytes);
+        for frame in decoded {
+            let _ = frame.request();
+            let _ = frame.message::<Response>();
+            assert_eq!(read_frame(&mut reader).unwrap().unwrap(), frame);
+        }
+    }
+}
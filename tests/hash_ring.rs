@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+
+use kvs::HashRing;
+
+fn owners(ring: &HashRing, keys: &[String]) -> Vec<String> {
+    keys.iter()
+        .map(|key| ring.node(key).unwrap().to_owned())
+        .collect()
+}
+
+#[test]
+fn empty_ring_has_no_owner() {
+    let mut ring = HashRing::new(16);
+    assert_eq!(ring.node("key"), None);
+    assert!(ring.add("a"));
+    assert!(!ring.add("a"));
+    assert_eq!(ring.node("key"), Some("a"));
+    assert!(ring.remove("a"));
+    assert!(!ring.remove("a"));
+    assert_eq!(ring.node("key"), None);
+}
+
+// Keys spread about evenly, and a new node only takes keys from the others
+// rather than shuffling them around.
+#[test]
+fn adding_a_node_moves_only_its_share() {
+    let keys: Vec<String> = (0..10_000).map(|i| format!("key{}", i)).collect();
+    let mut ring = HashRing::new(64);
+    for node in ["a", "b", "c"] {
+        ring.add(node);
+    }
+    let before = owners(&ring, &keys);
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for owner in &before {
+        *counts.entry(owner).or_default() += 1;
+    }
+    for node in ["a", "b", "c"] {
+        let share = counts[node];
+        assert!((2_000..4_700).contains(&share), "{} owns {}", node, share);
+    }
+
+    ring.add("d");
+    let after = owners(&ring, &keys);
+    let mut moved = 0;
+    for (old, new) in before.iter().zip(&after) {
+        if old != new {
+            assert_eq!(new, "d");
+            moved += 1;
+        }
+    }
+    assert!((1_500..3_500).contains(&moved), "{} moved", moved);
+
+    // and removing it gives them back
+    ring.remove("d");
+    assert_eq!(owners(&ring, &keys), before);
+    assert_eq!(ring.nodes().collect::<Vec<_>>(), vec!["a", "b", "c"]);
+}
@@ -0,0 +1,193 @@
+use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsClient, KvsEngine};
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const ADDR: &str = "127.0.0.1:4038";
+const HTTP_ADDR: &str = "127.0.0.1:4039";
+const CONNECTIONS_ADDR: &str = "127.0.0.1:4040";
+const CONNECTIONS_HTTP_ADDR: &str = "127.0.0.1:4041";
+
+/// Process, killed when dropped so that a failing test frees its port.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn server(dir: &Path, addr: &str, http_addr: &str) -> Server {
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--http-addr", http_addr])
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+    assert!(child.try_wait().unwrap().is_none(), "kvs-server exited");
+    Server(child)
+}
+
+/// Sends `request` over a connection of its own and returns the status and
+/// body of the response.
+fn raw(request: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(HTTP_ADDR).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    (head[9..12].parse().unwrap(), body.to_owned())
+}
+
+fn call(method: &str, target: &str, body: &str) -> (u16, String) {
+    raw(&format!(
+        "{} {} HTTP/1.1\r\nHost: kvs\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        target,
+        body.len(),
+        body
+    ))
+}
+
+fn call_json(method: &str, target: &str, body: &str) -> (u16, Value) {
+    let (status, body) = call(method, target, body);
+    (status, serde_json::from_str(&body).unwrap())
+}
+
+// Keys are read, written and listed over HTTP with the status codes of
+// REST, and the native protocol sees the same keys.
+#[test]
+fn http_gateway() {
+    let dir = TempDir::new().unwrap();
+    KvStore::open(dir.path())
+        .unwrap()
+        .create_namespace("users")
+        .unwrap();
+    let _server = server(dir.path(), ADDR, HTTP_ADDR);
+
+    assert_eq!(
+        call_json("GET", "/health", ""),
+        (200, json!({ "status": "ok" }))
+    );
+    assert_eq!(call("GET", "/keys/key1", "").0, 404);
+    assert_eq!(call("PUT", "/keys/key1", "value1"), (204, String::new()));
+    assert_eq!(call("GET", "/keys/key1", ""), (200, "value1".to_owned()));
+    // keys are percent-decoded, slashes included
+    assert_eq!(call("PUT", "/keys/a%20b/c", "spaced"), (204, String::new()));
+    assert_eq!(call("GET", "/keys/a%20b/c", "").1, "spaced");
+    assert_eq!(call("DELETE", "/keys/a%20b%2Fc", "").0, 204);
+    assert_eq!(call("DELETE", "/keys/a%20b%2Fc", "").0, 404);
+
+    let client = KvsClient::connect(ADDR).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap().as_deref(),
+        Some("value1")
+    );
+    client.set("native".to_owned(), "n".to_owned()).unwrap();
+    assert_eq!(call("GET", "/keys/native", "").1, "n");
+
+    assert_eq!(
+        call(
+            "POST",
+            "/batch/set",
+            r#"{"key2": "value2", "key3": "value3"}"#
+        )
+        .0,
+        204
+    );
+    assert_eq!(
+        call_json("POST", "/batch/get", r#"["key1", "missing", "key3"]"#),
+        (
+            200,
+            json!({ "key1": "value1", "missing": null, "key3": "value3" })
+        )
+    );
+    assert_eq!(
+        call_json("GET", "/keys?start=key1&end=key3", ""),
+        (
+            200,
+            json!({ "pairs": [
+                { "key": "key1", "value": "value1" },
+                { "key": "key2", "value": "value2" },
+            ], "next": null })
+        )
+    );
+    let (status, page) = call_json("GET", "/keys?prefix=key&limit=2", "");
+    assert_eq!(status, 200);
+    assert_eq!(page["pairs"].as_array().unwrap().len(), 2);
+    assert_eq!(page["next"], json!("key3"));
+    let (_, page) = call_json("GET", "/keys?start=key3&prefix=key", "");
+    assert!(page["error"].is_string());
+    assert_eq!(
+        call_json("POST", "/batch/delete", r#"["key2", "missing"]"#),
+        (200, json!({ "removed": 1 }))
+    );
+
+    // namespaces
+    assert_eq!(call("PUT", "/keys/key1?ns=users", "user").0, 204);
+    assert_eq!(call("GET", "/keys/key1?ns=users", "").1, "user");
+    assert_eq!(call("GET", "/keys/key1", "").1, "value1");
+    assert_eq!(call("GET", "/keys/key1?ns=missing", "").0, 404);
+
+    let (status, stats) = call_json("GET", "/stats", "");
+    assert_eq!(status, 200);
+    assert!(stats.is_object());
+
+    // errors
+    assert_eq!(call("GET", "/nowhere", "").0, 404);
+    let (status, response) = raw("POST /keys/key1 HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert_eq!(status, 405);
+    assert!(response.contains("method not allowed"));
+    assert_eq!(call("POST", "/batch/get", "not json").0, 400);
+    assert_eq!(call("GET", "/keys?limit=many", "").0, 400);
+}
+
+// Connections stay open across requests unless closed, bodies come in
+// chunks too, and a request that cannot be read gets a 400 and closes the
+// connection.
+#[test]
+fn http_connections() {
+    let dir = TempDir::new().unwrap();
+    let _server = server(dir.path(), CONNECTIONS_ADDR, CONNECTIONS_HTTP_ADDR);
+
+    let mut stream = TcpStream::connect(CONNECTIONS_HTTP_ADDR).unwrap();
+    stream
+        .write_all(
+            b"PUT /keys/key1 HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+              3\r\nval\r\n3;ext=1\r\nue1\r\n0\r\n\r\n\
+              HEAD /keys/key1 HTTP/1.1\r\n\r\n\
+              GET /keys/key1 HTTP/1.1\r\n\r\n\
+              GET /keys/key1 HTTP/1.0\r\n\r\n",
+        )
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let value = "Content-Type: text/plain; charset=utf-8\r\nContent-Length: 6\r\n";
+    assert_eq!(
+        response,
+        format!(
+            "HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n\
+             HTTP/1.1 200 OK\r\n{value}\r\n\
+             HTTP/1.1 200 OK\r\n{value}\r\nvalue1\
+             HTTP/1.1 200 OK\r\n{value}Connection: close\r\n\r\nvalue1"
+        )
+    );
+
+    let mut stream = TcpStream::connect(CONNECTIONS_HTTP_ADDR).unwrap();
+    stream
+        .write_all(b"GET /health HTTP/1.1\r\n\r\nGARBAGE\r\n\r\nGET /health HTTP/1.1\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 1);
+    assert!(response.contains("HTTP/1.1 400 Bad Request\r\n"));
+    assert!(response.ends_with(r#"{"error":"invalid request line"}"#));
+}
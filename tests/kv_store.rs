@@ -1,4 +1,5 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{KvStore, KvStoreOptions, KvsEngine, KvsError, Result, Retention};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Barrier};
 use std::thread;
 use tempfile::TempDir;
@@ -210,3 +211,220 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Reads that do not hold the index while reading the log must still see
+// whole values while writes pile up and compaction moves them.
+#[test]
+fn get_during_compaction() -> Result<()> {
+    let configs = [
+        KvStoreOptions::default(),
+        KvStoreOptions {
+            mmap_reads: true,
+            ..KvStoreOptions::default()
+        },
+    ];
+    for options in configs {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), "0".to_owned())?;
+        }
+
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..4)
+            .map(|thread_id| {
+                let store = store.clone();
+                let done = done.clone();
+                thread::spawn(move || {
+                    let mut i = 0;
+                    while !done.load(Ordering::SeqCst) {
+                        i += 1;
+                        let key_id = (i * 7 + thread_id * 250) % 1000;
+                        let value = store.get(format!("key{}", key_id)).unwrap().unwrap();
+                        assert!(value.parse::<u32>().unwrap() < 25);
+                    }
+                })
+            })
+            .collect();
+        // enough overwrites to trigger compaction
+        for iter in 1..25 {
+            for key_id in 0..1000 {
+                store.set(format!("key{}", key_id), iter.to_string())?;
+            }
+        }
+        done.store(true, Ordering::SeqCst);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(store.get("key0".to_owned())?, Some("24".to_owned()));
+    }
+    Ok(())
+}
+
+// A store holding far more keys than fit in its index memory limit should
+// spill the index to disk and still find every key, also after compaction
+// and reopening.
+#[test]
+fn memory_bounded_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        index_memory_limit: Some(16 * 1024),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    // enough overwrites to trigger compaction
+    for _ in 0..6 {
+        for i in 0..5000 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+    }
+    for i in (0..5000).step_by(5) {
+        store.remove(format!("key{}", i))?;
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..5000 {
+            let expected = if i % 5 == 0 {
+                None
+            } else {
+                Some(format!("value{}", i))
+            };
+            assert_eq!(store.get(format!("key{}", i))?, expected);
+        }
+        assert_eq!(
+            store.scan("key4990".to_owned().."key5".to_owned())?.len(),
+            8
+        );
+        let stats = store.stats()?;
+        assert_eq!(stats["keys"], "4000");
+        let memory: usize = stats["index_memory_bytes"].parse().unwrap();
+        assert!(memory < 64 * 1024, "index uses {} bytes", memory);
+        Ok(())
+    };
+    check(&store)?;
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    check(&store)?;
+    Ok(())
+}
+
+// Reads through the memory map, which the first read maps however small the
+// log, must see values appended after the file was mapped and values moved
+// by compaction.
+#[test]
+fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        mmap_reads: true,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.stats()?["mmap_bytes"], "0");
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_ne!(store.stats()?["mmap_bytes"], "0");
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // enough overwrites to trigger compaction
+    for iter in 0..100 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        assert_eq!(store.get("key999".to_owned())?, Some(format!("{}", iter)));
+    }
+    assert_eq!(store.get("key0".to_owned())?, Some("99".to_owned()));
+
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for i in 0..1000 {
+                let key_id = (i + thread_id * 100) % 1000;
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).unwrap(),
+                    Some("99".to_owned())
+                );
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key500".to_owned())?, Some("99".to_owned()));
+    Ok(())
+}
+
+// Old versions are readable by sequence number as long as the retention
+// policy covers them, also after compaction and reopening.
+#[test]
+fn version_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        retention: Some(Retention::Versions(3)),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..5 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    store.remove("key1".to_owned())?;
+
+    let history = store.history("key1".to_owned())?;
+    let values: Vec<Option<String>> = history.iter().map(|v| v.value.clone()).collect();
+    assert_eq!(
+        values,
+        vec![Some("value3".to_owned()), Some("value4".to_owned()), None,]
+    );
+    assert!(history.windows(2).all(|w| w[0].seq < w[1].seq));
+    let (seq3, removed) = (history[0].seq, history[2].seq);
+    assert_eq!(
+        store.get_version("key1".to_owned(), seq3)?,
+        Some("value3".to_owned())
+    );
+    assert_eq!(store.get_version("key1".to_owned(), removed)?, None);
+    assert!(store.history("key2".to_owned())?.is_empty());
+
+    // overwrite other keys until compaction runs, the retained versions of
+    // key1 must survive it
+    for iter in 0..200 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id + 2), format!("{:0>100}", iter))?;
+        }
+    }
+    assert_eq!(store.history("key1".to_owned())?, history);
+    assert_eq!(store.history("key2".to_owned())?.len(), 3);
+    drop(store);
+    let log_size = temp_dir.path().join("db").metadata()?.len();
+    assert!(log_size < 2 * 1024 * 1024, "no compaction detected");
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.history("key1".to_owned())?, history);
+    assert_eq!(
+        store.get_version("key1".to_owned(), seq3 + 1)?,
+        Some("value4".to_owned())
+    );
+    assert_eq!(
+        store.get("key2".to_owned())?,
+        Some(format!("{:0>100}", 199))
+    );
+    store.set("key1".to_owned(), "value5".to_owned())?;
+    assert!(store.history("key1".to_owned())?.last().unwrap().seq > removed);
+    Ok(())
+}
+
+#[test]
+fn version_history_needs_retention() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(
+        store.history("key1".to_owned()),
+        Err(KvsError::UnsupportedOperation)
+    ));
+    Ok(())
+}
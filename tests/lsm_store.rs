@@ -0,0 +1,233 @@
+use kvs::{KvsEngine, KvsError, LsmKvsEngine, LsmOptions, Result};
+use std::fs::{self, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn small_options() -> LsmOptions {
+    LsmOptions {
+        memtable_size: 4 * 1024,
+        block_size: 256,
+        table_size: 8 * 1024,
+        l0_compaction_trigger: 2,
+        level_base_size: 32 * 1024,
+        level_multiplier: 4,
+        max_levels: 4,
+        ..LsmOptions::default()
+    }
+}
+
+// Should get previously stored value
+#[test]
+fn get_stored_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsEngine::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = LsmKvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should overwrite existent value
+#[test]
+fn overwrite_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsEngine::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    drop(store);
+    let store = LsmKvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn remove_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsEngine::open(temp_dir.path())?;
+    assert!(store.remove("key1".to_owned()).is_err());
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.remove("key1".to_owned()).is_ok());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
+    Ok(())
+}
+
+// Values must survive being flushed and compacted through several levels,
+// and removed keys must stay removed.
+#[test]
+fn flush_and_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsEngine::open_with_options(temp_dir.path(), small_options())?;
+    for iter in 0..5 {
+        for key_id in 0..3000 {
+            store.set(format!("key{:04}", key_id), format!("value{}", iter))?;
+        }
+    }
+    for key_id in (0..3000).step_by(3) {
+        store.remove(format!("key{:04}", key_id))?;
+    }
+
+    let check = |store: &LsmKvsEngine| -> Result<()> {
+        for key_id in 0..3000 {
+            let expected = if key_id % 3 == 0 {
+                None
+            } else {
+                Some("value4".to_owned())
+            };
+            assert_eq!(store.get(format!("key{:04}", key_id))?, expected);
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    let store = LsmKvsEngine::open_with_options(temp_dir.path(), small_options())?;
+    check(&store)?;
+
+    // compactions run in the background and drain level 0
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while store.stats()?["level0_tables"].parse::<usize>().unwrap() >= 2 {
+        assert!(Instant::now() < deadline, "level 0 was never compacted");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_ne!(store.stats()?["level1_tables"], "0");
+    check(&store)?;
+
+    let tables = std::fs::read_dir(temp_dir.path())?
+        .filter(|entry| {
+            entry.as_ref().unwrap().path().extension() == Some(std::ffi::OsStr::new("sst"))
+        })
+        .count();
+    assert!(tables > 0, "memtable was never flushed");
+    Ok(())
+}
+
+#[test]
+fn ordered_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsEngine::open_with_options(temp_dir.path(), small_options())?;
+    for key_id in (0..1000).rev() {
+        store.set(format!("key{:04}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0101".to_owned())?;
+
+    let pairs = store.scan("key0100".to_owned().."key0105".to_owned())?;
+    let keys: Vec<&str> = pairs.iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(keys, ["key0100", "key0102", "key0103", "key0104"]);
+    assert_eq!(pairs[0].1, "value100");
+
+    assert_eq!(store.scan(..)?.len(), 999);
+    assert!(store.scan("key2".to_owned().."key1".to_owned())?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsEngine::open_with_options(temp_dir.path(), small_options())?;
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for i in 0..200 {
+                store
+                    .set(format!("key{}_{}", thread_id, i), format!("value{}", i))
+                    .unwrap();
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    drop(store);
+    let store = LsmKvsEngine::open_with_options(temp_dir.path(), small_options())?;
+    for thread_id in 0..8 {
+        for i in 0..200 {
+            assert_eq!(
+                store.get(format!("key{}_{}", thread_id, i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+    Ok(())
+}
+
+// A record torn by a crash is cut off the log, so that writes made after
+// recovering from it survive the next restart.
+#[test]
+fn torn_log_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let wal = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "wal"))
+        .unwrap();
+    OpenOptions::new()
+        .append(true)
+        .open(&wal)?
+        .write_all(br#"{"key":"key2","val"#)?;
+
+    let store = LsmKvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = LsmKvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A table whose footer claims an index past the end of the file is reported
+// as corrupted instead of read.
+#[test]
+fn corrupted_table_footer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsEngine::open_with_options(temp_dir.path(), small_options())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{:04}", key_id), "value".to_owned())?;
+    }
+    let table = || {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().is_some_and(|ext| ext == "sst"))
+    };
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while table().is_none() {
+        assert!(Instant::now() < deadline, "memtable was never flushed");
+        thread::sleep(Duration::from_millis(10));
+    }
+    drop(store);
+
+    // the length of the index, second in the footer of 40 bytes
+    let mut file = OpenOptions::new().write(true).open(table().unwrap())?;
+    file.seek(SeekFrom::End(-32))?;
+    file.write_all(&(1u64 << 40).to_le_bytes())?;
+    drop(file);
+    assert!(matches!(
+        LsmKvsEngine::open_with_options(temp_dir.path(), small_options()),
+        Err(KvsError::Corrupted(_))
+    ));
+    Ok(())
+}
@@ -0,0 +1,149 @@
+use std::thread;
+
+use kvs::{
+    merge, KvStore, KvStoreOptions, KvsEngine, KvsError, LsmKvsEngine, LsmOptions, MergeOperators,
+    Result, SledKvsEngine, SledOptions,
+};
+use tempfile::TempDir;
+
+fn operators() -> MergeOperators {
+    let mut operators = MergeOperators::default();
+    operators.register("upper", |_: &str, _: Option<&str>, operand: &str| {
+        Ok(operand.to_uppercase())
+    });
+    operators
+}
+
+// Concurrent increments must not lose updates.
+fn concurrent_incr(store: impl KvsEngine) -> Result<()> {
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    store.incr("counter".to_owned(), 1, 0).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("800".to_owned()));
+    assert_eq!(store.decr("counter".to_owned(), 10, 0)?, 790);
+    Ok(())
+}
+
+fn merge_operators(store: impl KvsEngine) -> Result<()> {
+    assert_eq!(store.incr("visits".to_owned(), 5, 100)?, 105);
+    assert_eq!(store.incr("visits".to_owned(), 5, 100)?, 110);
+
+    store.set("text".to_owned(), "not a number".to_owned())?;
+    assert!(matches!(
+        store.incr("text".to_owned(), 1, 0),
+        Err(KvsError::Merge(_))
+    ));
+    assert_eq!(
+        store.get("text".to_owned())?,
+        Some("not a number".to_owned())
+    );
+    store.set("full".to_owned(), i64::MAX.to_string())?;
+    assert!(matches!(
+        store.incr("full".to_owned(), 1, 0),
+        Err(KvsError::MergeOverflow)
+    ));
+    assert!(matches!(
+        store.decr("full".to_owned(), i64::MIN, 0),
+        Err(KvsError::MergeOverflow)
+    ));
+
+    store.append("log".to_owned(), "a".to_owned())?;
+    store.append("log".to_owned(), "b".to_owned())?;
+    assert_eq!(store.get("log".to_owned())?, Some("ab".to_owned()));
+
+    for value in ["3", "7", "5"] {
+        store.merge("max".to_owned(), merge::MAX, value.to_owned())?;
+    }
+    assert_eq!(store.get("max".to_owned())?, Some("7".to_owned()));
+
+    store.merge("set".to_owned(), merge::UNION, "[1,2]".to_owned())?;
+    let value = store.merge("set".to_owned(), merge::UNION, "[2,3]".to_owned())?;
+    assert_eq!(value, "[1,2,3]");
+
+    assert_eq!(
+        store.merge("custom".to_owned(), "upper", "abc".to_owned())?,
+        "ABC"
+    );
+    assert!(matches!(
+        store.merge("custom".to_owned(), "missing", "abc".to_owned()),
+        Err(KvsError::UnknownMergeOperator(_))
+    ));
+
+    store.remove("visits".to_owned())?;
+    assert_eq!(store.incr("visits".to_owned(), 1, 0)?, 1);
+    Ok(())
+}
+
+#[test]
+fn kvs_merge() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        merge_operators: operators(),
+        ..KvStoreOptions::default()
+    };
+    concurrent_incr(KvStore::open_with_options(
+        temp_dir.path(),
+        options.clone(),
+    )?)?;
+    merge_operators(KvStore::open_with_options(temp_dir.path(), options)?)
+}
+
+#[test]
+fn sled_merge() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = SledOptions {
+        merge_operators: operators(),
+        ..SledOptions::default()
+    };
+    let store = SledKvsEngine::open_with_options(temp_dir.path(), options)?;
+    concurrent_incr(store.clone())?;
+    merge_operators(store)
+}
+
+#[test]
+fn lsm_merge() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = LsmOptions {
+        merge_operators: operators(),
+        ..LsmOptions::default()
+    };
+    let store = LsmKvsEngine::open_with_options(temp_dir.path(), options)?;
+    concurrent_incr(store.clone())?;
+    merge_operators(store)
+}
+
+// Merge records are resolved after reopening and folded by compaction.
+#[test]
+fn kvs_merge_records_survive_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.incr("counter".to_owned(), 1, 0)?;
+        store.append("log".to_owned(), (i % 10).to_string())?;
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("counter".to_owned())?, Some("100".to_owned()));
+    let log = "0123456789".repeat(10);
+    assert_eq!(store.get("log".to_owned())?, Some(log.clone()));
+
+    let value = "x".repeat(1000);
+    for i in 0..2000 {
+        store.set(format!("key{}", i % 10), value.clone())?;
+    }
+    assert!(temp_dir.path().join("db").metadata()?.len() < 1024 * 1024);
+    assert_eq!(store.incr("counter".to_owned(), 1, 0)?, 101);
+    assert_eq!(store.get("log".to_owned())?, Some(log));
+    Ok(())
+}
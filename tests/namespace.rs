@@ -0,0 +1,91 @@
+use kvs::{CachedEngine, KvStore, KvsEngine, KvsError, Result, SledKvsEngine};
+use tempfile::TempDir;
+
+// Keys of namespaces are independent of each other and of the default
+// keyspace, and dropping a namespace leaves the others alone.
+fn namespaces(store: impl KvsEngine) -> Result<()> {
+    assert!(store.namespaces()?.is_empty());
+    assert!(matches!(
+        store.namespace("users"),
+        Err(KvsError::NamespaceNotFound(_))
+    ));
+    for name in ["", "a/b", "..", ".hidden"] {
+        assert!(matches!(
+            store.create_namespace(name),
+            Err(KvsError::InvalidNamespace(_))
+        ));
+    }
+
+    store.create_namespace("users")?;
+    store.create_namespace("orders")?;
+    store.create_namespace("users")?;
+    assert_eq!(store.namespaces()?, vec!["orders", "users"]);
+
+    let users = store.namespace("users")?;
+    let orders = store.namespace("orders")?;
+    store.set("key1".to_owned(), "root".to_owned())?;
+    users.set("key1".to_owned(), "user".to_owned())?;
+    orders.set("key2".to_owned(), "order".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("root".to_owned()));
+    assert_eq!(users.get("key1".to_owned())?, Some("user".to_owned()));
+    assert_eq!(orders.get("key1".to_owned())?, None);
+    assert_eq!(
+        users.scan(..)?,
+        vec![("key1".to_owned(), "user".to_owned())]
+    );
+
+    // handles to the same namespace see each other's writes
+    let users_again = users.namespace("users")?;
+    users_again.set("key3".to_owned(), "user".to_owned())?;
+    assert_eq!(users.get("key3".to_owned())?, Some("user".to_owned()));
+
+    store.drop_namespace("users")?;
+    assert_eq!(store.namespaces()?, vec!["orders"]);
+    assert!(matches!(
+        store.drop_namespace("users"),
+        Err(KvsError::NamespaceNotFound(_))
+    ));
+    assert_eq!(orders.get("key2".to_owned())?, Some("order".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("root".to_owned()));
+
+    store.create_namespace("users")?;
+    assert!(store.namespace("users")?.scan(..)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn kvs_namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    namespaces(KvStore::open(temp_dir.path())?)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.namespaces()?, vec!["orders", "users"]);
+    assert_eq!(
+        store.namespace("orders")?.get("key2".to_owned())?,
+        Some("order".to_owned())
+    );
+    Ok(())
+}
+
+#[test]
+fn sled_namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    namespaces(SledKvsEngine::open(temp_dir.path())?)?;
+
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.namespaces()?, vec!["orders", "users"]);
+    assert_eq!(
+        store.namespace("orders")?.get("key2".to_owned())?,
+        Some("order".to_owned())
+    );
+    Ok(())
+}
+
+#[test]
+fn cached_namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    namespaces(CachedEngine::new(
+        KvStore::open(temp_dir.path())?,
+        1024 * 1024,
+    ))
+}
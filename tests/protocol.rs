@@ -0,0 +1,375 @@
+use assert_cmd::prelude::*;
+use kvs::{
+    read_frame, Capability, Envelope, ErrorCode, Frame, KvsClient, KvsClientOptions, KvsError,
+    Reply, Request, Response, Welcome, PROTOCOL_VERSION,
+};
+use serde::Deserialize;
+use serde_json::{json, Deserializer, Value};
+use std::io::{BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Bound;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const ADDR: &str = "127.0.0.1:4029";
+const HANDSHAKE_ADDR: &str = "127.0.0.1:4030";
+/// Stand-in for a server of protocol version 2, from before handshakes.
+const OLD_ADDR: &str = "127.0.0.1:4031";
+const FRAMES_ADDR: &str = "127.0.0.1:4032";
+const WATCH_ADDR: &str = "127.0.0.1:4050";
+const ASYNC_WATCH_ADDR: &str = "127.0.0.1:4051";
+
+/// Process, killed when dropped so that a failing test frees its port.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn server(dir: &Path, args: &[&str]) -> Server {
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(args)
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+    assert!(child.try_wait().unwrap().is_none(), "kvs-server exited");
+    Server(child)
+}
+
+/// Sends each message over one connection and returns the responses.
+fn exchange(addr: &str, messages: &[Value]) -> Vec<Value> {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut reader = Deserializer::from_reader(BufReader::new(stream.try_clone().unwrap()));
+    messages
+        .iter()
+        .map(|message| {
+            stream
+                .write_all(&serde_json::to_vec(message).unwrap())
+                .unwrap();
+            Value::deserialize(&mut reader).unwrap()
+        })
+        .collect()
+}
+
+// Responses tell misses from values and carry the error of failures, and
+// clients of other protocol versions are turned away in terms they read.
+#[test]
+fn protocol() {
+    let dir = TempDir::new().unwrap();
+    let _server = server(dir.path(), &["--addr", ADDR]);
+
+    let client = KvsClient::connect(ADDR).unwrap();
+    // a value that reads like an error is a value all the same
+    client
+        .set("key1".to_owned(), "Key not found".to_owned())
+        .unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap().as_deref(),
+        Some("Key not found")
+    );
+    assert_eq!(client.get("key2".to_owned()).unwrap(), None);
+    let get = Request::Get {
+        key: "key1".to_owned(),
+        ns: Some("missing".to_owned()),
+    };
+    let response = client.request(&get).unwrap();
+    assert!(matches!(
+        &response,
+        Response::Error {
+            code: ErrorCode::NamespaceNotFound,
+            message,
+        } if message == "missing"
+    ));
+    assert!(matches!(
+        response.into_reply(),
+        Err(KvsError::NamespaceNotFound(name)) if name == "missing"
+    ));
+    client.set("count".to_owned(), "x".to_owned()).unwrap();
+    let incr = Request::Incr {
+        key: "count".to_owned(),
+        delta: 1,
+        initial: 0,
+        ns: None,
+    };
+    assert!(matches!(
+        client.request(&incr).unwrap().into_reply(),
+        Err(KvsError::Merge(_))
+    ));
+    client.remove("count".to_owned()).unwrap();
+    assert!(matches!(
+        client.request(&incr).unwrap().into_reply(),
+        Ok(Reply::Integer(1))
+    ));
+
+    let responses = exchange(
+        ADDR,
+        &[
+            // clients from before protocol versions
+            json!({ "Get": { "key": "key1" } }),
+            json!({ "Set": { "key": "key1", "value": "value1" } }),
+            json!({ "Stats": {} }),
+            // a client of a later version
+            json!({ "version": 4, "request": { "Get": { "key": "key1" } } }),
+            json!({ "version": 2, "request": { "Frobnicate": {} } }),
+            json!({ "version": 2, "request": { "Get": { "key": "key1" } } }),
+        ],
+    );
+    let rejection = "protocol version 1 is not supported";
+    assert_eq!(responses[0], json!({ "Get": { "value": rejection } }));
+    assert_eq!(responses[1], json!({ "Set": { "value": rejection } }));
+    assert_eq!(
+        responses[2],
+        json!({ "Stats": { "value": { "Err": rejection } } })
+    );
+    assert_eq!(
+        responses[3],
+        json!({ "Error": { "code": "UnsupportedVersion", "message": "4" } })
+    );
+    assert_eq!(responses[4]["Error"]["code"], json!("InvalidRequest"));
+    assert_eq!(responses[5], json!({ "Ok": { "Value": "Key not found" } }));
+}
+
+/// Answers like a server of protocol version 2 until the client hangs up:
+/// hellos are unknown to it, and it holds a single pair.
+fn old_server(listener: TcpListener) {
+    let (stream, _) = listener.accept().unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    for message in Deserializer::from_reader(BufReader::new(stream)).into_iter::<Value>() {
+        let message = message.unwrap();
+        let response = if message["version"] != json!(2) {
+            json!({ "Error": { "code": "UnsupportedVersion", "message": message["version"].to_string() } })
+        } else if message["request"].get("Hello").is_some() {
+            json!({ "Error": { "code": "InvalidRequest", "message": "unknown variant `Hello`" } })
+        } else if message["request"].get("Get").is_some() {
+            json!({ "Ok": { "Value": "old" } })
+        } else {
+            json!({ "Ok": "Done" })
+        };
+        writer
+            .write_all(&serde_json::to_vec(&response).unwrap())
+            .unwrap();
+    }
+}
+
+// Connections agree on the highest version and the capabilities both sides
+// support, and fall back to version 2 with servers from before handshakes.
+#[test]
+fn handshake() {
+    let dir = TempDir::new().unwrap();
+    let _server = server(dir.path(), &["--addr", HANDSHAKE_ADDR]);
+
+    // new client, new server
+    let client = KvsClient::connect(HANDSHAKE_ADDR).unwrap();
+    let welcome = client.welcome(HANDSHAKE_ADDR).unwrap();
+    assert_eq!(welcome.version, PROTOCOL_VERSION);
+    assert_eq!(
+        welcome.capabilities,
+        [
+            Capability::Scan,
+            Capability::BinaryFrames,
+            Capability::Pipelining
+        ]
+        .into()
+    );
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    let responses = exchange(
+        HANDSHAKE_ADDR,
+        &[
+            // a later client, offering capabilities unknown to the server
+            json!({ "version": 2, "request": { "Hello": {
+                "version": 9,
+                "capabilities": ["Ttl", "Scan", "Teleport"],
+            } } }),
+            // a client too old
+            json!({ "version": 2, "request": { "Hello": { "version": 1, "capabilities": [] } } }),
+            // a client of version 2, which does not say hello
+            json!({ "version": 2, "request": { "Get": { "key": "key1" } } }),
+        ],
+    );
+    let welcome: Response = serde_json::from_value(responses[0].clone()).unwrap();
+    assert!(matches!(
+        welcome.into_reply(),
+        Ok(Reply::Welcome(Welcome { version: PROTOCOL_VERSION, capabilities }))
+            if capabilities == [Capability::Scan].into()
+    ));
+    assert_eq!(
+        responses[1],
+        json!({ "Error": { "code": "UnsupportedVersion", "message": "1" } })
+    );
+    assert_eq!(responses[2], json!({ "Ok": { "Value": "value1" } }));
+
+    // new client, old server
+    let listener = TcpListener::bind(OLD_ADDR).unwrap();
+    let old = thread::spawn(move || old_server(listener));
+    let client = KvsClient::connect(OLD_ADDR).unwrap();
+    assert_eq!(client.welcome(OLD_ADDR).unwrap(), Welcome::unversioned());
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap().as_deref(),
+        Some("old")
+    );
+    let hello = Request::Hello {
+        version: PROTOCOL_VERSION,
+        capabilities: [Capability::Scan].into(),
+    };
+    assert!(matches!(
+        client.request(&hello).unwrap().into_reply(),
+        Err(KvsError::InvalidRequest(_))
+    ));
+    drop(client);
+    old.join().unwrap();
+}
+
+// Connections whose handshake agrees on binary frames switch to them, and
+// others keep to JSON on the same server. Frames answered carry the ID of
+// their request, and a payload that is not a request does not put the
+// connection out of step.
+#[test]
+fn binary_frames() {
+    let dir = TempDir::new().unwrap();
+    let _server = server(dir.path(), &["--addr", FRAMES_ADDR]);
+
+    let client = KvsClient::connect(FRAMES_ADDR).unwrap();
+    let welcome = client.welcome(FRAMES_ADDR).unwrap();
+    assert!(welcome.capabilities.contains(&Capability::BinaryFrames));
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let options = KvsClientOptions {
+        binary_frames: false,
+        ..KvsClientOptions::default()
+    };
+    let json_client = KvsClient::connect_with_options(FRAMES_ADDR, options).unwrap();
+    let welcome = json_client.welcome(FRAMES_ADDR).unwrap();
+    assert_eq!(welcome.capabilities, [Capability::Scan].into());
+    assert_eq!(
+        json_client.get("key1".to_owned()).unwrap().as_deref(),
+        Some("value1")
+    );
+    json_client.remove("key1".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+
+    let mut stream = TcpStream::connect(FRAMES_ADDR).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let hello = json!({ "version": 2, "request": { "Hello": {
+        "version": PROTOCOL_VERSION,
+        "capabilities": ["BinaryFrames"],
+    } } });
+    stream
+        .write_all(&serde_json::to_vec(&hello).unwrap())
+        .unwrap();
+    let welcome = Response::deserialize(&mut Deserializer::from_reader(&mut reader)).unwrap();
+    assert!(matches!(
+        welcome.into_reply(),
+        Ok(Reply::Welcome(Welcome { capabilities, .. }))
+            if capabilities == [Capability::BinaryFrames].into()
+    ));
+    let mut call = |frame: Frame| {
+        stream.write_all(&frame.encode()).unwrap();
+        let response = read_frame(&mut reader).unwrap().unwrap();
+        assert_eq!(response.id, frame.id);
+        response.message::<Response>().unwrap()
+    };
+    let set = Request::Set {
+        key: "key2".to_owned(),
+        value: "value2".to_owned(),
+        ns: None,
+    };
+    assert!(matches!(
+        call(Frame::new(7, &Envelope::new(set)).unwrap()),
+        Response::Ok(Reply::Done)
+    ));
+    assert!(matches!(
+        call(Frame::new(3, &"not a request").unwrap()),
+        Response::Error {
+            code: ErrorCode::InvalidRequest,
+            ..
+        }
+    ));
+    let later = Envelope {
+        version: 9,
+        request: Request::Stats { ns: None },
+    };
+    assert!(matches!(
+        call(Frame::new(u64::MAX, &later).unwrap()),
+        Response::Error {
+            code: ErrorCode::UnsupportedVersion,
+            message,
+        } if message == "9"
+    ));
+    let get = Request::Get {
+        key: "key2".to_owned(),
+        ns: None,
+    };
+    assert!(matches!(
+        call(Frame::new(8, &Envelope::new(get)).unwrap()),
+        Response::Ok(Reply::Value(value)) if value == "value2"
+    ));
+}
+
+// A watch on a pipelined connection streams on when the client sends
+// further frames, on either runtime.
+#[test]
+fn frames_after_watch() {
+    let servers = [
+        Some((WATCH_ADDR, "threads")),
+        cfg!(feature = "async").then_some((ASYNC_WATCH_ADDR, "async")),
+    ];
+    for (addr, runtime) in servers.into_iter().flatten() {
+        let dir = TempDir::new().unwrap();
+        let _server = server(dir.path(), &["--addr", addr, "--runtime", runtime]);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let hello = json!({ "version": 2, "request": { "Hello": {
+            "version": PROTOCOL_VERSION,
+            "capabilities": ["BinaryFrames", "Pipelining"],
+        } } });
+        stream
+            .write_all(&serde_json::to_vec(&hello).unwrap())
+            .unwrap();
+        let welcome = Response::deserialize(&mut Deserializer::from_reader(&mut reader)).unwrap();
+        assert!(matches!(
+            welcome.into_reply(),
+            Ok(Reply::Welcome(Welcome { capabilities, .. }))
+                if capabilities.contains(&Capability::Pipelining)
+        ));
+        let watch = Request::Watch {
+            start: Bound::Included("watched".to_owned()),
+            end: Bound::Included("watched".to_owned()),
+            ns: None,
+        };
+        let get = Request::Get {
+            key: "watched".to_owned(),
+            ns: None,
+        };
+        stream
+            .write_all(&Frame::new(1, &Envelope::new(watch)).unwrap().encode())
+            .unwrap();
+        thread::sleep(Duration::from_millis(200));
+        stream
+            .write_all(&Frame::new(2, &Envelope::new(get)).unwrap().encode())
+            .unwrap();
+        // past a poll for a hang-up
+        thread::sleep(Duration::from_secs(1));
+        let client = KvsClient::connect(addr).unwrap();
+        client.set("watched".to_owned(), "1".to_owned()).unwrap();
+
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let frame = read_frame(&mut reader).unwrap().unwrap();
+        assert_eq!(frame.id, 1);
+        assert!(matches!(
+            frame.message::<Response>().unwrap(),
+            Response::Ok(Reply::Change(change)) if change.value.as_deref() == Some("1")
+        ));
+    }
+}
@@ -0,0 +1,137 @@
+use assert_cmd::prelude::*;
+use predicates::prelude::PredicateBooleanExt;
+use predicates::str::contains;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const BACKENDS: [&str; 3] = ["127.0.0.1:4019", "127.0.0.1:4020", "127.0.0.1:4021"];
+const PROXY: &str = "127.0.0.1:4022";
+
+/// Process, killed when dropped so that a failing test frees its port.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn server(bin: &str, dir: &Path, args: &[&str]) -> Server {
+    let mut child = Command::cargo_bin(bin)
+        .unwrap()
+        .args(args)
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+    assert!(child.try_wait().unwrap().is_none(), "{} exited", bin);
+    Server(child)
+}
+
+fn client(addr: &str, args: &[&str]) -> Command {
+    let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+    cmd.args(args).args(["--addr", addr]);
+    cmd
+}
+
+/// Keys stored on `addr`.
+fn keys(addr: &str) -> BTreeSet<String> {
+    let output = client(addr, &["scan"]).output().unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| line.split('\t').next().unwrap().to_owned())
+        .collect()
+}
+
+/// Asserts that every key is on exactly one of `backends`, and that the
+/// proxy finds them all.
+fn assert_sharded(backends: &[&str], expected: &BTreeSet<String>) {
+    let mut all = BTreeSet::new();
+    for backend in backends {
+        let keys = keys(backend);
+        assert!(!keys.is_empty(), "{} holds no keys", backend);
+        for key in keys {
+            assert!(all.insert(key.clone()), "{} is on two backends", key);
+        }
+    }
+    assert_eq!(&all, expected);
+    assert_eq!(&keys(PROXY), expected);
+    for key in expected.iter().step_by(7) {
+        client(PROXY, &["get", key])
+            .assert()
+            .success()
+            .stdout(format!("value-{}\n", key));
+    }
+}
+
+// The proxy spreads keys over its backends, answers scans, bulk deletes and
+// stats from all of them, and moves keys when backends come and go.
+#[test]
+fn proxy() {
+    let dirs: Vec<TempDir> = (0..4).map(|_| TempDir::new().unwrap()).collect();
+    let _backends: Vec<Server> = BACKENDS
+        .iter()
+        .zip(&dirs)
+        .map(|(addr, dir)| server("kvs-server", dir.path(), &["--addr", addr]))
+        .collect();
+    let backends = format!("{},{}", BACKENDS[0], BACKENDS[1]);
+    let _proxy = server(
+        "kvs-proxy",
+        dirs[3].path(),
+        &["--addr", PROXY, "--backends", &backends],
+    );
+
+    let mut expected = BTreeSet::new();
+    for i in 0..60 {
+        let key = format!("key{:02}", i);
+        client(PROXY, &["set", &key, &format!("value-{}", key)])
+            .assert()
+            .success();
+        expected.insert(key);
+    }
+    assert_sharded(&BACKENDS[..2], &expected);
+    client(PROXY, &["incr", "key00-count", "3"])
+        .assert()
+        .success()
+        .stdout("3\n");
+    client(PROXY, &["rm", "--prefix", "key00-"])
+        .assert()
+        .success()
+        .stdout("1\n");
+    client(PROXY, &["stats"])
+        .assert()
+        .success()
+        .stdout(contains(format!("backends\t{}\n", backends)))
+        .stdout(contains("keys\t60\n"))
+        .stdout(contains(format!("{}/keys\t", BACKENDS[0])));
+    client(PROXY, &["get", "key01", "--ns", "ns"])
+        .assert()
+        .failure()
+        .stderr(contains("namespaces are not sharded"));
+
+    client(PROXY, &["add-node", BACKENDS[2]])
+        .assert()
+        .success()
+        .stdout(contains(BACKENDS[2]));
+    assert_sharded(&BACKENDS, &expected);
+
+    client(PROXY, &["remove-node", BACKENDS[0]])
+        .assert()
+        .success()
+        .stdout(contains(BACKENDS[0]).not());
+    assert!(keys(BACKENDS[0]).is_empty());
+    assert_sharded(&BACKENDS[1..], &expected);
+
+    client(PROXY, &["rm", "--prefix", "key"])
+        .assert()
+        .success()
+        .stdout("60\n");
+    assert!(keys(PROXY).is_empty());
+}
@@ -0,0 +1,466 @@
+use std::collections::{BTreeMap, HashMap};
+
+use kvs::raft::{Command, Message, Raft, RaftOptions, Role};
+use kvs::{KvStore, KvsEngine, KvsError, Result};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use tempfile::TempDir;
+
+/// Nodes of one cluster in one process, exchanging messages through a
+/// network that can be partitioned and lose messages.
+struct Network {
+    nodes: BTreeMap<String, Raft<KvStore>>,
+    dirs: HashMap<String, TempDir>,
+    options: RaftOptions,
+    /// Side of the partition of each node; nodes on different sides cannot
+    /// reach each other.
+    sides: HashMap<String, usize>,
+    drop_rate: f64,
+    rng: SmallRng,
+    /// Index and term of every entry each node applied, in order.
+    applied: HashMap<String, Vec<(u64, u64)>>,
+}
+
+impl Network {
+    fn new(size: usize, options: RaftOptions) -> Result<Network> {
+        let members: Vec<String> = (1..=size).map(|i| format!("n{}", i)).collect();
+        let mut network = Network {
+            nodes: BTreeMap::new(),
+            dirs: HashMap::new(),
+            options,
+            sides: HashMap::new(),
+            drop_rate: 0.0,
+            rng: SmallRng::seed_from_u64(7),
+            applied: HashMap::new(),
+        };
+        for id in &members {
+            network.start(id, &members)?;
+        }
+        Ok(network)
+    }
+
+    /// Starts node `id`, with the state it had if it ran before.
+    fn start(&mut self, id: &str, members: &[String]) -> Result<()> {
+        if !self.dirs.contains_key(id) {
+            self.dirs.insert(id.to_owned(), TempDir::new()?);
+        }
+        let dir = self.dirs[id].path();
+        let engine = KvStore::open(dir)?;
+        let raft = Raft::open(
+            id,
+            members.iter().cloned(),
+            engine,
+            Some(dir.join("raft")),
+            self.options.clone(),
+        )?;
+        self.nodes.insert(id.to_owned(), raft);
+        Ok(())
+    }
+
+    fn stop(&mut self, id: &str) {
+        self.nodes.remove(id);
+    }
+
+    fn node(&mut self, id: &str) -> &mut Raft<KvStore> {
+        self.nodes.get_mut(id).unwrap()
+    }
+
+    /// Ticks every node once, then delivers messages until there are none.
+    fn tick(&mut self) -> Result<()> {
+        for raft in self.nodes.values_mut() {
+            raft.tick()?;
+        }
+        loop {
+            let mut messages: Vec<(String, String, Message)> = Vec::new();
+            for (id, raft) in self.nodes.iter_mut() {
+                for (to, message) in raft.take_messages() {
+                    messages.push((id.clone(), to, message));
+                }
+                let applied = self.applied.entry(id.clone()).or_default();
+                applied.extend(raft.take_applied().iter().map(|a| (a.index, a.term)));
+            }
+            if messages.is_empty() {
+                return Ok(());
+            }
+            for (from, to, message) in messages {
+                let reachable = self.sides.get(&from) == self.sides.get(&to);
+                if !reachable || self.rng.gen_bool(self.drop_rate) {
+                    continue;
+                }
+                if let Some(raft) = self.nodes.get_mut(&to) {
+                    raft.step(&from, message)?;
+                }
+            }
+        }
+    }
+
+    fn run(&mut self, ticks: usize) -> Result<()> {
+        for _ in 0..ticks {
+            self.tick()?;
+        }
+        Ok(())
+    }
+
+    /// Runs until `done` holds, failing after a while.
+    fn run_until(&mut self, mut done: impl FnMut(&Network) -> bool) -> Result<()> {
+        for _ in 0..1000 {
+            if done(self) {
+                return Ok(());
+            }
+            self.tick()?;
+        }
+        panic!("cluster did not get there in 1000 ticks");
+    }
+
+    /// The leader of the latest term, if any.
+    fn leader(&self) -> Option<String> {
+        self.nodes
+            .values()
+            .filter(|raft| raft.role() == Role::Leader)
+            .max_by_key(|raft| raft.term())
+            .map(|raft| raft.id().to_owned())
+    }
+
+    fn elect(&mut self) -> Result<String> {
+        self.run_until(|network| network.leader().is_some())?;
+        Ok(self.leader().unwrap())
+    }
+
+    fn partition(&mut self, sides: &[&[&str]]) {
+        self.sides.clear();
+        for (side, ids) in sides.iter().enumerate() {
+            for id in ids.iter() {
+                self.sides.insert((*id).to_owned(), side);
+            }
+        }
+    }
+
+    fn heal(&mut self) {
+        self.sides.clear();
+    }
+
+    fn set(&mut self, id: &str, key: &str, value: &str) -> Result<(u64, u64)> {
+        self.node(id).propose(Command::Set {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        })
+    }
+
+    fn get(&self, id: &str, key: &str) -> Result<Option<String>> {
+        self.nodes[id].engine().get(key.to_owned())
+    }
+
+    fn committed(&self, id: &str, index: u64) -> bool {
+        self.nodes[id].last_applied() >= index
+    }
+
+    /// Asserts that no two nodes applied different entries at an index.
+    fn assert_consistent(&self) {
+        let mut terms: HashMap<u64, u64> = HashMap::new();
+        for applied in self.applied.values() {
+            for &(index, term) in applied {
+                assert_eq!(*terms.entry(index).or_insert(term), term, "index {}", index);
+            }
+        }
+    }
+}
+
+fn ids(ids: &[&str]) -> Vec<String> {
+    ids.iter().map(|id| (*id).to_owned()).collect()
+}
+
+#[test]
+fn elects_one_leader() -> Result<()> {
+    let mut network = Network::new(3, RaftOptions::default())?;
+    let leader = network.elect()?;
+    network.run(50)?;
+    assert_eq!(network.leader(), Some(leader.clone()));
+    for raft in network.nodes.values() {
+        assert_eq!(raft.leader(), Some(leader.as_str()));
+        if raft.id() != leader {
+            assert_eq!(raft.role(), Role::Follower);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn only_the_leader_takes_writes() -> Result<()> {
+    let mut network = Network::new(3, RaftOptions::default())?;
+    let leader = network.elect()?;
+    network.run(5)?;
+    let follower = if leader == "n1" { "n2" } else { "n1" };
+    match network.set(follower, "key", "value") {
+        Err(KvsError::NotLeader(Some(to))) => assert_eq!(to, leader),
+        rv => panic!("unexpected {:?}", rv),
+    }
+    Ok(())
+}
+
+#[test]
+fn commits_once_a_majority_has_the_write() -> Result<()> {
+    let mut network = Network::new(5, RaftOptions::default())?;
+    let leader = network.elect()?;
+    network.run(5)?;
+    let (index, _) = network.set(&leader, "key1", "value1")?;
+    network.run_until(|network| network.nodes.keys().all(|id| network.committed(id, index)))?;
+    for id in ids(&["n1", "n2", "n3", "n4", "n5"]) {
+        assert_eq!(network.get(&id, "key1")?, Some("value1".to_owned()));
+    }
+
+    // two nodes fail, three still make a majority
+    let down: Vec<String> = network
+        .nodes
+        .keys()
+        .filter(|id| **id != leader)
+        .take(2)
+        .cloned()
+        .collect();
+    let up: Vec<String> = network
+        .nodes
+        .keys()
+        .filter(|id| !down.contains(id))
+        .cloned()
+        .collect();
+    for id in &down {
+        network.stop(id);
+    }
+    let (index, _) = network.set(&leader, "key2", "value2")?;
+    network.run_until(|network| network.committed(&leader, index))?;
+    for id in &up {
+        network.run_until(|network| network.committed(id, index))?;
+        assert_eq!(network.get(id, "key2")?, Some("value2".to_owned()));
+    }
+
+    // with three down it cannot commit
+    let third = up.iter().find(|id| **id != leader).unwrap().clone();
+    network.stop(&third);
+    let (index, _) = network.set(&leader, "key3", "value3")?;
+    network.run(100)?;
+    assert!(!network.committed(&leader, index));
+    assert_eq!(network.get(&leader, "key3")?, None);
+    // and the leader steps down
+    assert_ne!(network.leader(), Some(leader.clone()));
+
+    // the write commits once the nodes come back
+    let members = ids(&["n1", "n2", "n3", "n4", "n5"]);
+    for id in down.iter().chain([&third]) {
+        network.start(id, &members)?;
+    }
+    network.run_until(|network| network.nodes.keys().all(|id| network.committed(id, index)))?;
+    for id in &members {
+        assert_eq!(network.get(id, "key1")?, Some("value1".to_owned()));
+        assert_eq!(network.get(id, "key2")?, Some("value2".to_owned()));
+        assert_eq!(network.get(id, "key3")?, Some("value3".to_owned()));
+    }
+    network.assert_consistent();
+    Ok(())
+}
+
+#[test]
+fn partitioned_leader_is_replaced() -> Result<()> {
+    let mut network = Network::new(5, RaftOptions::default())?;
+    let old = network.elect()?;
+    network.run(5)?;
+    let (index, _) = network.set(&old, "key", "value1")?;
+    network.run_until(|network| network.committed(&old, index))?;
+
+    let mut others: Vec<String> = network
+        .nodes
+        .keys()
+        .filter(|id| **id != old)
+        .cloned()
+        .collect();
+    let minority = [old.as_str(), others[0].as_str()];
+    let majority: Vec<&str> = others[1..].iter().map(String::as_str).collect();
+    network.partition(&[&minority, &majority]);
+
+    // the old leader cannot commit, the majority elects a new one
+    let (lost, lost_term) = network.set(&old, "key", "lost")?;
+    network.run_until(|network| network.leader().is_some_and(|new| new != old))?;
+    let new = network.leader().unwrap();
+    assert!(majority.contains(&new.as_str()));
+    let (index, _) = network.set(&new, "key", "value2")?;
+    network.run_until(|network| network.committed(&new, index))?;
+    assert!(!network.committed(&old, lost));
+    // without a majority the old leader steps down
+    network.run_until(|network| network.nodes[&old].role() != Role::Leader)?;
+
+    // after healing the old leader drops its write for the new ones
+    network.heal();
+    others.push(old.clone());
+    network.run_until(|network| others.iter().all(|id| network.committed(id, index)))?;
+    for id in &others {
+        assert_eq!(network.get(id, "key")?, Some("value2".to_owned()));
+    }
+    assert!(network.node(&old).term() > lost_term);
+    network.assert_consistent();
+    Ok(())
+}
+
+// A leader lets reads go once a majority answered it after they started, so
+// one cut off from the majority never serves a read missing newer writes.
+#[test]
+fn partitioned_leader_serves_no_stale_reads() -> Result<()> {
+    let mut network = Network::new(5, RaftOptions::default())?;
+    let old = network.elect()?;
+    network.run(5)?;
+    let (index, _) = network.set(&old, "key", "value1")?;
+    network.run_until(|network| network.committed(&old, index))?;
+    let read = network.node(&old).read_index()?;
+    network.run(1)?;
+    assert!(matches!(network.node(&old).take_reads()[..], [(id, Ok(()))] if id == read));
+
+    let others: Vec<String> = network
+        .nodes
+        .keys()
+        .filter(|id| **id != old)
+        .cloned()
+        .collect();
+    // followers send reads to the leader
+    assert!(matches!(
+        network.node(&others[0]).read_index(),
+        Err(KvsError::NotLeader(Some(to))) if to == old
+    ));
+
+    let minority = [old.as_str(), others[0].as_str()];
+    let majority: Vec<&str> = others[1..].iter().map(String::as_str).collect();
+    network.partition(&[&minority, &majority]);
+    let stale = network.node(&old).read_index()?;
+    network.run_until(|network| network.leader().is_some_and(|new| new != old))?;
+    let new = network.leader().unwrap();
+    let (index, _) = network.set(&new, "key", "value2")?;
+    network.run_until(|network| network.committed(&new, index))?;
+    network.run_until(|network| network.nodes[&old].role() != Role::Leader)?;
+    // the old leader still has the old value, but never let the read go
+    assert_eq!(network.get(&old, "key")?, Some("value1".to_owned()));
+    assert!(matches!(
+        network.node(&old).take_reads()[..],
+        [(id, Err(KvsError::NotLeader(_)))] if id == stale
+    ));
+
+    let read = network.node(&new).read_index()?;
+    network.run(1)?;
+    assert!(matches!(network.node(&new).take_reads()[..], [(id, Ok(()))] if id == read));
+    assert_eq!(network.get(&new, "key")?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn stays_consistent_on_a_lossy_network() -> Result<()> {
+    let mut network = Network::new(5, RaftOptions::default())?;
+    network.drop_rate = 0.2;
+    for i in 0..50 {
+        let leader = network.elect()?;
+        // a leader that lost its majority may still think it leads
+        let _ = network.set(&leader, "key", &format!("value{}", i));
+        network.run(2)?;
+    }
+    network.assert_consistent();
+
+    network.drop_rate = 0.0;
+    let leader = network.elect()?;
+    let (index, _) = network.set(&leader, "done", "yes")?;
+    network.run_until(|network| network.nodes.keys().all(|id| network.committed(id, index)))?;
+    network.assert_consistent();
+    let expected = network.get(&leader, "key")?;
+    assert!(expected.is_some());
+    for id in ids(&["n1", "n2", "n3", "n4", "n5"]) {
+        assert_eq!(network.get(&id, "key")?, expected);
+    }
+    Ok(())
+}
+
+#[test]
+fn lagging_follower_catches_up_from_a_snapshot() -> Result<()> {
+    let options = RaftOptions {
+        snapshot_entries: 10,
+        ..RaftOptions::default()
+    };
+    let mut network = Network::new(3, options)?;
+    let leader = network.elect()?;
+    network.run(5)?;
+    let lagging = network
+        .nodes
+        .keys()
+        .find(|id| **id != leader)
+        .unwrap()
+        .clone();
+    network.stop(&lagging);
+
+    let mut index = 0;
+    for i in 0..50 {
+        index = network.set(&leader, &format!("key{}", i), "value")?.0;
+        network.tick()?;
+    }
+    network.node(&leader).propose(Command::Remove {
+        key: "key0".to_owned(),
+    })?;
+    network.run_until(|network| network.committed(&leader, index + 1))?;
+    assert!(network.node(&leader).snapshot_index() >= 40);
+
+    network.start(&lagging, &ids(&["n1", "n2", "n3"]))?;
+    network.run_until(|network| network.committed(&lagging, index + 1))?;
+    assert!(network.node(&lagging).snapshot_index() > 0);
+    assert_eq!(network.get(&lagging, "key0")?, None);
+    for i in 1..50 {
+        assert_eq!(
+            network.get(&lagging, &format!("key{}", i))?,
+            Some("value".to_owned())
+        );
+    }
+
+    // a restarted node comes back from its own snapshot and log
+    network.stop(&lagging);
+    network.start(&lagging, &ids(&["n1", "n2", "n3"]))?;
+    assert_eq!(network.get(&lagging, "key1")?, Some("value".to_owned()));
+    network.run(20)?;
+    assert_eq!(network.get(&lagging, "key49")?, Some("value".to_owned()));
+    assert_eq!(network.get(&lagging, "key0")?, None);
+    Ok(())
+}
+
+#[test]
+fn members_join_and_leave() -> Result<()> {
+    let mut network = Network::new(3, RaftOptions::default())?;
+    let initial = ids(&["n1", "n2", "n3"]);
+    let leader = network.elect()?;
+    network.run(5)?;
+    let (index, _) = network.set(&leader, "key", "value")?;
+    network.run_until(|network| network.committed(&leader, index))?;
+
+    // a new node waits until it is added
+    network.start("n4", &initial)?;
+    network.run(50)?;
+    assert_eq!(network.node("n4").role(), Role::Follower);
+    assert_eq!(network.get("n4", "key")?, None);
+
+    let (index, _) = network.node(&leader).change_membership("n4", true)?;
+    assert!(matches!(
+        network.node(&leader).change_membership("n5", true),
+        Err(KvsError::Raft(_))
+    ));
+    network.run_until(|network| network.committed("n4", index))?;
+    let members = ids(&["n1", "n2", "n3", "n4"]);
+    assert_eq!(
+        network.node("n4").members().iter().collect::<Vec<_>>(),
+        members.iter().collect::<Vec<_>>()
+    );
+    assert_eq!(network.get("n4", "key")?, Some("value".to_owned()));
+
+    // the leader removes itself, the others carry on
+    let (index, _) = network.node(&leader).change_membership(&leader, false)?;
+    network.run_until(|network| network.committed(&leader, index))?;
+    network.run_until(|network| network.leader().is_some_and(|new| new != leader))?;
+    let new = network.leader().unwrap();
+    assert_ne!(network.node(&leader).role(), Role::Leader);
+    assert!(!network.node(&new).members().contains(&leader));
+    let (index, _) = network.set(&new, "key", "value2")?;
+    network.stop(&leader);
+    network.run_until(|network| network.nodes.keys().all(|id| network.committed(id, index)))?;
+    for id in network.nodes.keys() {
+        assert_eq!(network.get(id, "key")?, Some("value2".to_owned()));
+    }
+    network.assert_consistent();
+    Ok(())
+}
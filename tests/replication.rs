@@ -0,0 +1,114 @@
+use assert_cmd::prelude::*;
+use predicates::str::contains;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const PRIMARY: &str = "127.0.0.1:4013";
+const REPLICA: &str = "127.0.0.1:4014";
+
+/// Server process, killed when dropped so that a failing test frees its
+/// port.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn server(dir: &Path, args: &[&str]) -> Server {
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(args)
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    assert!(child.try_wait().unwrap().is_none(), "server exited");
+    Server(child)
+}
+
+fn client(addr: &str, args: &[&str]) -> Command {
+    let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+    cmd.args(args).args(["--addr", addr]);
+    cmd
+}
+
+/// Runs `args` against `addr` until it prints `expected`.
+fn wait_for(addr: &str, args: &[&str], expected: &str) {
+    for _ in 0..50 {
+        let output = client(addr, args).output().unwrap();
+        if String::from_utf8_lossy(&output.stdout).contains(expected) {
+            return;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("{:?} on {} never printed {:?}", args, addr, expected);
+}
+
+// A replica bootstraps from the primary, follows its writes, rejects writes
+// of its own and catches up again after the primary restarts.
+#[test]
+fn replication() {
+    let primary_dir = TempDir::new().unwrap();
+    let replica_dir = TempDir::new().unwrap();
+    let primary = server(primary_dir.path(), &["--engine", "kvs", "--addr", PRIMARY]);
+    client(PRIMARY, &["set", "key1", "value1"])
+        .assert()
+        .success();
+    client(PRIMARY, &["set", "key2", "value2"])
+        .assert()
+        .success();
+
+    let _replica = server(
+        replica_dir.path(),
+        &[
+            "--engine",
+            "sled",
+            "--addr",
+            REPLICA,
+            "--replica-of",
+            PRIMARY,
+        ],
+    );
+    wait_for(REPLICA, &["get", "key1"], "value1");
+    wait_for(REPLICA, &["get", "key2"], "value2");
+
+    client(PRIMARY, &["set", "key3", "value3"])
+        .assert()
+        .success();
+    client(PRIMARY, &["rm", "key1"]).assert().success();
+    wait_for(REPLICA, &["get", "key3"], "value3");
+    wait_for(REPLICA, &["get", "key1"], "Key not found");
+
+    client(REPLICA, &["set", "key4", "value4"])
+        .assert()
+        .failure()
+        .stderr(contains("read-only replica"));
+    client(REPLICA, &["rm", "key2"]).assert().failure();
+    client(REPLICA, &["stats"])
+        .assert()
+        .success()
+        .stdout(contains(format!("replica_of\t{}", PRIMARY)))
+        .stdout(contains("replica_connected\ttrue"))
+        .stdout(contains("replica_lag_ms\t"));
+
+    // reads go on while the primary is down
+    drop(primary);
+    wait_for(REPLICA, &["stats"], "replica_connected\tfalse");
+    client(REPLICA, &["get", "key2"])
+        .assert()
+        .success()
+        .stdout("value2\n");
+
+    let _primary = server(primary_dir.path(), &["--addr", PRIMARY]);
+    client(PRIMARY, &["set", "key5", "value5"])
+        .assert()
+        .success();
+    wait_for(REPLICA, &["get", "key5"], "value5");
+    wait_for(REPLICA, &["get", "key3"], "value3");
+}
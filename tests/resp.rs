@@ -0,0 +1,214 @@
+use assert_cmd::prelude::*;
+use kvs::KvsClient;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const ADDR: &str = "127.0.0.1:4034";
+const RESP_ADDR: &str = "127.0.0.1:4035";
+const FRAMING_ADDR: &str = "127.0.0.1:4036";
+const FRAMING_RESP_ADDR: &str = "127.0.0.1:4037";
+
+/// Process, killed when dropped so that a failing test frees its port.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn server(dir: &Path, addr: &str, resp_addr: &str) -> Server {
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--resp-addr", resp_addr])
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+    assert!(child.try_wait().unwrap().is_none(), "kvs-server exited");
+    Server(child)
+}
+
+/// Redis client speaking RESP2, replies read back as text.
+struct Redis {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Redis {
+    fn connect() -> Redis {
+        let stream = TcpStream::connect(RESP_ADDR).unwrap();
+        let reader = BufReader::new(stream.try_clone().unwrap());
+        Redis { stream, reader }
+    }
+
+    fn call(&mut self, args: &[&str]) -> String {
+        self.stream.write_all(&command(args)).unwrap();
+        self.reply()
+    }
+
+    /// Reads a reply as its lines, bulk strings and arrays included.
+    fn reply(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        let len = line[1..].trim_end().parse::<i64>();
+        match line.as_bytes()[0] {
+            b'$' if len != Ok(-1) => {
+                let mut bulk = vec![0; len.unwrap() as usize + 2];
+                self.reader.read_exact(&mut bulk).unwrap();
+                line + &String::from_utf8(bulk).unwrap()
+            }
+            b'*' => (0..len.unwrap()).fold(line, |reply, _| reply + &self.reply()),
+            _ => line,
+        }
+    }
+}
+
+fn command(args: &[&str]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+    }
+    out
+}
+
+// Redis commands map onto the engine the native protocol serves, with
+// RESP replies and errors.
+#[test]
+fn resp_commands() {
+    let dir = TempDir::new().unwrap();
+    let _server = server(dir.path(), ADDR, RESP_ADDR);
+    let mut redis = Redis::connect();
+
+    assert_eq!(redis.call(&["PING"]), "+PONG\r\n");
+    assert_eq!(redis.call(&["ping", "hi"]), "$2\r\nhi\r\n");
+    assert_eq!(redis.call(&["GET", "key1"]), "$-1\r\n");
+    assert_eq!(redis.call(&["SET", "key1", "value1"]), "+OK\r\n");
+    assert_eq!(redis.call(&["GET", "key1"]), "$6\r\nvalue1\r\n");
+    assert_eq!(redis.call(&["SET", "key1", "other", "NX"]), "$-1\r\n");
+    assert_eq!(redis.call(&["SET", "key2", "value2", "XX"]), "$-1\r\n");
+    assert_eq!(redis.call(&["SET", "key2", "value2", "NX"]), "+OK\r\n");
+    assert_eq!(redis.call(&["SET", "key2", "new", "XX"]), "+OK\r\n");
+    assert_eq!(redis.call(&["EXISTS", "key1", "key2", "key3"]), ":2\r\n");
+    assert_eq!(
+        redis.call(&["MGET", "key1", "key3", "key2"]),
+        "*3\r\n$6\r\nvalue1\r\n$-1\r\n$3\r\nnew\r\n"
+    );
+    assert_eq!(redis.call(&["MSET", "a", "1", "b", "2"]), "+OK\r\n");
+    assert_eq!(redis.call(&["DEL", "a", "missing", "key2"]), ":2\r\n");
+    assert_eq!(redis.call(&["INCR", "count"]), ":1\r\n");
+    assert_eq!(redis.call(&["INCR", "count"]), ":2\r\n");
+    assert_eq!(
+        redis.call(&["INCR", "key1"]),
+        "-ERR value is not an integer or out of range\r\n"
+    );
+    assert_eq!(
+        redis.call(&["SET", "big", "9223372036854775807"]),
+        "+OK\r\n"
+    );
+    assert_eq!(
+        redis.call(&["INCR", "big"]),
+        "-ERR increment or decrement would overflow\r\n"
+    );
+
+    // keys expire, and are gone before the sweep reaches them
+    assert_eq!(redis.call(&["SET", "temp", "x", "PX", "100"]), "+OK\r\n");
+    assert_eq!(redis.call(&["SET", "kept", "x", "EX", "100"]), "+OK\r\n");
+    assert_eq!(redis.call(&["EXISTS", "temp"]), ":1\r\n");
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(redis.call(&["GET", "temp"]), "$-1\r\n");
+    assert_eq!(redis.call(&["SET", "temp", "y", "NX"]), "+OK\r\n");
+    assert_eq!(redis.call(&["GET", "temp"]), "$1\r\ny\r\n");
+    assert_eq!(redis.call(&["GET", "kept"]), "$1\r\nx\r\n");
+    assert!(redis.call(&["INFO"]).contains("db0:keys=6,expires=1\r\n"));
+
+    // errors
+    assert_eq!(
+        redis.call(&["SET", "key1", "v", "EX", "0"]),
+        "-ERR invalid expire time in 'set' command\r\n"
+    );
+    // deadlines too far off to keep are refused, and nothing is written
+    assert_eq!(
+        redis.call(&["SET", "far", "v", "EX", "9223372036854775807"]),
+        "-ERR invalid expire time in 'set' command\r\n"
+    );
+    assert_eq!(redis.call(&["GET", "far"]), "$-1\r\n");
+    assert_eq!(redis.call(&["PING"]), "+PONG\r\n");
+    assert_eq!(
+        redis.call(&["SET", "key1", "v", "NX", "XX"]),
+        "-ERR syntax error\r\n"
+    );
+    assert_eq!(
+        redis.call(&["GET"]),
+        "-ERR wrong number of arguments for 'get' command\r\n"
+    );
+    assert_eq!(
+        redis.call(&["FLUSHALL"]),
+        "-ERR unknown command 'flushall'\r\n"
+    );
+
+    // the native protocol sees the same keys
+    let client = KvsClient::connect(ADDR).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap().as_deref(),
+        Some("value1")
+    );
+    assert_eq!(client.get("a".to_owned()).unwrap(), None);
+    client.set("native".to_owned(), "n".to_owned()).unwrap();
+    assert_eq!(redis.call(&["GET", "native"]), "$1\r\nn\r\n");
+
+    // which clears deadlines with its writes, and misses expired keys
+    assert_eq!(redis.call(&["SET", "short", "x", "PX", "100"]), "+OK\r\n");
+    assert_eq!(redis.call(&["SET", "gone", "x", "PX", "100"]), "+OK\r\n");
+    client.set("short".to_owned(), "native".to_owned()).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(redis.call(&["GET", "short"]), "$6\r\nnative\r\n");
+    assert_eq!(client.get("gone".to_owned()).unwrap(), None);
+
+    // a scan in pages of two, of the keys starting with k
+    let mut cursor = "0".to_owned();
+    let mut keys = Vec::new();
+    loop {
+        let reply = redis.call(&["SCAN", &cursor, "MATCH", "k*", "COUNT", "2"]);
+        let lines: Vec<&str> = reply.split("\r\n").collect();
+        cursor = lines[2].to_owned();
+        keys.extend(lines[4..].iter().skip(1).step_by(2).map(|k| k.to_string()));
+        if cursor == "0" {
+            break;
+        }
+    }
+    keys.sort();
+    assert_eq!(keys, ["kept", "key1"]);
+}
+
+// Commands sent together are answered together, inline commands work as in
+// a terminal, and a protocol error closes the connection.
+#[test]
+fn resp_framing() {
+    let dir = TempDir::new().unwrap();
+    let _server = server(dir.path(), FRAMING_ADDR, FRAMING_RESP_ADDR);
+
+    let mut stream = TcpStream::connect(FRAMING_RESP_ADDR).unwrap();
+    let mut batch = Vec::new();
+    for i in 0..100 {
+        batch.extend(command(&["SET", &format!("key{}", i), "v"]));
+    }
+    batch.extend(command(&["EXISTS", "key0", "key99"]));
+    batch.extend_from_slice(b"PING\r\nget  key5\n");
+    stream.write_all(&batch).unwrap();
+    let expected = "+OK\r\n".repeat(100) + ":2\r\n+PONG\r\n$1\r\nv\r\n";
+    let mut replies = vec![0; expected.len()];
+    stream.read_exact(&mut replies).unwrap();
+    assert_eq!(String::from_utf8(replies).unwrap(), expected);
+
+    stream.write_all(b"*1\r\n$x\r\n").unwrap();
+    let mut rest = String::new();
+    stream.read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "-ERR Protocol error: invalid length\r\n");
+}
@@ -0,0 +1,231 @@
+use assert_cmd::prelude::*;
+use kvs::{KvsClient, KvsClientOptions, KvsError, Reply, Request, Response, TlsClientConfig};
+use predicates::str::contains;
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa, KeyPair};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const ADDR: &str = "127.0.0.1:4042";
+const MUTUAL_ADDR: &str = "127.0.0.1:4043";
+
+/// Process, killed when dropped so that a failing test frees its port.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn server(dir: &Path, args: &[&str]) -> Server {
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(args)
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+    assert!(child.try_wait().unwrap().is_none(), "kvs-server exited");
+    Server(child)
+}
+
+/// Certificate authority writing the certificates it signs to a directory.
+struct Ca {
+    cert: Certificate,
+    key: KeyPair,
+    dir: PathBuf,
+    name: &'static str,
+}
+
+impl Ca {
+    fn new(dir: &Path, name: &'static str) -> Ca {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let cert = params.self_signed(&key).unwrap();
+        fs::write(dir.join(format!("{}.pem", name)), cert.pem()).unwrap();
+        Ca {
+            cert,
+            key,
+            dir: dir.to_owned(),
+            name,
+        }
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir.join(format!("{}.pem", self.name))
+    }
+
+    /// Signs a certificate for `names`, and returns the paths of it and its
+    /// key.
+    fn sign(&self, file: &str, names: &[&str]) -> (PathBuf, PathBuf) {
+        let key = KeyPair::generate().unwrap();
+        let names: Vec<String> = names.iter().map(|&name| name.to_owned()).collect();
+        let cert = CertificateParams::new(names)
+            .unwrap()
+            .signed_by(&key, &self.cert, &self.key)
+            .unwrap();
+        let paths = (
+            self.dir.join(format!("{}.pem", file)),
+            self.dir.join(format!("{}-key.pem", file)),
+        );
+        fs::write(&paths.0, cert.pem()).unwrap();
+        fs::write(&paths.1, key.serialize_pem()).unwrap();
+        paths
+    }
+}
+
+fn options(tls: Option<TlsClientConfig>) -> KvsClientOptions {
+    KvsClientOptions {
+        read_timeout: Some(Duration::from_secs(2)),
+        tls,
+        ..KvsClientOptions::default()
+    }
+}
+
+// Clients trusting the CA of the server speak with it over TLS, pipelined
+// and through kvs-client, and others cannot.
+#[test]
+fn tls() {
+    let dir = TempDir::new().unwrap();
+    let certs = TempDir::new().unwrap();
+    let ca = Ca::new(certs.path(), "ca");
+    let (cert, key) = ca.sign("server", &["127.0.0.1", "kvs.test"]);
+    let _server = server(
+        dir.path(),
+        &[
+            "--addr",
+            ADDR,
+            "--tls-cert",
+            cert.to_str().unwrap(),
+            "--tls-key",
+            key.to_str().unwrap(),
+        ],
+    );
+
+    let tls = TlsClientConfig::new(&ca.path(), None).unwrap();
+    let client = KvsClient::connect_with_options(ADDR, options(Some(tls.clone()))).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap().as_deref(),
+        Some("value1")
+    );
+    // values spanning many records, sent without waiting
+    let big = "v".repeat(100_000);
+    let sets: Vec<Request> = (0..50)
+        .map(|i| Request::Set {
+            key: format!("big{}", i),
+            value: big.clone(),
+            ns: None,
+        })
+        .collect();
+    for response in client.request_all(&sets) {
+        assert!(matches!(response, Ok(Response::Ok(Reply::Done))));
+    }
+    // pipelined requests run side by side, so the gets wait for the sets
+    let gets: Vec<Request> = (0..50)
+        .map(|i| Request::Get {
+            key: format!("big{}", i),
+            ns: None,
+        })
+        .collect();
+    for response in &client.request_all(&gets) {
+        assert!(matches!(
+            response,
+            Ok(Response::Ok(Reply::Value(value))) if *value == big
+        ));
+    }
+
+    let named = tls.clone().with_server_name("kvs.test").unwrap();
+    let client = KvsClient::connect_with_options(ADDR, options(Some(named))).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap().as_deref(),
+        Some("value1")
+    );
+    let misnamed = tls.with_server_name("other.test").unwrap();
+    assert!(matches!(
+        KvsClient::connect_with_options(ADDR, options(Some(misnamed))),
+        Err(KvsError::Tls(_))
+    ));
+    let stranger = Ca::new(certs.path(), "stranger");
+    let untrusting = TlsClientConfig::new(&stranger.path(), None).unwrap();
+    assert!(matches!(
+        KvsClient::connect_with_options(ADDR, options(Some(untrusting))),
+        Err(KvsError::Tls(_))
+    ));
+    assert!(KvsClient::connect_with_options(ADDR, options(None)).is_err());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", ADDR, "--ca"])
+        .arg(ca.path())
+        .current_dir(dir.path())
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", ADDR, "--server-name", "kvs.test"])
+        .current_dir(dir.path())
+        .assert()
+        .failure()
+        .stderr(contains("--ca"));
+}
+
+// A server with a client CA turns away clients without a certificate that
+// CA signed.
+#[test]
+fn mutual_tls() {
+    let dir = TempDir::new().unwrap();
+    let certs = TempDir::new().unwrap();
+    let ca = Ca::new(certs.path(), "ca");
+    let client_ca = Ca::new(certs.path(), "client-ca");
+    let (cert, key) = ca.sign("server", &["127.0.0.1"]);
+    let _server = server(
+        dir.path(),
+        &[
+            "--addr",
+            MUTUAL_ADDR,
+            "--tls-cert",
+            cert.to_str().unwrap(),
+            "--tls-key",
+            key.to_str().unwrap(),
+            "--client-ca",
+            client_ca.path().to_str().unwrap(),
+        ],
+    );
+
+    let (client_cert, client_key) = client_ca.sign("client", &["client"]);
+    let tls = TlsClientConfig::new(&ca.path(), Some((&client_cert, &client_key))).unwrap();
+    let client = KvsClient::connect_with_options(MUTUAL_ADDR, options(Some(tls))).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap().as_deref(),
+        Some("value1")
+    );
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", MUTUAL_ADDR, "--ca"])
+        .arg(ca.path())
+        .arg("--cert")
+        .arg(&client_cert)
+        .arg("--cert-key")
+        .arg(&client_key)
+        .current_dir(dir.path())
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    // the server may only turn them away once their handshake is done
+    let anonymous = TlsClientConfig::new(&ca.path(), None).unwrap();
+    assert!(KvsClient::connect_with_options(MUTUAL_ADDR, options(Some(anonymous))).is_err());
+    let (cert, key) = ca.sign("impostor", &["client"]);
+    let impostor = TlsClientConfig::new(&ca.path(), Some((&cert, &key))).unwrap();
+    assert!(KvsClient::connect_with_options(MUTUAL_ADDR, options(Some(impostor))).is_err());
+}
@@ -0,0 +1,106 @@
+use std::sync::mpsc::TryRecvError;
+
+use kvs::{
+    prefix_range, CachedEngine, Change, KvStore, KvsEngine, LsmKvsEngine, Result, SledKvsEngine,
+};
+use tempfile::TempDir;
+
+fn change(key: &str, value: Option<&str>) -> (String, Option<String>) {
+    (key.to_owned(), value.map(str::to_owned))
+}
+
+// Watchers see every committed write in their range, in order, and nothing
+// else.
+fn watch(store: impl KvsEngine) -> Result<()> {
+    let changes = store.watch(prefix_range("user:"))?;
+    let key_changes = store.watch("user:1".to_owned()..="user:1".to_owned())?;
+
+    store.set("user:1".to_owned(), "a".to_owned())?;
+    store.set("order:1".to_owned(), "b".to_owned())?;
+    store.append("user:1".to_owned(), "c".to_owned())?;
+    store.set("user:2".to_owned(), "d".to_owned())?;
+    store.remove("user:1".to_owned())?;
+    assert!(store.remove("user:1".to_owned()).is_err());
+    assert_eq!(store.remove_prefix("user:")?, 1);
+
+    let received: Vec<Change> = changes.try_iter().collect();
+    assert!(received.windows(2).all(|w| w[0].seq < w[1].seq));
+    assert_eq!(
+        received
+            .into_iter()
+            .map(|c| (c.key, c.value))
+            .collect::<Vec<_>>(),
+        vec![
+            change("user:1", Some("a")),
+            change("user:1", Some("ac")),
+            change("user:2", Some("d")),
+            change("user:1", None),
+            change("user:2", None),
+        ]
+    );
+    assert_eq!(key_changes.try_iter().count(), 3);
+
+    // writes go on once a watcher is gone
+    drop(changes);
+    store.set("user:3".to_owned(), "e".to_owned())?;
+    assert!(key_changes.try_recv().is_err());
+    Ok(())
+}
+
+#[test]
+fn kvs_watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    watch(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    watch(SledKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn lsm_watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    watch(LsmKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn cached_watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    watch(CachedEngine::new(
+        KvStore::open(temp_dir.path())?,
+        1024 * 1024,
+    ))
+}
+
+// Watches of a namespace only see the writes to it.
+#[test]
+fn namespace_watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.create_namespace("users")?;
+    let users = store.namespace("users")?;
+    let changes = users.watch(..)?;
+    store.set("key1".to_owned(), "root".to_owned())?;
+    users.set("key1".to_owned(), "user".to_owned())?;
+    let received: Vec<Change> = changes.try_iter().collect();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].value, Some("user".to_owned()));
+    Ok(())
+}
+
+// A watcher that does not keep up is disconnected instead of slowing down
+// writers.
+#[test]
+fn lagging_watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let changes = store.watch(..)?;
+    for i in 0..2000 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    assert!(changes.try_iter().count() < 2000);
+    assert_eq!(changes.try_recv(), Err(TryRecvError::Disconnected));
+    Ok(())
+}